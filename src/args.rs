@@ -0,0 +1,95 @@
+// Command-line arguments. Each option overrides the environment variable or
+// configuration parameter it stands for, and takes its value either as the
+// next argument or after an equals sign: `--port 6380` or `--port=6380`.
+
+pub const USAGE: &str = "\
+Usage: redis [OPTIONS]
+
+Options:
+  --port PORT          Port to listen on (default: 6379, or REDIS_PORT)
+  --bind ADDRESS       Address to listen on (default: 127.0.0.1), as bind
+  --password PASSWORD  Password clients must AUTH with, as requirepass
+  --maxmemory BYTES    Memory limit, units like 100mb accepted, as maxmemory
+  --help               Show this help message
+  --version            Show the version";
+
+// Options given on the command line, None for those left out
+#[derive(Default, PartialEq, Debug)]
+pub struct Options {
+    pub port: Option<u16>,
+    pub bind: Option<String>,
+    pub password: Option<String>,
+    pub maxmemory: Option<String>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Action {
+    Run(Options),
+    Help,
+    Version,
+}
+
+// Parse the arguments after the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Action, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        match name.as_str() {
+            "--help" | "-h" => return Ok(Action::Help),
+            "--version" | "-v" => return Ok(Action::Version),
+            "--port" | "--bind" | "--password" | "--maxmemory" => {}
+            _ => return Err(format!("Unknown option '{}'", name)),
+        }
+        
+        let Some(value) = inline.or_else(|| args.next()) else {
+            return Err(format!("{} requires a value", name));
+        };
+        match name.as_str() {
+            "--port" => {
+                let port = value.parse().map_err(|_| format!("Invalid port '{}'", value))?;
+                options.port = Some(port);
+            }
+            "--bind" => options.bind = Some(value),
+            "--password" => options.password = Some(value),
+            _ => options.maxmemory = Some(value),
+        }
+    }
+    Ok(Action::Run(options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn parse_args(args: &[&str]) -> Result<Action, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+    
+    #[test]
+    fn options_take_separate_or_inline_values() {
+        let action = parse_args(&["--port", "6380", "--bind=0.0.0.0", "--password", "a=b", "--maxmemory=100mb"]);
+        assert_eq!(action, Ok(Action::Run(Options {
+            port: Some(6380),
+            bind: Some("0.0.0.0".to_string()),
+            password: Some("a=b".to_string()),
+            maxmemory: Some("100mb".to_string()),
+        })));
+        assert_eq!(parse_args(&[]), Ok(Action::Run(Options::default())));
+        
+        // Help and version are answered as soon as they come up
+        assert_eq!(parse_args(&["--port", "6380", "--help"]), Ok(Action::Help));
+        assert_eq!(parse_args(&["--version", "--bogus"]), Ok(Action::Version));
+    }
+    
+    #[test]
+    fn bad_arguments_are_refused() {
+        assert_eq!(parse_args(&["--port"]), Err("--port requires a value".to_string()));
+        assert_eq!(parse_args(&["--port", "70000"]), Err("Invalid port '70000'".to_string()));
+        assert_eq!(parse_args(&["--daemonize"]), Err("Unknown option '--daemonize'".to_string()));
+        assert_eq!(parse_args(&["6380"]), Err("Unknown option '6380'".to_string()));
+    }
+}
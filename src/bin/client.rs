@@ -17,17 +17,21 @@ fn main() -> Result<(), Box<dyn Error>> {
     let stream = TcpStream::connect(addr)?;
     
     println!("Connected! Type Redis commands or 'exit' to quit.");
-    println!("Available commands: GET, SET, DEL, EXISTS, EXPIRE, TTL, KEYS, FLUSHALL, PING, HELP");
+    println!("Available commands: GET, GETDEL, GETEX, SET, SETEX, PSETEX, GETRANGE, SETRANGE, APPEND, SETBIT, GETBIT, BITCOUNT, PFADD, PFCOUNT, LPUSH, RPUSH, LPUSHX, RPUSHX, LPOP, RPOP, BLPOP, BRPOP, LMOVE, RPOPLPUSH, LLEN, LRANGE, LINDEX, LSET, LINSERT, LREM, LPOS, LTRIM, HSET, HGET, HMGET, HSETNX, HDEL, HGETALL, HEXISTS, HKEYS, HVALS, HLEN, HINCRBY, HINCRBYFLOAT, SADD, SREM, SMEMBERS, SISMEMBER, SCARD, SPOP, SRANDMEMBER, SMOVE, SINTER, SUNION, SDIFF, SINTERSTORE, SUNIONSTORE, SDIFFSTORE, SORT, ZADD, ZSCORE, ZRANGE, ZREVRANGE, ZRANK, ZREVRANK, ZRANGEBYSCORE, ZCOUNT, ZREM, ZREMRANGEBYSCORE, ZREMRANGEBYRANK, ZINCRBY, ZCARD, GEOADD, GEOSEARCH, DEL, UNLINK, EXISTS, TOUCH, TYPE, OBJECT, COPY, DUMP, RESTORE, EXPIRE, EXPIREAT, PEXPIREAT, TTL, PTTL, KEYS, RANDOMKEY, SCAN, HSCAN, SSCAN, ZSCAN, SELECT, FLUSHALL, FLUSHDB, MULTI, EXEC, DISCARD, WATCH, UNWATCH, RESET, QUIT, SUBSCRIBE, UNSUBSCRIBE, PSUBSCRIBE, PUNSUBSCRIBE, PUBLISH, INFO, DBSIZE, CLIENT, CONFIG, SLOWLOG, LATENCY, DEBUG, MONITOR, SAVE, BGSAVE, LASTSAVE, REPLICAOF, SYNC, WAIT, EVAL, SCRIPT, FUNCTION, FCALL, COMMAND, SHUTDOWN, PING, ECHO, TIME, LOLWUT, AUTH, HELLO, HELP");
     println!("Examples:");
     println!("  SET key value");
     println!("  SET key value EX 10  (expire in 10 seconds)");
+    println!("  SET key value NX GET  (set only if missing, returning the old value)");
     println!("  GET key");
     println!("  EXPIRE key 30");
     println!("  TTL key");
     println!("  KEYS *");
+    println!("  SCAN 0 MATCH user:* COUNT 100");
     println!("  DEL key");
     println!("  EXISTS key");
     println!("  FLUSHALL");
+    println!("  CONFIG GET *");
+    println!("  CONFIG SET hz 10");
     
     let stdin = io::stdin();
     let mut reader = stdin.lock();
@@ -48,8 +52,12 @@ fn main() -> Result<(), Box<dyn Error>> {
         
         let trimmed_input = input.trim();
         
-        // Check if user wants to exit
-        if trimmed_input.to_lowercase() == "exit" {
+        // Check if user wants to exit, and let the server know
+        if matches!(trimmed_input.to_lowercase().as_str(), "exit" | "quit") {
+            stream_writer.write_all(b"QUIT\n")?;
+            stream_writer.flush()?;
+            response.clear();
+            stream_reader.read_line(&mut response)?;
             println!("Goodbye!");
             break;
         }
@@ -0,0 +1,136 @@
+// Clients blocked in BLPOP or BRPOP, by the keys they wait on. Any change to
+// one of those keys wakes its clients to try their pop again, longest waiting
+// first; a client that finds nothing to pop just waits on. Every client has
+// its own Notify, so a wakeup that comes before it starts waiting is kept.
+// CLIENT UNBLOCK wakes a client the same way, telling it to stop waiting.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+// How CLIENT UNBLOCK ends a client's wait: as if it timed out, or with an error
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Unblock {
+    Timeout,
+    Error,
+}
+
+struct Blocked {
+    db: usize,
+    keys: Vec<String>,
+    woken: Arc<Notify>,
+    // Set by CLIENT UNBLOCK
+    unblocked: Option<Unblock>,
+}
+
+#[derive(Default)]
+pub struct Blocking {
+    // By client ID
+    clients: HashMap<u64, Blocked>,
+    // IDs of the clients waiting on each key of each database, in the order they blocked
+    keys: HashMap<(usize, String), Vec<u64>>,
+}
+
+impl Blocking {
+    // The number of blocked clients, for INFO
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+    
+    pub fn block(&mut self, client: u64, db: usize, keys: &[&str], woken: Arc<Notify>) {
+        self.unblock(client);
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        for key in &keys {
+            let waiting = self.keys.entry((db, key.clone())).or_default();
+            // A key named twice only needs one wakeup
+            if !waiting.contains(&client) {
+                waiting.push(client);
+            }
+        }
+        self.clients.insert(client, Blocked { db, keys, woken, unblocked: None });
+    }
+    
+    pub fn unblock(&mut self, client: u64) {
+        let Some(blocked) = self.clients.remove(&client) else {
+            return;
+        };
+        for key in blocked.keys {
+            let slot = (blocked.db, key);
+            if let Some(waiting) = self.keys.get_mut(&slot) {
+                waiting.retain(|&waiting| waiting != client);
+                if waiting.is_empty() {
+                    self.keys.remove(&slot);
+                }
+            }
+        }
+    }
+    
+    // Make a blocked client stop waiting, reporting whether it was blocked.
+    // It leaves the registry once it wakes and sees how it was unblocked.
+    pub fn force_unblock(&mut self, client: u64, how: Unblock) -> bool {
+        match self.clients.get_mut(&client) {
+            Some(blocked) if blocked.unblocked.is_none() => {
+                blocked.unblocked = Some(how);
+                blocked.woken.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+    
+    // How the client was unblocked by CLIENT UNBLOCK, if it was
+    pub fn unblocked(&self, client: u64) -> Option<Unblock> {
+        self.clients.get(&client).and_then(|blocked| blocked.unblocked)
+    }
+    
+    // Wake the clients waiting on a key that changed
+    pub fn wake(&self, db: usize, key: &str) {
+        let Some(waiting) = self.keys.get(&(db, key.to_string())) else {
+            return;
+        };
+        for client in waiting {
+            self.clients[client].woken.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn changed_keys_wake_their_clients() {
+        let mut blocking = Blocking::default();
+        let (first, second) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        blocking.block(1, 0, &["jobs", "jobs", "urgent"], first.clone());
+        blocking.block(2, 0, &["jobs"], second.clone());
+        assert_eq!(blocking.len(), 2);
+        
+        // Keys are per database
+        assert!(!blocking.keys.contains_key(&(1, "urgent".to_string())));
+        blocking.wake(0, "urgent");
+        first.notified().await;
+        
+        blocking.wake(0, "jobs");
+        first.notified().await;
+        second.notified().await;
+        
+        // Unblocking by hand works once per wait
+        assert!(blocking.force_unblock(2, Unblock::Error));
+        assert!(!blocking.force_unblock(2, Unblock::Timeout));
+        assert!(!blocking.force_unblock(3, Unblock::Timeout));
+        second.notified().await;
+        assert_eq!(blocking.unblocked(2), Some(Unblock::Error));
+        assert_eq!(blocking.unblocked(1), None);
+        
+        blocking.unblock(1);
+        blocking.unblock(2);
+        assert!(blocking.is_empty());
+        assert!(blocking.keys.is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+// The connected clients, as CLIENT LIST shows them and CLIENT KILL finds
+// them. Each connection keeps its own state in its session; the registry
+// holds a copy of what CLIENT LIST reports, updated by the commands that
+// change it, and a way to tell the connection to close.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::sync::Notify;
+
+pub struct Client {
+    addr: String,
+    connected_at: Instant,
+    pub name: Option<String>,
+    pub db: usize,
+    // The keys it is blocked on, in a BLPOP or BRPOP
+    pub blocked: Vec<String>,
+    // Notified to make the connection close once its current command is done
+    kill: Arc<Notify>,
+}
+
+#[derive(Default)]
+pub struct Clients {
+    // By ID, which is also the order the clients connected in
+    clients: BTreeMap<u64, Client>,
+}
+
+impl Clients {
+    // Register a new connection, returning what it waits on to be killed
+    pub fn add(&mut self, id: u64, addr: &str) -> Arc<Notify> {
+        let kill = Arc::new(Notify::new());
+        self.clients.insert(id, Client {
+            addr: addr.to_string(),
+            connected_at: Instant::now(),
+            name: None,
+            db: 0,
+            blocked: Vec::new(),
+            kill: kill.clone(),
+        });
+        kill
+    }
+    
+    pub fn remove(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+    
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Client> {
+        self.clients.get_mut(&id)
+    }
+    
+    // One line per client, oldest connection first
+    pub fn list(&self) -> Vec<String> {
+        self.clients.iter()
+            .map(|(id, client)| {
+                format!(
+                    "id={} addr={} name={} age={} db={} blocked={}",
+                    id,
+                    client.addr,
+                    client.name.as_deref().unwrap_or(""),
+                    client.connected_at.elapsed().as_secs(),
+                    client.db,
+                    client.blocked.join(","),
+                )
+            })
+            .collect()
+    }
+    
+    // Close the connections with the given ID or address, returning how many.
+    // They leave the registry right away, though each only closes once the
+    // command it is running has been answered.
+    pub fn kill(&mut self, id: Option<u64>, addr: Option<&str>) -> usize {
+        let killed: Vec<u64> = self.clients.iter()
+            .filter(|(client_id, client)| {
+                id.is_none_or(|id| **client_id == id) && addr.is_none_or(|addr| client.addr == addr)
+            })
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for id in &killed {
+            if let Some(client) = self.clients.remove(id) {
+                // Kept until the connection next waits, if it isn't waiting now
+                client.kill.notify_one();
+            }
+        }
+        killed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn kills_by_id_or_address() {
+        let mut clients = Clients::default();
+        let first = clients.add(1, "127.0.0.1:5000");
+        clients.add(2, "127.0.0.1:5001");
+        clients.add(3, "127.0.0.1:5002");
+        clients.get_mut(2).unwrap().name = Some("worker".to_string());
+        clients.get_mut(3).unwrap().db = 4;
+        clients.get_mut(3).unwrap().blocked = vec!["jobs".to_string(), "urgent".to_string()];
+        
+        let list = clients.list();
+        assert_eq!(list, [
+            "id=1 addr=127.0.0.1:5000 name= age=0 db=0 blocked=",
+            "id=2 addr=127.0.0.1:5001 name=worker age=0 db=0 blocked=",
+            "id=3 addr=127.0.0.1:5002 name= age=0 db=4 blocked=jobs,urgent",
+        ]);
+        
+        assert_eq!(clients.kill(Some(1), None), 1);
+        assert_eq!(clients.kill(Some(1), None), 0);
+        assert_eq!(clients.kill(None, Some("127.0.0.1:5002")), 1);
+        assert_eq!(clients.kill(Some(2), Some("127.0.0.1:5000")), 0);
+        assert_eq!(clients.list().len(), 1);
+        
+        // The signal is kept for a connection that wasn't waiting yet
+        first.notified().await;
+    }
+}
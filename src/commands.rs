@@ -0,0 +1,305 @@
+use crate::protocol::Reply;
+
+// Properties of a command, as COMMAND reports them. WRITE decides what is
+// logged to the append-only file, DENY_OOM what maxmemory refuses, NO_AUTH
+// what a client may run before it authenticates and NO_SCRIPT what a script
+// can't run; READONLY and ADMIN are informational only.
+pub const WRITE: u8 = 1;
+// Can grow memory use, so it is refused once over maxmemory
+pub const DENY_OOM: u8 = 1 << 1;
+pub const READONLY: u8 = 1 << 2;
+// Server administration rather than data access
+pub const ADMIN: u8 = 1 << 3;
+pub const NO_AUTH: u8 = 1 << 4;
+// Changes the connection's state in a way a script has no use for, or would
+// hold up the server
+pub const NO_SCRIPT: u8 = 1 << 5;
+
+const FLAG_NAMES: [(u8, &str); 6] = [
+    (WRITE, "write"), (DENY_OOM, "denyoom"), (READONLY, "readonly"), (ADMIN, "admin"), (NO_AUTH, "no_auth"),
+    (NO_SCRIPT, "noscript"),
+];
+
+// A command the server understands
+pub struct CommandSpec {
+    pub name: &'static str,
+    // Number of arguments including the command name, or minus the minimum
+    // number for commands taking a variable number, as in Redis. The
+    // dispatcher refuses calls with another number before running them.
+    pub arity: i64,
+    pub flags: u8,
+    // Positions of the first and last key argument and the step between keys,
+    // all 0 for commands that take no keys. A negative last key counts from the end.
+    pub first_key: i64,
+    pub last_key: i64,
+    pub key_step: i64,
+}
+
+impl CommandSpec {
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+    
+    // Whether a call with `argc` arguments, counting the name, fits the arity
+    pub fn accepts(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        if self.arity >= 0 { argc == self.arity } else { argc >= -self.arity }
+    }
+    
+    // The key arguments of a call to the command, found by its key positions
+    pub fn keys<'a>(&self, parts: &[&'a str]) -> Vec<&'a str> {
+        if self.first_key == 0 {
+            return Vec::new();
+        }
+        let len = parts.len() as i64;
+        let last = if self.last_key < 0 { len + self.last_key } else { self.last_key.min(len - 1) };
+        (self.first_key..=last)
+            .step_by(self.key_step as usize)
+            .map(|position| parts[position as usize])
+            .collect()
+    }
+    
+    // Whether the argument at `position` in a call with `argc` arguments is a
+    // value the command stores or sends back as the bytes it was sent. Keys,
+    // members, fields and options are handled as text, so only values may
+    // be bytes that aren't valid UTF-8.
+    pub fn is_value(&self, position: usize, argc: usize) -> bool {
+        // First and last value position and the step between them, as for keys
+        let (first, last, step): (i64, i64, i64) = match self.name {
+            "ECHO" | "PING" => (1, 1, 1),
+            "SET" | "APPEND" | "LPOS" | "PUBLISH" => (2, 2, 1),
+            "SETEX" | "PSETEX" | "SETRANGE" | "LSET" | "LREM" | "HSETNX" => (3, 3, 1),
+            "LINSERT" => (3, 4, 1),
+            "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" | "PFADD" => (2, -1, 1),
+            "HSET" => (3, -1, 2),
+            _ => return false,
+        };
+        let position = position as i64;
+        let last = if last < 0 { argc as i64 + last } else { last };
+        position >= first && position <= last && (position - first) % step == 0
+    }
+    
+    // The command's entry in COMMAND and COMMAND INFO replies
+    pub fn info(&self) -> Reply {
+        let flags = FLAG_NAMES.iter()
+            .filter(|(flag, _)| self.has(*flag))
+            .map(|(_, name)| Reply::Simple(name.to_string()))
+            .collect();
+        
+        Reply::Array(vec![
+            Reply::bulk(self.name.to_lowercase()),
+            Reply::Integer(self.arity),
+            Reply::Array(flags),
+            Reply::Integer(self.first_key),
+            Reply::Integer(self.last_key),
+            Reply::Integer(self.key_step),
+        ])
+    }
+}
+
+const fn command(name: &'static str, arity: i64, flags: u8, keys: (i64, i64, i64)) -> CommandSpec {
+    CommandSpec { name, arity, flags, first_key: keys.0, last_key: keys.1, key_step: keys.2 }
+}
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+
+// Every command the server understands, in the order HELP lists them
+pub const COMMANDS: &[CommandSpec] = &[
+    command("GET", 2, READONLY, ONE_KEY),
+    command("GETDEL", 2, WRITE, ONE_KEY),
+    command("GETEX", -2, WRITE, ONE_KEY),
+    command("SET", -3, WRITE | DENY_OOM, ONE_KEY),
+    command("SETEX", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("PSETEX", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("GETRANGE", 4, READONLY, ONE_KEY),
+    command("SETRANGE", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("APPEND", 3, WRITE | DENY_OOM, ONE_KEY),
+    command("SETBIT", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("GETBIT", 3, READONLY, ONE_KEY),
+    command("BITCOUNT", -2, READONLY, ONE_KEY),
+    command("PFADD", -2, WRITE | DENY_OOM, ONE_KEY),
+    command("PFCOUNT", -2, READONLY, (1, -1, 1)),
+    command("LPUSH", -3, WRITE | DENY_OOM, ONE_KEY),
+    command("RPUSH", -3, WRITE | DENY_OOM, ONE_KEY),
+    command("LPUSHX", -3, WRITE | DENY_OOM, ONE_KEY),
+    command("RPUSHX", -3, WRITE | DENY_OOM, ONE_KEY),
+    command("LPOP", -2, WRITE, ONE_KEY),
+    command("RPOP", -2, WRITE, ONE_KEY),
+    command("BLPOP", -3, WRITE, (1, -2, 1)),
+    command("BRPOP", -3, WRITE, (1, -2, 1)),
+    command("LMOVE", 5, WRITE | DENY_OOM, (1, 2, 1)),
+    command("RPOPLPUSH", 3, WRITE | DENY_OOM, (1, 2, 1)),
+    command("LLEN", 2, READONLY, ONE_KEY),
+    command("LRANGE", 4, READONLY, ONE_KEY),
+    command("LINDEX", 3, READONLY, ONE_KEY),
+    command("LSET", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("LINSERT", 5, WRITE | DENY_OOM, ONE_KEY),
+    command("LREM", 4, WRITE, ONE_KEY),
+    command("LPOS", -3, READONLY, ONE_KEY),
+    command("LTRIM", 4, WRITE, ONE_KEY),
+    command("HSET", -4, WRITE | DENY_OOM, ONE_KEY),
+    command("HGET", 3, READONLY, ONE_KEY),
+    command("HMGET", -3, READONLY, ONE_KEY),
+    command("HSETNX", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("HDEL", -3, WRITE, ONE_KEY),
+    command("HGETALL", 2, READONLY, ONE_KEY),
+    command("HEXISTS", 3, READONLY, ONE_KEY),
+    command("HKEYS", 2, READONLY, ONE_KEY),
+    command("HVALS", 2, READONLY, ONE_KEY),
+    command("HLEN", 2, READONLY, ONE_KEY),
+    command("HINCRBY", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("HINCRBYFLOAT", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("SADD", -3, WRITE | DENY_OOM, ONE_KEY),
+    command("SREM", -3, WRITE, ONE_KEY),
+    command("SMEMBERS", 2, READONLY, ONE_KEY),
+    command("SORT", -2, READONLY, ONE_KEY),
+    command("SISMEMBER", 3, READONLY, ONE_KEY),
+    command("SCARD", 2, READONLY, ONE_KEY),
+    command("SPOP", -2, WRITE, ONE_KEY),
+    command("SRANDMEMBER", -2, READONLY, ONE_KEY),
+    command("SMOVE", 4, WRITE, (1, 2, 1)),
+    command("SINTER", -2, READONLY, (1, -1, 1)),
+    command("SUNION", -2, READONLY, (1, -1, 1)),
+    command("SDIFF", -2, READONLY, (1, -1, 1)),
+    command("SINTERSTORE", -3, WRITE | DENY_OOM, (1, -1, 1)),
+    command("SUNIONSTORE", -3, WRITE | DENY_OOM, (1, -1, 1)),
+    command("SDIFFSTORE", -3, WRITE | DENY_OOM, (1, -1, 1)),
+    command("ZADD", -4, WRITE | DENY_OOM, ONE_KEY),
+    command("ZSCORE", 3, READONLY, ONE_KEY),
+    command("ZRANGE", -4, READONLY, ONE_KEY),
+    command("ZREVRANGE", -4, READONLY, ONE_KEY),
+    command("ZRANK", 3, READONLY, ONE_KEY),
+    command("ZREVRANK", 3, READONLY, ONE_KEY),
+    command("ZRANGEBYSCORE", -4, READONLY, ONE_KEY),
+    command("ZCOUNT", 4, READONLY, ONE_KEY),
+    command("ZREM", -3, WRITE, ONE_KEY),
+    command("ZREMRANGEBYSCORE", 4, WRITE, ONE_KEY),
+    command("ZREMRANGEBYRANK", 4, WRITE, ONE_KEY),
+    command("ZINCRBY", 4, WRITE | DENY_OOM, ONE_KEY),
+    command("ZCARD", 2, READONLY, ONE_KEY),
+    command("GEOADD", -5, WRITE | DENY_OOM, ONE_KEY),
+    command("GEOSEARCH", -7, READONLY, ONE_KEY),
+    command("DEL", -2, WRITE, (1, -1, 1)),
+    command("UNLINK", -2, WRITE, (1, -1, 1)),
+    command("EXISTS", -2, READONLY, (1, -1, 1)),
+    command("TOUCH", -2, READONLY, (1, -1, 1)),
+    command("TYPE", 2, READONLY, ONE_KEY),
+    command("OBJECT", -2, READONLY, (2, 2, 1)),
+    command("COPY", -3, WRITE | DENY_OOM, (1, 2, 1)),
+    command("DUMP", 2, READONLY, ONE_KEY),
+    command("RESTORE", -4, WRITE | DENY_OOM, ONE_KEY),
+    command("EXPIRE", -3, WRITE, ONE_KEY),
+    command("EXPIREAT", 3, WRITE, ONE_KEY),
+    command("PEXPIREAT", 3, WRITE, ONE_KEY),
+    command("TTL", 2, READONLY, ONE_KEY),
+    command("PTTL", 2, READONLY, ONE_KEY),
+    command("KEYS", 2, READONLY, NO_KEYS),
+    command("RANDOMKEY", 1, READONLY, NO_KEYS),
+    command("SCAN", -2, READONLY, NO_KEYS),
+    command("HSCAN", -3, READONLY, ONE_KEY),
+    command("SSCAN", -3, READONLY, ONE_KEY),
+    command("ZSCAN", -3, READONLY, ONE_KEY),
+    command("SELECT", 2, 0, NO_KEYS),
+    command("FLUSHALL", -1, WRITE, NO_KEYS),
+    command("FLUSHDB", -1, WRITE, NO_KEYS),
+    command("MULTI", 1, NO_SCRIPT, NO_KEYS),
+    command("EXEC", 1, NO_SCRIPT, NO_KEYS),
+    command("DISCARD", 1, NO_SCRIPT, NO_KEYS),
+    command("WATCH", -2, NO_SCRIPT, (1, -1, 1)),
+    command("UNWATCH", 1, NO_SCRIPT, NO_KEYS),
+    command("RESET", 1, NO_AUTH | NO_SCRIPT, NO_KEYS),
+    command("QUIT", 1, NO_AUTH | NO_SCRIPT, NO_KEYS),
+    command("SUBSCRIBE", -2, NO_SCRIPT, NO_KEYS),
+    command("UNSUBSCRIBE", -1, NO_SCRIPT, NO_KEYS),
+    command("PSUBSCRIBE", -2, NO_SCRIPT, NO_KEYS),
+    command("PUNSUBSCRIBE", -1, NO_SCRIPT, NO_KEYS),
+    command("PUBLISH", 3, 0, NO_KEYS),
+    command("INFO", -1, 0, NO_KEYS),
+    command("DBSIZE", 1, READONLY, NO_KEYS),
+    command("CLIENT", -2, NO_SCRIPT, NO_KEYS),
+    command("CONFIG", -2, ADMIN, NO_KEYS),
+    command("SLOWLOG", -2, ADMIN, NO_KEYS),
+    command("LATENCY", -2, ADMIN, NO_KEYS),
+    command("DEBUG", -2, ADMIN | NO_SCRIPT, NO_KEYS),
+    command("MONITOR", 1, ADMIN | NO_SCRIPT, NO_KEYS),
+    command("SAVE", 1, ADMIN | NO_SCRIPT, NO_KEYS),
+    command("BGSAVE", -1, ADMIN | NO_SCRIPT, NO_KEYS),
+    command("LASTSAVE", 1, 0, NO_KEYS),
+    command("REPLICAOF", 3, ADMIN | NO_SCRIPT, NO_KEYS),
+    command("SYNC", 1, ADMIN | NO_SCRIPT, NO_KEYS),
+    command("WAIT", 3, NO_SCRIPT, NO_KEYS),
+    command("EVAL", -3, NO_SCRIPT, NO_KEYS),
+    command("SCRIPT", -2, NO_SCRIPT, NO_KEYS),
+    command("FUNCTION", -2, WRITE | NO_SCRIPT, NO_KEYS),
+    command("FCALL", -3, NO_SCRIPT, NO_KEYS),
+    command("COMMAND", -1, 0, NO_KEYS),
+    command("SHUTDOWN", -1, ADMIN | NO_SCRIPT, NO_KEYS),
+    command("PING", -1, 0, NO_KEYS),
+    command("ECHO", 2, 0, NO_KEYS),
+    command("TIME", 1, 0, NO_KEYS),
+    command("LOLWUT", -1, 0, NO_KEYS),
+    command("AUTH", -2, NO_AUTH | NO_SCRIPT, NO_KEYS),
+    command("HELLO", -1, NO_AUTH | NO_SCRIPT, NO_KEYS),
+    command("HELP", 1, 0, NO_KEYS),
+];
+
+// Look up a command by name, ignoring case
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn lookup_ignores_case() {
+        assert_eq!(lookup("get").unwrap().name, "GET");
+        assert!(lookup("SET").unwrap().has(WRITE));
+        assert!(!lookup("DEL").unwrap().has(DENY_OOM));
+        assert!(lookup("NOSUCHCOMMAND").is_none());
+    }
+    
+    #[test]
+    fn arity_counts_the_name() {
+        let get = lookup("GET").unwrap();
+        assert!(!get.accepts(1));
+        assert!(get.accepts(2));
+        assert!(!get.accepts(3));
+        
+        let del = lookup("DEL").unwrap();
+        assert!(!del.accepts(1));
+        assert!(del.accepts(2));
+        assert!(del.accepts(100));
+    }
+    
+    #[test]
+    fn keys_follow_positions() {
+        assert_eq!(lookup("GET").unwrap().keys(&["GET", "key"]), ["key"]);
+        assert_eq!(lookup("EXISTS").unwrap().keys(&["EXISTS", "a", "b", "c"]), ["a", "b", "c"]);
+        assert_eq!(lookup("SMOVE").unwrap().keys(&["SMOVE", "from", "to", "member"]), ["from", "to"]);
+        assert!(lookup("PING").unwrap().keys(&["PING"]).is_empty());
+        // Too few arguments only give the keys that are there
+        assert!(lookup("GET").unwrap().keys(&["GET"]).is_empty());
+    }
+    
+    #[test]
+    fn values_follow_positions() {
+        let set = lookup("SET").unwrap();
+        assert!(!set.is_value(1, 3));
+        assert!(set.is_value(2, 3));
+        assert!(!set.is_value(3, 5));
+        
+        let hset = lookup("HSET").unwrap();
+        assert_eq!((0..6).filter(|&position| hset.is_value(position, 6)).collect::<Vec<_>>(), [3, 5]);
+        assert!(!lookup("GET").unwrap().is_value(1, 2));
+    }
+    
+    #[test]
+    fn names_are_unique() {
+        for (i, spec) in COMMANDS.iter().enumerate() {
+            assert!(COMMANDS[i + 1..].iter().all(|other| other.name != spec.name), "{}", spec.name);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+// Hashes and sets that start out compact, like Redis's listpack and intset
+// encodings: a plain vector searched one element at a time, which for a few
+// small elements is smaller and about as fast as a hash table. The keyspace
+// switches one to a hash table once a change takes it past the limits in the
+// configuration; like Redis, it never switches back.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::config::EncodingLimits;
+use crate::is_canonical_int;
+
+#[derive(Clone, Debug)]
+enum Fields {
+    // Field-value pairs in the order they were added
+    ListPack(Vec<(String, Vec<u8>)>),
+    HashTable(HashMap<String, Vec<u8>>),
+}
+
+// Field names to their values
+#[derive(Clone, Debug)]
+pub struct Hash(Fields);
+
+impl Default for Hash {
+    fn default() -> Self {
+        Hash(Fields::ListPack(Vec::new()))
+    }
+}
+
+impl Hash {
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            Fields::ListPack(pairs) => pairs.len(),
+            Fields::HashTable(map) => map.len(),
+        }
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    pub fn get(&self, field: &str) -> Option<&Vec<u8>> {
+        match &self.0 {
+            Fields::ListPack(pairs) => pairs.iter().find(|(name, _)| name == field).map(|(_, value)| value),
+            Fields::HashTable(map) => map.get(field),
+        }
+    }
+    
+    pub fn contains_key(&self, field: &str) -> bool {
+        self.get(field).is_some()
+    }
+    
+    // Set a field, returning its old value if it had one
+    pub fn insert(&mut self, field: String, value: Vec<u8>) -> Option<Vec<u8>> {
+        match &mut self.0 {
+            Fields::ListPack(pairs) => match pairs.iter_mut().find(|(name, _)| *name == field) {
+                Some((_, old)) => Some(std::mem::replace(old, value)),
+                None => {
+                    pairs.push((field, value));
+                    None
+                }
+            },
+            Fields::HashTable(map) => map.insert(field, value),
+        }
+    }
+    
+    pub fn remove(&mut self, field: &str) -> Option<Vec<u8>> {
+        match &mut self.0 {
+            Fields::ListPack(pairs) => {
+                let i = pairs.iter().position(|(name, _)| name == field)?;
+                Some(pairs.remove(i).1)
+            }
+            Fields::HashTable(map) => map.remove(field),
+        }
+    }
+    
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<u8>)> {
+        let (pairs, map) = match &self.0 {
+            Fields::ListPack(pairs) => (Some(pairs), None),
+            Fields::HashTable(map) => (None, Some(map)),
+        };
+        pairs.into_iter().flatten().map(|(field, value)| (field, value)).chain(map.into_iter().flatten())
+    }
+    
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(field, _)| field)
+    }
+    
+    pub fn values(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.iter().map(|(_, value)| value)
+    }
+    
+    // The encoding as OBJECT ENCODING reports it
+    pub fn encoding(&self) -> &'static str {
+        match &self.0 {
+            Fields::ListPack(_) => "listpack",
+            Fields::HashTable(_) => "hashtable",
+        }
+    }
+    
+    // Switch to a hash table if the hash has more fields, or a longer field
+    // or value, than a listpack may hold
+    pub fn fit(&mut self, limits: &EncodingLimits) {
+        let Fields::ListPack(pairs) = &mut self.0 else {
+            return;
+        };
+        let max_len = limits.hash_max_listpack_value;
+        let fits = pairs.len() <= limits.hash_max_listpack_entries
+            && pairs.iter().all(|(field, value)| field.len() <= max_len && value.len() <= max_len);
+        if !fits {
+            self.0 = Fields::HashTable(std::mem::take(pairs).into_iter().collect());
+        }
+    }
+}
+
+// Equal with the same fields and values, however each is encoded
+impl PartialEq for Hash {
+    fn eq(&self, other: &Hash) -> bool {
+        self.len() == other.len() && self.iter().all(|(field, value)| other.get(field) == Some(value))
+    }
+}
+
+// Built compact, for the keyspace to switch over if it's too big
+impl From<HashMap<String, Vec<u8>>> for Hash {
+    fn from(map: HashMap<String, Vec<u8>>) -> Self {
+        Hash(Fields::ListPack(map.into_iter().collect()))
+    }
+}
+
+impl FromIterator<(String, Vec<u8>)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (String, Vec<u8>)>>(pairs: I) -> Self {
+        Hash::from(pairs.into_iter().collect::<HashMap<_, _>>())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Members {
+    // Only integers, in the order they were added
+    IntSet(Vec<String>),
+    ListPack(Vec<String>),
+    HashTable(HashSet<String>),
+}
+
+// Distinct members in no particular order
+#[derive(Clone, Debug)]
+pub struct Set(Members);
+
+impl Default for Set {
+    fn default() -> Self {
+        Set(Members::IntSet(Vec::new()))
+    }
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            Members::IntSet(members) | Members::ListPack(members) => members.len(),
+            Members::HashTable(set) => set.len(),
+        }
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    pub fn contains(&self, member: &str) -> bool {
+        match &self.0 {
+            Members::IntSet(members) | Members::ListPack(members) => members.iter().any(|other| other == member),
+            Members::HashTable(set) => set.contains(member),
+        }
+    }
+    
+    // Add a member, reporting whether it is new
+    pub fn insert(&mut self, member: String) -> bool {
+        match &mut self.0 {
+            Members::IntSet(members) | Members::ListPack(members) => {
+                if members.contains(&member) {
+                    return false;
+                }
+                members.push(member);
+                true
+            }
+            Members::HashTable(set) => set.insert(member),
+        }
+    }
+    
+    // Remove a member, reporting whether it was there
+    pub fn remove(&mut self, member: &str) -> bool {
+        match &mut self.0 {
+            Members::IntSet(members) | Members::ListPack(members) => {
+                let Some(i) = members.iter().position(|other| other == member) else {
+                    return false;
+                };
+                members.remove(i);
+                true
+            }
+            Members::HashTable(set) => set.remove(member),
+        }
+    }
+    
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        let (members, set) = match &self.0 {
+            Members::IntSet(members) | Members::ListPack(members) => (Some(members), None),
+            Members::HashTable(set) => (None, Some(set)),
+        };
+        members.into_iter().flatten().chain(set.into_iter().flatten())
+    }
+    
+    // The encoding as OBJECT ENCODING reports it
+    pub fn encoding(&self) -> &'static str {
+        match &self.0 {
+            Members::IntSet(_) => "intset",
+            Members::ListPack(_) => "listpack",
+            Members::HashTable(_) => "hashtable",
+        }
+    }
+    
+    // Switch an intset that took a member other than an integer, or grew too
+    // big, to a listpack if the members fit one and to a hash table if not;
+    // and a listpack that grew too big to a hash table
+    pub fn fit(&mut self, limits: &EncodingLimits) {
+        let intset = matches!(self.0, Members::IntSet(_));
+        let (Members::IntSet(members) | Members::ListPack(members)) = &mut self.0 else {
+            return;
+        };
+        if intset && members.len() <= limits.set_max_intset_entries && members.iter().all(|member| is_canonical_int(member.as_bytes())) {
+            return;
+        }
+        let fits_listpack = members.len() <= limits.set_max_listpack_entries
+            && members.iter().all(|member| member.len() <= limits.set_max_listpack_value);
+        self.0 = if fits_listpack {
+            Members::ListPack(std::mem::take(members))
+        } else {
+            Members::HashTable(std::mem::take(members).into_iter().collect())
+        };
+    }
+}
+
+// Equal with the same members, however each is encoded
+impl PartialEq for Set {
+    fn eq(&self, other: &Set) -> bool {
+        self.len() == other.len() && self.iter().all(|member| other.contains(member))
+    }
+}
+
+// Built compact, for the keyspace to switch over if it's too big
+impl From<HashSet<String>> for Set {
+    fn from(set: HashSet<String>) -> Self {
+        Set(Members::IntSet(set.into_iter().collect()))
+    }
+}
+
+impl FromIterator<String> for Set {
+    fn from_iter<I: IntoIterator<Item = String>>(members: I) -> Self {
+        Set::from(members.into_iter().collect::<HashSet<_>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn hashes_outgrow_the_listpack() {
+        let limits = EncodingLimits { hash_max_listpack_entries: 2, hash_max_listpack_value: 4, ..EncodingLimits::default() };
+        let mut hash = Hash::default();
+        hash.insert("a".to_string(), b"1".to_vec());
+        hash.insert("b".to_string(), b"2".to_vec());
+        assert_eq!(hash.insert("a".to_string(), b"3".to_vec()), Some(b"1".to_vec()));
+        hash.fit(&limits);
+        assert_eq!(hash.encoding(), "listpack");
+        
+        // Too many fields make a hash table, which stays one once they're gone
+        hash.insert("c".to_string(), b"4".to_vec());
+        hash.fit(&limits);
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.remove("c"), Some(b"4".to_vec()));
+        hash.fit(&limits);
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash, Hash::from_iter([("a".to_string(), b"3".to_vec()), ("b".to_string(), b"2".to_vec())]));
+        
+        // As does a value that's too long
+        let mut hash = Hash::default();
+        hash.insert("a".to_string(), b"12345".to_vec());
+        hash.fit(&limits);
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.get("a"), Some(&b"12345".to_vec()));
+    }
+    
+    #[test]
+    fn sets_go_from_intset_to_listpack_to_hashtable() {
+        let limits = EncodingLimits { set_max_intset_entries: 3, set_max_listpack_entries: 2, ..EncodingLimits::default() };
+        let mut set = Set::default();
+        for member in ["1", "2", "3"] {
+            set.insert(member.to_string());
+        }
+        assert!(!set.insert("2".to_string()));
+        set.fit(&limits);
+        assert_eq!(set.encoding(), "intset");
+        
+        // Past the intset limit the set is too big for a listpack as well
+        set.insert("4".to_string());
+        set.fit(&limits);
+        assert_eq!(set.encoding(), "hashtable");
+        
+        let mut set = Set::default();
+        set.insert("1".to_string());
+        set.insert("apple".to_string());
+        set.fit(&limits);
+        assert_eq!(set.encoding(), "listpack");
+        assert!(set.remove("apple"));
+        set.fit(&limits);
+        assert_eq!(set.encoding(), "listpack");
+        set.insert("pear".to_string());
+        set.insert("plum".to_string());
+        set.fit(&limits);
+        assert_eq!(set.encoding(), "hashtable");
+        assert_eq!(set, Set::from_iter(["1", "pear", "plum"].map(String::from)));
+    }
+}
@@ -0,0 +1,652 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// When the append-only file is fsynced to disk (the appendfsync parameter)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AppendFsync {
+    // After every write, before the client gets its reply
+    Always,
+    // At most once per second, losing up to a second of writes on power loss
+    EverySec,
+    // Whenever the operating system decides to flush
+    No,
+}
+
+impl AppendFsync {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+    
+    fn as_str(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+// What to do when a write would take memory use past maxmemory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MaxmemoryPolicy {
+    // Refuse the write with an OOM error
+    NoEviction,
+    // Evict the least recently used keys until there is room
+    AllKeysLru,
+    // Evict the least frequently used keys until there is room
+    AllKeysLfu,
+}
+
+impl MaxmemoryPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "noeviction" => Some(MaxmemoryPolicy::NoEviction),
+            "allkeys-lru" => Some(MaxmemoryPolicy::AllKeysLru),
+            "allkeys-lfu" => Some(MaxmemoryPolicy::AllKeysLfu),
+            _ => None,
+        }
+    }
+    
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxmemoryPolicy::AllKeysLfu => "allkeys-lfu",
+        }
+    }
+}
+
+// When hashes, lists and sets leave their compact encodings
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EncodingLimits {
+    // Most fields a listpack hash holds, and the longest field or value
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    // Most members an intset holds
+    pub set_max_intset_entries: usize,
+    // Most members a listpack set holds, and the longest member
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    // Longest list element a listpack holds. It's set by DEBUG
+    // QUICKLIST-PACKED-THRESHOLD rather than a parameter, as in Redis.
+    pub list_packed_threshold: usize,
+}
+
+// Redis's defaults
+impl Default for EncodingLimits {
+    fn default() -> Self {
+        EncodingLimits {
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+            set_max_intset_entries: 512,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+            list_packed_threshold: 1 << 30,
+        }
+    }
+}
+
+// A save point: save a snapshot in the background once at least `changes`
+// writes have been made and `seconds` have passed since the last save
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl SavePoint {
+    // Parse save points such as "900 1 300 10", in pairs of seconds and
+    // changes. An empty value means none, so snapshots are only saved on demand.
+    fn parse_all(value: &str) -> Option<Vec<Self>> {
+        let numbers: Vec<u64> = value.split_whitespace().map(|n| n.parse().ok()).collect::<Option<_>>()?;
+        if !numbers.len().is_multiple_of(2) {
+            return None;
+        }
+        Some(numbers.chunks(2).map(|pair| SavePoint { seconds: pair[0], changes: pair[1] }).collect())
+    }
+    
+    fn format_all(points: &[Self]) -> String {
+        points.iter()
+            .map(|point| format!("{} {}", point.seconds, point.changes))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// Classes of keyspace events, by the letter notify-keyspace-events enables
+// them with: generic commands such as DEL and EXPIRE, string commands, list
+// commands, set commands, hash commands, sorted set commands, keys expiring and
+// keys evicted
+const EVENT_CLASSES: &str = "g$lshzxe";
+
+// Which keyspace events are published (the notify-keyspace-events parameter).
+// Events go to __keyspace@<db>__:<key> channels with K and to
+// __keyevent@<db>__:<event> channels with E, so without either nothing is
+// published whatever classes are enabled.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct KeyspaceEvents {
+    pub keyspace: bool,
+    pub keyevent: bool,
+    // Letters of the enabled classes, in EVENT_CLASSES order
+    classes: String,
+}
+
+impl KeyspaceEvents {
+    // Parse flags such as "Ex" or "KEA", where A stands for every class
+    fn parse(value: &str) -> Option<Self> {
+        let mut events = KeyspaceEvents::default();
+        let mut enabled = String::new();
+        for flag in value.chars() {
+            match flag {
+                'K' => events.keyspace = true,
+                'E' => events.keyevent = true,
+                'A' => enabled.push_str(EVENT_CLASSES),
+                flag if EVENT_CLASSES.contains(flag) => enabled.push(flag),
+                _ => return None,
+            }
+        }
+        events.classes = EVENT_CLASSES.chars().filter(|class| enabled.contains(*class)).collect();
+        Some(events)
+    }
+    
+    fn as_string(&self) -> String {
+        let mut flags = if self.classes == EVENT_CLASSES { "A".to_string() } else { self.classes.clone() };
+        if self.keyspace {
+            flags.push('K');
+        }
+        if self.keyevent {
+            flags.push('E');
+        }
+        flags
+    }
+    
+    // Whether events of this class are published to any channel
+    pub fn enabled(&self, class: char) -> bool {
+        (self.keyspace || self.keyevent) && self.classes.contains(class)
+    }
+}
+
+// Runtime-tunable server settings, exposed through CONFIG GET/SET
+pub struct Config {
+    // Memory limit in bytes (0 means no limit)
+    pub maxmemory: u64,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    // Keys of each database sampled for every eviction. More samples pick
+    // closer to the exact least recently or frequently used key, at more CPU.
+    pub maxmemory_samples: usize,
+    // Maximum number of simultaneously connected clients
+    pub maxclients: u64,
+    // Seconds a client may stay idle before it is disconnected (0 means never)
+    pub timeout: u64,
+    // Most times per second the expiration sweep runs. It only runs once a
+    // key is due, so this just batches keys expiring close together.
+    pub hz: u64,
+    // Number of databases clients can SELECT between
+    pub databases: usize,
+    // Directory the snapshot file is written to
+    pub dir: String,
+    // File name of the snapshot written by SAVE/BGSAVE and loaded on startup
+    pub dbfilename: String,
+    // When a snapshot is saved in the background without a SAVE or BGSAVE
+    pub save: Vec<SavePoint>,
+    // Whether every write command is logged to the append-only file
+    pub appendonly: bool,
+    // File name of the append-only file, inside `dir`
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    // Most commands a connection may have queued before their replies are
+    // written; the connection isn't read from again until they are
+    pub max_pipeline: usize,
+    pub notify_keyspace_events: KeyspaceEvents,
+    // Whether replies are sent right away rather than held back by Nagle's
+    // algorithm to be coalesced with later ones
+    pub tcp_nodelay: bool,
+    // Seconds a client connection may stay silent before the operating system
+    // checks the client is still there (0 means never)
+    pub tcp_keepalive: u64,
+    // Path of a Unix socket to accept clients on as well as TCP (empty means
+    // none)
+    pub unixsocket: String,
+    // Port to accept TLS clients on as well (0 means none), with the PEM
+    // files of the certificate chain and private key to present to them
+    pub tls_port: u16,
+    pub tls_cert_file: String,
+    pub tls_key_file: String,
+    // Milliseconds a command must take to be sampled by the latency monitor
+    // (0 means none are)
+    pub latency_monitor_threshold: u64,
+    // Microseconds a command must take to be added to the slow log (0 logs
+    // every command, a negative value none)
+    pub slowlog_log_slower_than: i64,
+    // Most entries the slow log keeps before dropping the oldest
+    pub slowlog_max_len: usize,
+    // Password clients must AUTH with before running other commands (empty
+    // means none is needed)
+    pub requirepass: String,
+    // Address the TCP listener binds to. Only the local machine can connect
+    // by default.
+    pub bind: IpAddr,
+    // Longest string in bytes, whether a bulk string a client sends or one a
+    // command grows
+    pub proto_max_bulk_len: usize,
+    // Most bytes of a command still arriving a connection may buffer before
+    // it is closed
+    pub client_query_buffer_limit: usize,
+    // Milliseconds a script may run before other clients are told the server
+    // is busy and can kill it
+    pub busy_reply_threshold: u64,
+    pub encoding_limits: EncodingLimits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::NoEviction,
+            maxmemory_samples: 5,
+            maxclients: 10000,
+            timeout: 0,
+            hz: 1,
+            databases: 16,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            // Redis's defaults: after an hour for any change, 5 minutes for
+            // 100 changes or a minute for 10000
+            save: vec![
+                SavePoint { seconds: 3600, changes: 1 },
+                SavePoint { seconds: 300, changes: 100 },
+                SavePoint { seconds: 60, changes: 10000 },
+            ],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            max_pipeline: 1024,
+            // Keyspace notifications cost CPU, so like Redis they are off by default
+            notify_keyspace_events: KeyspaceEvents::default(),
+            tcp_nodelay: true,
+            tcp_keepalive: 300,
+            unixsocket: String::new(),
+            tls_port: 0,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            latency_monitor_threshold: 0,
+            slowlog_log_slower_than: 10000,
+            slowlog_max_len: 128,
+            requirepass: String::new(),
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            proto_max_bulk_len: 512 << 20,
+            client_query_buffer_limit: 1 << 30,
+            busy_reply_threshold: 5000,
+            encoding_limits: EncodingLimits::default(),
+        }
+    }
+}
+
+// Names of all parameters, in the order CONFIG GET * reports them
+const PARAMETERS: [&str; 34] = [
+    "maxmemory", "maxmemory-policy", "maxmemory-samples", "maxclients", "timeout", "hz", "databases",
+    "dir", "dbfilename", "save", "appendonly", "appendfilename", "appendfsync", "max-pipeline",
+    "notify-keyspace-events", "tcp-nodelay", "tcp-keepalive", "unixsocket", "tls-port", "tls-cert-file",
+    "tls-key-file", "latency-monitor-threshold",
+    "slowlog-log-slower-than", "slowlog-max-len", "requirepass", "bind", "proto-max-bulk-len",
+    "client-query-buffer-limit", "busy-reply-threshold", "hash-max-listpack-entries",
+    "hash-max-listpack-value", "set-max-intset-entries", "set-max-listpack-entries", "set-max-listpack-value",
+];
+
+// Parameters that are only read when the server starts, so CONFIG SET refuses them
+const STARTUP_ONLY: [&str; 8] = [
+    "databases", "appendonly", "appendfilename", "unixsocket", "tls-port", "tls-cert-file", "tls-key-file",
+    "bind",
+];
+
+impl Config {
+    // Start from the defaults and apply any REDIS_<PARAMETER> environment
+    // variables, e.g. REDIS_MAXCLIENTS=100 or REDIS_DBFILENAME=backup.rdb
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Config::default();
+        
+        for name in PARAMETERS {
+            let var = format!("REDIS_{}", name.to_uppercase().replace('-', "_"));
+            if let Ok(value) = env::var(&var) {
+                config.set(name, &value).map_err(|e| format!("{}: {}", var, e))?;
+            }
+        }
+        
+        Ok(config)
+    }
+    
+    // Full path of the snapshot file
+    pub fn snapshot_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.dbfilename)
+    }
+    
+    // Full path of the append-only file
+    pub fn aof_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.appendfilename)
+    }
+    
+    pub fn is_startup_only(name: &str) -> bool {
+        STARTUP_ONLY.iter().any(|param| param.eq_ignore_ascii_case(name))
+    }
+    
+    // How long a client may stay idle before it is disconnected, if limited
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.timeout > 0).then(|| Duration::from_secs(self.timeout))
+    }
+    
+    // How long a connection may stay silent before keepalive probes check on
+    // the client, if they are enabled
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        (self.tcp_keepalive > 0).then(|| Duration::from_secs(self.tcp_keepalive))
+    }
+    
+    // Shortest interval between two runs of the expiration sweep
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_millis(1000 / self.hz)
+    }
+    
+    // Return (name, value) pairs for the requested parameter, or for every
+    // parameter when the pattern is *
+    pub fn get(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        PARAMETERS.iter()
+            .filter(|name| pattern == "*" || name.eq_ignore_ascii_case(pattern))
+            .map(|name| (*name, self.value_of(name)))
+            .collect()
+    }
+    
+    // Update a parameter, validating the new value first
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name.to_lowercase().as_str() {
+            "maxmemory" => {
+                self.maxmemory = parse_memory(value)
+                    .ok_or_else(|| format!("Invalid maxmemory value '{}'", value))?;
+            }
+            "maxmemory-policy" => {
+                self.maxmemory_policy = MaxmemoryPolicy::parse(value)
+                    .ok_or_else(|| format!("maxmemory-policy must be noeviction, allkeys-lru or allkeys-lfu, got '{}'", value))?;
+            }
+            "maxmemory-samples" => {
+                self.maxmemory_samples = value.parse::<usize>().ok()
+                    .filter(|samples| (1..=64).contains(samples))
+                    .ok_or_else(|| format!("maxmemory-samples must be between 1 and 64, got '{}'", value))?;
+            }
+            "maxclients" => {
+                self.maxclients = value.parse::<u64>().ok()
+                    .filter(|&clients| clients >= 1)
+                    .ok_or_else(|| format!("Invalid maxclients value '{}'", value))?;
+            }
+            "timeout" => {
+                self.timeout = value.parse::<u64>()
+                    .map_err(|_| format!("Invalid timeout value '{}'", value))?;
+            }
+            "hz" => {
+                self.hz = value.parse::<u64>().ok()
+                    .filter(|hz| (1..=500).contains(hz))
+                    .ok_or_else(|| format!("hz must be between 1 and 500, got '{}'", value))?;
+            }
+            "databases" => {
+                self.databases = value.parse::<usize>().ok()
+                    .filter(|&databases| databases >= 1)
+                    .ok_or_else(|| format!("Invalid databases value '{}'", value))?;
+            }
+            "dir" => {
+                self.dir = value.to_string();
+            }
+            "dbfilename" => {
+                // Like Redis, the snapshot must live directly inside `dir`
+                if value.is_empty() || value.contains('/') {
+                    return Err(format!("Invalid dbfilename '{}'", value));
+                }
+                self.dbfilename = value.to_string();
+            }
+            "save" => {
+                self.save = SavePoint::parse_all(value)
+                    .ok_or_else(|| format!("Invalid save value '{}', expected pairs of seconds and changes", value))?;
+            }
+            "appendonly" => {
+                self.appendonly = parse_yes_no("appendonly", value)?;
+            }
+            "appendfilename" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err(format!("Invalid appendfilename '{}'", value));
+                }
+                self.appendfilename = value.to_string();
+            }
+            "appendfsync" => {
+                self.appendfsync = AppendFsync::parse(value)
+                    .ok_or_else(|| format!("appendfsync must be always, everysec or no, got '{}'", value))?;
+            }
+            "max-pipeline" => {
+                self.max_pipeline = value.parse::<usize>().ok()
+                    .filter(|&max| max >= 1)
+                    .ok_or_else(|| format!("Invalid max-pipeline value '{}'", value))?;
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = KeyspaceEvents::parse(value)
+                    .ok_or_else(|| format!("Invalid notify-keyspace-events value '{}'", value))?;
+            }
+            "tcp-nodelay" => {
+                self.tcp_nodelay = parse_yes_no("tcp-nodelay", value)?;
+            }
+            "tcp-keepalive" => {
+                self.tcp_keepalive = value.parse::<u64>()
+                    .map_err(|_| format!("Invalid tcp-keepalive value '{}'", value))?;
+            }
+            "unixsocket" => {
+                self.unixsocket = value.to_string();
+            }
+            "tls-port" => {
+                self.tls_port = value.parse::<u16>()
+                    .map_err(|_| format!("Invalid tls-port value '{}'", value))?;
+            }
+            "tls-cert-file" => {
+                self.tls_cert_file = value.to_string();
+            }
+            "tls-key-file" => {
+                self.tls_key_file = value.to_string();
+            }
+            "latency-monitor-threshold" => {
+                self.latency_monitor_threshold = value.parse::<u64>()
+                    .map_err(|_| format!("Invalid latency-monitor-threshold value '{}'", value))?;
+            }
+            "slowlog-log-slower-than" => {
+                self.slowlog_log_slower_than = value.parse::<i64>()
+                    .map_err(|_| format!("Invalid slowlog-log-slower-than value '{}'", value))?;
+            }
+            "slowlog-max-len" => {
+                self.slowlog_max_len = value.parse::<usize>()
+                    .map_err(|_| format!("Invalid slowlog-max-len value '{}'", value))?;
+            }
+            "requirepass" => {
+                self.requirepass = value.to_string();
+            }
+            "bind" => {
+                self.bind = value.parse::<IpAddr>()
+                    .map_err(|_| format!("Invalid bind address '{}', expected an IPv4 or IPv6 address", value))?;
+            }
+            "proto-max-bulk-len" => {
+                // Like Redis, no lower than 1mb
+                self.proto_max_bulk_len = parse_memory(value)
+                    .and_then(|len| usize::try_from(len).ok())
+                    .filter(|&len| len >= 1 << 20)
+                    .ok_or_else(|| format!("Invalid proto-max-bulk-len value '{}', the least is 1mb", value))?;
+            }
+            "client-query-buffer-limit" => {
+                self.client_query_buffer_limit = parse_memory(value)
+                    .and_then(|len| usize::try_from(len).ok())
+                    .filter(|&len| len >= 1 << 20)
+                    .ok_or_else(|| format!("Invalid client-query-buffer-limit value '{}', the least is 1mb", value))?;
+            }
+            "busy-reply-threshold" => {
+                self.busy_reply_threshold = value.parse::<u64>()
+                    .map_err(|_| format!("Invalid busy-reply-threshold value '{}'", value))?;
+            }
+            parameter @ ("hash-max-listpack-entries" | "hash-max-listpack-value" | "set-max-intset-entries"
+            | "set-max-listpack-entries" | "set-max-listpack-value") => {
+                let limit = value.parse::<usize>()
+                    .map_err(|_| format!("Invalid {} value '{}'", parameter, value))?;
+                let limits = &mut self.encoding_limits;
+                *match parameter {
+                    "hash-max-listpack-entries" => &mut limits.hash_max_listpack_entries,
+                    "hash-max-listpack-value" => &mut limits.hash_max_listpack_value,
+                    "set-max-intset-entries" => &mut limits.set_max_intset_entries,
+                    "set-max-listpack-entries" => &mut limits.set_max_listpack_entries,
+                    _ => &mut limits.set_max_listpack_value,
+                } = limit;
+            }
+            _ => return Err(format!("Unknown CONFIG parameter '{}'", name)),
+        }
+        
+        Ok(())
+    }
+    
+    fn value_of(&self, name: &str) -> String {
+        match name {
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.as_str().to_string(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "maxclients" => self.maxclients.to_string(),
+            "timeout" => self.timeout.to_string(),
+            "hz" => self.hz.to_string(),
+            "databases" => self.databases.to_string(),
+            "dir" => self.dir.clone(),
+            "dbfilename" => self.dbfilename.clone(),
+            "save" => SavePoint::format_all(&self.save),
+            "appendonly" => if self.appendonly { "yes" } else { "no" }.to_string(),
+            "appendfilename" => self.appendfilename.clone(),
+            "appendfsync" => self.appendfsync.as_str().to_string(),
+            "max-pipeline" => self.max_pipeline.to_string(),
+            "notify-keyspace-events" => self.notify_keyspace_events.as_string(),
+            "tcp-nodelay" => if self.tcp_nodelay { "yes" } else { "no" }.to_string(),
+            "tcp-keepalive" => self.tcp_keepalive.to_string(),
+            "unixsocket" => self.unixsocket.clone(),
+            "tls-port" => self.tls_port.to_string(),
+            "tls-cert-file" => self.tls_cert_file.clone(),
+            "tls-key-file" => self.tls_key_file.clone(),
+            "latency-monitor-threshold" => self.latency_monitor_threshold.to_string(),
+            "slowlog-log-slower-than" => self.slowlog_log_slower_than.to_string(),
+            "slowlog-max-len" => self.slowlog_max_len.to_string(),
+            "requirepass" => self.requirepass.clone(),
+            "bind" => self.bind.to_string(),
+            "proto-max-bulk-len" => self.proto_max_bulk_len.to_string(),
+            "client-query-buffer-limit" => self.client_query_buffer_limit.to_string(),
+            "busy-reply-threshold" => self.busy_reply_threshold.to_string(),
+            "hash-max-listpack-entries" => self.encoding_limits.hash_max_listpack_entries.to_string(),
+            "hash-max-listpack-value" => self.encoding_limits.hash_max_listpack_value.to_string(),
+            "set-max-intset-entries" => self.encoding_limits.set_max_intset_entries.to_string(),
+            "set-max-listpack-entries" => self.encoding_limits.set_max_listpack_entries.to_string(),
+            "set-max-listpack-value" => self.encoding_limits.set_max_listpack_value.to_string(),
+            _ => unreachable!("unknown parameter {}", name),
+        }
+    }
+}
+
+fn parse_yes_no(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("{} must be yes or no, got '{}'", name, value)),
+    }
+}
+
+// Parse a memory size such as 1048576, 512kb, 100mb or 1gb into bytes
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits_end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits_end);
+    
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn parse_memory_units() {
+        assert_eq!(parse_memory("1048576"), Some(1048576));
+        assert_eq!(parse_memory("512kb"), Some(512 * 1024));
+        assert_eq!(parse_memory("100MB"), Some(100 * 1024 * 1024));
+        assert_eq!(parse_memory("1g"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_memory("mb"), None);
+        assert_eq!(parse_memory("10tb"), None);
+    }
+    
+    #[test]
+    fn set_rejects_invalid_values() {
+        let mut config = Config::default();
+        
+        assert!(config.set("hz", "0").is_err());
+        assert!(config.set("maxclients", "-1").is_err());
+        assert!(config.set("maxmemory-samples", "65").is_err());
+        assert!(config.set("nosuchparam", "1").is_err());
+        assert_eq!(config.hz, 1);
+        
+        assert!(config.set("appendfsync", "sometimes").is_err());
+        assert_eq!(config.appendfsync, AppendFsync::EverySec);
+        
+        config.set("HZ", "10").unwrap();
+        assert_eq!(config.cleanup_interval(), Duration::from_millis(100));
+        
+        assert!(config.set("bind", "localhost").is_err());
+        assert!(config.set("bind", "127.0.0.1:6379").is_err());
+        assert_eq!(config.bind, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        config.set("bind", "::").unwrap();
+        assert_eq!(config.value_of("bind"), "::");
+        
+        assert!(config.set("proto-max-bulk-len", "1023kb").is_err());
+        config.set("proto-max-bulk-len", "1mb").unwrap();
+        assert_eq!(config.proto_max_bulk_len, 1 << 20);
+        assert!(config.set("client-query-buffer-limit", "64kb").is_err());
+        assert_eq!(config.client_query_buffer_limit, 1 << 30);
+    }
+    
+    #[test]
+    fn save_points() {
+        let mut config = Config::default();
+        assert_eq!(config.value_of("save"), "3600 1 300 100 60 10000");
+        
+        config.set("save", "900 1  300 10").unwrap();
+        assert_eq!(config.save, [SavePoint { seconds: 900, changes: 1 }, SavePoint { seconds: 300, changes: 10 }]);
+        assert_eq!(config.value_of("save"), "900 1 300 10");
+        
+        // Pairs only, of non-negative integers
+        assert!(config.set("save", "900").is_err());
+        assert!(config.set("save", "900 -1").is_err());
+        assert!(config.set("save", "a b").is_err());
+        assert_eq!(config.save.len(), 2);
+        
+        config.set("save", "").unwrap();
+        assert!(config.save.is_empty());
+        assert_eq!(config.value_of("save"), "");
+    }
+    
+    #[test]
+    fn keyspace_event_flags() {
+        let events = KeyspaceEvents::parse("xgE").unwrap();
+        assert!(events.enabled('x') && events.enabled('g'));
+        assert!(!events.enabled('$'));
+        assert_eq!(events.as_string(), "gxE");
+        
+        // A stands for every class, and classes alone publish nothing
+        assert_eq!(KeyspaceEvents::parse("KA").unwrap().as_string(), "AK");
+        assert!(!KeyspaceEvents::parse("A").unwrap().enabled('x'));
+        assert!(KeyspaceEvents::parse("Kq").is_none());
+        assert_eq!(KeyspaceEvents::parse("").unwrap(), KeyspaceEvents::default());
+    }
+}
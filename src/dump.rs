@@ -0,0 +1,105 @@
+// DUMP/RESTORE payloads: one value with its type, laid out as
+//
+//   format version (u8)
+//   value type and value, as in the snapshot
+//   CRC-64 of everything before it (u64)
+//
+// with integers little-endian. Arguments have to be valid UTF-8, so the bytes
+// travel hex-encoded. Like Redis's, a payload carries no TTL: RESTORE is given
+// the one the new key gets.
+
+use crate::persistence;
+use crate::Value;
+
+// Version 1 payloads also held the TTL
+const VERSION: u8 = 2;
+
+// CRC-64/Jones, the checksum Redis uses for its own payloads (reflected form)
+const CRC64_POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+
+pub const BAD_CHECKSUM: &str = "DUMP payload version or checksum are wrong";
+pub const BAD_FORMAT: &str = "Bad data format";
+
+pub fn dump(value: &Value) -> String {
+    let mut out = vec![VERSION];
+    persistence::encode_value(&mut out, value);
+    out.extend_from_slice(&crc64(&out).to_le_bytes());
+    
+    out.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The value in a payload, or the error to reply with if the payload was
+// damaged or written by another version
+pub fn restore(payload: &str) -> Result<Value, &'static str> {
+    let bytes = decode_hex(payload).ok_or(BAD_CHECKSUM)?;
+    let Some(body_len) = bytes.len().checked_sub(8).filter(|&len| len > 0) else {
+        return Err(BAD_CHECKSUM);
+    };
+    let (body, checksum) = bytes.split_at(body_len);
+    if body[0] != VERSION || crc64(body).to_le_bytes() != checksum {
+        return Err(BAD_CHECKSUM);
+    }
+    persistence::decode_value(&body[1..]).map_err(|_| BAD_FORMAT)
+}
+
+fn crc64(bytes: &[u8]) -> u64 {
+    let mut crc = 0;
+    for &byte in bytes {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC64_POLY } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zset::SortedSet;
+    
+    #[test]
+    fn checksum_matches_redis() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+    
+    #[test]
+    fn payloads_round_trip() {
+        let mut zset = SortedSet::default();
+        zset.insert("member".to_string(), 2.5);
+        let value = Value::SortedSet(zset);
+        assert_eq!(restore(&dump(&value)), Ok(value));
+        
+        let value = Value::String(b"plain".to_vec());
+        assert_eq!(restore(&dump(&value)), Ok(value));
+    }
+    
+    #[test]
+    fn damaged_payloads_are_rejected() {
+        let payload = dump(&Value::String(b"value".to_vec()));
+        
+        // A flipped bit, a truncated payload, a future version and stray characters
+        let mut flipped = payload.clone().into_bytes();
+        flipped[8] = if flipped[8] == b'0' { b'1' } else { b'0' };
+        assert_eq!(restore(std::str::from_utf8(&flipped).unwrap()), Err(BAD_CHECKSUM));
+        assert_eq!(restore(&payload[..payload.len() - 2]), Err(BAD_CHECKSUM));
+        assert_eq!(restore(&format!("03{}", &payload[2..])), Err(BAD_CHECKSUM));
+        assert_eq!(restore("not hex"), Err(BAD_CHECKSUM));
+        assert_eq!(restore(""), Err(BAD_CHECKSUM));
+        
+        // A checksum that matches doesn't make a well-formed value
+        let mut body = vec![VERSION, 9];
+        body.extend_from_slice(&crc64(&body).to_le_bytes());
+        let hex: String = body.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(restore(&hex), Err(BAD_FORMAT));
+    }
+}
@@ -0,0 +1,147 @@
+// Approximate LRU and LFU eviction, done the way Redis does it. Ordering every
+// key by access would cost on every read, and scanning them all on every
+// eviction would hold the data lock for as long as the keyspace is big, so
+// each eviction samples maxmemory-samples keys of every database instead. The
+// best candidates seen so far are kept in a small pool across evictions, and
+// the best of them is evicted, so the pick gets closer to the exact one the
+// more keys have been sampled.
+
+use std::time::Instant;
+
+use crate::config::MaxmemoryPolicy;
+use crate::RedisValue;
+
+// Candidates the pool keeps, as in Redis
+const POOL_SIZE: usize = 16;
+
+// How good a candidate a key is, lowest first: its access frequency for LFU
+// (always 0 for LRU), then its last access, so the least recently used of
+// keys used equally often goes first
+type Score = (u8, Instant);
+
+struct Candidate {
+    score: Score,
+    db: usize,
+    key: String,
+}
+
+pub struct EvictionPool {
+    // The policy the candidates were scored for; they are dropped when it changes
+    policy: MaxmemoryPolicy,
+    // Best first
+    candidates: Vec<Candidate>,
+}
+
+impl Default for EvictionPool {
+    fn default() -> Self {
+        EvictionPool { policy: MaxmemoryPolicy::NoEviction, candidates: Vec::new() }
+    }
+}
+
+impl EvictionPool {
+    // Offer sampled keys of a database, keeping those better than the worst
+    // candidate while the pool is full. A key already in the pool gets its
+    // score updated, since it may have been used since.
+    pub fn offer<'a>(&mut self, policy: MaxmemoryPolicy, db: usize, keys: impl Iterator<Item = (&'a String, &'a RedisValue)>) {
+        if policy != self.policy {
+            self.policy = policy;
+            self.candidates.clear();
+        }
+        
+        for (key, value) in keys {
+            let frequency = if policy == MaxmemoryPolicy::AllKeysLfu { value.frequency() } else { 0 };
+            let score = (frequency, value.last_access);
+            
+            if let Some(position) = self.candidates.iter().position(|candidate| candidate.db == db && candidate.key == *key) {
+                self.candidates.remove(position);
+            }
+            if self.candidates.len() == POOL_SIZE && self.candidates.last().is_some_and(|worst| worst.score <= score) {
+                continue;
+            }
+            let position = self.candidates.partition_point(|candidate| candidate.score <= score);
+            self.candidates.insert(position, Candidate { score, db, key: key.clone() });
+            self.candidates.truncate(POOL_SIZE);
+        }
+    }
+    
+    // Take the best candidate and its database. It may have been deleted
+    // since it was sampled, which the caller has to check.
+    pub fn pop(&mut self) -> Option<(usize, String)> {
+        if self.candidates.is_empty() {
+            return None;
+        }
+        let best = self.candidates.remove(0);
+        Some((best.db, best.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    fn value(last_access: Instant) -> RedisValue {
+        let mut value = RedisValue::new("value".to_string(), None);
+        value.last_access = last_access;
+        value
+    }
+    
+    #[test]
+    fn least_recently_used_goes_first() {
+        let now = Instant::now();
+        let keys: Vec<(String, RedisValue)> = (0..3u64)
+            .map(|age| (format!("key{}", age), value(now - Duration::from_secs(age))))
+            .collect();
+        
+        let mut pool = EvictionPool::default();
+        pool.offer(MaxmemoryPolicy::AllKeysLru, 0, keys.iter().map(|(key, value)| (key, value)));
+        // A key sampled again isn't a second candidate
+        pool.offer(MaxmemoryPolicy::AllKeysLru, 0, keys[..1].iter().map(|(key, value)| (key, value)));
+        pool.offer(MaxmemoryPolicy::AllKeysLru, 1, keys[..1].iter().map(|(key, value)| (key, value)));
+        
+        assert_eq!(pool.pop(), Some((0, "key2".to_string())));
+        assert_eq!(pool.pop(), Some((0, "key1".to_string())));
+        let last = [pool.pop().unwrap(), pool.pop().unwrap()];
+        assert!(last.contains(&(0, "key0".to_string())) && last.contains(&(1, "key0".to_string())));
+        assert_eq!(pool.pop(), None);
+    }
+    
+    #[test]
+    fn least_frequently_used_goes_first() {
+        let (rare, mut frequent) = (value(Instant::now()), value(Instant::now()));
+        // A new key starts at 5. The first access always counts, later ones
+        // ever less often.
+        for _ in 0..100 {
+            frequent.touch();
+        }
+        assert_eq!(rare.frequency(), 5);
+        assert!(frequent.frequency() > rare.frequency());
+        let keys = [("frequent".to_string(), frequent), ("rare".to_string(), rare)];
+        
+        let mut pool = EvictionPool::default();
+        pool.offer(MaxmemoryPolicy::AllKeysLfu, 0, keys.iter().map(|(key, value)| (key, value)));
+        assert_eq!(pool.pop(), Some((0, "rare".to_string())));
+        
+        // Changing the policy drops the candidates scored for the old one
+        pool.offer(MaxmemoryPolicy::AllKeysLru, 0, keys[1..].iter().map(|(key, value)| (key, value)));
+        assert_eq!(pool.pop(), Some((0, "rare".to_string())));
+        assert_eq!(pool.pop(), None);
+    }
+    
+    #[test]
+    fn pool_keeps_the_best_candidates() {
+        let now = Instant::now();
+        let keys: Vec<(String, RedisValue)> = (0..POOL_SIZE as u64 + 4)
+            .map(|age| (format!("key{}", age), value(now - Duration::from_secs(age))))
+            .collect();
+        
+        let mut pool = EvictionPool::default();
+        pool.offer(MaxmemoryPolicy::AllKeysLru, 0, keys.iter().map(|(key, value)| (key, value)));
+        let mut popped = Vec::new();
+        while let Some((_, key)) = pool.pop() {
+            popped.push(key);
+        }
+        let expected: Vec<String> = (4..POOL_SIZE as u64 + 4).rev().map(|age| format!("key{}", age)).collect();
+        assert_eq!(popped, expected);
+    }
+}
@@ -0,0 +1,170 @@
+// Function libraries loaded with FUNCTION LOAD, for FCALL to call by name. A
+// library's code starts with a `#!lua name=<library>` line and registers its
+// functions with redis.register_function. Lua values can't outlive the
+// interpreter that made them, so a library is kept as its checked code, and
+// every call runs that code again to register its functions and then calls
+// the one asked for.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::lua::{Function, Script};
+
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    pub functions: Vec<Function>,
+    script: Arc<Script>,
+}
+
+#[derive(Default)]
+pub struct Functions {
+    // By name
+    libraries: BTreeMap<String, Library>,
+}
+
+impl Functions {
+    // Load a library, or with `replace` load it over one of the same name,
+    // returning its name
+    pub fn load(&mut self, code: &str, replace: bool) -> Result<String, String> {
+        let (header, body) = code.split_once('\n').unwrap_or((code, ""));
+        let Some(header) = header.strip_prefix("#!") else {
+            return Err("Missing library metadata".to_string());
+        };
+        let mut header = header.split_whitespace();
+        let engine = header.next().unwrap_or_default();
+        if !engine.eq_ignore_ascii_case("lua") {
+            return Err(format!("Engine '{}' not found", engine));
+        }
+        let mut name = None;
+        for param in header {
+            match param.strip_prefix("name=") {
+                Some(value) => name = Some(value.to_string()),
+                None => return Err(format!("Invalid metadata value given: {}", param)),
+            }
+        }
+        let Some(name) = name else {
+            return Err("Library name was not given".to_string());
+        };
+        if !valid_name(&name) {
+            return Err("Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+        }
+        if self.libraries.contains_key(&name) && !replace {
+            return Err(format!("Library '{}' already exists", name));
+        }
+        
+        // The header becomes an empty line, so errors give the right line numbers
+        let script = Script::compile(&format!("\n{}", body))
+            .map_err(|e| format!("Error compiling function: {}", e))?;
+        let functions = script.register_functions()
+            .map_err(|e| format!("Error registering functions: {}", e))?;
+        if functions.is_empty() {
+            return Err("No functions registered".to_string());
+        }
+        for (i, function) in functions.iter().enumerate() {
+            if !valid_name(&function.name) {
+                return Err("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+            }
+            if functions[..i].iter().any(|earlier| earlier.name == function.name) {
+                return Err("Function already exists in the library".to_string());
+            }
+            let taken = self.libraries.values()
+                .any(|library| library.name != name && library.functions.iter().any(|other| other.name == function.name));
+            if taken {
+                return Err(format!("Function {} already exists", function.name));
+            }
+        }
+        
+        let library = Library { name: name.clone(), code: code.to_string(), functions, script: Arc::new(script) };
+        self.libraries.insert(name.clone(), library);
+        Ok(name)
+    }
+    
+    // Remove a library, reporting whether there was one
+    pub fn delete(&mut self, name: &str) -> bool {
+        self.libraries.remove(name).is_some()
+    }
+    
+    // The code of the library that registered a function
+    pub fn find(&self, function: &str) -> Option<Arc<Script>> {
+        self.libraries.values()
+            .find(|library| library.functions.iter().any(|registered| registered.name == function))
+            .map(|library| library.script.clone())
+    }
+    
+    // Every library, by name
+    pub fn libraries(&self) -> impl Iterator<Item = &Library> {
+        self.libraries.values()
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Reply;
+    
+    #[test]
+    fn libraries_register_functions() {
+        let mut functions = Functions::default();
+        let code = "#!lua name=greetings\n\
+            redis.register_function('hello', function(keys, args) return 'Hello ' .. args[1] end)\n\
+            redis.register_function{function_name='keys', callback=function(keys) return #keys end, description='Count'}";
+        assert_eq!(functions.load(code, false), Ok("greetings".to_string()));
+        let library = functions.libraries().next().unwrap();
+        assert_eq!(library.functions, [
+            Function { name: "hello".to_string(), description: None },
+            Function { name: "keys".to_string(), description: Some("Count".to_string()) },
+        ]);
+        
+        let script = functions.find("hello").unwrap();
+        let reply = script.call_function("hello", &[], &["world"], &mut |_| Reply::Nil, &Arc::default());
+        assert_eq!(reply, Reply::bulk("Hello world"));
+        let reply = functions.find("keys").unwrap().call_function("keys", &["a", "b"], &[], &mut |_| Reply::Nil, &Arc::default());
+        assert_eq!(reply, Reply::Integer(2));
+        assert!(functions.find("missing").is_none());
+        
+        // A library's functions are its own until it's replaced or deleted
+        assert_eq!(functions.load(code, false), Err("Library 'greetings' already exists".to_string()));
+        let other = "#!lua name=other\nredis.register_function('hello', function() return 1 end)";
+        assert_eq!(functions.load(other, false), Err("Function hello already exists".to_string()));
+        let replacement = "#!lua name=greetings\nredis.register_function('hello', function() return 2 end)";
+        assert_eq!(functions.load(replacement, true), Ok("greetings".to_string()));
+        assert!(functions.find("keys").is_none());
+        assert!(functions.delete("greetings"));
+        assert!(!functions.delete("greetings"));
+        assert_eq!(functions.load(other, false), Ok("other".to_string()));
+    }
+    
+    #[test]
+    fn bad_libraries_are_refused() {
+        let mut functions = Functions::default();
+        let load = |functions: &mut Functions, code: &str| functions.load(code, false).unwrap_err();
+        assert_eq!(load(&mut functions, "return 1"), "Missing library metadata");
+        assert_eq!(load(&mut functions, "#!js name=lib\n"), "Engine 'js' not found");
+        assert_eq!(load(&mut functions, "#!lua\n"), "Library name was not given");
+        assert_eq!(load(&mut functions, "#!lua name=lib version=1\n"), "Invalid metadata value given: version=1");
+        assert_eq!(load(&mut functions, "#!lua name=lib\nlocal x = 1"), "No functions registered");
+        assert_eq!(load(&mut functions, "#!lua name=lib\nx ="), "Error compiling function: user_script:2: unexpected symbol near <eof>");
+        assert_eq!(
+            load(&mut functions, "#!lua name=lib\nredis.call('SET', 'a', 1)"),
+            "Error registering functions: Commands can't be run while a function library loads",
+        );
+        assert_eq!(
+            load(&mut functions, "#!lua name=lib\nredis.register_function('f', function() end)\nredis.register_function('f', function() end)"),
+            "Function already exists in the library",
+        );
+        assert_eq!(
+            load(&mut functions, "#!lua name=lib\nredis.register_function('bad-name', function() end)"),
+            "Function names can only contain letters, numbers, or underscores(_) and must be at least one character long",
+        );
+        assert_eq!(
+            load(&mut functions, "#!lua name=lib\nredis.register_function{function_name='f', callback=function() end, flags={}}"),
+            "Error registering functions: unknown argument given to redis.register_function",
+        );
+        assert_eq!(functions.libraries().count(), 0);
+    }
+}
@@ -0,0 +1,107 @@
+// Geospatial indexes: positions kept as the scores of a sorted set, like
+// Redis. A longitude/latitude pair becomes a 52-bit geohash, the two
+// coordinates quantized to 26 bits each and interleaved, which a f64 holds
+// exactly. Decoding gives back the centre of the geohash cell, within about
+// 0.6 metres of the original position.
+
+// Latitudes past these can't be projected in Web Mercator, so Redis refuses them
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+
+const STEP: u32 = 26;
+
+// The Earth's radius in metres, as Redis uses for distances
+const EARTH_RADIUS: f64 = 6_372_797.560856;
+
+pub fn is_valid(lon: f64, lat: f64) -> bool {
+    (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+// The sorted set score for a valid position
+pub fn encode(lon: f64, lat: f64) -> f64 {
+    let quantize = |value: f64, min: f64, max: f64| (((value - min) / (max - min)) * (1u64 << STEP) as f64) as u64;
+    let lat_bits = quantize(lat, LAT_MIN, LAT_MAX).min((1 << STEP) - 1);
+    let lon_bits = quantize(lon, LON_MIN, LON_MAX).min((1 << STEP) - 1);
+    
+    // Latitude bits take the even positions and longitude bits the odd ones
+    let mut hash = 0u64;
+    for i in 0..STEP {
+        hash |= ((lat_bits >> i) & 1) << (2 * i);
+        hash |= ((lon_bits >> i) & 1) << (2 * i + 1);
+    }
+    hash as f64
+}
+
+// The longitude and latitude a score stands for
+pub fn decode(score: f64) -> (f64, f64) {
+    let hash = score as u64;
+    let (mut lat_bits, mut lon_bits) = (0u64, 0u64);
+    for i in 0..STEP {
+        lat_bits |= ((hash >> (2 * i)) & 1) << i;
+        lon_bits |= ((hash >> (2 * i + 1)) & 1) << i;
+    }
+    
+    let centre = |bits: u64, min: f64, max: f64| {
+        let cell = (max - min) / (1u64 << STEP) as f64;
+        (min + (bits as f64 + 0.5) * cell).clamp(min, max)
+    };
+    (centre(lon_bits, LON_MIN, LON_MAX), centre(lat_bits, LAT_MIN, LAT_MAX))
+}
+
+// Great-circle distance in metres between two positions, by the haversine formula
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let half_lat = (lat2 - lat1) / 2.0;
+    let half_lon = (lon2 - lon1).to_radians() / 2.0;
+    let a = half_lat.sin().powi(2) + lat1.cos() * lat2.cos() * half_lon.sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+// Metres per unit of a distance, as GEOSEARCH takes them
+pub fn unit_in_metres(unit: &str) -> Option<f64> {
+    match unit.to_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "ft" => Some(0.3048),
+        "mi" => Some(1609.34),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // Palermo and Catania, the positions of Redis's own GEO examples
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+    
+    #[test]
+    fn scores_match_redis() {
+        assert_eq!(encode(PALERMO.0, PALERMO.1), 3479099956230698.0);
+        assert_eq!(encode(CATANIA.0, CATANIA.1), 3479447370796909.0);
+        
+        let (lon, lat) = decode(encode(PALERMO.0, PALERMO.1));
+        assert!((lon - PALERMO.0).abs() < 1e-5 && (lat - PALERMO.1).abs() < 1e-5, "{} {}", lon, lat);
+        let (lon, lat) = decode(encode(LON_MAX, LAT_MIN));
+        assert!(is_valid(lon, lat));
+    }
+    
+    #[test]
+    fn distances_match_redis() {
+        // GEODIST Sicily Palermo Catania in Redis's documentation
+        let metres = distance(PALERMO.0, PALERMO.1, CATANIA.0, CATANIA.1);
+        assert!((metres - 166274.1516).abs() < 1.0, "{}", metres);
+        assert_eq!(distance(PALERMO.0, PALERMO.1, PALERMO.0, PALERMO.1), 0.0);
+    }
+    
+    #[test]
+    fn positions_are_validated() {
+        assert!(is_valid(-180.0, 85.0));
+        assert!(!is_valid(180.5, 0.0));
+        assert!(!is_valid(0.0, 86.0));
+        assert!(!is_valid(f64::NAN, 0.0));
+    }
+}
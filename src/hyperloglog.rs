@@ -0,0 +1,249 @@
+// HyperLogLog: an estimate of how many distinct elements were added, in a
+// fixed 12kb whatever the count. Each element's 64-bit hash picks one of
+// 16384 registers with its low 14 bits, and the register keeps the longest
+// run of trailing zeros (plus one) seen in the remaining bits. The estimate
+// comes from the histogram of the registers, with Otmar Ertl's improved
+// estimator like Redis uses, and is within about 0.81% on average.
+//
+// The value is an ordinary string laid out like Redis's dense encoding:
+//
+//   magic "HYLL", encoding (u8, 0 for dense), 11 unused bytes
+//   16384 registers of 6 bits, packed from the least significant bit
+//
+// so GET and SET see the same bytes Redis would, and DUMP, snapshots and
+// the append-only file need nothing special.
+
+const MAGIC: &[u8; 4] = b"HYLL";
+const DENSE: u8 = 0;
+const HEADER_LEN: usize = 16;
+
+// 2^14 registers
+const INDEX_BITS: u32 = 14;
+const REGISTERS: usize = 1 << INDEX_BITS;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << REGISTER_BITS) - 1;
+// Bits of the hash left after the register index
+const RUN_BITS: u32 = 64 - INDEX_BITS;
+
+const DENSE_LEN: usize = HEADER_LEN + (REGISTERS * REGISTER_BITS).div_ceil(8);
+
+// The seed Redis hashes elements with, so registers match its own
+const HASH_SEED: u64 = 0xadc8_3b19;
+
+pub const INVALID: &str = "Key is not a valid HyperLogLog string value.";
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct HyperLogLog {
+    // One byte per register while in memory
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog { registers: vec![0; REGISTERS] }
+    }
+}
+
+impl HyperLogLog {
+    // Read a stored value, or None if the string isn't a dense HyperLogLog
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != DENSE_LEN || &bytes[..4] != MAGIC || bytes[4] != DENSE {
+            return None;
+        }
+        
+        let packed = &bytes[HEADER_LEN..];
+        let registers = (0..REGISTERS)
+            .map(|index| {
+                let bit = index * REGISTER_BITS;
+                let (byte, shift) = (bit / 8, bit % 8);
+                let low = packed[byte] as u16 >> shift;
+                let high = packed.get(byte + 1).map_or(0, |&next| (next as u16) << (8 - shift));
+                ((low | high) as u8) & REGISTER_MAX
+            })
+            .collect();
+        Some(HyperLogLog { registers })
+    }
+    
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; DENSE_LEN];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4] = DENSE;
+        
+        let packed = &mut bytes[HEADER_LEN..];
+        for (index, &register) in self.registers.iter().enumerate() {
+            let bit = index * REGISTER_BITS;
+            let (byte, shift) = (bit / 8, bit % 8);
+            let shifted = (register as u16) << shift;
+            packed[byte] |= shifted as u8;
+            if let Some(next) = packed.get_mut(byte + 1) {
+                *next |= (shifted >> 8) as u8;
+            }
+        }
+        bytes
+    }
+    
+    // Add an element, returning whether a register changed and so whether
+    // the estimate may have
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let hash = murmur_hash64a(element, HASH_SEED);
+        let index = (hash & (REGISTERS as u64 - 1)) as usize;
+        // The extra bit caps the run for a hash whose remaining bits are all 0
+        let run = ((hash >> INDEX_BITS) | (1 << RUN_BITS)).trailing_zeros() as u8 + 1;
+        
+        if run > self.registers[index] {
+            self.registers[index] = run;
+            true
+        } else {
+            false
+        }
+    }
+    
+    // Fold another HyperLogLog in, so this one estimates their union
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, &theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(theirs);
+        }
+    }
+    
+    pub fn count(&self) -> u64 {
+        let mut histogram = [0u32; RUN_BITS as usize + 2];
+        for &register in &self.registers {
+            histogram[register as usize] += 1;
+        }
+        
+        let m = REGISTERS as f64;
+        let q = RUN_BITS as usize;
+        let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+        for &count in histogram[1..=q].iter().rev() {
+            z = 0.5 * (z + count as f64);
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        
+        let alpha = 0.5 / std::f64::consts::LN_2;
+        (alpha * m * m / z).round() as u64
+    }
+}
+
+// The correction for registers still at 0, from Ertl's estimator
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if z == previous {
+            return z;
+        }
+    }
+}
+
+// The correction for registers at their maximum, from Ertl's estimator
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == previous {
+            return z / 3.0;
+        }
+    }
+}
+
+// MurmurHash64A, the hash Redis uses for HyperLogLog elements
+fn murmur_hash64a(bytes: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    
+    let mut h = seed ^ (bytes.len() as u64).wrapping_mul(M);
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &byte) in rest.iter().enumerate() {
+            h ^= (byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn registers_survive_packing() {
+        let mut hll = HyperLogLog::default();
+        for i in 0..1000 {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        // Including the largest value a register holds, next to a packed neighbour
+        hll.registers[REGISTERS - 1] = REGISTER_MAX;
+        hll.registers[REGISTERS - 2] = 1;
+        
+        let bytes = hll.to_bytes();
+        assert_eq!(bytes.len(), DENSE_LEN);
+        assert_eq!(HyperLogLog::from_bytes(&bytes), Some(hll));
+        
+        assert_eq!(HyperLogLog::from_bytes(b"HYLL"), None);
+        let mut sparse = bytes;
+        sparse[4] = 1;
+        assert_eq!(HyperLogLog::from_bytes(&sparse), None);
+    }
+    
+    #[test]
+    fn estimates_are_close() {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.count(), 0);
+        
+        assert!(hll.add(b"first"));
+        assert!(!hll.add(b"first"));
+        assert_eq!(hll.count(), 1);
+        
+        for n in [100u64, 10_000, 200_000] {
+            let mut hll = HyperLogLog::default();
+            for i in 0..n {
+                hll.add(&i.to_le_bytes());
+            }
+            let error = (hll.count() as f64 - n as f64).abs() / n as f64;
+            assert!(error < 0.03, "{} elements estimated as {}", n, hll.count());
+        }
+    }
+    
+    #[test]
+    fn merging_counts_the_union() {
+        let (mut a, mut b) = (HyperLogLog::default(), HyperLogLog::default());
+        for i in 0..6000u64 {
+            a.add(&i.to_le_bytes());
+        }
+        for i in 4000..10_000u64 {
+            b.add(&i.to_le_bytes());
+        }
+        a.merge(&b);
+        let error = (a.count() as f64 - 10_000.0).abs() / 10_000.0;
+        assert!(error < 0.03, "estimated {}", a.count());
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::hash_map::{self, DefaultHasher, HashMap};
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, Deref, DerefMut};
+use std::time::Instant;
+
+use crate::config::EncodingLimits;
+use crate::RedisValue;
+
+// Rough per-key bookkeeping cost on top of the key and value bytes: the hash
+// table slot, the scan order entry, the String headers, the expiry and the
+// access time
+const ENTRY_OVERHEAD: usize = 96;
+
+// When a key with an expiry is gone: only once its expiry time has passed, so
+// a key looked at that very instant is still there. Lazy expiry and the sweep
+// both go by this, so no command can see a key another one has given up on.
+pub fn has_expired(expires_at: Instant, now: Instant) -> bool {
+    now > expires_at
+}
+
+// Approximate memory used by one key and its value. The key is stored twice,
+// once in the map and once in the scan order, and a third time in the expiry
+// order if it has a TTL.
+fn entry_size(key_len: usize, value: &RedisValue) -> usize {
+    let copies = if value.expires_at.is_some() { 3 } else { 2 };
+    copies * key_len + value.value.memory() + ENTRY_OVERHEAD
+}
+
+// Position of a key in SCAN order. DefaultHasher::new() always uses the same
+// keys, so the order is stable for as long as the server runs.
+pub fn scan_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// A change to a key that keyspace notifications can report, such as "set" or
+// "expired". The class is the notify-keyspace-events letter that enables it.
+#[derive(Debug, PartialEq)]
+pub struct KeyspaceEvent {
+    pub class: char,
+    pub event: &'static str,
+    pub key: String,
+}
+
+// A key some clients WATCH: how many of them, and how many times the key was
+// modified since the first of them started
+#[derive(Default)]
+struct Watch {
+    watchers: usize,
+    version: u64,
+}
+
+// The keys with a TTL, soonest expiring first
+#[derive(Default)]
+struct ExpiryOrder {
+    keys: BTreeSet<(Instant, String)>,
+    // Set when a key got an expiry sooner than every other key's
+    sooner: bool,
+}
+
+impl ExpiryOrder {
+    // Move a key to its place for a new expiry, or out of the order without one
+    fn update(&mut self, key: &str, before: Option<Instant>, after: Option<Instant>) {
+        if before == after {
+            return;
+        }
+        if let Some(before) = before {
+            self.keys.remove(&(before, key.to_string()));
+        }
+        if let Some(after) = after {
+            self.sooner |= self.keys.first().is_none_or(|(first, _)| after < *first);
+            self.keys.insert((after, key.to_string()));
+        }
+    }
+}
+
+// The key-value map, keeping a running estimate of the memory it uses so
+// maxmemory can be enforced without walking every key, the keys sorted in SCAN
+// order so a SCAN call only visits the keys it returns, and the keys with a TTL
+// sorted by expiry so expired keys are found without looking at the others.
+// All changes go through these methods (or through an EntryMut) so all three
+// stay accurate, so the versions of watched keys count every change, and so
+// every hash or set that outgrows its compact encoding is switched over.
+#[derive(Default)]
+pub struct Keyspace {
+    entries: HashMap<String, RedisValue>,
+    scan_order: BTreeSet<(u64, String)>,
+    expiry_order: ExpiryOrder,
+    used_memory: usize,
+    // Events since the last take_events(), in the order they happened. Keys
+    // expiring or being evicted are recorded here; commands record the rest.
+    events: Vec<KeyspaceEvent>,
+    // Set by clear() until take_flushed()
+    flushed: bool,
+    // Keys being WATCHed. Only those are versioned, so the map stays as small
+    // as the watch sets and doesn't grow with every key ever written.
+    watched: HashMap<String, Watch>,
+    // Changes since the last take_changes(), which save points count
+    changes: u64,
+    limits: EncodingLimits,
+}
+
+impl Keyspace {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+    
+    pub fn limits(&self) -> &EncodingLimits {
+        &self.limits
+    }
+    
+    // Change the encoding limits. Values already too big for the new ones are
+    // only switched over the next time they change.
+    pub fn set_limits(&mut self, limits: EncodingLimits) {
+        self.limits = limits;
+    }
+    
+    pub fn iter(&self) -> hash_map::Iter<'_, String, RedisValue> {
+        self.entries.iter()
+    }
+    
+    pub fn keys(&self) -> hash_map::Keys<'_, String, RedisValue> {
+        self.entries.keys()
+    }
+    
+    pub fn values(&self) -> hash_map::Values<'_, String, RedisValue> {
+        self.entries.values()
+    }
+    
+    pub fn get(&self, key: &str) -> Option<&RedisValue> {
+        self.entries.get(key)
+    }
+    
+    // Keys in SCAN order, starting with the first whose hash is at least `cursor`
+    pub fn scan_from(&self, cursor: u64) -> impl Iterator<Item = (u64, &String)> {
+        self.scan_order
+            .range((Bound::Included((cursor, String::new())), Bound::Unbounded))
+            .map(|(hash, key)| (*hash, key))
+    }
+    
+    // When the soonest expiring key expires, if any key has a TTL
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.expiry_order.keys.first().map(|(expires_at, _)| *expires_at)
+    }
+    
+    // Whether a key got an expiry sooner than every other key's since the last call
+    pub fn take_sooner_expiry(&mut self) -> bool {
+        std::mem::take(&mut self.expiry_order.sooner)
+    }
+    
+    // Mutable access to a value; the memory estimate and the expiry order are
+    // updated when the returned guard is dropped
+    pub fn get_mut<'a>(&'a mut self, key: &'a str) -> Option<EntryMut<'a>> {
+        let value = self.entries.get_mut(key)?;
+        let size_before = entry_size(key.len(), value);
+        let expires_before = value.expires_at;
+        
+        Some(EntryMut {
+            key,
+            entry: value,
+            used_memory: &mut self.used_memory,
+            expiry_order: &mut self.expiry_order,
+            size_before,
+            expires_before,
+            version: self.watched.get_mut(key).map(|watch| &mut watch.version),
+            changes: &mut self.changes,
+            limits: &self.limits,
+            modified: false,
+        })
+    }
+    
+    pub fn insert(&mut self, key: String, mut value: RedisValue) -> Option<RedisValue> {
+        self.modified(&key);
+        value.value.fit(&self.limits);
+        let key_len = key.len();
+        self.used_memory += entry_size(key_len, &value);
+        let expires_at = value.expires_at;
+        let old = self.entries.insert(key.clone(), value);
+        self.expiry_order.update(&key, old.as_ref().and_then(|old| old.expires_at), expires_at);
+        match &old {
+            Some(old) => self.used_memory -= entry_size(key_len, old),
+            None => {
+                self.scan_order.insert((scan_hash(&key), key));
+            }
+        }
+        old
+    }
+    
+    pub fn remove(&mut self, key: &str) -> Option<RedisValue> {
+        let value = self.entries.remove(key)?;
+        self.modified(key);
+        self.used_memory -= entry_size(key.len(), &value);
+        self.scan_order.remove(&(scan_hash(key), key.to_string()));
+        self.expiry_order.update(key, value.expires_at, None);
+        Some(value)
+    }
+    
+    pub fn clear(&mut self) {
+        for (key, watch) in self.watched.iter_mut() {
+            if self.entries.contains_key(key) {
+                watch.version += 1;
+            }
+        }
+        self.changes += self.entries.len() as u64;
+        self.entries.clear();
+        self.flushed = true;
+        self.scan_order.clear();
+        self.expiry_order.keys.clear();
+        self.used_memory = 0;
+    }
+    
+    // Replace every key with those of another keyspace, such as a leader's
+    // snapshot. Like a flush, this changes every watched key and invalidates
+    // every tracked one.
+    pub fn load(&mut self, other: Keyspace) {
+        self.clear();
+        for (key, value) in other.entries {
+            self.insert(key, value);
+        }
+    }
+    
+    // Whether the keyspace was cleared since the last call
+    pub fn take_flushed(&mut self) -> bool {
+        std::mem::take(&mut self.flushed)
+    }
+    
+    // Start watching a key, returning its version to compare at EXEC
+    pub fn watch(&mut self, key: &str) -> u64 {
+        let watch = self.watched.entry(key.to_string()).or_default();
+        watch.watchers += 1;
+        watch.version
+    }
+    
+    // Undo one watch() of the key, forgetting its version once nobody watches it
+    pub fn unwatch(&mut self, key: &str) {
+        if let Some(watch) = self.watched.get_mut(key) {
+            watch.watchers -= 1;
+            if watch.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+    
+    // The version of a watched key, None if nobody watches it
+    pub fn version(&self, key: &str) -> Option<u64> {
+        self.watched.get(key).map(|watch| watch.version)
+    }
+    
+    // The number of changes since the last call
+    pub fn take_changes(&mut self) -> u64 {
+        std::mem::take(&mut self.changes)
+    }
+    
+    fn modified(&mut self, key: &str) {
+        self.changes += 1;
+        if let Some(watch) = self.watched.get_mut(key) {
+            watch.version += 1;
+        }
+    }
+    
+    pub fn notify(&mut self, class: char, event: &'static str, key: &str) {
+        self.events.push(KeyspaceEvent { class, event, key: key.to_string() });
+    }
+    
+    pub fn take_events(&mut self) -> Vec<KeyspaceEvent> {
+        std::mem::take(&mut self.events)
+    }
+    
+    // Delete the key if it has expired, reporting whether it did
+    pub fn remove_if_expired(&mut self, key: &str) -> bool {
+        if self.get(key).is_some_and(|value| value.is_expired()) {
+            self.remove(key);
+            self.notify('x', "expired", key);
+            return true;
+        }
+        false
+    }
+    
+    // Delete every expired key. Only the expired keys are looked at, so this
+    // costs nothing while no key is due.
+    pub fn remove_expired(&mut self) {
+        let now = Instant::now();
+        while let Some((expires_at, key)) = self.expiry_order.keys.first() {
+            if !has_expired(*expires_at, now) {
+                break;
+            }
+            let key = key.clone();
+            self.remove(&key);
+            self.notify('x', "expired", &key);
+        }
+    }
+    
+    // Up to `count` keys for eviction to choose from, the ones that follow a
+    // random point in SCAN order. Like Redis's sampling, this looks at a few
+    // keys rather than all of them, and keys next to each other in SCAN order
+    // are as random a pick as any since the order is a hash.
+    pub fn sample(&self, count: usize) -> impl Iterator<Item = (&String, &RedisValue)> {
+        let start = (rand::random::<u64>(), String::new());
+        self.scan_order.range(start.clone()..)
+            .chain(self.scan_order.range(..start))
+            .take(count)
+            .map(|(_, key)| (key, &self.entries[key]))
+    }
+    
+    // Remove a key to free memory, reporting whether it was there
+    pub fn evict(&mut self, key: &str) -> bool {
+        if self.remove(key).is_none() {
+            return false;
+        }
+        self.notify('e', "evicted", key);
+        true
+    }
+}
+
+// A value borrowed mutably from the keyspace. Changes made through it are
+// accounted for in the memory estimate once it goes out of scope.
+pub struct EntryMut<'a> {
+    key: &'a str,
+    entry: &'a mut RedisValue,
+    used_memory: &'a mut usize,
+    expiry_order: &'a mut ExpiryOrder,
+    size_before: usize,
+    expires_before: Option<Instant>,
+    // The key's version, if it is watched
+    version: Option<&'a mut u64>,
+    changes: &'a mut u64,
+    limits: &'a EncodingLimits,
+    // Set by mark_modified once a command has really changed the value
+    modified: bool,
+}
+
+impl EntryMut<'_> {
+    // Mark the key as just used. This doesn't count as modifying it, so
+    // reads don't break WATCH.
+    pub fn touch(&mut self) {
+        self.entry.touch();
+    }
+    
+    // Count the key as changed, for WATCH, save points and the encoding
+    // limits. Borrowing the value mutably doesn't, since a command that
+    // turns out to change nothing, like SREM of a missing member, must
+    // leave a watching transaction alone.
+    pub fn mark_modified(&mut self) {
+        self.modified = true;
+    }
+}
+
+impl Deref for EntryMut<'_> {
+    type Target = RedisValue;
+    
+    fn deref(&self) -> &RedisValue {
+        self.entry
+    }
+}
+
+impl DerefMut for EntryMut<'_> {
+    fn deref_mut(&mut self) -> &mut RedisValue {
+        self.entry
+    }
+}
+
+impl Drop for EntryMut<'_> {
+    fn drop(&mut self) {
+        if self.modified {
+            self.entry.value.fit(self.limits);
+        }
+        *self.used_memory = *self.used_memory - self.size_before + entry_size(self.key.len(), self.entry);
+        self.expiry_order.update(self.key, self.expires_before, self.entry.expires_at);
+        if self.modified {
+            *self.changes += 1;
+            if let Some(version) = self.version.as_deref_mut() {
+                *version += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+    use std::time::Duration;
+    
+    #[test]
+    fn used_memory_follows_changes() {
+        let mut keyspace = Keyspace::default();
+        
+        keyspace.insert("a".to_string(), RedisValue::new("1234".to_string(), None));
+        keyspace.insert("b".to_string(), RedisValue::new("12".to_string(), None));
+        assert_eq!(keyspace.used_memory(), 6 + 4 + 2 * ENTRY_OVERHEAD);
+        
+        // Overwriting replaces the old value's size
+        keyspace.insert("a".to_string(), RedisValue::new("1".to_string(), None));
+        assert_eq!(keyspace.used_memory(), 3 + 4 + 2 * ENTRY_OVERHEAD);
+        
+        keyspace.get_mut("b").unwrap().value = Value::String(b"123456".to_vec());
+        assert_eq!(keyspace.used_memory(), 3 + 8 + 2 * ENTRY_OVERHEAD);
+        
+        // A TTL adds the key to the expiry order
+        keyspace.get_mut("b").unwrap().expires_at = Some(Instant::now());
+        assert_eq!(keyspace.used_memory(), 3 + 9 + 2 * ENTRY_OVERHEAD);
+        
+        keyspace.remove("a");
+        keyspace.remove("b");
+        assert_eq!(keyspace.used_memory(), 0);
+    }
+    
+    #[test]
+    fn scan_order_follows_changes() {
+        let mut keyspace = Keyspace::default();
+        for key in ["a", "b", "c", "d"] {
+            keyspace.insert(key.to_string(), RedisValue::new("value".to_string(), None));
+        }
+        keyspace.insert("a".to_string(), RedisValue::new("again".to_string(), None));
+        keyspace.remove("b");
+        keyspace.remove("c");
+        
+        let order: Vec<&str> = keyspace.scan_from(0).map(|(_, key)| key.as_str()).collect();
+        let mut expected = vec!["a", "d"];
+        expected.sort_by_key(|key| scan_hash(key));
+        assert_eq!(order, expected);
+        
+        // A cursor skips the keys hashed before it
+        let last = scan_hash(expected[1]);
+        assert_eq!(keyspace.scan_from(last).map(|(_, key)| key.as_str()).collect::<Vec<_>>(), vec![expected[1]]);
+    }
+    
+    #[test]
+    fn expiry_order_follows_changes() {
+        let mut keyspace = Keyspace::default();
+        let later = Instant::now() + Duration::from_secs(100);
+        keyspace.insert("later".to_string(), RedisValue::new("value".to_string(), Some(later)));
+        assert!(keyspace.take_sooner_expiry());
+        assert_eq!(keyspace.next_expiry(), Some(later));
+        
+        // Only an expiry sooner than all the others is flagged
+        keyspace.insert("latest".to_string(), RedisValue::new("value".to_string(), Some(later + Duration::from_secs(1))));
+        assert!(!keyspace.take_sooner_expiry());
+        let sooner = later - Duration::from_secs(90);
+        keyspace.get_mut("latest").unwrap().expires_at = Some(sooner);
+        assert!(keyspace.take_sooner_expiry());
+        assert_eq!(keyspace.next_expiry(), Some(sooner));
+        
+        // Keys leave the order when they lose their TTL or are deleted
+        keyspace.get_mut("latest").unwrap().expires_at = None;
+        assert_eq!(keyspace.next_expiry(), Some(later));
+        keyspace.remove("later");
+        assert_eq!(keyspace.next_expiry(), None);
+    }
+    
+    #[test]
+    fn changes_are_counted() {
+        let mut keyspace = Keyspace::default();
+        keyspace.insert("a".to_string(), RedisValue::new("value".to_string(), None));
+        keyspace.insert("b".to_string(), RedisValue::new("value".to_string(), None));
+        let mut entry = keyspace.get_mut("a").unwrap();
+        entry.value = Value::String(b"other".to_vec());
+        entry.mark_modified();
+        drop(entry);
+        keyspace.remove("b");
+        assert_eq!(keyspace.take_changes(), 4);
+        
+        // Touching a key or removing a missing one changes nothing
+        keyspace.get_mut("a").unwrap().touch();
+        keyspace.remove("missing");
+        assert_eq!(keyspace.take_changes(), 0);
+        
+        // A flush counts every key it deletes
+        keyspace.insert("b".to_string(), RedisValue::new("value".to_string(), None));
+        keyspace.clear();
+        assert_eq!(keyspace.take_changes(), 3);
+    }
+    
+    #[test]
+    fn load_replaces_every_key() {
+        let mut keyspace = Keyspace::default();
+        keyspace.insert("old".to_string(), RedisValue::new("value".to_string(), None));
+        keyspace.insert("kept".to_string(), RedisValue::new("old value".to_string(), None));
+        let version = keyspace.watch("kept");
+        
+        let mut other = Keyspace::default();
+        let later = Instant::now() + Duration::from_secs(100);
+        other.insert("kept".to_string(), RedisValue::new("new value".to_string(), Some(later)));
+        other.insert("new".to_string(), RedisValue::new("value".to_string(), None));
+        let used_memory = other.used_memory();
+        keyspace.load(other);
+        
+        let mut keys: Vec<&String> = keyspace.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["kept", "new"]);
+        assert_eq!(keyspace.get("kept").unwrap().value, Value::String(b"new value".to_vec()));
+        assert_eq!(keyspace.used_memory(), used_memory);
+        assert_eq!(keyspace.next_expiry(), Some(later));
+        assert_ne!(keyspace.version("kept"), Some(version));
+        assert!(keyspace.take_flushed());
+    }
+    
+    #[test]
+    fn samples_follow_scan_order() {
+        let mut keyspace = Keyspace::default();
+        assert_eq!(keyspace.sample(5).count(), 0);
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            keyspace.insert(key.to_string(), RedisValue::new("value".to_string(), None));
+        }
+        
+        // A sample of every key holds each of them once
+        let mut keys: Vec<&str> = keyspace.sample(10).map(|(key, _)| key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "b", "c", "d", "e", "f"]);
+        
+        // A smaller one is a run of keys in SCAN order, wrapping around
+        let order: Vec<&str> = keyspace.scan_from(0).map(|(_, key)| key.as_str()).collect();
+        for _ in 0..20 {
+            let sample: Vec<&str> = keyspace.sample(3).map(|(key, _)| key.as_str()).collect();
+            let first = order.iter().position(|key| *key == sample[0]).unwrap();
+            let expected: Vec<&str> = (first..first + 3).map(|i| order[i % order.len()]).collect();
+            assert_eq!(sample, expected);
+        }
+    }
+    
+    #[test]
+    fn evicting_removes_the_key() {
+        let mut keyspace = Keyspace::default();
+        keyspace.insert("key".to_string(), RedisValue::new("value".to_string(), None));
+        assert!(keyspace.evict("key"));
+        assert!(keyspace.is_empty());
+        assert!(!keyspace.evict("key"));
+    }
+    
+    #[test]
+    fn watched_keys_count_changes() {
+        let mut keyspace = Keyspace::default();
+        keyspace.insert("key".to_string(), RedisValue::new("value".to_string(), None));
+        assert_eq!(keyspace.version("key"), None);
+        assert_eq!(keyspace.watch("key"), 0);
+        
+        // Reading and touching leave the version alone
+        keyspace.get_mut("key").unwrap().touch();
+        assert_eq!(keyspace.get_mut("key").unwrap().value, Value::String(b"value".to_vec()));
+        assert_eq!(keyspace.version("key"), Some(0));
+        
+        // So does a mutable borrow that isn't marked as a change
+        if let Value::String(bytes) = &mut keyspace.get_mut("key").unwrap().value {
+            bytes.clear();
+            bytes.extend_from_slice(b"value");
+        }
+        assert_eq!(keyspace.version("key"), Some(0));
+        
+        let mut entry = keyspace.get_mut("key").unwrap();
+        entry.value = Value::String(b"other".to_vec());
+        entry.mark_modified();
+        drop(entry);
+        assert_eq!(keyspace.version("key"), Some(1));
+        keyspace.remove("key");
+        keyspace.insert("key".to_string(), RedisValue::new("again".to_string(), None));
+        assert_eq!(keyspace.version("key"), Some(3));
+        
+        // Flushing only changes the watched keys that existed
+        assert_eq!(keyspace.watch("missing"), 0);
+        keyspace.clear();
+        assert_eq!(keyspace.version("key"), Some(4));
+        assert_eq!(keyspace.version("missing"), Some(0));
+        
+        // The version is kept until the last watcher is done
+        assert_eq!(keyspace.watch("key"), 4);
+        keyspace.unwatch("key");
+        assert_eq!(keyspace.version("key"), Some(4));
+        keyspace.unwatch("key");
+        assert_eq!(keyspace.version("key"), None);
+    }
+    
+    #[test]
+    fn keys_expire_just_after_their_time() {
+        let now = Instant::now();
+        assert!(!has_expired(now, now));
+        assert!(has_expired(now, now + Duration::from_nanos(1)));
+        assert!(!has_expired(now + Duration::from_nanos(1), now));
+    }
+    
+    #[test]
+    fn expiring_and_evicting_record_events() {
+        let mut keyspace = Keyspace::default();
+        let expired = RedisValue::new("value".to_string(), Some(Instant::now()));
+        keyspace.insert("gone".to_string(), expired.clone());
+        keyspace.insert("swept".to_string(), expired);
+        keyspace.insert("live".to_string(), RedisValue::new("value".to_string(), None));
+        std::thread::sleep(Duration::from_millis(1));
+        
+        assert!(keyspace.remove_if_expired("gone"));
+        assert!(!keyspace.remove_if_expired("live"));
+        keyspace.remove_expired();
+        keyspace.evict("live");
+        
+        let events: Vec<(char, &str, String)> = keyspace.take_events().into_iter()
+            .map(|event| (event.class, event.event, event.key))
+            .collect();
+        assert_eq!(events, [
+            ('x', "expired", "gone".to_string()),
+            ('x', "expired", "swept".to_string()),
+            ('e', "evicted", "live".to_string()),
+        ]);
+        assert!(keyspace.take_events().is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Number of samples kept per event, matching Redis
+const HISTORY_LEN: usize = 160;
+
+// Buckets of a command's latency histogram: the first counts calls that took
+// up to a microsecond, each next one calls taking up to twice as long as the
+// bucket before, and the last one everything slower
+const HISTOGRAM_BUCKETS: usize = 32;
+
+// A single latency measurement for an event
+pub struct LatencySample {
+    // Unix time in seconds when the sample was recorded
+    pub time: u64,
+    pub latency_ms: u64,
+}
+
+#[derive(Default)]
+struct EventHistory {
+    samples: VecDeque<LatencySample>,
+    max_ms: u64,
+}
+
+// How long every call of a command took, in power-of-two buckets
+#[derive(Default)]
+pub struct Histogram {
+    calls: u64,
+    buckets: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().max(1);
+        let bucket = (micros.next_power_of_two().trailing_zeros() as usize).min(HISTOGRAM_BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.calls += 1;
+    }
+    
+    pub fn calls(&self) -> u64 {
+        self.calls
+    }
+    
+    // The upper bound in microseconds of every bucket up to the slowest one
+    // used, with the calls that took at most that long, cumulative like
+    // Redis's LATENCY HISTOGRAM
+    pub fn cumulative(&self) -> Vec<(u64, u64)> {
+        let used = self.buckets.iter().rposition(|&calls| calls > 0).map_or(0, |last| last + 1);
+        let mut total = 0;
+        self.buckets[..used].iter().enumerate()
+            .map(|(bucket, &calls)| {
+                total += calls;
+                (1 << bucket, total)
+            })
+            .collect()
+    }
+}
+
+// Latency samples grouped by event name, reported by LATENCY LATEST/HISTORY,
+// and the latency histogram of every command run
+#[derive(Default)]
+pub struct LatencyMonitor {
+    events: HashMap<String, EventHistory>,
+    histograms: HashMap<String, Histogram>,
+}
+
+impl LatencyMonitor {
+    // Record a sample for an event. Samples recorded within the same second
+    // are merged, keeping the highest latency.
+    pub fn add_sample(&mut self, event: &str, latency_ms: u64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        
+        self.add_sample_at(event, latency_ms, now);
+    }
+    
+    fn add_sample_at(&mut self, event: &str, latency_ms: u64, now: u64) {
+        let history = self.events.entry(event.to_string()).or_default();
+        history.max_ms = history.max_ms.max(latency_ms);
+        
+        match history.samples.back_mut() {
+            Some(last) if last.time == now => {
+                last.latency_ms = last.latency_ms.max(latency_ms);
+            }
+            _ => {
+                if history.samples.len() == HISTORY_LEN {
+                    history.samples.pop_front();
+                }
+                history.samples.push_back(LatencySample { time: now, latency_ms });
+            }
+        }
+    }
+    
+    // Record how long a command took. Commands reaching the threshold, in
+    // milliseconds, are also sampled as an event named after the command;
+    // a threshold of 0 samples nothing, like Redis's latency-monitor-threshold.
+    pub fn record_command(&mut self, command: &str, latency: Duration, threshold_ms: u64) {
+        self.histograms.entry(command.to_string()).or_default().record(latency);
+        
+        let latency_ms = latency.as_millis() as u64;
+        if threshold_ms > 0 && latency_ms >= threshold_ms {
+            self.add_sample(command, latency_ms);
+        }
+    }
+    
+    // Forget the samples of the given events, or of every event if none are
+    // given, returning how many events had samples. Histograms are kept.
+    pub fn reset(&mut self, events: &[&str]) -> usize {
+        if events.is_empty() {
+            let count = self.events.len();
+            self.events.clear();
+            return count;
+        }
+        events.iter().filter(|event| self.events.remove(**event).is_some()).count()
+    }
+    
+    // The histograms of the given commands, or of every command run if none
+    // are given, sorted by name. Commands never run are left out.
+    pub fn histograms(&self, commands: &[&str]) -> Vec<(&str, &Histogram)> {
+        let mut histograms: Vec<_> = self.histograms.iter()
+            .filter(|(name, _)| commands.is_empty() || commands.iter().any(|command| command.eq_ignore_ascii_case(name)))
+            .map(|(name, histogram)| (name.as_str(), histogram))
+            .collect();
+        histograms.sort_by_key(|(name, _)| *name);
+        histograms
+    }
+    
+    // Latest sample and all-time maximum for every event, sorted by name
+    pub fn latest(&self) -> Vec<(&str, &LatencySample, u64)> {
+        let mut latest: Vec<_> = self.events.iter()
+            .filter_map(|(name, history)| {
+                history.samples.back().map(|sample| (name.as_str(), sample, history.max_ms))
+            })
+            .collect();
+        latest.sort_by_key(|(name, _, _)| *name);
+        latest
+    }
+    
+    // All recorded samples for an event, oldest first
+    pub fn history(&self, event: &str) -> Vec<&LatencySample> {
+        self.events.get(event)
+            .map(|history| history.samples.iter().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn samples_in_the_same_second_are_merged() {
+        let mut monitor = LatencyMonitor::default();
+        monitor.add_sample_at("command", 10, 100);
+        monitor.add_sample_at("command", 30, 100);
+        monitor.add_sample_at("command", 20, 101);
+        
+        let history = monitor.history("command");
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].time, history[0].latency_ms), (100, 30));
+        assert_eq!((history[1].time, history[1].latency_ms), (101, 20));
+        
+        // LATEST reports the newest sample alongside the all-time maximum
+        let latest = monitor.latest();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].0, "command");
+        assert_eq!(latest[0].1.latency_ms, 20);
+        assert_eq!(latest[0].2, 30);
+        
+        assert!(monitor.history("unknown").is_empty());
+    }
+    
+    #[test]
+    fn commands_fill_histograms() {
+        let mut monitor = LatencyMonitor::default();
+        monitor.record_command("get", Duration::from_nanos(300), 100);
+        monitor.record_command("get", Duration::from_micros(3), 100);
+        monitor.record_command("get", Duration::from_micros(4), 100);
+        monitor.record_command("debug", Duration::from_millis(250), 100);
+        
+        let histograms = monitor.histograms(&[]);
+        assert_eq!(histograms.iter().map(|(name, _)| *name).collect::<Vec<_>>(), ["debug", "get"]);
+        let get = histograms[1].1;
+        assert_eq!(get.calls(), 3);
+        assert_eq!(get.cumulative(), [(1, 1), (2, 1), (4, 3)]);
+        assert_eq!(monitor.histograms(&["GET"]).len(), 1);
+        
+        // Only the command over the threshold is sampled as an event
+        assert!(monitor.history("get").is_empty());
+        assert_eq!(monitor.history("debug")[0].latency_ms, 250);
+        
+        // Without a threshold nothing is sampled
+        monitor.record_command("sort", Duration::from_secs(1), 0);
+        assert!(monitor.history("sort").is_empty());
+        
+        assert_eq!(monitor.reset(&["debug", "missing"]), 1);
+        assert!(monitor.latest().is_empty());
+        assert_eq!(monitor.histograms(&[]).len(), 3);
+    }
+}
@@ -0,0 +1,545 @@
+// Lua scripting for EVAL and function libraries, on the Lua 5.4 interpreter
+// mlua embeds. Every run gets an interpreter of its own with the basic,
+// string, table and math libraries and a redis table for running commands.
+// Like Redis, a script can't create global variables, so every run starts
+// out the same. A script that runs on holds up the server until it ends or
+// is killed.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use log::{info, warn};
+use mlua::{HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value, Variadic, VmState};
+
+use crate::protocol::{format_double, Reply};
+
+// Nesting of tables converted to a reply, as a table can hold itself
+const MAX_REPLY_DEPTH: usize = 100;
+
+// Lua instructions a script runs between checks of whether it was killed
+const KILL_CHECK_INTERVAL: u32 = 1000;
+
+// Run with the function behind redis.pcall once the script's globals are
+// set, before the script itself. redis.call raises the error replies
+// redis.pcall returns, and from then on globals can't be created, or read
+// before they exist.
+const PRELUDE: &str = r#"
+local command = ...
+redis.pcall = command
+function redis.call(...)
+    local reply = command(...)
+    if type(reply) == 'table' and reply.err then
+        error(reply)
+    end
+    return reply
+end
+unpack = table.unpack
+dofile, loadfile = nil, nil
+setmetatable(_G, {
+    __newindex = function(_, name)
+        error("Script attempted to create global variable '" .. tostring(name) .. "'", 0)
+    end,
+    __index = function(_, name)
+        error("Script attempted to access nonexistent global variable '" .. tostring(name) .. "'", 0)
+    end,
+})
+"#;
+
+// A script whose syntax has been checked, ready to run any number of times
+pub struct Script {
+    source: String,
+}
+
+impl Script {
+    // Check a script's syntax, returning its syntax error if it has one
+    pub fn compile(source: &str) -> Result<Script, String> {
+        let lua = Lua::new_with(StdLib::NONE, LuaOptions::default()).map_err(|e| error_message(&e))?;
+        lua.load(source).set_name("=user_script").into_function().map_err(|e| error_message(&e))?;
+        Ok(Script { source: source.to_string() })
+    }
+    
+    // Run the script with KEYS and ARGV set, calling `host` for each command
+    // it runs with redis.call, and convert what it returns to a reply. Setting
+    // `kill` stops the script with an error.
+    pub fn run(&self, keys: &[&str], args: &[&str], host: &mut dyn FnMut(&[String]) -> Reply, kill: &Arc<AtomicBool>) -> Reply {
+        // The interpreter has to outlive the values converted to the reply
+        let lua = match Interpreter::new(false, kill) {
+            Ok(lua) => lua,
+            Err(error) => return Reply::error(error_message(&error)),
+        };
+        let mut run = || {
+            for (name, values) in [("KEYS", keys), ("ARGV", args)] {
+                lua.lua.globals().raw_set(name, lua.lua.create_sequence_from(values.iter().copied())?)?;
+            }
+            lua.with_host(host, || {
+                let main = lua.lua.load(&self.source).set_name("=user_script").into_function()?;
+                Ok(lua.call(&main, ()))
+            })
+        };
+        match run() {
+            Ok(Ok(value)) => to_reply(&value, 0),
+            Ok(Err(error)) => error_reply(&error),
+            Err(error) => Reply::error(error_message(&error)),
+        }
+    }
+    
+    // Run the script as a function library, returning the functions it
+    // registered with redis.register_function, in order, or its error
+    pub fn register_functions(&self) -> Result<Vec<Function>, String> {
+        // Commands are refused while a library loads, so this is never called
+        let mut host = |_: &[String]| Reply::Nil;
+        let lua = Interpreter::new(true, &Arc::default()).map_err(|e| error_message(&e))?;
+        match lua.with_host(&mut host, || lua.load_library(&self.source)) {
+            Ok(Ok(registered)) => Ok(registered.into_iter().map(|registered| registered.function).collect()),
+            Ok(Err(error)) => Err(error_text(&error)),
+            Err(error) => Err(error_message(&error)),
+        }
+    }
+    
+    // Run the script as a function library and call the function it
+    // registers as `name`, with a table of the keys and a table of the other
+    // arguments, converting what it returns to a reply. Setting `kill` stops
+    // the function with an error.
+    pub fn call_function(
+        &self,
+        name: &str,
+        keys: &[&str],
+        args: &[&str],
+        host: &mut dyn FnMut(&[String]) -> Reply,
+        kill: &Arc<AtomicBool>,
+    ) -> Reply {
+        let lua = match Interpreter::new(true, kill) {
+            Ok(lua) => lua,
+            Err(error) => return Reply::error(error_message(&error)),
+        };
+        let call = || {
+            let registered = match lua.load_library(&self.source)? {
+                Ok(registered) => registered,
+                Err(error) => return Ok(Err(error)),
+            };
+            let Some(registered) = registered.into_iter().find(|registered| registered.function.name == name) else {
+                return Ok(Ok(None));
+            };
+            let keys = lua.lua.create_sequence_from(keys.iter().copied())?;
+            let args = lua.lua.create_sequence_from(args.iter().copied())?;
+            Ok(lua.call(&registered.callback, (keys, args)).map(Some))
+        };
+        match lua.with_host(host, call) {
+            Ok(Ok(Some(value))) => to_reply(&value, 0),
+            Ok(Ok(None)) => Reply::error("Function not found"),
+            Ok(Err(error)) => error_reply(&error),
+            Err(error) => Reply::error(error_message(&error)),
+        }
+    }
+}
+
+// A function a library registered, as FUNCTION LIST shows it
+#[derive(Clone, PartialEq, Debug)]
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+struct Registered {
+    function: Function,
+    callback: mlua::Function,
+}
+
+struct Interpreter {
+    lua: Lua,
+    // Lua's own pcall, taken before a script can replace it
+    pcall: mlua::Function,
+    // The functions registered so far while a library loads, None when
+    // running anything else
+    registered: Rc<RefCell<Option<Vec<Registered>>>>,
+}
+
+impl Interpreter {
+    // An interpreter for a script, or with `library` for a function library,
+    // that raises an error once `kill` is set
+    fn new(library: bool, kill: &Arc<AtomicBool>) -> mlua::Result<Self> {
+        let lua = Lua::new_with(StdLib::STRING | StdLib::TABLE | StdLib::MATH, LuaOptions::default())?;
+        let kill = kill.clone();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(KILL_CHECK_INTERVAL), move |lua, _| {
+            if kill.load(Ordering::Relaxed) {
+                // Raised again at every instruction from then on, so a
+                // script's own pcall can't keep it running
+                lua.set_hook(HookTriggers::new().every_nth_instruction(1), |_, _| Err(killed()));
+                return Err(killed());
+            }
+            Ok(VmState::Continue)
+        });
+        let registered = Rc::new(RefCell::new(library.then(Vec::new)));
+        
+        let redis = lua.create_table()?;
+        redis.set("error_reply", lua.create_function(|lua, error: mlua::String| field_table(lua, "err", error))?)?;
+        redis.set("status_reply", lua.create_function(|lua, status: mlua::String| field_table(lua, "ok", status))?)?;
+        redis.set("log", lua.create_function(redis_log)?)?;
+        let registering = registered.clone();
+        let register = lua.create_function(move |_, args: MultiValue| redis_register_function(&registering, args))?;
+        redis.set("register_function", register)?;
+        for (level, name) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"].iter().enumerate() {
+            redis.set(*name, level)?;
+        }
+        lua.globals().set("redis", redis)?;
+        
+        let pcall = lua.globals().get("pcall")?;
+        Ok(Interpreter { lua, pcall, registered })
+    }
+    
+    // Run `f` with redis.call and redis.pcall running their commands
+    // through `host`, once the prelude has locked the globals
+    fn with_host<R>(&self, host: &mut dyn FnMut(&[String]) -> Reply, f: impl FnOnce() -> mlua::Result<R>) -> mlua::Result<R> {
+        let loading = self.registered.clone();
+        self.lua.scope(|scope| {
+            let command = scope.create_function_mut(|lua, args: MultiValue| {
+                if loading.borrow().is_some() {
+                    return Err(mlua::Error::runtime("Commands can't be run while a function library loads"));
+                }
+                run_command(lua, args, host)
+            })?;
+            self.lua.load(PRELUDE).set_name("=prelude").call::<()>(command)?;
+            f()
+        })
+    }
+    
+    // Call a Lua function, returning what it returns first or the value it
+    // raised, which for a Redis error is a table with an err field
+    fn call(&self, function: &mlua::Function, args: impl mlua::IntoLuaMulti) -> Result<Value, Value> {
+        let mut results = match args.into_lua_multi(&self.lua).and_then(|mut args| {
+            args.push_front(Value::Function(function.clone()));
+            self.pcall.call::<MultiValue>(args)
+        }) {
+            Ok(results) => results.into_iter(),
+            Err(error) => return Err(Value::Error(Box::new(error))),
+        };
+        let succeeded = matches!(results.next(), Some(Value::Boolean(true)));
+        let value = results.next().unwrap_or(Value::Nil);
+        if succeeded { Ok(value) } else { Err(value) }
+    }
+    
+    // Run a library's main chunk, collecting the functions it registers.
+    // Commands can be run once it is done.
+    fn load_library(&self, source: &str) -> mlua::Result<Result<Vec<Registered>, Value>> {
+        let main = self.lua.load(source).set_name("=user_script").into_function()?;
+        let result = self.call(&main, ());
+        let registered = self.registered.borrow_mut().take().unwrap_or_default();
+        Ok(result.map(|_| registered))
+    }
+}
+
+// The error a script is stopped with by SCRIPT KILL or FUNCTION KILL
+fn killed() -> mlua::Error {
+    mlua::Error::runtime("Script killed by user with SCRIPT KILL...")
+}
+
+// A table with one string field, like the ones status and error replies become
+fn field_table(lua: &Lua, name: &str, value: impl mlua::IntoLua) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    table.raw_set(name, value)?;
+    Ok(table)
+}
+
+// Run a command for redis.call or redis.pcall. Either way a Redis error
+// comes back as a table with an err field; redis.call raises it.
+fn run_command(lua: &Lua, args: MultiValue, host: &mut dyn FnMut(&[String]) -> Reply) -> mlua::Result<Value> {
+    let command: Option<Vec<String>> = args.iter()
+        .map(|arg| match arg {
+            Value::String(s) => s.to_str().ok().map(|s| s.to_string()),
+            Value::Integer(_) | Value::Number(_) => arg.to_string().ok(),
+            _ => None,
+        })
+        .collect();
+    let reply = match command {
+        _ if args.is_empty() => Reply::error("Please specify at least one argument for this redis lib call"),
+        Some(command) => host(&command),
+        None => Reply::error("Lua redis lib command arguments must be strings or integers"),
+    };
+    reply_to_value(lua, reply)
+}
+
+// A reply to redis.call as a Lua value, converted the way Redis does: an
+// integer becomes a number, nil becomes false, a status or error reply a
+// table with an ok or err field, and an array (or map, flattened) a table
+fn reply_to_value(lua: &Lua, reply: Reply) -> mlua::Result<Value> {
+    Ok(match reply {
+        Reply::Simple(status) => Value::Table(field_table(lua, "ok", status)?),
+        Reply::Error(error) => Value::Table(field_table(lua, "err", error)?),
+        Reply::Integer(n) => Value::Integer(n),
+        Reply::Bulk(bytes) => Value::String(lua.create_string(bytes)?),
+        Reply::Nil => Value::Boolean(false),
+        Reply::Double(d) => Value::String(lua.create_string(format_double(d))?),
+        Reply::Array(items) | Reply::Push(items) | Reply::Several(items) => {
+            let values = items.into_iter().map(|item| reply_to_value(lua, item)).collect::<mlua::Result<Vec<_>>>()?;
+            Value::Table(lua.create_sequence_from(values)?)
+        }
+        Reply::Map(pairs) => {
+            let values = pairs.into_iter()
+                .flat_map(|(field, value)| [field, value])
+                .map(|item| reply_to_value(lua, item))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            Value::Table(lua.create_sequence_from(values)?)
+        }
+    })
+}
+
+// What a script returned as a reply, converted the way Redis does: numbers
+// become integers, dropping any fraction, true becomes 1, false and nil a nil
+// reply, and a table an array of its elements up to the first nil, or a
+// status or error reply if it has an ok or err field
+fn to_reply(value: &Value, depth: usize) -> Reply {
+    match value {
+        Value::Boolean(true) => Reply::Integer(1),
+        Value::Integer(n) => Reply::Integer(*n),
+        Value::Number(n) => Reply::Integer(*n as i64),
+        Value::String(s) => Reply::Bulk(s.as_bytes().to_vec()),
+        Value::Table(_) if depth == MAX_REPLY_DEPTH => Reply::error("reached lua stack limit"),
+        Value::Table(table) => {
+            if let Some(error) = string_field(table, "err") {
+                return Reply::Error(error);
+            }
+            if let Some(status) = string_field(table, "ok") {
+                return Reply::Simple(status);
+            }
+            Reply::Array(table.clone().sequence_values::<Value>()
+                .map_while(Result::ok)
+                .map(|value| to_reply(&value, depth + 1))
+                .collect())
+        }
+        _ => Reply::Nil,
+    }
+}
+
+// A table's string field, on one line as status and error replies must be
+fn string_field(table: &Table, name: &str) -> Option<String> {
+    match table.raw_get(name) {
+        Ok(Value::String(s)) => Some(single_line(&s.as_bytes())),
+        _ => None,
+    }
+}
+
+// Status and error replies can't span lines
+fn single_line(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).replace(['\r', '\n'], " ")
+}
+
+// The reply for an error a script raised and didn't catch: a Redis error
+// raised by redis.call is passed on as it is
+fn error_reply(error: &Value) -> Reply {
+    if let Value::Table(table) = error {
+        if let Some(error) = string_field(table, "err") {
+            return Reply::Error(error);
+        }
+    }
+    Reply::error(error_text(error))
+}
+
+// The message of an error a script raised, a Redis error's included
+fn error_text(error: &Value) -> String {
+    match error {
+        Value::Table(table) => string_field(table, "err").unwrap_or_else(|| "unknown error".to_string()),
+        Value::Error(error) => error_message(error),
+        other => single_line(other.to_string().unwrap_or_default().as_bytes()),
+    }
+}
+
+// The message of an error from the interpreter, without the traceback a
+// Rust function's error is wrapped in
+fn error_message(error: &mlua::Error) -> String {
+    match error {
+        mlua::Error::SyntaxError { message, .. } | mlua::Error::RuntimeError(message) | mlua::Error::MemoryError(message) => {
+            single_line(message.as_bytes())
+        }
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        other => single_line(other.to_string().as_bytes()),
+    }
+}
+
+// Write to the server's log, at warning level for redis.LOG_WARNING
+fn redis_log(_: &Lua, args: Variadic<Value>) -> mlua::Result<()> {
+    let level = match args.first() {
+        Some(Value::Integer(level)) => *level as f64,
+        Some(Value::Number(level)) => *level,
+        _ => return Err(mlua::Error::runtime("First argument must be a number (log level).")),
+    };
+    if args.len() < 2 {
+        return Err(mlua::Error::runtime("redis.log() requires two arguments or more."));
+    }
+    let message: Vec<String> = args[1..].iter().map(|arg| arg.to_string().unwrap_or_default()).collect();
+    if level >= 3.0 {
+        warn!("Script: {}", message.join(" "));
+    } else {
+        info!("Script: {}", message.join(" "));
+    }
+    Ok(())
+}
+
+// redis.register_function(name, callback), or with a table of its
+// function_name, callback and description
+fn redis_register_function(registered: &RefCell<Option<Vec<Registered>>>, args: MultiValue) -> mlua::Result<()> {
+    let (name, callback, description) = match (args.front(), args.len()) {
+        (Some(Value::Table(fields)), 1) => {
+            for pair in fields.clone().pairs::<Value, Value>() {
+                let (key, _) = pair?;
+                match &key {
+                    Value::String(name) if [&b"function_name"[..], b"callback", b"description"].contains(&&name.as_bytes()[..]) => {}
+                    _ => return Err(mlua::Error::runtime("unknown argument given to redis.register_function")),
+                }
+            }
+            (fields.raw_get("function_name")?, fields.raw_get("callback")?, fields.raw_get("description")?)
+        }
+        (_, 2) => (args[0].clone(), args[1].clone(), Value::Nil),
+        _ => return Err(mlua::Error::runtime("wrong number of arguments to redis.register_function")),
+    };
+    let mut registered = registered.borrow_mut();
+    let Some(registered) = registered.as_mut() else {
+        return Err(mlua::Error::runtime("redis.register_function can only be called while a function library loads"));
+    };
+    let Value::String(name) = name else {
+        return Err(mlua::Error::runtime("function_name argument given to redis.register_function must be a string"));
+    };
+    let Value::Function(callback) = callback else {
+        return Err(mlua::Error::runtime("callback argument given to redis.register_function must be a function"));
+    };
+    let description = match description {
+        Value::Nil => None,
+        Value::String(description) => Some(description.to_string_lossy()),
+        _ => return Err(mlua::Error::runtime("description argument given to redis.register_function must be a string")),
+    };
+    
+    let function = Function { name: name.to_string_lossy(), description };
+    registered.push(Registered { function, callback });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // Run a script whose commands all just echo their arguments back
+    fn eval(source: &str, keys: &[&str], args: &[&str]) -> Reply {
+        let script = Script::compile(source).unwrap();
+        script.run(keys, args, &mut |command| {
+            Reply::Array(command.iter().map(|arg| Reply::bulk(arg.as_str())).collect())
+        }, &Arc::default())
+    }
+    
+    #[test]
+    fn runs_the_language() {
+        assert_eq!(eval("return 1 + 2 * 3 ^ 2 - 10 / 4", &[], &[]), Reply::Integer(16));
+        assert_eq!(eval("return 'a' .. 1 .. 'b' .. 2.5", &[], &[]), Reply::bulk("a1b2.5"));
+        assert_eq!(eval("local x, y = 1 return x, y", &[], &[]), Reply::Integer(1));
+        
+        let source = "
+            local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+            local squares = {}
+            for i = 1, 5 do squares[#squares + 1] = i * i end
+            local total = 0
+            for _, square in ipairs(squares) do total = total + square end
+            local i = 0
+            while true do i = i + 1 if i == 3 then break end end
+            repeat i = i - 1 until i == 0
+            return {fib(10), total, select('#', 1, 2, 3), string.format('%05.1f|%-3s|%x', 3.14159, 'a', 255)}
+        ";
+        assert_eq!(eval(source, &[], &[]), Reply::Array(vec![
+            Reply::Integer(55), Reply::Integer(55), Reply::Integer(3), Reply::bulk("003.1|a  |ff"),
+        ]));
+        
+        // Closures keep their own variables, and the whole language is there,
+        // string patterns included
+        let source = "
+            local function counter() local n = 0 return function() n = n + 1 return n end end
+            local a, b = counter(), counter()
+            a() a()
+            local words = {}
+            for word in ('one two'):gmatch('%a+') do words[#words + 1] = word end
+            return {a(), b(), ('abc'):upper(), #'abc', tostring(nil), 7 % 3, -7 % 3, unpack(words)}
+        ";
+        assert_eq!(eval(source, &[], &[]), Reply::Array(vec![
+            Reply::Integer(3), Reply::Integer(1), Reply::bulk("ABC"), Reply::Integer(3), Reply::bulk("nil"),
+            Reply::Integer(1), Reply::Integer(2), Reply::bulk("one"), Reply::bulk("two"),
+        ]));
+    }
+    
+    #[test]
+    fn replies_convert_both_ways() {
+        // Command replies become Lua values and back
+        assert_eq!(eval("return redis.call('SET', KEYS[1], ARGV[1], 3)", &["key"], &["value"]), Reply::Array(vec![
+            Reply::bulk("SET"), Reply::bulk("key"), Reply::bulk("value"), Reply::bulk("3"),
+        ]));
+        let script = Script::compile("
+            local ok = redis.call('SET')
+            local missing = redis.call('GET')
+            local _, failed = pcall(redis.call, 'FAIL')
+            return {ok.ok, type(missing), failed.err, redis.pcall('FAIL').err, 3.9, true, false, 'after'}
+        ").unwrap();
+        let reply = script.run(&[], &[], &mut |command| match command[0].as_str() {
+            "SET" => Reply::ok(),
+            "GET" => Reply::Nil,
+            _ => Reply::error("failed"),
+        }, &Arc::default());
+        assert_eq!(reply, Reply::Array(vec![
+            Reply::bulk("OK"), Reply::bulk("boolean"), Reply::bulk("ERR failed"), Reply::bulk("ERR failed"),
+            Reply::Integer(3), Reply::Integer(1), Reply::Nil, Reply::bulk("after"),
+        ]));
+        
+        // Tables with ok or err are status and error replies, and so are raised errors
+        assert_eq!(eval("return redis.status_reply('FINE')", &[], &[]), Reply::Simple("FINE".to_string()));
+        assert_eq!(eval("return {err = 'CODE bad'}", &[], &[]), Reply::Error("CODE bad".to_string()));
+        assert_eq!(eval("error('boom')", &[], &[]), Reply::error("user_script:1: boom"));
+        let script = Script::compile("return redis.call('FAIL')").unwrap();
+        assert_eq!(script.run(&[], &[], &mut |_| Reply::error("failed"), &Arc::default()), Reply::error("failed"));
+        
+        // Arrays stop at the first nil
+        assert_eq!(eval("return {1, 2, nil, 4}", &[], &[]), Reply::Array(vec![Reply::Integer(1), Reply::Integer(2)]));
+    }
+    
+    #[test]
+    fn mistakes_are_errors() {
+        let compile_error = |source: &str| Script::compile(source).err().unwrap();
+        assert_eq!(compile_error("return 1 +"), "user_script:1: unexpected symbol near <eof>");
+        assert_eq!(compile_error("if x\nthen"), "user_script:2: 'end' expected (to close 'if' at line 1) near <eof>");
+        assert_eq!(compile_error("break"), "user_script:1: break outside loop at line 1");
+        assert_eq!(compile_error("x = 'open"), "user_script:1: unfinished string near <eof>");
+        
+        // Globals can't be created, or read before they exist
+        assert_eq!(eval("x = 1", &[], &[]), Reply::error("Script attempted to create global variable 'x'"));
+        assert_eq!(eval("return y", &[], &[]), Reply::error("Script attempted to access nonexistent global variable 'y'"));
+        assert_eq!(eval("return 1 < 'a'", &[], &[]), Reply::error("user_script:1: attempt to compare number with string"));
+        assert_eq!(eval("return {} .. 'a'", &[], &[]), Reply::error("user_script:1: attempt to concatenate a table value"));
+        
+        // Nor can scripts reach the filesystem
+        assert_eq!(eval("return dofile", &[], &[]), Reply::error("Script attempted to access nonexistent global variable 'dofile'"));
+        assert_eq!(eval("return io", &[], &[]), Reply::error("Script attempted to access nonexistent global variable 'io'"));
+        
+        // Runaway recursion fails instead of overflowing the stack, and a
+        // table holding itself can't make a reply nest forever
+        let Reply::Error(overflow) = eval("local function f() return f() + 1 end return f()", &[], &[]) else {
+            panic!("expected an error");
+        };
+        assert!(overflow.contains("stack overflow"), "{}", overflow);
+        let mut reply = eval("local t = {} t[1] = t return t", &[], &[]);
+        for _ in 0..MAX_REPLY_DEPTH {
+            let Reply::Array(mut items) = reply else { panic!("expected an array") };
+            reply = items.remove(0);
+        }
+        assert_eq!(reply, Reply::error("reached lua stack limit"));
+    }
+    
+    #[test]
+    fn killed_scripts_stop() {
+        let kill = Arc::new(AtomicBool::new(false));
+        let killer = kill.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            killer.store(true, Ordering::Relaxed);
+        });
+        
+        // Even catching the error doesn't keep the script going
+        let script = Script::compile("while true do pcall(function() while true do end end) end").unwrap();
+        let reply = script.run(&[], &[], &mut |_| Reply::Nil, &kill);
+        assert_eq!(reply, Reply::error("Script killed by user with SCRIPT KILL..."));
+    }
+}
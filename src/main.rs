@@ -1,14 +1,68 @@
 use bytes::BytesMut;
 use dotenv::dotenv;
-use log::{error, info};
-use std::collections::HashMap;
+use log::{debug, error, info};
+use rand::Rng;
+use rand::seq::{IteratorRandom, IndexedRandom};
+use std::collections::{HashSet, VecDeque};
 use std::env;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::time::sleep;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify};
+use tokio::task::JoinSet;
+use tokio::time::sleep_until;
+use tokio_rustls::TlsAcceptor;
+
+mod args;
+mod blocking;
+mod clients;
+mod commands;
+mod compact;
+mod config;
+mod dump;
+mod eviction;
+mod functions;
+mod geo;
+mod glob;
+mod hyperloglog;
+mod keyspace;
+mod latency;
+mod lua;
+mod monitor;
+mod persistence;
+mod protocol;
+mod pubsub;
+mod replication;
+mod slowlog;
+mod tls;
+mod tracking;
+mod zset;
+
+use args::Action;
+use blocking::{Blocking, Unblock};
+use clients::Clients;
+use compact::{Hash, Set};
+use commands::{COMMANDS, DENY_OOM, NO_SCRIPT, READONLY, WRITE};
+use config::Config;
+use config::{EncodingLimits, MaxmemoryPolicy};
+use eviction::EvictionPool;
+use functions::Functions;
+use glob::glob_match;
+use hyperloglog::HyperLogLog;
+use keyspace::{scan_hash, EntryMut, Keyspace};
+use latency::LatencyMonitor;
+use lua::Script;
+use slowlog::SlowLog;
+use tracking::{Invalidation, Target, Tracking};
+use persistence::AppendOnlyFile;
+use protocol::{Args, Protocol, Reply};
+use pubsub::PubSub;
+use replication::{Leader, ReplicaFeed};
+use zset::{ScoreBound, SortedSet};
 
 // Error types our Redis server might encounter
 #[derive(Error, Debug)]
@@ -16,264 +70,5109 @@ enum RedisError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     
-    #[error("Unknown command: {0}")]
+    #[error("unknown command '{0}'")]
     UnknownCommand(String),
+    
+    // A command asked a key for a kind of value it doesn't hold
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+}
+
+// The kinds of value a key can hold
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    // Binary safe, like Redis strings
+    String(Vec<u8>),
+    // Elements in order from the head (left) to the tail (right)
+    List(VecDeque<Vec<u8>>),
+    Hash(Hash),
+    Set(Set),
+    SortedSet(SortedSet),
+}
+
+// Rough bookkeeping cost of each list element, hash field or set member on top
+// of its bytes
+const LIST_ELEMENT_OVERHEAD: usize = 16;
+const HASH_FIELD_OVERHEAD: usize = 32;
+const SET_MEMBER_OVERHEAD: usize = 24;
+
+impl Value {
+    // The type name TYPE reports
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+    
+    // Roughly how much work freeing the value takes: the number of separate
+    // allocations it is made of, like Redis's lazyfree effort
+    fn free_effort(&self) -> usize {
+        match self {
+            Value::String(_) => 1,
+            Value::List(list) => list.len(),
+            Value::Hash(hash) => hash.len(),
+            Value::Set(set) => set.len(),
+            Value::SortedSet(zset) => zset.len(),
+        }
+    }
+    
+    // How many elements a collection holds, None for a string
+    fn elements(&self) -> Option<usize> {
+        match self {
+            Value::String(_) => None,
+            Value::List(list) => Some(list.len()),
+            Value::Hash(hash) => Some(hash.len()),
+            Value::Set(set) => Some(set.len()),
+            Value::SortedSet(zset) => Some(zset.len()),
+        }
+    }
+    
+    // Switch a compact hash or set to a hash table once it outgrows the limits
+    fn fit(&mut self, limits: &EncodingLimits) {
+        match self {
+            Value::Hash(hash) => hash.fit(limits),
+            Value::Set(set) => set.fit(limits),
+            _ => {}
+        }
+    }
+    
+    // The value as the kind a command needs, or RedisError::WrongType for a
+    // key holding another kind
+    fn as_string(&self) -> Result<&Vec<u8>, RedisError> {
+        match self {
+            Value::String(bytes) => Ok(bytes),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_string_mut(&mut self) -> Result<&mut Vec<u8>, RedisError> {
+        match self {
+            Value::String(bytes) => Ok(bytes),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_list(&self) -> Result<&VecDeque<Vec<u8>>, RedisError> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_list_mut(&mut self) -> Result<&mut VecDeque<Vec<u8>>, RedisError> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_hash(&self) -> Result<&Hash, RedisError> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_hash_mut(&mut self) -> Result<&mut Hash, RedisError> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_set(&self) -> Result<&Set, RedisError> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_set_mut(&mut self) -> Result<&mut Set, RedisError> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_zset(&self) -> Result<&SortedSet, RedisError> {
+        match self {
+            Value::SortedSet(zset) => Ok(zset),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    fn as_zset_mut(&mut self) -> Result<&mut SortedSet, RedisError> {
+        match self {
+            Value::SortedSet(zset) => Ok(zset),
+            _ => Err(RedisError::WrongType),
+        }
+    }
+    
+    // Approximate memory used by the value itself
+    pub fn memory(&self) -> usize {
+        match self {
+            Value::String(bytes) => bytes.len(),
+            Value::List(list) => list.iter().map(|element| element.len() + LIST_ELEMENT_OVERHEAD).sum(),
+            Value::Hash(hash) => hash.iter().map(|(field, value)| field.len() + value.len() + HASH_FIELD_OVERHEAD).sum(),
+            Value::Set(set) => set.iter().map(|member| member.len() + SET_MEMBER_OVERHEAD).sum(),
+            Value::SortedSet(zset) => zset.memory(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::String(bytes)
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Self {
+        Value::String(string.into_bytes())
+    }
+}
+
+impl From<VecDeque<Vec<u8>>> for Value {
+    fn from(list: VecDeque<Vec<u8>>) -> Self {
+        Value::List(list)
+    }
+}
+
+impl From<Hash> for Value {
+    fn from(hash: Hash) -> Self {
+        Value::Hash(hash)
+    }
 }
 
+impl From<Set> for Value {
+    fn from(set: Set) -> Self {
+        Value::Set(set)
+    }
+}
+
+impl From<HashSet<String>> for Value {
+    fn from(set: HashSet<String>) -> Self {
+        Value::Set(Set::from(set))
+    }
+}
+
+impl From<SortedSet> for Value {
+    fn from(zset: SortedSet) -> Self {
+        Value::SortedSet(zset)
+    }
+}
+
+// What LOLWUT draws above the version
+const LOLWUT_ART: &str = "\
+ _._
+/   \\
+\\_._/";
+
+// The current Unix time in seconds
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// Reply to a command used on a key holding another type of value
+fn wrong_type() -> Reply {
+    Reply::error_code("WRONGTYPE", RedisError::WrongType.to_string())
+}
+
+// When a TTL starting now runs out, or the error to reply with if that's too
+// far in the future for the clock to represent
+fn expires_after(command: &str, ttl: Duration) -> Result<Instant, Reply> {
+    Instant::now().checked_add(ttl).ok_or_else(|| invalid_expire_time(command))
+}
+
+// The timeout of BLPOP or BRPOP, in seconds, or None for 0 to wait forever
+fn block_timeout(timeout: &str) -> Result<Option<Duration>, Reply> {
+    let out_of_range = || Reply::error("timeout is not a float or out of range");
+    let secs = timeout.parse::<f64>().ok().filter(|secs| secs.is_finite()).ok_or_else(out_of_range)?;
+    if secs < 0.0 {
+        return Err(Reply::error("timeout is negative"));
+    }
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(secs).map(Some).map_err(|_| out_of_range())
+}
+
+// The expiry an EX, PX, EXAT or PXAT option of SET or GETEX sets. Like SETEX
+// and PSETEX, the amount must be a positive integer.
+fn expiry_option(command: &str, unit: &str, amount: &str) -> Result<Instant, Reply> {
+    let amount = match amount.parse::<u64>() {
+        Ok(0) | Err(_) => return Err(invalid_expire_time(command)),
+        Ok(amount) => amount,
+    };
+    match unit {
+        "EX" => expires_after(command, Duration::from_secs(amount)),
+        "PX" => expires_after(command, Duration::from_millis(amount)),
+        "EXAT" => amount.checked_mul(1000)
+            .and_then(persistence::unix_ms_to_instant)
+            .ok_or_else(|| invalid_expire_time(command)),
+        _ => persistence::unix_ms_to_instant(amount).ok_or_else(|| invalid_expire_time(command)),
+    }
+}
+
+fn invalid_expire_time(command: &str) -> Reply {
+    Reply::error(format!("invalid expire time in '{}' command", command.to_lowercase()))
+}
+
+// The access frequency counter of a new key, high enough that it isn't
+// evicted before it had a chance to be used
+const LFU_INIT_VAL: u8 = 5;
+// How slowly the access frequency counter grows, and the minutes a key goes
+// unused for each point it loses, Redis's default lfu-log-factor and lfu-decay-time
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_MINUTES: u64 = 1;
+
 // Struct to store the value along with expiration time
-struct RedisValue {
-    value: String,
+#[derive(Clone)]
+pub struct RedisValue {
+    value: Value,
     expires_at: Option<Instant>,
+    // Last time the key was read or written, for LRU eviction and OBJECT IDLETIME
+    last_access: Instant,
+    // Logarithmic access frequency counter as of the last access, for LFU
+    // eviction and OBJECT FREQ
+    frequency: u8,
 }
 
 impl RedisValue {
-    fn new(value: String, ttl_seconds: Option<u64>) -> Self {
-        let expires_at = ttl_seconds.map(|ttl| Instant::now() + Duration::from_secs(ttl));
-        
+    fn new(value: impl Into<Value>, expires_at: Option<Instant>) -> Self {
         RedisValue {
-            value,
+            value: value.into(),
             expires_at,
+            last_access: Instant::now(),
+            frequency: LFU_INIT_VAL,
+        }
+    }
+    
+    // Record an access. Like Redis, the frequency counter only goes up by one
+    // with a chance that shrinks the higher it already is, so it takes about
+    // a million accesses to reach its maximum of 255.
+    fn touch(&mut self) {
+        let frequency = self.frequency();
+        let base = frequency.saturating_sub(LFU_INIT_VAL) as f64;
+        let increment = frequency < u8::MAX && rand::rng().random::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        self.frequency = frequency + increment as u8;
+        self.last_access = Instant::now();
+    }
+    
+    // The access frequency counter, less a point for each period the key went unused
+    fn frequency(&self) -> u8 {
+        let periods = self.last_access.elapsed().as_secs() / (60 * LFU_DECAY_MINUTES);
+        self.frequency.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+    
+    // The encoding of this value, as OBJECT ENCODING reports it. Hashes and
+    // sets report the one they're stored in; for the others it's the one
+    // Redis would pick: for strings, int for a canonical 64-bit integer,
+    // embstr for short strings Redis allocates together with their header,
+    // raw for longer ones; for lists, a single listpack until it outgrows the
+    // 8kb Redis's default list-max-listpack-size allows or takes an element
+    // past the packed threshold, then a quicklist; for sorted sets, the
+    // compact listpack until they grow past Redis's default
+    // zset-max-listpack-entries/value limits, then a skiplist
+    fn encoding(&self, limits: &EncodingLimits) -> &'static str {
+        match &self.value {
+            Value::String(bytes) => {
+                if is_canonical_int(bytes) {
+                    "int"
+                } else if bytes.len() <= 44 {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            Value::List(list) => {
+                let packed = list.iter().all(|element| element.len() <= limits.list_packed_threshold);
+                if packed && list.iter().map(Vec::len).sum::<usize>() <= 8192 {
+                    "listpack"
+                } else {
+                    "quicklist"
+                }
+            }
+            Value::Hash(hash) => hash.encoding(),
+            Value::Set(set) => set.encoding(),
+            Value::SortedSet(zset) => {
+                if zset.len() <= 128 && zset.iter().all(|(member, _)| member.len() <= 64) {
+                    "listpack"
+                } else {
+                    "skiplist"
+                }
+            }
         }
     }
     
     fn is_expired(&self) -> bool {
-        match self.expires_at {
-            Some(expires_at) => Instant::now() > expires_at,
-            None => false,
+        self.expires_at.is_some_and(|expires_at| keyspace::has_expired(expires_at, Instant::now()))
+    }
+}
+
+// Whether the bytes are a 64-bit integer written the way Redis would write it,
+// with no sign, leading zeros or spaces it would drop
+fn is_canonical_int(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes).ok()
+        .and_then(|text| text.parse::<i64>().ok().filter(|n| n.to_string() == text))
+        .is_some()
+}
+
+// The arguments of a command as text. The dispatcher refuses anything but a
+// value that isn't valid UTF-8, and commands read values from the arguments
+// as sent, so the placeholder only ever stands in for one of those.
+fn text_args(args: &[Vec<u8>]) -> Vec<&str> {
+    args.iter().map(|arg| std::str::from_utf8(arg).unwrap_or("\u{FFFD}")).collect()
+}
+
+// The arguments of a command as logs show them: AUTH and HELLO only by
+// name, since they can carry a password
+fn logged_args<'a, T: AsRef<str>>(command: &str, args: &'a [T]) -> &'a [T] {
+    if command.eq_ignore_ascii_case("AUTH") || command.eq_ignore_ascii_case("HELLO") {
+        &args[..1]
+    } else {
+        args
+    }
+}
+
+// Look up a key, treating an expired entry as absent and deleting it on the
+// spot (lazy expiry) instead of waiting for the background sweep. A live key
+// counts as accessed, which keeps it from being evicted.
+fn get_live<'a>(data: &'a mut Keyspace, key: &'a str) -> Option<EntryMut<'a>> {
+    if data.remove_if_expired(key) {
+        return None;
+    }
+    
+    let mut value = data.get_mut(key)?;
+    value.touch();
+    Some(value)
+}
+
+// The positions from `start` to `end` inclusive in a sequence of `len`
+// elements, where negative indexes count back from the end. Out of range
+// indexes are clamped to the sequence; None if nothing is left.
+fn index_range(len: usize, start: i64, end: i64) -> Option<std::ops::RangeInclusive<usize>> {
+    let len = len as i64;
+    let resolve = |index: i64| if index < 0 { (len + index).max(0) } else { index };
+    let (start, end) = (resolve(start), resolve(end).min(len - 1));
+    
+    if start > end {
+        return None;
+    }
+    Some(start as usize..=end as usize)
+}
+
+// The bytes from `start` to `end` inclusive, as index_range picks them
+fn byte_range(bytes: &[u8], start: i64, end: i64) -> &[u8] {
+    index_range(bytes.len(), start, end).map_or(&[], |range| &bytes[range])
+}
+
+// A LIMIT offset count option, which skips `offset` results and returns at
+// most `count` of the rest. As in Redis, a negative count returns all of the
+// rest and a negative offset returns nothing.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Limit {
+    offset: i64,
+    count: i64,
+}
+
+impl Limit {
+    // Parse the offset and count following LIMIT
+    fn parse(offset: &str, count: &str) -> Option<Self> {
+        Some(Limit { offset: offset.parse().ok()?, count: count.parse().ok()? })
+    }
+    
+    fn apply<I: Iterator>(&self, results: I) -> std::iter::Take<std::iter::Skip<I>> {
+        let (offset, count) = match usize::try_from(self.offset) {
+            Ok(offset) => (offset, usize::try_from(self.count).unwrap_or(usize::MAX)),
+            Err(_) => (0, 0),
+        };
+        results.skip(offset).take(count)
+    }
+}
+
+// EXPIRE's NX, XX, GT and LT options, which only let a new expiry replace
+// the current one under some condition
+#[derive(Default)]
+struct ExpireCondition {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+}
+
+impl ExpireCondition {
+    fn parse(options: &[&str]) -> Result<Self, Reply> {
+        let mut condition = ExpireCondition::default();
+        for option in options {
+            match option.to_uppercase().as_str() {
+                "NX" => condition.nx = true,
+                "XX" => condition.xx = true,
+                "GT" => condition.gt = true,
+                "LT" => condition.lt = true,
+                _ => return Err(Reply::error(format!("Unsupported option {}", option))),
+            }
+        }
+        
+        if condition.nx && (condition.xx || condition.gt || condition.lt) {
+            return Err(Reply::error("NX and XX, GT or LT options at the same time are not compatible"));
+        }
+        if condition.gt && condition.lt {
+            return Err(Reply::error("GT and LT options at the same time are not compatible"));
+        }
+        Ok(condition)
+    }
+    
+    // Whether `new` may replace the `current` expiry. As in Redis, a key
+    // without one counts as never expiring, so LT applies to it and GT doesn't.
+    fn allows(&self, current: Option<Instant>, new: Instant) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) => !self.nx && (!self.gt || new > current) && (!self.lt || new < current),
+        }
+    }
+}
+
+// The position of a list element by index, where negative indexes count back
+// from the tail; None if it is out of range
+fn list_position(len: usize, index: i64) -> Option<usize> {
+    let position = if index < 0 { (len as i64).checked_add(index)? } else { index };
+    usize::try_from(position).ok().filter(|&position| position < len)
+}
+
+// Set a hash field to what `update` makes of its current value, if any,
+// creating the hash if needed, and return the new value. If `update` refuses,
+// the reply it gives is returned instead and nothing changes.
+fn update_hash_field(
+    data: &mut Keyspace,
+    key: &str,
+    field: &str,
+    update: impl FnOnce(Option<&[u8]>) -> Result<Vec<u8>, Reply>,
+) -> Result<Vec<u8>, Reply> {
+    let new_value = match get_live(data, key) {
+        Some(value) => match &value.value {
+            Value::Hash(hash) => update(hash.get(field).map(Vec::as_slice))?,
+            _ => return Err(wrong_type()),
+        },
+        None => update(None)?,
+    };
+    
+    if data.get(key).is_none() {
+        data.insert(key.to_string(), RedisValue::new(Hash::default(), None));
+    }
+    let mut value = data.get_mut(key).unwrap();
+    if let Value::Hash(hash) = &mut value.value {
+        hash.insert(field.to_string(), new_value.clone());
+    }
+    value.mark_modified();
+    Ok(new_value)
+}
+
+// Remove the members `select` picks from the sorted set at a key, returning
+// how many were removed. Like Redis, a sorted set that loses its last member
+// is deleted.
+fn remove_from_zset(
+    data: &mut Keyspace,
+    key: &str,
+    event: &'static str,
+    select: impl FnOnce(&SortedSet) -> Vec<String>,
+) -> Reply {
+    let Some(mut value) = get_live(data, key) else {
+        return Reply::Integer(0);
+    };
+    let Value::SortedSet(zset) = &mut value.value else {
+        return wrong_type();
+    };
+    let members = select(zset);
+    let removed = members.iter().filter(|member| zset.remove(member)).count();
+    let now_empty = zset.is_empty();
+    if removed > 0 {
+        value.mark_modified();
+    }
+    drop(value);
+    
+    if removed > 0 {
+        data.notify('z', event, key);
+    }
+    if now_empty {
+        data.remove(key);
+        data.notify('g', "del", key);
+    }
+    Reply::Integer(removed as i64)
+}
+
+// The HyperLogLog stored at a key, None if the key is missing, or the error to
+// reply with if it holds anything else
+fn hyperloglog_at(data: &mut Keyspace, key: &str) -> Result<Option<HyperLogLog>, Reply> {
+    let Some(value) = get_live(data, key) else {
+        return Ok(None);
+    };
+    match &value.value {
+        Value::String(bytes) => match HyperLogLog::from_bytes(bytes) {
+            Some(hll) => Ok(Some(hll)),
+            None => Err(Reply::error_code("WRONGTYPE", hyperloglog::INVALID)),
+        },
+        _ => Err(wrong_type()),
+    }
+}
+
+// How SINTER, SUNION and SDIFF combine their sets
+#[derive(Clone, Copy)]
+enum SetOperation {
+    Intersection,
+    Union,
+    // Members of the first set that are in none of the others
+    Difference,
+}
+
+// Combine the sets stored at `keys`, counting missing keys as empty sets, or
+// fail with WRONGTYPE if any of them holds another type of value
+fn combine_sets(data: &mut Keyspace, operation: SetOperation, keys: &[&str]) -> Result<HashSet<String>, Reply> {
+    // Expire and type-check every key before reading them side by side
+    for key in keys {
+        if let Some(value) = get_live(data, key) {
+            if !matches!(value.value, Value::Set(_)) {
+                return Err(wrong_type());
+            }
+        }
+    }
+    let empty = Set::default();
+    let sets: Vec<&Set> = keys.iter()
+        .map(|key| match data.get(key).map(|value| &value.value) {
+            Some(Value::Set(set)) => set,
+            _ => &empty,
+        })
+        .collect();
+    let Some((first, rest)) = sets.split_first() else {
+        return Ok(HashSet::new());
+    };
+    
+    let combined = match operation {
+        SetOperation::Intersection => {
+            // Only members of the smallest set can be in all of them
+            let smallest = sets.iter().min_by_key(|set| set.len()).unwrap();
+            smallest.iter().filter(|member| sets.iter().all(|set| set.contains(member))).cloned().collect()
+        }
+        SetOperation::Union => sets.iter().flat_map(|set| set.iter()).cloned().collect(),
+        SetOperation::Difference => first.iter().filter(|member| rest.iter().all(|set| !set.contains(member))).cloned().collect(),
+    };
+    Ok(combined)
+}
+
+// Write `patch` into `bytes` at `offset`, zero-padding if it starts past the end
+fn overwrite_at(bytes: &mut Vec<u8>, offset: usize, patch: &[u8]) {
+    let end = offset + patch.len();
+    if bytes.len() < end {
+        bytes.resize(end, 0);
+    }
+    bytes[offset..end].copy_from_slice(patch);
+}
+
+// Parse a SETBIT/GETBIT offset, which addresses the bits of a string from the
+// most significant bit of its first byte. Like Redis, it is limited to the
+// bits of the longest string allowed, `max_len` bytes.
+fn parse_bit_offset(offset: &str, max_len: usize) -> Option<(usize, u8)> {
+    let offset = offset.parse::<u64>().ok().filter(|&offset| offset / 8 < max_len as u64)?;
+    Some(((offset / 8) as usize, 7 - (offset % 8) as u8))
+}
+
+// A connection counted in connected_clients for as long as it is alive
+struct ConnectedClient<'a> {
+    connected_clients: &'a AtomicU64,
+    // Clients connected, this one included, when it was counted
+    count: u64,
+}
+
+impl<'a> ConnectedClient<'a> {
+    fn new(connected_clients: &'a AtomicU64) -> Self {
+        let count = connected_clients.fetch_add(1, Ordering::Relaxed) + 1;
+        ConnectedClient { connected_clients, count }
+    }
+}
+
+impl Drop for ConnectedClient<'_> {
+    fn drop(&mut self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Counters reported by the INFO command
+struct ServerStats {
+    // Random identifier of this server instance, so clients can detect a restart
+    run_id: String,
+    started_at: Instant,
+    connected_clients: AtomicU64,
+    total_connections_received: AtomicU64,
+    // Connections refused because maxclients was reached
+    rejected_connections: AtomicU64,
+    total_commands_processed: AtomicU64,
+    evicted_keys: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+    // Changes to the keyspace since the last successful save, which save
+    // points compare against
+    changes_since_save: AtomicU64,
+    // Unix time in seconds of the last successful save, or of startup
+    last_save: AtomicU64,
+}
+
+impl ServerStats {
+    fn new() -> Self {
+        // 20 random bytes give the 40 hex characters Redis uses
+        let mut rng = rand::rng();
+        let run_id = (0..20).map(|_| format!("{:02x}", rng.random::<u8>())).collect();
+        
+        ServerStats {
+            run_id,
+            started_at: Instant::now(),
+            connected_clients: AtomicU64::new(0),
+            total_connections_received: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            total_commands_processed: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            bgsave_in_progress: AtomicBool::new(false),
+            changes_since_save: AtomicU64::new(0),
+            last_save: AtomicU64::new(unix_time()),
+        }
+    }
+    
+    // Note a successful save of a snapshot that covered `changes` changes.
+    // Those made while it was being written still count towards the next.
+    fn record_save(&self, changes: u64) {
+        let _ = self.changes_since_save.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+            Some(current.saturating_sub(changes))
+        });
+        self.last_save.store(unix_time(), Ordering::SeqCst);
+    }
+}
+
+// One SCAN step: visit up to `count` keys in hash order starting at `cursor`
+// and return the cursor to continue from (0 once every key has been visited)
+// together with the visited keys that are live and match the pattern.
+//
+// COUNT is a work budget, not an exact reply size: keys filtered out by MATCH
+// or found expired still use it up, so a call may return fewer keys, even none.
+// The cursor strictly increases on every call, so iteration always terminates,
+// even while keys are being added. Keys present for the whole iteration are
+// returned at least once; keys added or removed meanwhile may or may not be.
+// The keyspace keeps its keys in hash order, so a call only does work for the
+// keys it visits, however many keys there are.
+fn scan_keys(
+    data: &mut Keyspace,
+    cursor: u64,
+    count: usize,
+    pattern: Option<&str>,
+) -> (u64, Vec<String>) {
+    let (next_cursor, visited) = scan_batch(data.scan_from(cursor).map(|(hash, key)| (hash, key.clone())), count);
+    
+    // Visited keys that turn out to be expired are reclaimed on the way
+    let keys = visited.into_iter()
+        .filter(|key| !data.remove_if_expired(key))
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .collect();
+    
+    (next_cursor, keys)
+}
+
+// One HSCAN, SSCAN or ZSCAN step over the elements of a collection, with
+// each element's value. Elements come in the same hash order as keys do for
+// SCAN, with the same guarantees, but collections don't keep their elements
+// in that order, so every call hashes all of them.
+fn scan_elements<'a, T>(
+    elements: impl Iterator<Item = (&'a str, T)>,
+    cursor: u64,
+    count: usize,
+    pattern: Option<&str>,
+) -> (u64, Vec<(&'a str, T)>) {
+    let mut ordered: Vec<(u64, (&str, T))> = elements
+        .map(|element| (scan_hash(element.0), element))
+        .filter(|(hash, _)| *hash >= cursor)
+        .collect();
+    ordered.sort_unstable_by(|(a, (a_name, _)), (b, (b_name, _))| a.cmp(b).then(a_name.cmp(b_name)));
+    
+    let (next_cursor, visited) = scan_batch(ordered.into_iter(), count);
+    let matching = visited.into_iter()
+        .filter(|(name, _)| pattern.is_none_or(|pattern| glob_match(pattern, name)))
+        .collect();
+    (next_cursor, matching)
+}
+
+// Take up to `count` items from an iterator in hash order, and the cursor of
+// the item after them (0 if there is none). Items sharing the last hash are
+// kept together, or the next cursor would skip some.
+fn scan_batch<T>(ordered: impl Iterator<Item = (u64, T)>, count: usize) -> (u64, Vec<T>) {
+    let count = count.max(1);
+    let mut batch = Vec::new();
+    let mut last_hash = None;
+    for (hash, item) in ordered {
+        if batch.len() >= count && last_hash != Some(hash) {
+            return (hash, batch);
+        }
+        batch.push(item);
+        last_hash = Some(hash);
+    }
+    (0, batch)
+}
+
+// The MATCH pattern and COUNT options of SCAN and its HSCAN, SSCAN and ZSCAN
+// variants, given as option and value pairs. COUNT defaults to 10.
+fn scan_options<'a>(command: &str, options: &[&'a str]) -> Result<(Option<&'a str>, usize), Reply> {
+    let mut pattern = None;
+    let mut count = 10;
+    for option in options.chunks(2) {
+        match option[0].to_uppercase().as_str() {
+            "MATCH" => pattern = Some(option[1]),
+            "COUNT" => match option[1].parse::<usize>() {
+                Ok(n) if n >= 1 => count = n,
+                _ => return Err(Reply::error(format!("{} COUNT must be a positive integer", command))),
+            },
+            _ => return Err(Reply::error(format!("Unknown {} option '{}'", command, option[0]))),
         }
     }
+    Ok((pattern, count))
+}
+
+// Values that take more work than this to free are freed in the background
+// by UNLINK, as with Redis's LAZYFREE_THRESHOLD
+const LAZYFREE_THRESHOLD: usize = 64;
+
+// The channel a redirect target subscribes to for tracking invalidations
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+// Commands that can give a key a TTL relative to the time they run. The log
+// records the absolute expiry they resolved to instead, so replaying it
+// doesn't restart the TTL from the time of loading.
+const RELATIVE_EXPIRY_COMMANDS: &[&str] = &["SET", "SETEX", "PSETEX", "GETEX", "EXPIRE", "RESTORE"];
+
+// State kept for each client connection
+#[derive(Default)]
+struct Session {
+    // Unique among the server's clients, starting from 1
+    id: u64,
+    // Address of the client, as shown to monitors
+    addr: String,
+    // Set with CLIENT SETNAME
+    name: Option<String>,
+    // Protocol of the command being run, which pushed replies are sent in too
+    protocol: Protocol,
+    // Set by HELLO 3, so commands sent in RESP are answered in RESP3
+    resp3: bool,
+    // Database selected with SELECT
+    db: usize,
+    // Lines describing other clients' commands, once this one sent MONITOR
+    monitor: Option<broadcast::Receiver<String>>,
+    // The writes to pass on, once a replica sent SYNC on this connection
+    replica: Option<ReplicaFeed>,
+    // Messages published to the channels this client subscribed to, once it
+    // subscribed to any
+    messages: Option<mpsc::Receiver<Reply>>,
+    // Commands queued since MULTI, if a transaction is open
+    transaction: Option<Transaction>,
+    // Keys WATCHed for the next EXEC, with their database and their version
+    // when the watch started
+    watched: Vec<(usize, String, u64)>,
+    // Set by QUIT, to close the connection once its reply is written
+    quit: bool,
+    // A BLPOP or BRPOP waiting for an element to pop
+    blocked: Option<BlockedCommand>,
+    // Whether it has authenticated, which it starts out as while no
+    // requirepass is set. Until then only NO_AUTH commands run.
+    authenticated: bool,
+}
+
+#[derive(Default)]
+struct Transaction {
+    // Arguments of each queued command
+    commands: Vec<Args>,
+    // Set when a command was refused while queueing, so EXEC aborts
+    failed: bool,
+}
+
+struct BlockedCommand {
+    // Arguments of the command, run again whenever one of its keys changes
+    args: Args,
+    // When it gives up and replies nil, or None to wait forever
+    deadline: Option<Instant>,
+    woken: Arc<Notify>,
+}
+
+// The script EVAL or FCALL is running, for SCRIPT KILL or FUNCTION KILL to stop
+struct RunningScript {
+    started: Instant,
+    // Whether it is a library's function, which FUNCTION KILL stops, rather
+    // than an EVAL script, which SCRIPT KILL does
+    function: bool,
+    // Set once it has written, after which killing it would leave its writes
+    // half done
+    wrote: bool,
+    kill: Arc<AtomicBool>,
 }
 
 // Define our Redis server's state
+#[derive(Clone)]
 struct RedisServer {
-    data: Arc<Mutex<HashMap<String, RedisValue>>>,
+    // One keyspace per database, all behind one lock so commands such as
+    // COPY can work across databases atomically
+    data: Arc<Mutex<Vec<Keyspace>>>,
+    // Best eviction candidates sampled so far, locked after the data
+    eviction_pool: Arc<Mutex<EvictionPool>>,
+    config: Arc<Mutex<Config>>,
+    latency: Arc<Mutex<LatencyMonitor>>,
+    slowlog: Arc<Mutex<SlowLog>>,
+    stats: Arc<ServerStats>,
+    // Flipped to true by SHUTDOWN or a signal to stop the server gracefully
+    shutdown: Arc<watch::Sender<bool>>,
+    // Log of write commands, when appendonly is enabled. Its lock is held while
+    // a write executes, so the log records writes in the order they happened.
+    aof: Option<Arc<Mutex<AppendOnlyFile>>>,
+    // Held for reading by every command and for writing by EXEC, so no other
+    // client's command runs in the middle of a transaction
+    exec_lock: Arc<RwLock<()>>,
+    // Feed of the writes to pass on to replicas. Like the log's, its lock is
+    // held while a write executes, so replicas apply writes in the order they happened.
+    replicas: Arc<Mutex<broadcast::Sender<Arc<replication::Write>>>>,
+    // The leader this server replicates, once REPLICAOF named one
+    leader: Arc<Mutex<Option<Leader>>>,
+    // Feed of the commands clients send, for connections in MONITOR mode
+    monitors: broadcast::Sender<String>,
+    pubsub: Arc<Mutex<PubSub>>,
+    clients: Arc<Mutex<Clients>>,
+    tracking: Arc<Mutex<Tracking>>,
+    blocking: Arc<Mutex<Blocking>>,
+    // Function libraries, for FCALL
+    functions: Arc<Mutex<Functions>>,
+    running_script: Arc<Mutex<Option<RunningScript>>>,
+    // The last client ID handed out
+    last_client_id: Arc<AtomicU64>,
+    // Wakes the expiration sweep when a key expires sooner than it planned
+    // for, or when it is switched back on
+    sooner_expiry: Arc<Notify>,
+    // Whether the expiration sweep runs; DEBUG SET-ACTIVE-EXPIRE 0 turns it
+    // off so tests can watch keys only expire on access
+    active_expire: Arc<AtomicBool>,
 }
 
 impl RedisServer {
-    fn new() -> Self {
-        let data = Arc::new(Mutex::new(HashMap::new()));
+    fn new(config: Config) -> Self {
+        let databases = (0..config.databases)
+            .map(|_| {
+                let mut data = Keyspace::default();
+                data.set_limits(config.encoding_limits);
+                data
+            })
+            .collect();
+        let server = RedisServer {
+            data: Arc::new(Mutex::new(databases)),
+            eviction_pool: Arc::new(Mutex::new(EvictionPool::default())),
+            config: Arc::new(Mutex::new(config)),
+            latency: Arc::new(Mutex::new(LatencyMonitor::default())),
+            slowlog: Arc::new(Mutex::new(SlowLog::default())),
+            stats: Arc::new(ServerStats::new()),
+            shutdown: Arc::new(watch::channel(false).0),
+            aof: None,
+            exec_lock: Arc::new(RwLock::new(())),
+            replicas: Arc::new(Mutex::new(broadcast::channel(replication::BACKLOG).0)),
+            leader: Arc::new(Mutex::new(None)),
+            // A monitor that falls this many lines behind skips ahead
+            monitors: broadcast::channel(1024).0,
+            pubsub: Arc::new(Mutex::new(PubSub::default())),
+            clients: Arc::new(Mutex::new(Clients::default())),
+            tracking: Arc::new(Mutex::new(Tracking::default())),
+            blocking: Arc::new(Mutex::new(Blocking::default())),
+            functions: Arc::new(Mutex::new(Functions::default())),
+            running_script: Arc::new(Mutex::new(None)),
+            last_client_id: Arc::new(AtomicU64::new(0)),
+            sooner_expiry: Arc::new(Notify::new()),
+            active_expire: Arc::new(AtomicBool::new(true)),
+        };
+        
+        // Start the expiration cleanup task. It sleeps until the next key is
+        // due, rather than polling, but sweeps at most hz times per second.
+        let sweeper = server.clone();
+        tokio::spawn(async move {
+            let mut last_sweep = Instant::now();
+            loop {
+                // Re-read the interval every time so CONFIG SET hz takes effect live
+                let interval = sweeper.config.lock().unwrap().cleanup_interval();
+                let next_expiry = if sweeper.active_expire.load(Ordering::Relaxed) {
+                    sweeper.data.lock().unwrap().iter().filter_map(Keyspace::next_expiry).min()
+                } else {
+                    None
+                };
+                
+                // A key only counts as expired once its time has passed
+                let wake_at = next_expiry.map(|expires_at| {
+                    let expired_at = expires_at.checked_add(Duration::from_millis(1)).unwrap_or(expires_at);
+                    expired_at.max(last_sweep + interval)
+                });
+                let due = async {
+                    match wake_at {
+                        Some(wake_at) => sleep_until(wake_at.into()).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    _ = due => {}
+                    _ = sweeper.sooner_expiry.notified() => continue,
+                }
+                
+                sweeper.cleanup_expired_keys();
+                last_sweep = Instant::now();
+            }
+        });
         
-        // Start the expiration cleanup task
-        let data_clone = data.clone();
+        // And the save point check, once a second as save points count in seconds
+        let saver = server.clone();
         tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs(1));
             loop {
-                // Clean expired keys every second
-                sleep(Duration::from_secs(1)).await;
-                RedisServer::cleanup_expired_keys(&data_clone);
+                ticks.tick().await;
+                saver.check_save_points();
             }
         });
         
-        RedisServer { data }
+        server
     }
     
     // Cleanup expired keys
-    fn cleanup_expired_keys(data: &Arc<Mutex<HashMap<String, RedisValue>>>) {
-        let mut data = data.lock().unwrap();
-        let expired_keys: Vec<String> = data.iter()
-            .filter(|(_, value)| value.is_expired())
-            .map(|(key, _)| key.clone())
+    fn cleanup_expired_keys(&self) {
+        let mut databases = self.data.lock().unwrap();
+        for data in databases.iter_mut() {
+            data.remove_expired();
+        }
+        drop(databases);
+        
+        self.handle_changes();
+    }
+    
+    // Follow up on the changes made since the last call: wake the expiration
+    // sweep if a key now expires sooner than it planned for, wake the clients
+    // blocked on the changed keys, invalidate them in tracking clients'
+    // caches, and publish the keyspace events of the classes
+    // notify-keyspace-events enables
+    fn handle_changes(&self) {
+        let mut databases = self.data.lock().unwrap();
+        let changes: u64 = databases.iter_mut().map(Keyspace::take_changes).sum();
+        self.stats.changes_since_save.fetch_add(changes, Ordering::SeqCst);
+        // Every flag is taken, not just the first one set
+        if databases.iter_mut().map(Keyspace::take_sooner_expiry).filter(|&sooner| sooner).count() > 0 {
+            self.sooner_expiry.notify_one();
+        }
+        let flushed = databases.iter_mut().map(Keyspace::take_flushed).filter(|&flushed| flushed).count() > 0;
+        let events: Vec<_> = databases.iter_mut()
+            .map(Keyspace::take_events)
+            .enumerate()
+            .filter(|(_, events)| !events.is_empty())
             .collect();
+        drop(databases);
+        
+        {
+            let blocking = self.blocking.lock().unwrap();
+            if !blocking.is_empty() {
+                for (db, events) in &events {
+                    for event in events {
+                        blocking.wake(*db, &event.key);
+                    }
+                }
+            }
+        }
+        
+        let invalidations = {
+            let mut tracking = self.tracking.lock().unwrap();
+            if tracking.is_empty() {
+                Vec::new()
+            } else if flushed {
+                tracking.flush()
+            } else {
+                tracking.invalidate(events.iter().flat_map(|(_, events)| events.iter().map(|event| event.key.as_str())))
+            }
+        };
+        self.send_invalidations(invalidations);
         
-        for key in expired_keys {
-            data.remove(&key);
+        if events.is_empty() {
+            return;
         }
-    }
-
-    // Method to handle a client connection
-    async fn handle_client(&self, mut socket: TcpStream) -> Result<(), RedisError> {
-        let mut buffer = BytesMut::with_capacity(1024);
         
-        loop {
-            // Read data from the socket
-            match socket.read_buf(&mut buffer).await {
-                Ok(0) => {
-                    // Connection closed
-                    return Ok(());
+        let enabled = self.config.lock().unwrap().notify_keyspace_events.clone();
+        let mut pubsub = self.pubsub.lock().unwrap();
+        for (db, events) in events {
+            for event in events.iter().filter(|event| enabled.enabled(event.class)) {
+                if enabled.keyspace {
+                    pubsub.publish(&format!("__keyspace@{}__:{}", db, event.key), event.event);
                 }
-                Ok(n) => {
-                    // Process the command
-                    let input = String::from_utf8_lossy(&buffer[..n]);
-                    let response = self.process_command(&input).await?;
-                    
-                    // Send response
-                    socket.write_all(response.as_bytes()).await?;
-                    socket.flush().await?;
-                    
-                    // Clear the buffer for the next command
-                    buffer.clear();
+                if enabled.keyevent {
+                    pubsub.publish(&format!("__keyevent@{}__:{}", db, event.event), &event.key);
                 }
-                Err(e) => {
+            }
+        }
+    }
+    
+    // Deliver invalidations as Redis does: an `invalidate` push with the keys,
+    // or nil for all of them, or the same as a message on
+    // __redis__:invalidate for a redirect target subscribed to it
+    fn send_invalidations(&self, invalidations: Vec<Invalidation>) {
+        if invalidations.is_empty() {
+            return;
+        }
+        let mut pubsub = self.pubsub.lock().unwrap();
+        for Invalidation { target, keys } in invalidations {
+            let keys = keys.map_or(Reply::Nil, |keys| Reply::Array(keys.into_iter().map(Reply::bulk).collect()));
+            match target {
+                Target::Push(client) => pubsub.send(client, Reply::Push(vec![Reply::bulk("invalidate"), keys])),
+                Target::Redirect(client) if pubsub.is_subscribed_to(client, INVALIDATE_CHANNEL) => {
+                    pubsub.send(client, Reply::Array(vec![Reply::bulk("message"), Reply::bulk(INVALIDATE_CHANNEL), keys]));
+                }
+                Target::Redirect(_) => {}
+            }
+        }
+    }
+    
+    // Method to handle a client connection, over TCP or a Unix socket
+    async fn handle_client<S>(&self, mut socket: S, addr: String) -> Result<(), RedisError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Counted before the check, so clients connecting at the same time
+        // can't all slip in under the limit. The guard takes the client off
+        // the count however the connection ends, even if its handler panics.
+        let clients = ConnectedClient::new(&self.stats.connected_clients);
+        let maxclients = self.config.lock().unwrap().maxclients;
+        if clients.count > maxclients {
+            self.stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+            
+            // Sent before the client has said anything, so always in RESP
+            let reply = Reply::error("max number of clients reached").encode(Protocol::Resp2);
+            socket.write_all(&reply).await?;
+            return Ok(());
+        }
+        self.stats.total_connections_received.fetch_add(1, Ordering::Relaxed);
+        
+        let id = self.last_client_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let kill = self.clients.lock().unwrap().add(id, &addr);
+        let authenticated = self.config.lock().unwrap().requirepass.is_empty();
+        let mut session = Session { id, addr, authenticated, ..Session::default() };
+        let result = self.serve_client(socket, &mut session, &kill).await;
+        // Its subscriptions, tracking, watches and blocked command end with the connection
+        self.clients.lock().unwrap().remove(id);
+        self.blocking.lock().unwrap().unblock(id);
+        self.tracking.lock().unwrap().disable(id);
+        self.pubsub.lock().unwrap().remove_client(id);
+        self.unwatch(&mut session);
+        result
+    }
+    
+    // Read commands from the socket and answer them until the client disconnects.
+    // Commands are delimited, by a newline or by their RESP framing, so a client
+    // may pipeline several of them in one write; a command split across reads
+    // waits in the buffer until the rest of it arrives. Notifying `kill`
+    // closes the connection once its replies so far are written.
+    async fn serve_client<S>(&self, mut socket: S, session: &mut Session, kill: &Notify) -> Result<(), RedisError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buffer = BytesMut::with_capacity(1024);
+        let mut responses = Vec::new();
+        let mut shutdown = self.shutdown.subscribe();
+        
+        loop {
+            // Answer the complete commands already buffered, at most max-pipeline
+            // of them before their replies are written out
+            let (max_pipeline, max_bulk_len, query_buffer_limit) = {
+                let config = self.config.lock().unwrap();
+                (config.max_pipeline, config.proto_max_bulk_len, config.client_query_buffer_limit)
+            };
+            let mut queued = 0;
+            // A blocked command holds up the commands pipelined after it
+            while queued < max_pipeline && session.blocked.is_none() {
+                let (args, protocol) = match protocol::parse_command(&mut buffer, max_bulk_len) {
+                    Ok(Some(command)) => command,
+                    Ok(None) => break,
+                    Err(e) => {
+                        // Like Redis, give up on a client whose input can't be parsed
+                        responses.extend(Reply::error(e).encode(Protocol::Resp2));
+                        socket.write_all(&responses).await?;
+                        return Ok(());
+                    }
+                };
+                
+                session.protocol = match protocol {
+                    Protocol::Resp2 if session.resp3 => Protocol::Resp3,
+                    protocol => protocol,
+                };
+                let response = self.process_command(session, &args).await?;
+                // A command that blocked answers once it is unblocked. HELLO
+                // and RESET answer in the protocol they switch to.
+                if session.blocked.is_none() {
+                    responses.extend(response.encode(session.protocol));
+                }
+                queued += 1;
+                
+                // Anything pipelined after QUIT is dropped unanswered, as in Redis
+                if session.quit {
+                    socket.write_all(&responses).await?;
+                    socket.flush().await?;
+                    return Ok(());
+                }
+            }
+            
+            if !responses.is_empty() {
+                // Nothing more is read until the client takes these replies,
+                // so a client that never reads is held back instead of the
+                // server buffering its commands and replies without bound
+                socket.write_all(&responses).await?;
+                socket.flush().await?;
+                responses.clear();
+                
+                if queued == max_pipeline {
+                    continue;
+                }
+            }
+            
+            // What is left is the start of a command still arriving, refused
+            // once it outgrows the limit rather than buffered without bound
+            if buffer.len() > query_buffer_limit {
+                info!("Closing client whose request exceeds client-query-buffer-limit");
+                responses.extend(Reply::error("Protocol error: request exceeds client-query-buffer-limit").encode(Protocol::Resp2));
+                socket.write_all(&responses).await?;
+                return Ok(());
+            }
+            
+            // Read data from the socket, unless the server is shutting down or
+            // the client stays idle past the timeout. This is only checked
+            // between commands, so one in progress always completes. Monitors,
+            // subscribers, blocked clients and replicas only wait, so they are
+            // never idle.
+            let idle_timeout = match (&session.monitor, &session.messages, &session.blocked, &session.replica) {
+                (None, None, None, None) => self.config.lock().unwrap().idle_timeout(),
+                _ => None,
+            };
+            let read = async {
+                match idle_timeout {
+                    Some(idle_timeout) => tokio::time::timeout(idle_timeout, socket.read_buf(&mut buffer)).await.ok(),
+                    None => Some(socket.read_buf(&mut buffer).await),
+                }
+            };
+            let read = tokio::select! {
+                read = read => read,
+                line = next_monitor_line(&mut session.monitor) => {
+                    responses.extend(Reply::Simple(line).encode(session.protocol));
+                    continue;
+                }
+                write = next_replicated_write(&mut session.replica) => {
+                    let Some(write) = write else {
+                        info!("Closing replica that fell too far behind");
+                        return Ok(());
+                    };
+                    responses.extend(write);
+                    continue;
+                }
+                message = next_message(&mut session.messages) => {
+                    let Some(message) = message else {
+                        info!("Closing subscriber that fell too far behind");
+                        return Ok(());
+                    };
+                    responses.extend(message.encode(session.protocol));
+                    continue;
+                }
+                _ = next_wakeup(&session.blocked) => {
+                    if let Some(response) = self.retry_blocked(session).await? {
+                        responses.extend(response.encode(session.protocol));
+                    }
+                    continue;
+                }
+                // The borrow wait_for returns isn't Send, so it is dropped
+                // here rather than kept while another branch's handler awaits
+                _ = async { drop(shutdown.wait_for(|&stop| stop).await) } => return Ok(()),
+                _ = kill.notified() => {
+                    info!("Closing killed client");
+                    return Ok(());
+                }
+            };
+            let Some(read) = read else {
+                info!("Closing idle client");
+                return Ok(());
+            };
+            
+            match read {
+                Ok(0) => {
+                    // Connection closed
+                    return Ok(());
+                }
+                Ok(_) => {}
+                Err(e) => {
                     error!("Error reading from socket: {}", e);
                     return Err(RedisError::Io(e));
                 }
             }
-        }
-    }
-
-    // Process a command received from a client
-    async fn process_command(&self, input: &str) -> Result<String, RedisError> {
-        let input = input.trim();
-        let parts: Vec<&str> = input.split_whitespace().collect();
-        
-        if parts.is_empty() {
-            return Ok("ERROR: Empty command\n".to_string());
-        }
-        
-        info!("Processing command: {}", input);
-        
-        // Command parsing - simple text-based protocol
-        match parts[0].to_uppercase().as_str() {
-            "GET" => {
-                if parts.len() != 2 {
-                    return Ok("ERROR: GET requires exactly one argument\n".to_string());
+        }
+    }
+    
+    // Encode the keyspace for a snapshot, with the number of changes it covers
+    fn snapshot(&self) -> (Vec<u8>, u64) {
+        let mut databases = self.data.lock().unwrap();
+        // Changes handle_changes hasn't counted yet are in the snapshot too
+        let uncounted: u64 = databases.iter_mut().map(Keyspace::take_changes).sum();
+        let changes = self.stats.changes_since_save.fetch_add(uncounted, Ordering::SeqCst) + uncounted;
+        (persistence::encode_snapshot(&databases), changes)
+    }
+    
+    // Write a snapshot of the keyspace to disk, returning once it is written
+    fn save(&self) -> std::io::Result<()> {
+        let (snapshot, changes) = self.snapshot();
+        let path = self.config.lock().unwrap().snapshot_path();
+        persistence::write_snapshot(&path, &snapshot)?;
+        self.stats.record_save(changes);
+        Ok(())
+    }
+    
+    // Write a snapshot in the background. Only encoding the keyspace in memory
+    // happens under the lock; the file I/O runs on a blocking thread.
+    fn background_save(&self) -> Result<(), String> {
+        if self.stats.bgsave_in_progress.swap(true, Ordering::SeqCst) {
+            return Err("Background save already in progress".to_string());
+        }
+        
+        let (snapshot, changes) = self.snapshot();
+        let path = self.config.lock().unwrap().snapshot_path();
+        let stats = self.stats.clone();
+        
+        tokio::task::spawn_blocking(move || {
+            match persistence::write_snapshot(&path, &snapshot) {
+                Ok(()) => {
+                    stats.record_save(changes);
+                    info!("Background saving terminated with success");
+                }
+                Err(e) => error!("Background saving to {} failed: {}", path.display(), e),
+            }
+            stats.bgsave_in_progress.store(false, Ordering::SeqCst);
+        });
+        
+        Ok(())
+    }
+    
+    // Follow a leader as its replica, until the task is aborted: load its
+    // snapshot, then apply every write it passes on. A lost connection is
+    // retried a second later, with a new snapshot.
+    async fn follow(self, host: String, port: u16, link_up: Arc<AtomicBool>) {
+        loop {
+            match self.replicate(&host, port, &link_up).await {
+                Ok(()) => info!("Connection to leader {}:{} closed", host, port),
+                Err(e) => error!("Replicating {}:{} failed: {}", host, port, e),
+            }
+            link_up.store(false, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    
+    // One connection to the leader, returning once it closes
+    async fn replicate(&self, host: &str, port: u16, link_up: &AtomicBool) -> std::io::Result<()> {
+        let mut stream = TcpStream::connect((host, port)).await?;
+        stream.write_all(&replication::encode_command(&["SYNC"])).await?;
+        
+        let mut buffer = BytesMut::with_capacity(1024);
+        let snapshot = loop {
+            match protocol::parse_resp(&buffer, usize::MAX).map_err(std::io::Error::other)? {
+                Some((reply, used)) => {
+                    let _ = buffer.split_to(used);
+                    break reply;
+                }
+                None if stream.read_buf(&mut buffer).await? == 0 => return Ok(()),
+                None => {}
+            }
+        };
+        let snapshot = match snapshot {
+            Reply::Bulk(bytes) => bytes,
+            Reply::Error(e) => return Err(std::io::Error::other(format!("the leader refused SYNC: {}", e))),
+            _ => return Err(std::io::Error::other("the leader answered SYNC with something other than a snapshot")),
+        };
+        
+        let databases = self.config.lock().unwrap().databases;
+        let loaded = persistence::decode_snapshot(&snapshot, databases)?;
+        let keys: usize = loaded.iter().map(Keyspace::len).sum();
+        {
+            let _exec_lock = self.exec_lock.write().unwrap();
+            let mut data = self.data.lock().unwrap();
+            for (data, loaded) in data.iter_mut().zip(loaded) {
+                data.load(loaded);
+            }
+        }
+        self.handle_changes();
+        link_up.store(true, Ordering::SeqCst);
+        info!("Loaded {} keys from leader {}:{}", keys, host, port);
+        
+        // The writes are applied like a client's, so they are logged and
+        // passed on in turn, but none of a client's checks apply
+        let mut session = Session { authenticated: true, ..Session::default() };
+        loop {
+            while let Some((args, _)) = protocol::parse_command(&mut buffer, usize::MAX).map_err(std::io::Error::other)? {
+                let parts = text_args(&args);
+                if let Some(name) = parts.first() {
+                    if let Err(RedisError::Io(e)) = self.run(&mut session, &name.to_uppercase(), &parts, &args).await {
+                        return Err(e);
+                    }
+                }
+            }
+            if stream.read_buf(&mut buffer).await? == 0 {
+                return Ok(());
+            }
+        }
+    }
+    
+    // Start a background save if a save point is reached: enough changes
+    // since the last save, and long enough ago
+    fn check_save_points(&self) {
+        let changes = self.stats.changes_since_save.load(Ordering::SeqCst);
+        let elapsed = unix_time().saturating_sub(self.stats.last_save.load(Ordering::SeqCst));
+        let save = self.config.lock().unwrap().save.clone();
+        let Some(point) = save.iter().find(|point| changes >= point.changes && elapsed >= point.seconds) else {
+            return;
+        };
+        if self.stats.bgsave_in_progress.load(Ordering::SeqCst) {
+            return;
+        }
+        
+        info!("{} changes in {} seconds. Saving...", point.changes, point.seconds);
+        if let Err(e) = self.background_save() {
+            error!("Background saving failed to start: {}", e);
+        }
+    }
+    
+    // The replication section of INFO: this server's role, its replicas
+    // and, as a replica, its leader
+    fn replication_info(&self) -> String {
+        let replicas = self.replicas.lock().unwrap().receiver_count();
+        match self.leader.lock().unwrap().as_ref() {
+            Some(leader) => format!(
+                "role:slave\nmaster_host:{}\nmaster_port:{}\nmaster_link_status:{}\nconnected_slaves:{}\n",
+                leader.host,
+                leader.port,
+                if leader.is_link_up() { "up" } else { "down" },
+                replicas,
+            ),
+            None => format!("role:master\nconnected_slaves:{}\n", replicas),
+        }
+    }
+    
+    // Build the INFO report, optionally restricted to a single section
+    fn info(&self, section: Option<&str>) -> String {
+        let uptime = self.stats.started_at.elapsed().as_secs();
+        // Taken first, as writes hold the replicas' lock while they lock the data
+        let replication = self.replication_info();
+        let data = self.data.lock().unwrap();
+        let config = self.config.lock().unwrap();
+        let mut sections = vec![
+            ("server", format!(
+                "redis_version:{}\nprocess_id:{}\nrun_id:{}\nio_threads_active:0\nuptime_in_seconds:{}\nuptime_in_days:{}\n",
+                env!("CARGO_PKG_VERSION"),
+                std::process::id(),
+                self.stats.run_id,
+                uptime,
+                uptime / 86400,
+            )),
+            ("clients", format!(
+                "connected_clients:{}\nblocked_clients:{}\n",
+                self.stats.connected_clients.load(Ordering::Relaxed),
+                self.blocking.lock().unwrap().len(),
+            )),
+            ("memory", format!(
+                "used_memory:{}\nmaxmemory:{}\nmaxmemory_policy:{}\n",
+                data.iter().map(Keyspace::used_memory).sum::<usize>(),
+                config.maxmemory,
+                config.maxmemory_policy.as_str(),
+            )),
+            ("persistence", format!(
+                "rdb_changes_since_last_save:{}\nrdb_bgsave_in_progress:{}\nrdb_last_save_time:{}\n",
+                self.stats.changes_since_save.load(Ordering::SeqCst),
+                self.stats.bgsave_in_progress.load(Ordering::SeqCst) as u8,
+                self.stats.last_save.load(Ordering::SeqCst),
+            )),
+            ("stats", format!(
+                "total_connections_received:{}\ntotal_commands_processed:{}\nrejected_connections:{}\nevicted_keys:{}\n",
+                self.stats.total_connections_received.load(Ordering::Relaxed),
+                self.stats.total_commands_processed.load(Ordering::Relaxed),
+                self.stats.rejected_connections.load(Ordering::Relaxed),
+                self.stats.evicted_keys.load(Ordering::Relaxed),
+            )),
+            ("replication", replication),
+        ];
+        drop(config);
+        
+        // Like Redis, the keyspace section only lists databases that hold keys
+        let now = Instant::now();
+        let mut keyspace = String::new();
+        for (index, data) in data.iter().enumerate().filter(|(_, data)| !data.is_empty()) {
+            let ttls: Vec<u128> = data.values()
+                .filter_map(|value| value.expires_at)
+                .map(|expires_at| expires_at.saturating_duration_since(now).as_millis())
+                .collect();
+            let avg_ttl = if ttls.is_empty() { 0 } else { ttls.iter().sum::<u128>() / ttls.len() as u128 };
+            
+            keyspace += &format!("db{}:keys={},expires={},avg_ttl={}\n", index, data.len(), ttls.len(), avg_ttl);
+        }
+        sections.push(("keyspace", keyspace));
+        drop(data);
+        
+        let section = section.map(|name| name.to_lowercase());
+        let report: Vec<String> = sections.into_iter()
+            .filter(|(name, _)| match section.as_deref() {
+                None | Some("all") | Some("default") | Some("everything") => true,
+                Some(wanted) => *name == wanted,
+            })
+            .map(|(name, body)| {
+                // Section headers are capitalized, e.g. "# Server"
+                let mut title = name.to_string();
+                title[..1].make_ascii_uppercase();
+                format!("# {}\n{}", title, body)
+            })
+            .collect();
+        
+        // The reply itself is terminated by the protocol
+        let report = report.join("\n");
+        report.trim_end_matches('\n').to_string()
+    }
+    
+    // Process a command received from a client, already split into arguments,
+    // timing it for the latency monitor and the slow log. Only the command
+    // itself is timed, not reading it from the connection or writing the reply.
+    async fn process_command(&self, session: &mut Session, args: &[Vec<u8>]) -> Result<Reply, RedisError> {
+        let started = Instant::now();
+        let started_at = SystemTime::now();
+        let result = self.dispatch_command(session, args).await;
+        let elapsed = started.elapsed();
+        
+        // One line per command at debug level, in key=value pairs a log
+        // search can pick apart. A command that blocks is logged when it
+        // blocks, and its outcome isn't known yet.
+        let status = match &result {
+            _ if session.blocked.is_some() => "blocked",
+            Ok(Reply::Nil) => "nil",
+            Ok(reply) if !reply.is_error() => "ok",
+            _ => "error",
+        };
+        debug!(
+            "command={} client={} status={} elapsed_us={}",
+            args.first().map_or(String::new(), |name| String::from_utf8_lossy(name).to_lowercase()),
+            session.id,
+            status,
+            elapsed.as_micros(),
+        );
+        
+        // Unknown commands aren't recorded, so clients can't grow the histograms at will
+        let Some(spec) = args.first().and_then(|name| commands::lookup(&String::from_utf8_lossy(name))) else {
+            return result;
+        };
+        let (latency_threshold, slowlog_threshold, slowlog_max_len) = {
+            let config = self.config.lock().unwrap();
+            (config.latency_monitor_threshold, config.slowlog_log_slower_than, config.slowlog_max_len)
+        };
+        self.latency.lock().unwrap().record_command(&spec.name.to_lowercase(), elapsed, latency_threshold);
+        
+        // A negative threshold turns the slow log off, 0 logs every command
+        let micros = elapsed.as_micros() as u64;
+        if u64::try_from(slowlog_threshold).is_ok_and(|threshold| micros >= threshold) {
+            let time = started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let name = session.name.as_deref().unwrap_or("");
+            let args: Vec<String> = logged_args(spec.name, &text_args(args)).iter().map(|arg| arg.to_string()).collect();
+            self.slowlog.lock().unwrap().push(time, micros, &args, &session.addr, name, slowlog_max_len);
+        }
+        result
+    }
+    
+    async fn dispatch_command(&self, session: &mut Session, args: &[Vec<u8>]) -> Result<Reply, RedisError> {
+        let parts = text_args(args);
+        
+        if parts.is_empty() {
+            return Ok(Reply::error("Empty command"));
+        }
+        
+        // Only the name and each command's option keywords are matched
+        // ignoring case, and keywords only where an option can be, so keys
+        // and values are always taken as sent, even one spelled like a keyword
+        let command = parts[0].to_uppercase();
+        
+        info!("Processing command: {}", logged_args(&command, &parts).join(" "));
+        self.stats.total_commands_processed.fetch_add(1, Ordering::Relaxed);
+        
+        // Monitors see every command but their own, so they can't feed on
+        // each other, and like Redis none that can carry a password
+        if session.monitor.is_none() && !matches!(command.as_str(), "MONITOR" | "AUTH" | "HELLO") && self.monitors.receiver_count() > 0 {
+            let line = monitor::format_line(SystemTime::now(), session.db, &session.addr, args);
+            let _ = self.monitors.send(line);
+        }
+        
+        // Every command is checked against its arity in the registry first,
+        // with the same error whatever the command. Like an unknown command,
+        // a wrong count inside a transaction makes EXEC abort.
+        if let Some(spec) = commands::lookup(&command).filter(|spec| !spec.accepts(parts.len())) {
+            if let Some(transaction) = session.transaction.as_mut() {
+                transaction.failed = true;
+            }
+            return Ok(Reply::error(format!("wrong number of arguments for '{}' command", spec.name.to_lowercase())));
+        }
+        // Values are kept as the bytes sent, but keys and every other
+        // argument are handled as text, which has to be valid UTF-8
+        let not_text = |position: usize, spec: &commands::CommandSpec| {
+            !spec.is_value(position, args.len()) && std::str::from_utf8(&args[position]).is_err()
+        };
+        if let Some(spec) = commands::lookup(&command).filter(|spec| (1..args.len()).any(|position| not_text(position, spec))) {
+            if let Some(transaction) = session.transaction.as_mut() {
+                transaction.failed = true;
+            }
+            return Ok(Reply::error(format!("invalid UTF-8 in an argument of '{}' command", spec.name.to_lowercase())));
+        }
+        
+        // Clearing requirepass lets in clients that never authenticated
+        let no_auth = commands::lookup(&command).is_some_and(|spec| spec.has(commands::NO_AUTH));
+        if !session.authenticated && !no_auth && !self.config.lock().unwrap().requirepass.is_empty() {
+            return Ok(Reply::error_code("NOAUTH", "Authentication required."));
+        }
+        
+        // Until it unsubscribes from everything, a subscriber is only listening
+        let subscriptions = matches!(command.as_str(), "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE");
+        if !subscriptions && !matches!(command.as_str(), "PING" | "QUIT" | "RESET") && self.is_subscribed(session) {
+            return Ok(Reply::error(format!(
+                "Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                parts[0].to_lowercase(),
+            )));
+        }
+        
+        // A script still running past busy-reply-threshold holds up every other
+        // command, so clients are told rather than left waiting, and can kill
+        // it. Killing can't wait for the lock the script holds.
+        let kill = matches!(command.as_str(), "SCRIPT" | "FUNCTION") && parts.get(1).is_some_and(|sub| sub.eq_ignore_ascii_case("KILL"));
+        if kill && session.transaction.is_none() {
+            return Ok(self.kill_script(command == "FUNCTION"));
+        }
+        if let Some(busy) = self.busy_reply().filter(|_| !kill) {
+            return Ok(busy);
+        }
+        
+        // Inside a transaction everything but the transaction commands is queued
+        if let Some(transaction) = session.transaction.as_mut() {
+            // They answer once per channel, which can't be one of EXEC's results
+            if subscriptions {
+                return Ok(Reply::error(format!("{} inside MULTI is not allowed", command)));
+            }
+            // Keys have to be watched before the transaction starts
+            if command == "WATCH" {
+                return Ok(Reply::error("WATCH inside MULTI is not allowed"));
+            }
+            if command == "SYNC" {
+                return Ok(Reply::error("SYNC inside MULTI is not allowed"));
+            }
+            // Sleeping would hold up every other client until EXEC finishes
+            if command == "DEBUG" && parts.get(1).is_some_and(|sub| sub.eq_ignore_ascii_case("SLEEP")) {
+                return Ok(Reply::error("DEBUG SLEEP inside MULTI is not allowed"));
+            }
+            if !matches!(command.as_str(), "MULTI" | "EXEC" | "DISCARD" | "QUIT" | "RESET") {
+                if commands::lookup(&command).is_none() {
+                    transaction.failed = true;
+                    return Ok(Reply::error(format!("unknown command '{}'", parts[0])));
+                }
+                transaction.commands.push(args.to_vec());
+                return Ok(Reply::Simple("QUEUED".to_string()));
+            }
+        }
+        
+        match command.as_str() {
+            "MULTI" => {
+                if session.transaction.is_some() {
+                    return Ok(Reply::error("MULTI calls can not be nested"));
+                }
+                session.transaction = Some(Transaction::default());
+                Ok(Reply::ok())
+            }
+            "DISCARD" => {
+                match session.transaction.take() {
+                    Some(_) => {
+                        self.unwatch(session);
+                        Ok(Reply::ok())
+                    }
+                    None => Ok(Reply::error("DISCARD without MULTI")),
+                }
+            }
+            "EXEC" => {
+                match session.transaction.take() {
+                    Some(transaction) => self.exec(session, transaction).await,
+                    None => Ok(Reply::error("EXEC without MULTI")),
+                }
+            }
+            "QUIT" => {
+                if parts.len() != 1 {
+                    return Ok(Reply::error("QUIT takes no arguments"));
+                }
+                session.quit = true;
+                Ok(Reply::ok())
+            }
+            "RESET" => {
+                // Back to the state of a new connection, keeping only its ID
+                // and address: no transaction, watched keys, name, tracking,
+                // subscriptions or MONITOR, RESP2, database 0, and
+                // unauthenticated if a password is required
+                self.tracking.lock().unwrap().disable(session.id);
+                self.pubsub.lock().unwrap().remove_client(session.id);
+                self.unwatch(session);
+                *session = Session {
+                    id: session.id,
+                    addr: std::mem::take(&mut session.addr),
+                    protocol: match session.protocol {
+                        Protocol::Resp3 => Protocol::Resp2,
+                        protocol => protocol,
+                    },
+                    authenticated: self.config.lock().unwrap().requirepass.is_empty(),
+                    ..Session::default()
+                };
+                self.update_client(session);
+                Ok(Reply::Simple("RESET".to_string()))
+            }
+            // The connection becomes a replica's: it is sent a snapshot, then
+            // every write. The snapshot is taken with every other client held
+            // off, so each write is either in it or passed on after it.
+            "SYNC" => {
+                let (snapshot, feed) = {
+                    let _exec_lock = self.exec_lock.write().unwrap();
+                    let feed = ReplicaFeed::new(self.replicas.lock().unwrap().subscribe());
+                    (persistence::encode_snapshot(&self.data.lock().unwrap()), feed)
+                };
+                info!("Replica {} is synchronizing", session.addr);
+                session.replica = Some(feed);
+                Ok(Reply::Bulk(snapshot))
+            }
+            // A script runs with every other client held off, like a
+            // transaction. It may run for long, so the runtime moves other
+            // connections off this thread, letting them be told it's busy.
+            "EVAL" | "FCALL" => {
+                let result = tokio::task::block_in_place(|| {
+                    let _exec_lock = self.exec_lock.write().unwrap();
+                    self.run_command(session, &command, &parts, args)
+                });
+                let (response, written) = result?;
+                Ok(self.wait_written(written).await.unwrap_or(response))
+            }
+            // Only this connection waits, so it sleeps here rather than in
+            // execute, which runs with the data locked
+            "DEBUG" if parts.get(1).is_some_and(|sub| sub.eq_ignore_ascii_case("SLEEP")) => {
+                if parts.len() != 3 {
+                    return Ok(Reply::error("DEBUG SLEEP requires a number of seconds"));
+                }
+                let Some(duration) = parts[2].parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok()) else {
+                    return Ok(Reply::error("DEBUG SLEEP seconds must be a non-negative number"));
+                };
+                
+                tokio::time::sleep(duration).await;
+                Ok(Reply::ok())
+            }
+            // With nothing to pop yet, the connection waits in serve_client,
+            // holding no lock, and retries whenever one of the keys changes.
+            // It is blocked before the first try so no change in between is missed.
+            "BLPOP" | "BRPOP" if parts.len() >= 3 => {
+                let keys = &parts[1..parts.len() - 1];
+                let woken = Arc::new(Notify::new());
+                self.blocking.lock().unwrap().block(session.id, session.db, keys, woken.clone());
+                
+                let response = self.run(session, &command, &parts, args).await;
+                if !matches!(response, Ok(Reply::Nil)) {
+                    self.blocking.lock().unwrap().unblock(session.id);
+                    return response;
+                }
+                // The first try already checked the timeout
+                let timeout = block_timeout(parts[parts.len() - 1]).ok().flatten();
+                session.blocked = Some(BlockedCommand {
+                    args: args.to_vec(),
+                    deadline: timeout.and_then(|timeout| Instant::now().checked_add(timeout)),
+                    woken,
+                });
+                self.update_client(session);
+                Ok(Reply::Nil)
+            }
+            _ => self.run(session, &command, &parts, args).await,
+        }
+    }
+    
+    // Run a command with other clients' transactions held off, replying once
+    // its write is in the append-only file
+    async fn run(&self, session: &mut Session, command: &str, parts: &[&str], args: &[Vec<u8>]) -> Result<Reply, RedisError> {
+        let result = {
+            let _exec_lock = self.exec_lock.read().unwrap();
+            self.run_command(session, command, parts, args)
+        };
+        // An unknown command is the client's mistake, so it gets an
+        // error reply and the connection stays open
+        let (response, written) = match result {
+            Err(e @ RedisError::UnknownCommand(_)) => (Reply::error(e.to_string()), None),
+            result => result?,
+        };
+        
+        Ok(self.wait_written(written).await.unwrap_or(response))
+    }
+    
+    // Try a blocked command again after one of its keys changed, its timeout
+    // passed or CLIENT UNBLOCK woke it, returning its reply once it is no
+    // longer blocked
+    async fn retry_blocked(&self, session: &mut Session) -> Result<Option<Reply>, RedisError> {
+        let Some(blocked) = session.blocked.take() else {
+            return Ok(None);
+        };
+        let unblocked = self.blocking.lock().unwrap().unblocked(session.id);
+        let response = match unblocked {
+            Some(Unblock::Timeout) => Reply::Nil,
+            Some(Unblock::Error) => Reply::error_code("UNBLOCKED", "client unblocked via CLIENT UNBLOCK"),
+            None => {
+                let parts = text_args(&blocked.args);
+                let response = self.run(session, &parts[0].to_uppercase(), &parts, &blocked.args).await?;
+                if matches!(response, Reply::Nil) && blocked.deadline.is_none_or(|deadline| Instant::now() < deadline) {
+                    session.blocked = Some(blocked);
+                    return Ok(None);
+                }
+                response
+            }
+        };
+        self.blocking.lock().unwrap().unblock(session.id);
+        self.update_client(session);
+        Ok(Some(response))
+    }
+    
+    fn is_subscribed(&self, session: &Session) -> bool {
+        session.messages.is_some() && self.pubsub.lock().unwrap().subscription_count(session.id) > 0
+    }
+    
+    // Run the queued commands of a transaction back to back. A command that
+    // fails doesn't stop the others: its error takes its place in the results.
+    async fn exec(&self, session: &mut Session, transaction: Transaction) -> Result<Reply, RedisError> {
+        if transaction.failed {
+            self.unwatch(session);
+            return Ok(Reply::error_code("EXECABORT", "Transaction discarded because of previous errors"));
+        }
+        
+        let mut responses = Vec::new();
+        let mut last_written = None;
+        {
+            let _exec_lock = self.exec_lock.write().unwrap();
+            // Checked with every other client held off, so none can modify a
+            // watched key between the check and the queued commands
+            if self.unwatch(session) {
+                return Ok(Reply::Nil);
+            }
+            for args in &transaction.commands {
+                let parts = text_args(args);
+                let command = parts[0].to_uppercase();
+                
+                let response = match self.run_command(session, &command, &parts, args) {
+                    Ok((response, written)) => {
+                        last_written = written.or(last_written);
+                        response
+                    }
+                    Err(e) => Reply::error(e.to_string()),
+                };
+                responses.push(response);
+            }
+        }
+        
+        if let Some(error) = self.wait_written(last_written).await {
+            return Ok(error);
+        }
+        
+        Ok(Reply::Array(responses))
+    }
+    
+    // Authenticate a connection as the default user, whose password is
+    // requirepass, or any password at all while none is set
+    fn authenticate(&self, session: &mut Session, user: &str, password: &str) -> Result<(), Reply> {
+        let config = self.config.lock().unwrap();
+        if user != "default" || (!config.requirepass.is_empty() && password != config.requirepass) {
+            return Err(Reply::error_code("WRONGPASS", "invalid username-password pair or user is disabled."));
+        }
+        session.authenticated = true;
+        Ok(())
+    }
+    
+    // Copy the name, database and blocking keys of a connection to the
+    // registry, for CLIENT LIST
+    fn update_client(&self, session: &Session) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(session.id) {
+            client.name = session.name.clone();
+            client.db = session.db;
+            client.blocked = match &session.blocked {
+                Some(blocked) => text_args(&blocked.args[1..blocked.args.len() - 1]).iter().map(|key| key.to_string()).collect(),
+                None => Vec::new(),
+            };
+        }
+    }
+    
+    // Stop watching the keys this client WATCHed, reporting whether any of
+    // them was modified in the meantime
+    fn unwatch(&self, session: &mut Session) -> bool {
+        let mut databases = self.data.lock().unwrap();
+        let mut modified = false;
+        for (db, key, version) in std::mem::take(&mut session.watched) {
+            let data = &mut databases[db];
+            // Like Redis, a key that expired while watched counts as modified
+            data.remove_if_expired(&key);
+            modified |= data.version(&key) != Some(version);
+            data.unwatch(&key);
+        }
+        modified
+    }
+    
+    // Run one command. Writes are logged to the append-only file, if enabled,
+    // and passed on to replicas; the returned receiver completes once the
+    // write is safely in the log.
+    fn run_command(
+        &self,
+        session: &mut Session,
+        command: &str,
+        parts: &[&str],
+        args: &[Vec<u8>],
+    ) -> Result<(Reply, Option<oneshot::Receiver<()>>), RedisError> {
+        // A script isn't logged itself, the writes it makes are
+        if command == "EVAL" || command == "FCALL" {
+            return Ok(self.run_script(session, command, parts));
+        }
+        
+        // Only writes are logged to the append-only file
+        let spec = commands::lookup(command);
+        if !spec.is_some_and(|spec| spec.has(WRITE)) {
+            let response = self.execute(session, command, parts, args)?;
+            if let Some(spec) = spec.filter(|spec| spec.has(READONLY) && !response.is_error()) {
+                self.tracking.lock().unwrap().read(session.id, spec.keys(parts));
+            }
+            self.handle_changes();
+            return Ok((response, None));
+        }
+        
+        let mut aof = self.aof.as_ref().map(|aof| aof.lock().unwrap());
+        let replicas = self.replicas.lock().unwrap();
+        
+        // Make room before the write, refusing it if memory can't be freed
+        let (evicted, out_of_memory) = self.evict_keys();
+        let response = if out_of_memory && spec.is_some_and(|spec| spec.has(DENY_OOM)) {
+            Reply::error_code("OOM", "command not allowed when used memory > 'maxmemory'")
+        } else {
+            self.execute(session, command, parts, args)?
+        };
+        
+        let replicating = replicas.receiver_count() > 0;
+        let mut written = None;
+        if aof.is_some() || replicating {
+            for (db, args) in self.logged_writes(session.db, command, parts, args, &response, evicted) {
+                if let Some(aof) = aof.as_mut() {
+                    written = Some(aof.append(db, &args));
+                }
+                if replicating {
+                    // A replica that just went away is no concern of the writer's
+                    let _ = replicas.send(Arc::new(replication::Write { db, args }));
+                }
+            }
+        }
+        
+        // Published while the log is still locked, so subscribers see the
+        // events of different clients' writes in the order they were logged
+        self.handle_changes();
+        
+        // Entries are written in order, so the last one covers the others
+        Ok((response, written))
+    }
+    
+    // Run a Lua script for EVAL, or a library's function for FCALL, with each
+    // redis.call running its command as if in a transaction: on its own copy
+    // of the session, authenticated and in RESP2, so a SELECT in the script
+    // doesn't change the client's database. Returns the receiver of the last
+    // write the script logged.
+    fn run_script(&self, session: &Session, command: &str, parts: &[&str]) -> (Reply, Option<oneshot::Receiver<()>>) {
+        let Ok(numkeys) = parts[2].parse::<i64>() else {
+            return (Reply::error("value is not an integer or out of range"), None);
+        };
+        if numkeys < 0 {
+            return (Reply::error("Number of keys can't be negative"), None);
+        }
+        let Some((keys, args)) = usize::try_from(numkeys).ok().and_then(|numkeys| parts[3..].split_at_checked(numkeys)) else {
+            return (Reply::error("Number of keys can't be greater than number of args"), None);
+        };
+        let script = if command == "EVAL" {
+            match Script::compile(parts[1]) {
+                Ok(script) => Arc::new(script),
+                Err(e) => return (Reply::error(format!("Error compiling script (new function): {}", e)), None),
+            }
+        } else {
+            match self.functions.lock().unwrap().find(parts[1]) {
+                Some(script) => script,
+                None => return (Reply::error("Function not found"), None),
+            }
+        };
+        
+        let mut script_session = Session {
+            id: session.id,
+            addr: session.addr.clone(),
+            db: session.db,
+            authenticated: true,
+            ..Session::default()
+        };
+        let kill = Arc::new(AtomicBool::new(false));
+        *self.running_script.lock().unwrap() = Some(RunningScript {
+            started: Instant::now(),
+            function: command == "FCALL",
+            wrote: false,
+            kill: kill.clone(),
+        });
+        let mut last_written = None;
+        let mut host = |args: &[String]| {
+            let parts: Vec<&str> = args.iter().map(String::as_str).collect();
+            let args: Args = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+            let command = parts[0].to_uppercase();
+            let Some(spec) = commands::lookup(&command).filter(|spec| !spec.has(NO_SCRIPT)) else {
+                return Reply::error("Unknown Redis command called from script");
+            };
+            if !spec.accepts(parts.len()) {
+                return Reply::error("Wrong number of args calling Redis command from script");
+            }
+            match self.run_command(&mut script_session, &command, &parts, &args) {
+                Ok((response, written)) => {
+                    if spec.has(WRITE) && !response.is_error() {
+                        if let Some(running) = self.running_script.lock().unwrap().as_mut() {
+                            running.wrote = true;
+                        }
+                    }
+                    last_written = written.or(last_written.take());
+                    response
+                }
+                Err(e) => Reply::error(e.to_string()),
+            }
+        };
+        let response = if command == "EVAL" {
+            script.run(keys, args, &mut host, &kill)
+        } else {
+            script.call_function(parts[1], keys, args, &mut host, &kill)
+        };
+        *self.running_script.lock().unwrap() = None;
+        (response, last_written)
+    }
+    
+    // The BUSY error for a command sent while a script has been running for
+    // longer than busy-reply-threshold
+    fn busy_reply(&self) -> Option<Reply> {
+        let threshold = Duration::from_millis(self.config.lock().unwrap().busy_reply_threshold);
+        let running = self.running_script.lock().unwrap();
+        let running = running.as_ref().filter(|running| running.started.elapsed() >= threshold)?;
+        let kill = if running.function { "FUNCTION KILL" } else { "SCRIPT KILL" };
+        Some(Reply::error_code("BUSY", format!("Redis is busy running a script. You can only call {}.", kill)))
+    }
+    
+    // SCRIPT KILL, or FUNCTION KILL with `function`: stop the running script
+    // unless it has written
+    fn kill_script(&self, function: bool) -> Reply {
+        match self.running_script.lock().unwrap().as_ref() {
+            Some(running) if running.function == function => {
+                if running.wrote {
+                    return Reply::error_code(
+                        "UNKILLABLE",
+                        "Sorry the script already executed write commands against the dataset. You can only wait for it to finish.",
+                    );
+                }
+                running.kill.store(true, Ordering::Relaxed);
+                Reply::ok()
+            }
+            _ => Reply::error_code("NOTBUSY", "No scripts in execution right now."),
+        }
+    }
+    
+    // The commands to log for a write, with the database each applies to, so
+    // that replaying them, or a replica applying them, makes the same changes.
+    // Evicted keys are logged as deletions so a replay doesn't bring them
+    // back. Failed writes change nothing, so only successful ones are logged.
+    fn logged_writes(
+        &self,
+        db: usize,
+        command: &str,
+        parts: &[&str],
+        args: &[Vec<u8>],
+        response: &Reply,
+        evicted: Vec<(usize, String)>,
+    ) -> Vec<(usize, Args)> {
+        let owned = |args: &[&[u8]]| args.iter().map(|arg| arg.to_vec()).collect::<Args>();
+        let mut logged: Vec<(usize, Args)> = evicted.into_iter()
+            .map(|(db, key)| (db, vec![b"DEL".to_vec(), key.into_bytes()]))
+            .collect();
+        if response.is_error() {
+            return logged;
+        }
+        
+        let expiry = self.expiry_to_log(db, command, parts);
+        if command == "SPOP" {
+            // Replaying SPOP would pick other members, so the ones it
+            // did pop are logged as removed
+            let popped = match response {
+                Reply::Bulk(member) => vec![member],
+                Reply::Array(members) => members.iter().filter_map(|member| match member {
+                    Reply::Bulk(member) => Some(member),
+                    _ => None,
+                }).collect(),
+                _ => Vec::new(),
+            };
+            if !popped.is_empty() {
+                let mut srem = vec![b"SREM".as_slice(), &args[1]];
+                srem.extend(popped.iter().map(|member| member.as_slice()));
+                logged.push((db, owned(&srem)));
+            }
+        } else if command == "BLPOP" || command == "BRPOP" {
+            // Logged as the pop it made, if any, so a replay never blocks
+            let key = match response {
+                Reply::Array(popped) => popped.first(),
+                _ => None,
+            };
+            if let Some(Reply::Bulk(key)) = key {
+                let pop = if command == "BLPOP" { "LPOP" } else { "RPOP" };
+                logged.push((db, owned(&[pop.as_bytes(), key])));
+            }
+        } else if command == "FUNCTION" {
+            // Only the subcommands that change the libraries need replaying
+            if !parts[1].eq_ignore_ascii_case("LIST") && !parts[1].eq_ignore_ascii_case("KILL") {
+                logged.push((db, args.to_vec()));
+            }
+        } else if command != "EXPIRE" {
+            logged.push((db, args.to_vec()));
+        }
+        if let Some(unix_ms) = expiry {
+            logged.push((db, owned(&[b"PEXPIREAT", &args[1], unix_ms.to_string().as_bytes()])));
+        }
+        logged
+    }
+    
+    // The absolute expiry, in Unix milliseconds, to log after a command that
+    // may have set a relative one
+    fn expiry_to_log(&self, db: usize, command: &str, parts: &[&str]) -> Option<u64> {
+        if !RELATIVE_EXPIRY_COMMANDS.contains(&command) {
+            return None;
+        }
+        
+        let databases = self.data.lock().unwrap();
+        let expires_at = databases[db].get(parts[1])?.expires_at?;
+        Some(persistence::instant_to_unix_ms(expires_at))
+    }
+    
+    // Wait for a write to reach the append-only file, returning the error to
+    // reply with instead if it didn't make it
+    async fn wait_written(&self, written: Option<oneshot::Receiver<()>>) -> Option<Reply> {
+        match written?.await {
+            Ok(()) => None,
+            Err(_) => Some(Reply::error("Writing to the append-only file failed")),
+        }
+    }
+    
+    // Evict keys per maxmemory-policy until memory use is back under maxmemory.
+    // Returns the evicted keys with their database and whether memory use is
+    // still over the limit.
+    fn evict_keys(&self) -> (Vec<(usize, String)>, bool) {
+        let (maxmemory, policy, samples) = {
+            let config = self.config.lock().unwrap();
+            (config.maxmemory as usize, config.maxmemory_policy, config.maxmemory_samples)
+        };
+        if maxmemory == 0 {
+            return (Vec::new(), false);
+        }
+        
+        let mut databases = self.data.lock().unwrap();
+        let used_memory = |databases: &[Keyspace]| databases.iter().map(Keyspace::used_memory).sum::<usize>();
+        let mut pool = self.eviction_pool.lock().unwrap();
+        let mut evicted = Vec::new();
+        'evicting: while used_memory(&databases) > maxmemory && policy != MaxmemoryPolicy::NoEviction {
+            // The policies cover all keys, so every database is sampled and
+            // the best candidate of any of them goes
+            for (db, data) in databases.iter().enumerate() {
+                pool.offer(policy, db, data.sample(samples));
+            }
+            // A candidate may have been deleted since an earlier eviction
+            // sampled it, in which case the next one is tried
+            loop {
+                let Some((db, key)) = pool.pop() else {
+                    break 'evicting;
+                };
+                if databases[db].evict(&key) {
+                    evicted.push((db, key));
+                    break;
+                }
+            }
+        }
+        self.stats.evicted_keys.fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        
+        (evicted, used_memory(&databases) > maxmemory)
+    }
+    
+    // Give every database the new encoding limits, which they keep a copy of
+    fn set_encoding_limits(&self, limits: EncodingLimits) {
+        for data in self.data.lock().unwrap().iter_mut() {
+            data.set_limits(limits);
+        }
+    }
+    
+    // Re-execute the commands read from the append-only file on startup
+    fn replay(&self, commands: &[Args]) -> Result<(), RedisError> {
+        // The log selects databases as it goes, just like a client
+        let mut session = Session::default();
+        for args in commands {
+            let parts = text_args(args);
+            if let Some(name) = parts.first() {
+                self.execute(&mut session, &name.to_uppercase(), &parts, args)?;
+            }
+        }
+        // These events happened before the server started, so they are dropped
+        // rather than published to the first subscribers, and the changes are
+        // already on disk
+        for data in self.data.lock().unwrap().iter_mut() {
+            data.take_events();
+            data.take_changes();
+        }
+        
+        Ok(())
+    }
+    
+    // Execute a parsed command; `command` is the upper-cased command name,
+    // `parts` its arguments as text and `args` as sent, for values
+    fn execute(&self, session: &mut Session, command: &str, parts: &[&str], args: &[Vec<u8>]) -> Result<Reply, RedisError> {
+        // A key of the wrong type is the client's mistake, answered like any
+        // other error reply
+        match self.execute_command(session, command, parts, args) {
+            Err(RedisError::WrongType) => Ok(wrong_type()),
+            result => result,
+        }
+    }
+    
+    fn execute_command(&self, session: &mut Session, command: &str, parts: &[&str], args: &[Vec<u8>]) -> Result<Reply, RedisError> {
+        match command {
+            "GET" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("GET requires exactly one argument"));
+                }
+                
+                let key = parts[1];
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, key);
+                match value.as_ref().map(|value| value.value.as_string()).transpose()? {
+                    Some(bytes) => Ok(Reply::bulk(bytes.clone())),
+                    None => Ok(Reply::Nil),
+                }
+            }
+            "GETDEL" => {
+                // GETDEL key is GET that also deletes the key, in one step so
+                // no other client can read the value in between
+                if parts.len() != 2 {
+                    return Ok(Reply::error("GETDEL requires exactly one argument"));
+                }
+                
+                let key = parts[1];
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let bytes = match get_live(data, key).as_ref().map(|value| value.value.as_string()).transpose()? {
+                    Some(bytes) => bytes.clone(),
+                    None => return Ok(Reply::Nil),
+                };
+                data.remove(key);
+                data.notify('g', "del", key);
+                Ok(Reply::bulk(bytes))
+            }
+            "GETEX" => {
+                // GETEX key [EX seconds | PX milliseconds | EXAT unix-seconds |
+                // PXAT unix-milliseconds | PERSIST] is GET that also sets the
+                // key's expiry, or removes it with PERSIST
+                if parts.len() < 2 {
+                    return Ok(Reply::error("GETEX requires a key and an optional expiry"));
+                }
+                // None leaves the expiry as it is, Some(None) removes it
+                let expiry = match &parts[2..] {
+                    [] => None,
+                    [persist] if persist.eq_ignore_ascii_case("PERSIST") => Some(None),
+                    [unit, amount] if matches!(unit.to_uppercase().as_str(), "EX" | "PX" | "EXAT" | "PXAT") => {
+                        match expiry_option(command, &unit.to_uppercase(), amount) {
+                            Ok(expires_at) => Some(Some(expires_at)),
+                            Err(reply) => return Ok(reply),
+                        }
+                    }
+                    _ => return Ok(Reply::error("syntax error")),
+                };
+                
+                let key = parts[1];
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, key) else {
+                    return Ok(Reply::Nil);
+                };
+                let bytes = value.value.as_string()?;
+                let reply = Reply::bulk(bytes.clone());
+                let Some(expires_at) = expiry else {
+                    return Ok(reply);
+                };
+                let had_expiry = value.expires_at.is_some();
+                value.expires_at = expires_at;
+                value.mark_modified();
+                let expired = value.is_expired();
+                drop(value);
+                
+                // Like EXPIREAT, a time already passed deletes the key right away
+                if expired {
+                    data.remove(key);
+                    data.notify('g', "del", key);
+                } else if expires_at.is_some() {
+                    data.notify('g', "expire", key);
+                } else if had_expiry {
+                    data.notify('g', "persist", key);
+                }
+                Ok(reply)
+            }
+            "SET" => {
+                // SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | KEEPTTL]
+                if parts.len() < 3 {
+                    return Ok(Reply::error("SET requires two arguments (key value) with optional NX/XX/GET/EX/PX/KEEPTTL"));
+                }
+                
+                let key = parts[1];
+                let value = args[2].clone();
+                
+                let mut expires_at = None;
+                let (mut nx, mut xx, mut get, mut keep_ttl) = (false, false, false, false);
+                // How many of EX and PX were given
+                let mut ttls = 0;
+                let mut options = parts[3..].iter();
+                while let Some(option) = options.next() {
+                    match option.to_uppercase().as_str() {
+                        "NX" => nx = true,
+                        "XX" => xx = true,
+                        "GET" => get = true,
+                        "KEEPTTL" => keep_ttl = true,
+                        unit @ ("EX" | "PX") => {
+                            ttls += 1;
+                            let Some(amount) = options.next() else {
+                                return Ok(Reply::error("syntax error"));
+                            };
+                            match expiry_option(command, unit, amount) {
+                                Ok(instant) => expires_at = Some(instant),
+                                Err(reply) => return Ok(reply),
+                            }
+                        }
+                        _ => return Ok(Reply::error("syntax error")),
+                    }
+                }
+                if (nx && xx) || ttls > 1 || (ttls > 0 && keep_ttl) {
+                    return Ok(Reply::error("syntax error"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                let old = get_live(data, key).map(|value| (value.value.clone(), value.expires_at));
+                
+                // GET returns the old value whether or not the set goes ahead,
+                // and can only return a string
+                let old_value = match &old {
+                    Some((Value::String(old_value), _)) => Reply::bulk(old_value.clone()),
+                    Some(_) if get => return Err(RedisError::WrongType),
+                    _ => Reply::Nil,
+                };
+                if (nx && old.is_some()) || (xx && old.is_none()) {
+                    return Ok(if get { old_value } else { Reply::Nil });
+                }
+                
+                let mut redis_value = RedisValue::new(value, expires_at);
+                if keep_ttl {
+                    redis_value.expires_at = old.and_then(|(_, expires_at)| expires_at);
+                }
+                data.insert(key.to_string(), redis_value);
+                data.notify('$', "set", key);
+                if expires_at.is_some() {
+                    data.notify('g', "expire", key);
+                }
+                
+                Ok(if get { old_value } else { Reply::ok() })
+            }
+            "SETEX" | "PSETEX" => {
+                // SETEX key seconds value, PSETEX key milliseconds value
+                if parts.len() != 4 {
+                    return Ok(Reply::error(format!("{} requires a key, a duration and a value", command)));
+                }
+                
+                // Nothing is stored unless the duration is a positive integer
+                let ttl = match parts[2].parse::<u64>() {
+                    Ok(0) | Err(_) => return Ok(invalid_expire_time(command)),
+                    Ok(amount) if command == "SETEX" => Duration::from_secs(amount),
+                    Ok(amount) => Duration::from_millis(amount),
+                };
+                let expires_at = match expires_after(command, ttl) {
+                    Ok(expires_at) => expires_at,
+                    Err(reply) => return Ok(reply),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                data.insert(parts[1].to_string(), RedisValue::new(args[3].clone(), Some(expires_at)));
+                data.notify('$', "set", parts[1]);
+                data.notify('g', "expire", parts[1]);
+                
+                Ok(Reply::ok())
+            }
+            "GETRANGE" => {
+                // GETRANGE key start end, both ends inclusive and negative
+                // indexes counting back from the end of the string
+                if parts.len() != 4 {
+                    return Ok(Reply::error("GETRANGE requires a key, a start and an end"));
+                }
+                
+                let (start, end) = match (parts[2].parse::<i64>(), parts[3].parse::<i64>()) {
+                    (Ok(start), Ok(end)) => (start, end),
+                    _ => return Ok(Reply::error("GETRANGE start and end must be integers")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let bytes = match value.as_ref().map(|value| value.value.as_string()).transpose()? {
+                    Some(bytes) => bytes,
+                    None => &[][..],
+                };
+                Ok(Reply::bulk(byte_range(bytes, start, end)))
+            }
+            "SETRANGE" => {
+                // SETRANGE key offset value, returning the new length
+                if parts.len() != 4 {
+                    return Ok(Reply::error("SETRANGE requires a key, an offset and a value"));
+                }
+                
+                let offset = match parts[2].parse::<usize>() {
+                    Ok(offset) => offset,
+                    Err(_) => return Ok(Reply::error("SETRANGE offset must be a non-negative integer")),
+                };
+                let patch = args[3].as_slice();
+                
+                // An empty patch changes nothing, so only a real write can be
+                // too long, which is checked before the string grows
+                let max_len = self.config.lock().unwrap().proto_max_bulk_len;
+                let too_long = offset.checked_add(patch.len()).is_none_or(|end| end > max_len);
+                if !patch.is_empty() && too_long {
+                    return Ok(Reply::error("string exceeds maximum allowed size"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                if get_live(data, parts[1]).is_none() {
+                    // Like Redis, an empty patch doesn't create the key
+                    if patch.is_empty() {
+                        return Ok(Reply::Integer(0));
+                    }
+                    data.insert(parts[1].to_string(), RedisValue::new(Vec::new(), None));
+                }
+                let mut value = data.get_mut(parts[1]).unwrap();
+                let bytes = value.value.as_string_mut()?;
+                
+                // Overwritten in place, so an existing key keeps its TTL
+                if patch.is_empty() {
+                    return Ok(Reply::Integer(bytes.len() as i64));
+                }
+                overwrite_at(bytes, offset, patch);
+                let len = bytes.len();
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('$', "setrange", parts[1]);
+                Ok(Reply::Integer(len as i64))
+            }
+            "APPEND" => {
+                // APPEND key value, returning the new length. A missing key
+                // is appended to as an empty string.
+                let (key, patch) = (parts[1], args[2].as_slice());
+                let max_len = self.config.lock().unwrap().proto_max_bulk_len;
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let len = match get_live(data, key).as_ref().map(|value| value.value.as_string()).transpose()? {
+                    Some(bytes) => bytes.len(),
+                    None => 0,
+                };
+                if len.checked_add(patch.len()).is_none_or(|end| end > max_len) {
+                    return Ok(Reply::error("string exceeds maximum allowed size"));
+                }
+                
+                if len == 0 && data.get(key).is_none() {
+                    data.insert(key.to_string(), RedisValue::new(Vec::new(), None));
+                }
+                let mut value = data.get_mut(key).unwrap();
+                if let Value::String(bytes) = &mut value.value {
+                    bytes.extend_from_slice(patch);
+                }
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('$', "append", key);
+                Ok(Reply::Integer((len + patch.len()) as i64))
+            }
+            "SETBIT" => {
+                // SETBIT key offset 0|1, returning the bit's previous value
+                if parts.len() != 4 {
+                    return Ok(Reply::error("SETBIT requires a key, an offset and a bit"));
+                }
+                
+                let max_len = self.config.lock().unwrap().proto_max_bulk_len;
+                let Some((byte, shift)) = parse_bit_offset(parts[2], max_len) else {
+                    return Ok(Reply::error("bit offset is not an integer or out of range"));
+                };
+                let bit: u8 = match parts[3] {
+                    "0" => 0,
+                    "1" => 1,
+                    _ => return Ok(Reply::error("bit is not an integer or out of range")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                if get_live(data, parts[1]).is_none() {
+                    data.insert(parts[1].to_string(), RedisValue::new(Vec::new(), None));
+                }
+                let mut value = data.get_mut(parts[1]).unwrap();
+                let bytes = value.value.as_string_mut()?;
+                
+                // The string grows with zero bytes to reach the offset
+                if bytes.len() <= byte {
+                    bytes.resize(byte + 1, 0);
+                }
+                let old = (bytes[byte] >> shift) & 1;
+                bytes[byte] = (bytes[byte] & !(1 << shift)) | (bit << shift);
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('$', "setbit", parts[1]);
+                Ok(Reply::Integer(old as i64))
+            }
+            "GETBIT" => {
+                if parts.len() != 3 {
+                    return Ok(Reply::error("GETBIT requires a key and an offset"));
+                }
+                
+                let max_len = self.config.lock().unwrap().proto_max_bulk_len;
+                let Some((byte, shift)) = parse_bit_offset(parts[2], max_len) else {
+                    return Ok(Reply::error("bit offset is not an integer or out of range"));
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                // Bits past the end of the string, or of a missing key, are 0
+                let value = get_live(data, parts[1]);
+                let bytes = match value.as_ref().map(|value| value.value.as_string()).transpose()? {
+                    Some(bytes) => bytes,
+                    None => &[][..],
+                };
+                let bit = bytes.get(byte).map_or(0, |byte| (byte >> shift) & 1);
+                Ok(Reply::Integer(bit as i64))
+            }
+            "BITCOUNT" => {
+                // BITCOUNT key [start end], with a byte range like GETRANGE
+                if parts.len() != 2 && parts.len() != 4 {
+                    return Ok(Reply::error("BITCOUNT requires a key with an optional start and end"));
+                }
+                
+                let range = match parts.get(2..4) {
+                    None => None,
+                    Some(range) => match (range[0].parse::<i64>(), range[1].parse::<i64>()) {
+                        (Ok(start), Ok(end)) => Some((start, end)),
+                        _ => return Ok(Reply::error("BITCOUNT start and end must be integers")),
+                    },
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let bytes = match value.as_ref().map(|value| value.value.as_string()).transpose()? {
+                    Some(bytes) => bytes,
+                    None => &[][..],
+                };
+                let bytes = match range {
+                    Some((start, end)) => byte_range(bytes, start, end),
+                    None => bytes,
+                };
+                let count: u32 = bytes.iter().map(|byte| byte.count_ones()).sum();
+                Ok(Reply::Integer(count as i64))
+            }
+            "PFADD" => {
+                // PFADD key [element ...], returning 1 if the estimate may
+                // have changed, including when the key was just created
+                if parts.len() < 2 {
+                    return Ok(Reply::error("PFADD requires a key"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let (mut hll, created) = match hyperloglog_at(data, parts[1]) {
+                    Ok(Some(hll)) => (hll, false),
+                    Ok(None) => (HyperLogLog::default(), true),
+                    Err(reply) => return Ok(reply),
+                };
+                let mut changed = created;
+                for element in &args[2..] {
+                    changed |= hll.add(element);
+                }
+                if !changed {
+                    return Ok(Reply::Integer(0));
+                }
+                
+                // Stored back in place, so the key keeps its TTL
+                if created {
+                    data.insert(parts[1].to_string(), RedisValue::new(hll.to_bytes(), None));
+                } else {
+                    let mut value = data.get_mut(parts[1]).unwrap();
+                    value.value = Value::String(hll.to_bytes());
+                    value.mark_modified();
+                }
+                data.notify('$', "pfadd", parts[1]);
+                Ok(Reply::Integer(1))
+            }
+            "PFCOUNT" => {
+                // PFCOUNT key [key ...], estimating the union of several
+                // HyperLogLogs. Missing keys count as empty.
+                if parts.len() < 2 {
+                    return Ok(Reply::error("PFCOUNT requires at least one key"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let mut union = HyperLogLog::default();
+                for key in &parts[1..] {
+                    match hyperloglog_at(data, key) {
+                        Ok(Some(hll)) => union.merge(&hll),
+                        Ok(None) => {}
+                        Err(reply) => return Ok(reply),
+                    }
+                }
+                Ok(Reply::Integer(union.count() as i64))
+            }
+            "SADD" => {
+                // SADD key member [member ...], returning how many members
+                // weren't in the set yet
+                if parts.len() < 3 {
+                    return Ok(Reply::error("SADD requires a key and at least one member"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                if get_live(data, parts[1]).is_none() {
+                    data.insert(parts[1].to_string(), RedisValue::new(HashSet::new(), None));
+                }
+                let mut value = data.get_mut(parts[1]).unwrap();
+                let set = value.value.as_set_mut()?;
+                let added = parts[2..].iter().filter(|member| set.insert(member.to_string())).count();
+                if added > 0 {
+                    value.mark_modified();
+                }
+                drop(value);
+                
+                if added > 0 {
+                    data.notify('s', "sadd", parts[1]);
+                }
+                Ok(Reply::Integer(added as i64))
+            }
+            "SREM" => {
+                // SREM key member [member ...], returning how many were removed
+                if parts.len() < 3 {
+                    return Ok(Reply::error("SREM requires a key and at least one member"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(Reply::Integer(0));
+                };
+                let set = value.value.as_set_mut()?;
+                let removed = parts[2..].iter().filter(|member| set.remove(member)).count();
+                let now_empty = set.is_empty();
+                if removed > 0 {
+                    value.mark_modified();
+                }
+                drop(value);
+                
+                if removed > 0 {
+                    data.notify('s', "srem", parts[1]);
+                }
+                if now_empty {
+                    data.remove(parts[1]);
+                    data.notify('g', "del", parts[1]);
+                }
+                Ok(Reply::Integer(removed as i64))
+            }
+            "SMEMBERS" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("SMEMBERS requires exactly one argument"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_set()).transpose()? {
+                    Some(set) => Ok(Reply::Array(set.iter().map(|member| Reply::bulk(member.clone())).collect())),
+                    None => Ok(Reply::Array(Vec::new())),
+                }
+            }
+            "SISMEMBER" => {
+                if parts.len() != 3 {
+                    return Ok(Reply::error("SISMEMBER requires a key and a member"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_set()).transpose()? {
+                    Some(set) => Ok(Reply::Integer(set.contains(parts[2]) as i64)),
+                    None => Ok(Reply::Integer(0)),
+                }
+            }
+            "SCARD" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("SCARD requires exactly one argument"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_set()).transpose()? {
+                    Some(set) => Ok(Reply::Integer(set.len() as i64)),
+                    None => Ok(Reply::Integer(0)),
+                }
+            }
+            "SPOP" => {
+                // SPOP key [count] removes random members. With a count they
+                // come back as an array, even just one.
+                if parts.len() > 3 {
+                    return Ok(Reply::error("SPOP requires a key and an optional count"));
+                }
+                let count = match parts.get(2).map(|count| count.parse::<usize>()) {
+                    None => None,
+                    Some(Ok(count)) => Some(count),
+                    Some(Err(_)) => return Ok(Reply::error("value is out of range, must be positive")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let missing = if count.is_some() { Reply::Array(Vec::new()) } else { Reply::Nil };
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(missing);
+                };
+                let set = value.value.as_set_mut()?;
+                let chosen: Vec<String> = set.iter().choose_multiple(&mut rand::rng(), count.unwrap_or(1)).into_iter().cloned().collect();
+                for member in &chosen {
+                    set.remove(member);
+                }
+                let now_empty = set.is_empty();
+                if !chosen.is_empty() {
+                    value.mark_modified();
+                }
+                drop(value);
+                
+                if !chosen.is_empty() {
+                    data.notify('s', "spop", parts[1]);
+                }
+                if now_empty {
+                    data.remove(parts[1]);
+                    data.notify('g', "del", parts[1]);
+                }
+                let mut popped: Vec<Reply> = chosen.into_iter().map(Reply::bulk).collect();
+                match count {
+                    Some(_) => Ok(Reply::Array(popped)),
+                    None => Ok(popped.pop().unwrap_or(Reply::Nil)),
+                }
+            }
+            "SRANDMEMBER" => {
+                // SRANDMEMBER key [count] returns random members without
+                // removing them: up to count distinct ones, or with a negative
+                // count exactly -count of them, possibly repeated
+                if parts.len() > 3 {
+                    return Ok(Reply::error("SRANDMEMBER requires a key and an optional count"));
+                }
+                let count = match parts.get(2).map(|count| count.parse::<i64>()) {
+                    None => None,
+                    Some(Ok(count)) => Some(count),
+                    Some(Err(_)) => return Ok(Reply::error("value is not an integer or out of range")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let members: Vec<&String> = match value.as_ref().map(|value| value.value.as_set()).transpose()? {
+                    Some(set) => set.iter().collect(),
+                    None => Vec::new(),
+                };
+                let mut rng = rand::rng();
+                let chosen: Vec<&String> = match count {
+                    None => return Ok(members.choose(&mut rng).map_or(Reply::Nil, |member| Reply::bulk((*member).clone()))),
+                    Some(count) if count >= 0 => members.choose_multiple(&mut rng, count as usize).copied().collect(),
+                    Some(_) if members.is_empty() => Vec::new(),
+                    Some(count) => (0..count.unsigned_abs()).map(|_| *members.choose(&mut rng).unwrap()).collect(),
+                };
+                Ok(Reply::Array(chosen.into_iter().map(|member| Reply::bulk(member.clone())).collect()))
+            }
+            "SMOVE" => {
+                // SMOVE source destination member, returning 1 if the member
+                // was moved and 0 if it wasn't in the source
+                if parts.len() != 4 {
+                    return Ok(Reply::error("SMOVE requires a source, a destination and a member"));
+                }
+                let (source, destination, member) = (parts[1], parts[2], parts[3]);
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                // Both keys are type-checked before anything moves
+                let in_source = match get_live(data, source) {
+                    Some(value) => value.value.as_set()?.contains(member),
+                    None => false,
+                };
+                if let Some(value) = get_live(data, destination) {
+                    value.value.as_set()?;
+                }
+                if !in_source {
+                    return Ok(Reply::Integer(0));
+                }
+                if source == destination {
+                    return Ok(Reply::Integer(1));
+                }
+                
+                let mut value = data.get_mut(source).unwrap();
+                let Value::Set(set) = &mut value.value else {
+                    unreachable!("source checked to be a set");
+                };
+                set.remove(member);
+                let now_empty = set.is_empty();
+                value.mark_modified();
+                drop(value);
+                data.notify('s', "srem", source);
+                if now_empty {
+                    data.remove(source);
+                    data.notify('g', "del", source);
+                }
+                
+                if data.get(destination).is_none() {
+                    data.insert(destination.to_string(), RedisValue::new(HashSet::new(), None));
+                }
+                let mut value = data.get_mut(destination).unwrap();
+                if let Value::Set(set) = &mut value.value {
+                    set.insert(member.to_string());
+                }
+                value.mark_modified();
+                drop(value);
+                data.notify('s', "sadd", destination);
+                Ok(Reply::Integer(1))
+            }
+            "SINTER" | "SUNION" | "SDIFF" => {
+                // SINTER/SUNION/SDIFF key [key ...], with missing keys as empty sets
+                if parts.len() < 2 {
+                    return Ok(Reply::error(format!("{} requires at least one key", command)));
+                }
+                let operation = match command {
+                    "SINTER" => SetOperation::Intersection,
+                    "SUNION" => SetOperation::Union,
+                    _ => SetOperation::Difference,
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                match combine_sets(data, operation, &parts[1..]) {
+                    Ok(members) => Ok(Reply::Array(members.into_iter().map(Reply::bulk).collect())),
+                    Err(reply) => Ok(reply),
+                }
+            }
+            "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => {
+                // SINTERSTORE/SUNIONSTORE/SDIFFSTORE destination key [key ...]
+                // replaces the destination with the combined set and returns
+                // its size. An empty result deletes the destination instead.
+                if parts.len() < 3 {
+                    return Ok(Reply::error(format!("{} requires a destination and at least one key", command)));
+                }
+                let (operation, event) = match command {
+                    "SINTERSTORE" => (SetOperation::Intersection, "sinterstore"),
+                    "SUNIONSTORE" => (SetOperation::Union, "sunionstore"),
+                    _ => (SetOperation::Difference, "sdiffstore"),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let members = match combine_sets(data, operation, &parts[2..]) {
+                    Ok(members) => members,
+                    Err(reply) => return Ok(reply),
+                };
+                let count = members.len();
+                if members.is_empty() {
+                    if !data.remove_if_expired(parts[1]) && data.remove(parts[1]).is_some() {
+                        data.notify('g', "del", parts[1]);
+                    }
+                } else {
+                    data.insert(parts[1].to_string(), RedisValue::new(members, None));
+                    data.notify('s', event, parts[1]);
+                }
+                Ok(Reply::Integer(count as i64))
+            }
+            "SORT" => {
+                // SORT key [LIMIT offset count] [ASC | DESC] [ALPHA] returns the
+                // elements of a list or set sorted as numbers, or as strings
+                // with ALPHA. Like Redis, elements with equal values are
+                // ordered as strings so the result doesn't depend on the
+                // set's order.
+                if parts.len() < 2 {
+                    return Ok(Reply::error("SORT requires a key"));
+                }
+                
+                let (mut alpha, mut descending) = (false, false);
+                let mut limit = Limit { offset: 0, count: -1 };
+                let mut options = parts[2..].iter();
+                while let Some(option) = options.next() {
+                    match option.to_uppercase().as_str() {
+                        "ASC" => descending = false,
+                        "DESC" => descending = true,
+                        "ALPHA" => alpha = true,
+                        "LIMIT" => match (options.next(), options.next()) {
+                            (Some(offset), Some(count)) => match Limit::parse(offset, count) {
+                                Some(parsed) => limit = parsed,
+                                None => return Ok(Reply::error("LIMIT offset and count must be integers")),
+                            },
+                            _ => return Ok(Reply::error("syntax error")),
+                        },
+                        _ => return Ok(Reply::error(format!("Unknown SORT option '{}'", option))),
+                    }
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let mut elements: Vec<&[u8]> = match value.as_ref().map(|value| &value.value) {
+                    Some(Value::List(list)) => list.iter().map(Vec::as_slice).collect(),
+                    Some(Value::Set(set)) => set.iter().map(String::as_bytes).collect(),
+                    Some(_) => return Err(RedisError::WrongType),
+                    None => Vec::new(),
+                };
+                
+                if alpha {
+                    elements.sort_unstable();
+                } else {
+                    let mut scored = Vec::with_capacity(elements.len());
+                    for element in elements {
+                        let score = std::str::from_utf8(element).ok().and_then(zset::parse_score);
+                        let Some(score) = score else {
+                            return Ok(Reply::error("One or more scores can't be converted into double"));
+                        };
+                        scored.push((score, element));
+                    }
+                    scored.sort_unstable_by(|(a, a_element), (b, b_element)| a.total_cmp(b).then(a_element.cmp(b_element)));
+                    elements = scored.into_iter().map(|(_, element)| element).collect();
+                }
+                if descending {
+                    elements.reverse();
+                }
+                
+                let sorted = limit.apply(elements.into_iter()).map(Reply::bulk).collect();
+                Ok(Reply::Array(sorted))
+            }
+            "ZADD" => {
+                // ZADD key score member [score member ...], returning how many
+                // members were added rather than updated
+                if parts.len() < 4 || !parts.len().is_multiple_of(2) {
+                    return Ok(Reply::error("ZADD requires a key followed by score and member pairs"));
+                }
+                
+                // Every score is checked before anything is added
+                let mut pairs = Vec::new();
+                for pair in parts[2..].chunks(2) {
+                    match zset::parse_score(pair[0]) {
+                        Some(score) => pairs.push((score, pair[1])),
+                        None => return Ok(Reply::error("value is not a valid float")),
+                    }
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                if get_live(data, parts[1]).is_none() {
+                    data.insert(parts[1].to_string(), RedisValue::new(SortedSet::default(), None));
+                }
+                let mut value = data.get_mut(parts[1]).unwrap();
+                let zset = value.value.as_zset_mut()?;
+                
+                // Updating a score to the one it already has is no change
+                let (mut added, mut changed) = (0, false);
+                for (score, member) in pairs {
+                    changed |= zset.score(member) != Some(score);
+                    if zset.insert(member.to_string(), score) {
+                        added += 1;
+                    }
+                }
+                if changed {
+                    value.mark_modified();
+                }
+                drop(value);
+                
+                if changed {
+                    data.notify('z', "zadd", parts[1]);
+                }
+                Ok(Reply::Integer(added))
+            }
+            "ZSCORE" => {
+                if parts.len() != 3 {
+                    return Ok(Reply::error("ZSCORE requires a key and a member"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let score = match value.as_ref().map(|value| value.value.as_zset()).transpose()? {
+                    Some(zset) => zset.score(parts[2]),
+                    None => None,
+                };
+                Ok(score.map_or(Reply::Nil, Reply::Double))
+            }
+            "ZRANGE" | "ZREVRANGE" => {
+                // ZRANGE key start stop [WITHSCORES], by rank from the lowest
+                // score, with negative ranks counting back from the highest.
+                // ZREVRANGE ranks from the highest score instead.
+                let with_scores = parts.len() == 5 && parts[4].eq_ignore_ascii_case("WITHSCORES");
+                if parts.len() != 4 && !with_scores {
+                    return Ok(Reply::error(format!("{} requires a key, a start and a stop, optionally followed by WITHSCORES", command)));
+                }
+                
+                let (start, stop) = match (parts[2].parse::<i64>(), parts[3].parse::<i64>()) {
+                    (Ok(start), Ok(stop)) => (start, stop),
+                    _ => return Ok(Reply::error(format!("{} start and stop must be integers", command))),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let zset = match value.as_ref().map(|value| value.value.as_zset()).transpose()? {
+                    Some(zset) => zset,
+                    None => return Ok(Reply::Array(Vec::new())),
+                };
+                let Some(range) = index_range(zset.len(), start, stop) else {
+                    return Ok(Reply::Array(Vec::new()));
+                };
+                
+                // Scores follow their members in a flat array, as in RESP2
+                let ordered: Box<dyn Iterator<Item = (&str, f64)>> = if command == "ZREVRANGE" {
+                    Box::new(zset.iter().rev())
+                } else {
+                    Box::new(zset.iter())
+                };
+                let mut members = Vec::new();
+                for (member, score) in ordered.skip(*range.start()).take(range.count()) {
+                    members.push(Reply::bulk(member));
+                    if with_scores {
+                        members.push(Reply::Double(score));
+                    }
+                }
+                Ok(Reply::Array(members))
+            }
+            "ZCOUNT" => {
+                // ZCOUNT key min max, the number of members with a score in
+                // the range, with bounds as in ZRANGEBYSCORE
+                if parts.len() != 4 {
+                    return Ok(Reply::error("ZCOUNT requires a key, a min and a max"));
+                }
+                let (min, max) = match (ScoreBound::parse(parts[2]), ScoreBound::parse(parts[3])) {
+                    (Some(min), Some(max)) => (min, max),
+                    _ => return Ok(Reply::error("min or max is not a float")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_zset()).transpose()? {
+                    Some(zset) => Ok(Reply::Integer(zset.range_by_score(min, max).count() as i64)),
+                    None => Ok(Reply::Integer(0)),
+                }
+            }
+            "ZRANK" | "ZREVRANK" => {
+                // ZRANK key member, the member's 0-based rank from the lowest
+                // score, or from the highest for ZREVRANK; nil if it isn't there
+                if parts.len() != 3 {
+                    return Ok(Reply::error(format!("{} requires a key and a member", command)));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let zset = match value.as_ref().map(|value| value.value.as_zset()).transpose()? {
+                    Some(zset) => zset,
+                    None => return Ok(Reply::Nil),
+                };
+                match zset.rank(parts[2]) {
+                    Some(rank) if command == "ZREVRANK" => Ok(Reply::Integer((zset.len() - 1 - rank) as i64)),
+                    Some(rank) => Ok(Reply::Integer(rank as i64)),
+                    None => Ok(Reply::Nil),
+                }
+            }
+            "ZRANGEBYSCORE" => {
+                // ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count],
+                // where min and max may be -inf, +inf or prefixed with "(" to
+                // leave that score out
+                if parts.len() < 4 {
+                    return Ok(Reply::error("ZRANGEBYSCORE requires a key, a min and a max"));
+                }
+                
+                let (min, max) = match (ScoreBound::parse(parts[2]), ScoreBound::parse(parts[3])) {
+                    (Some(min), Some(max)) => (min, max),
+                    _ => return Ok(Reply::error("min or max is not a float")),
+                };
+                
+                let mut with_scores = false;
+                let mut limit = Limit { offset: 0, count: -1 };
+                let mut options = parts[4..].iter();
+                while let Some(option) = options.next() {
+                    match option.to_uppercase().as_str() {
+                        "WITHSCORES" => with_scores = true,
+                        "LIMIT" => match (options.next(), options.next()) {
+                            (Some(offset), Some(count)) => match Limit::parse(offset, count) {
+                                Some(parsed) => limit = parsed,
+                                None => return Ok(Reply::error("LIMIT offset and count must be integers")),
+                            },
+                            _ => return Ok(Reply::error("syntax error")),
+                        },
+                        _ => return Ok(Reply::error(format!("Unknown ZRANGEBYSCORE option '{}'", option))),
+                    }
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let zset = match value.as_ref().map(|value| value.value.as_zset()).transpose()? {
+                    Some(zset) => zset,
+                    None => return Ok(Reply::Array(Vec::new())),
+                };
+                
+                let mut members = Vec::new();
+                for (member, score) in limit.apply(zset.range_by_score(min, max)) {
+                    members.push(Reply::bulk(member));
+                    if with_scores {
+                        members.push(Reply::Double(score));
+                    }
+                }
+                Ok(Reply::Array(members))
+            }
+            "ZREM" => {
+                // ZREM key member [member ...], returning how many were removed
+                if parts.len() < 3 {
+                    return Ok(Reply::error("ZREM requires a key and at least one member"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let members = parts[2..].iter().map(|member| member.to_string()).collect();
+                Ok(remove_from_zset(data, parts[1], "zrem", |_| members))
+            }
+            "ZREMRANGEBYSCORE" => {
+                // ZREMRANGEBYSCORE key min max, with bounds as in
+                // ZRANGEBYSCORE, returning how many members were removed
+                if parts.len() != 4 {
+                    return Ok(Reply::error("ZREMRANGEBYSCORE requires a key, a min and a max"));
+                }
+                let (min, max) = match (ScoreBound::parse(parts[2]), ScoreBound::parse(parts[3])) {
+                    (Some(min), Some(max)) => (min, max),
+                    _ => return Ok(Reply::error("min or max is not a float")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                Ok(remove_from_zset(data, parts[1], "zremrangebyscore", |zset| {
+                    zset.range_by_score(min, max).map(|(member, _)| member.to_string()).collect()
+                }))
+            }
+            "ZREMRANGEBYRANK" => {
+                // ZREMRANGEBYRANK key start stop, with ranks as in ZRANGE,
+                // returning how many members were removed
+                if parts.len() != 4 {
+                    return Ok(Reply::error("ZREMRANGEBYRANK requires a key, a start and a stop"));
+                }
+                let (start, stop) = match (parts[2].parse::<i64>(), parts[3].parse::<i64>()) {
+                    (Ok(start), Ok(stop)) => (start, stop),
+                    _ => return Ok(Reply::error("ZREMRANGEBYRANK start and stop must be integers")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                Ok(remove_from_zset(data, parts[1], "zremrangebyrank", |zset| {
+                    let Some(range) = index_range(zset.len(), start, stop) else {
+                        return Vec::new();
+                    };
+                    zset.iter().skip(*range.start()).take(range.count()).map(|(member, _)| member.to_string()).collect()
+                }))
+            }
+            "ZINCRBY" => {
+                // ZINCRBY key increment member, adding the member with the
+                // increment as its score if it isn't in the set yet
+                if parts.len() != 4 {
+                    return Ok(Reply::error("ZINCRBY requires a key, an increment and a member"));
+                }
+                
+                let Some(increment) = zset::parse_score(parts[2]) else {
+                    return Ok(Reply::error("value is not a valid float"));
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                if get_live(data, parts[1]).is_none() {
+                    data.insert(parts[1].to_string(), RedisValue::new(SortedSet::default(), None));
+                }
+                let mut value = data.get_mut(parts[1]).unwrap();
+                let zset = value.value.as_zset_mut()?;
+                
+                // Only an existing infinite score can give NaN, so this never
+                // leaves behind the empty set created above
+                let score = zset.score(parts[3]).unwrap_or(0.0) + increment;
+                if score.is_nan() {
+                    return Ok(Reply::error("resulting score is not a number (NaN)"));
+                }
+                zset.insert(parts[3].to_string(), score);
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('z', "zincr", parts[1]);
+                Ok(Reply::Double(score))
+            }
+            "ZCARD" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("ZCARD requires exactly one argument"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_zset()).transpose()? {
+                    Some(zset) => Ok(Reply::Integer(zset.len() as i64)),
+                    None => Ok(Reply::Integer(0)),
+                }
+            }
+            "GEOADD" => {
+                // GEOADD key longitude latitude member [longitude latitude
+                // member ...] stores positions as sorted set scores and
+                // returns how many members were added rather than moved
+                if parts.len() < 5 || !(parts.len() - 2).is_multiple_of(3) {
+                    return Ok(Reply::error("GEOADD requires a key followed by longitude, latitude and member triples"));
+                }
+                
+                // Every position is checked before anything is added
+                let mut positions = Vec::new();
+                for triple in parts[2..].chunks(3) {
+                    let (Ok(lon), Ok(lat)) = (triple[0].parse::<f64>(), triple[1].parse::<f64>()) else {
+                        return Ok(Reply::error("value is not a valid float"));
+                    };
+                    if !geo::is_valid(lon, lat) {
+                        return Ok(Reply::error(format!("invalid longitude,latitude pair {},{}", triple[0], triple[1])));
+                    }
+                    positions.push((geo::encode(lon, lat), triple[2]));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                if get_live(data, parts[1]).is_none() {
+                    data.insert(parts[1].to_string(), RedisValue::new(SortedSet::default(), None));
+                }
+                let mut value = data.get_mut(parts[1]).unwrap();
+                let zset = value.value.as_zset_mut()?;
+                
+                let (mut added, mut changed) = (0, false);
+                for (score, member) in positions {
+                    changed |= zset.score(member) != Some(score);
+                    if zset.insert(member.to_string(), score) {
+                        added += 1;
+                    }
+                }
+                if changed {
+                    value.mark_modified();
+                }
+                drop(value);
+                
+                if changed {
+                    data.notify('z', "zadd", parts[1]);
+                }
+                Ok(Reply::Integer(added))
+            }
+            "GEOSEARCH" => {
+                // GEOSEARCH key FROMMEMBER member|FROMLONLAT longitude latitude
+                // BYRADIUS radius M|KM|FT|MI [ASC|DESC], returning the members
+                // within the radius, nearest first with ASC or farthest first
+                // with DESC
+                let usage = "GEOSEARCH requires a key, FROMMEMBER member or FROMLONLAT longitude latitude, BYRADIUS radius unit and optionally ASC or DESC";
+                if parts.len() < 6 {
+                    return Ok(Reply::error(usage));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let zset = value.as_ref().map(|value| value.value.as_zset()).transpose()?;
+                
+                let (centre, rest) = match parts[2].to_uppercase().as_str() {
+                    "FROMMEMBER" => match zset.and_then(|zset| zset.score(parts[3])) {
+                        Some(score) => (geo::decode(score), &parts[4..]),
+                        None => return Ok(Reply::error("could not decode requested zset member")),
+                    },
+                    "FROMLONLAT" if parts.len() >= 7 => {
+                        let (Ok(lon), Ok(lat)) = (parts[3].parse::<f64>(), parts[4].parse::<f64>()) else {
+                            return Ok(Reply::error("value is not a valid float"));
+                        };
+                        if !geo::is_valid(lon, lat) {
+                            return Ok(Reply::error(format!("invalid longitude,latitude pair {},{}", parts[3], parts[4])));
+                        }
+                        ((lon, lat), &parts[5..])
+                    }
+                    _ => return Ok(Reply::error(usage)),
+                };
+                let (radius, order) = match rest {
+                    [by, radius, unit, order @ ..] if by.eq_ignore_ascii_case("BYRADIUS") && order.len() <= 1 => {
+                        let Some(metres) = geo::unit_in_metres(unit) else {
+                            return Ok(Reply::error("unsupported unit provided. please use M, KM, FT, MI"));
+                        };
+                        match radius.parse::<f64>() {
+                            Ok(radius) if radius >= 0.0 => (radius * metres, order.first().map(|order| order.to_uppercase())),
+                            _ => return Ok(Reply::error("radius cannot be negative")),
+                        }
+                    }
+                    _ => return Ok(Reply::error(usage)),
+                };
+                if !matches!(order.as_deref(), None | Some("ASC") | Some("DESC")) {
+                    return Ok(Reply::error(usage));
+                }
+                
+                // Every member is checked, rather than only the geohash cells
+                // around the centre as Redis does
+                let mut found: Vec<(&str, f64)> = zset.into_iter()
+                    .flat_map(|zset| zset.iter())
+                    .map(|(member, score)| {
+                        let (lon, lat) = geo::decode(score);
+                        (member, geo::distance(centre.0, centre.1, lon, lat))
+                    })
+                    .filter(|(_, distance)| *distance <= radius)
+                    .collect();
+                match order.as_deref() {
+                    Some("ASC") => found.sort_by(|a, b| a.1.total_cmp(&b.1)),
+                    Some("DESC") => found.sort_by(|a, b| b.1.total_cmp(&a.1)),
+                    _ => {}
+                }
+                Ok(Reply::Array(found.into_iter().map(|(member, _)| Reply::bulk(member)).collect()))
+            }
+            "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" => {
+                // LPUSH key element [element ...] pushes each element onto the
+                // head in turn, so they end up in reverse order; RPUSH appends
+                // them to the tail. Both return the new length. LPUSHX and
+                // RPUSHX only push onto a list that exists, returning 0 otherwise.
+                if parts.len() < 3 {
+                    return Ok(Reply::error(format!("{} requires a key and at least one element", command)));
+                }
+                let head = command.starts_with('L');
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                if get_live(data, parts[1]).is_none() {
+                    if command.ends_with('X') {
+                        return Ok(Reply::Integer(0));
+                    }
+                    data.insert(parts[1].to_string(), RedisValue::new(VecDeque::new(), None));
+                }
+                let mut value = data.get_mut(parts[1]).unwrap();
+                let list = value.value.as_list_mut()?;
+                
+                for element in &args[2..] {
+                    if head {
+                        list.push_front(element.clone());
+                    } else {
+                        list.push_back(element.clone());
+                    }
+                }
+                let len = list.len();
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('l', if head { "lpush" } else { "rpush" }, parts[1]);
+                Ok(Reply::Integer(len as i64))
+            }
+            "LPOP" | "RPOP" => {
+                // LPOP key [count] from the head, RPOP from the tail. With a
+                // count the elements come back as an array, even just one.
+                if parts.len() > 3 {
+                    return Ok(Reply::error(format!("{} requires a key and an optional count", command)));
+                }
+                let count = match parts.get(2).map(|count| count.parse::<usize>()) {
+                    None => None,
+                    Some(Ok(count)) => Some(count),
+                    Some(Err(_)) => return Ok(Reply::error("value is out of range, must be positive")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(Reply::Nil);
+                };
+                let list = value.value.as_list_mut()?;
+                let mut popped = Vec::new();
+                while popped.len() < count.unwrap_or(1) {
+                    let element = if command == "LPOP" { list.pop_front() } else { list.pop_back() };
+                    match element {
+                        Some(element) => popped.push(Reply::bulk(element)),
+                        None => break,
+                    }
+                }
+                let now_empty = list.is_empty();
+                if !popped.is_empty() {
+                    value.mark_modified();
+                }
+                drop(value);
+                
+                if !popped.is_empty() {
+                    data.notify('l', if command == "LPOP" { "lpop" } else { "rpop" }, parts[1]);
+                }
+                // Like Redis, a list that loses its last element is deleted
+                if now_empty {
+                    data.remove(parts[1]);
+                    data.notify('g', "del", parts[1]);
+                }
+                match count {
+                    Some(_) => Ok(Reply::Array(popped)),
+                    None => Ok(popped.pop().unwrap_or(Reply::Nil)),
+                }
+            }
+            "BLPOP" | "BRPOP" => {
+                // BLPOP key [key ...] timeout pops from the head of the first
+                // key holding a list, BRPOP from its tail, replying with the
+                // key and the element. This is one try; dispatch_command
+                // blocks the connection if there is nothing to pop, except
+                // inside a transaction, where the reply is nil right away.
+                if parts.len() < 3 {
+                    return Ok(Reply::error(format!("{} requires at least one key and a timeout", command)));
+                }
+                if let Err(error) = block_timeout(parts[parts.len() - 1]) {
+                    return Ok(error);
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                for &key in &parts[1..parts.len() - 1] {
+                    let Some(mut value) = get_live(data, key) else {
+                        continue;
+                    };
+                    let list = value.value.as_list_mut()?;
+                    let element = if command == "BLPOP" { list.pop_front() } else { list.pop_back() };
+                    let Some(element) = element else {
+                        continue;
+                    };
+                    let now_empty = list.is_empty();
+                    value.mark_modified();
+                    drop(value);
+                    
+                    data.notify('l', if command == "BLPOP" { "lpop" } else { "rpop" }, key);
+                    if now_empty {
+                        data.remove(key);
+                        data.notify('g', "del", key);
+                    }
+                    return Ok(Reply::Array(vec![Reply::bulk(key), Reply::bulk(element)]));
+                }
+                Ok(Reply::Nil)
+            }
+            "LMOVE" | "RPOPLPUSH" => {
+                // LMOVE source destination LEFT|RIGHT LEFT|RIGHT pops an element
+                // off one end of the source and pushes it onto one end of the
+                // destination, which may be the same list to rotate it.
+                // RPOPLPUSH source destination is LMOVE with RIGHT LEFT.
+                let sides = match command {
+                    "LMOVE" if parts.len() == 5 => (parts[3], parts[4]),
+                    "RPOPLPUSH" if parts.len() == 3 => ("RIGHT", "LEFT"),
+                    "LMOVE" => return Ok(Reply::error("LMOVE requires a source, a destination and two of LEFT or RIGHT")),
+                    _ => return Ok(Reply::error("RPOPLPUSH requires a source and a destination")),
+                };
+                let from_left = |side: &str| match side.to_uppercase().as_str() {
+                    "LEFT" => Some(true),
+                    "RIGHT" => Some(false),
+                    _ => None,
+                };
+                let (Some(pop_left), Some(push_left)) = (from_left(sides.0), from_left(sides.1)) else {
+                    return Ok(Reply::error("syntax error"));
+                };
+                let (source, destination) = (parts[1], parts[2]);
+                
+                // Both lists are under the one lock, so no other client sees
+                // the element in neither or both of them
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let destination_is_list = get_live(data, destination).map(|value| matches!(value.value, Value::List(_)));
+                let Some(mut value) = get_live(data, source) else {
+                    return Ok(Reply::Nil);
+                };
+                let list = value.value.as_list_mut()?;
+                if destination_is_list == Some(false) {
+                    return Err(RedisError::WrongType);
+                }
+                
+                let Some(element) = (if pop_left { list.pop_front() } else { list.pop_back() }) else {
+                    return Ok(Reply::Nil);
+                };
+                if source == destination {
+                    if push_left {
+                        list.push_front(element.clone());
+                    } else {
+                        list.push_back(element.clone());
+                    }
+                }
+                let now_empty = list.is_empty();
+                value.mark_modified();
+                drop(value);
+                data.notify('l', if pop_left { "lpop" } else { "rpop" }, source);
+                
+                if source != destination {
+                    if now_empty {
+                        data.remove(source);
+                        data.notify('g', "del", source);
+                    }
+                    if destination_is_list.is_none() {
+                        data.insert(destination.to_string(), RedisValue::new(VecDeque::new(), None));
+                    }
+                    let mut value = data.get_mut(destination).unwrap();
+                    let Value::List(list) = &mut value.value else {
+                        unreachable!("destination checked to be a list");
+                    };
+                    if push_left {
+                        list.push_front(element.clone());
+                    } else {
+                        list.push_back(element.clone());
+                    }
+                    value.mark_modified();
+                }
+                data.notify('l', if push_left { "lpush" } else { "rpush" }, destination);
+                Ok(Reply::bulk(element))
+            }
+            "LLEN" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("LLEN requires exactly one argument"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_list()).transpose()? {
+                    Some(list) => Ok(Reply::Integer(list.len() as i64)),
+                    None => Ok(Reply::Integer(0)),
+                }
+            }
+            "LRANGE" => {
+                // LRANGE key start stop, with negative indexes counting back
+                // from the tail
+                if parts.len() != 4 {
+                    return Ok(Reply::error("LRANGE requires a key, a start and a stop"));
+                }
+                
+                let (start, stop) = match (parts[2].parse::<i64>(), parts[3].parse::<i64>()) {
+                    (Ok(start), Ok(stop)) => (start, stop),
+                    _ => return Ok(Reply::error("LRANGE start and stop must be integers")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let list = match value.as_ref().map(|value| value.value.as_list()).transpose()? {
+                    Some(list) => list,
+                    None => return Ok(Reply::Array(Vec::new())),
+                };
+                let Some(range) = index_range(list.len(), start, stop) else {
+                    return Ok(Reply::Array(Vec::new()));
+                };
+                Ok(Reply::Array(list.range(range).map(|element| Reply::bulk(element.clone())).collect()))
+            }
+            "LINDEX" => {
+                if parts.len() != 3 {
+                    return Ok(Reply::error("LINDEX requires a key and an index"));
+                }
+                let Ok(index) = parts[2].parse::<i64>() else {
+                    return Ok(Reply::error("LINDEX index must be an integer"));
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let list = match value.as_ref().map(|value| value.value.as_list()).transpose()? {
+                    Some(list) => list,
+                    None => return Ok(Reply::Nil),
+                };
+                match list_position(list.len(), index) {
+                    Some(position) => Ok(Reply::bulk(list[position].clone())),
+                    None => Ok(Reply::Nil),
+                }
+            }
+            "LSET" => {
+                // LSET key index element, replacing an element that must exist
+                if parts.len() != 4 {
+                    return Ok(Reply::error("LSET requires a key, an index and an element"));
+                }
+                let Ok(index) = parts[2].parse::<i64>() else {
+                    return Ok(Reply::error("LSET index must be an integer"));
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(Reply::error("no such key"));
+                };
+                let list = value.value.as_list_mut()?;
+                let Some(position) = list_position(list.len(), index) else {
+                    return Ok(Reply::error("index out of range"));
+                };
+                list[position] = args[3].clone();
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('l', "lset", parts[1]);
+                Ok(Reply::ok())
+            }
+            "LINSERT" => {
+                // LINSERT key BEFORE|AFTER pivot element, next to the first
+                // element equal to the pivot. Returns the new length, -1 if the
+                // pivot isn't there and 0 if the list isn't.
+                if parts.len() != 5 {
+                    return Ok(Reply::error("LINSERT requires a key, BEFORE or AFTER, a pivot and an element"));
+                }
+                let after = match parts[2].to_uppercase().as_str() {
+                    "BEFORE" => false,
+                    "AFTER" => true,
+                    _ => return Ok(Reply::error("syntax error")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(Reply::Integer(0));
+                };
+                let list = value.value.as_list_mut()?;
+                let Some(pivot) = list.iter().position(|element| *element == args[3]) else {
+                    return Ok(Reply::Integer(-1));
+                };
+                list.insert(pivot + after as usize, args[4].clone());
+                let len = list.len();
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('l', "linsert", parts[1]);
+                Ok(Reply::Integer(len as i64))
+            }
+            "LREM" => {
+                // LREM key count element removes up to count elements equal to
+                // the element, from the head for a positive count, from the
+                // tail for a negative one, or all of them for 0
+                if parts.len() != 4 {
+                    return Ok(Reply::error("LREM requires a key, a count and an element"));
+                }
+                let Ok(count) = parts[2].parse::<i64>() else {
+                    return Ok(Reply::error("LREM count must be an integer"));
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(Reply::Integer(0));
+                };
+                let list = value.value.as_list_mut()?;
+                let limit = if count == 0 { usize::MAX } else { usize::try_from(count.unsigned_abs()).unwrap_or(usize::MAX) };
+                let element = args[3].as_slice();
+                let mut removed = 0;
+                if count >= 0 {
+                    list.retain(|candidate| {
+                        let remove = removed < limit && candidate == element;
+                        removed += remove as usize;
+                        !remove
+                    });
+                } else {
+                    // retain goes from the head, so walk back from the tail by hand
+                    let mut position = list.len();
+                    while position > 0 && removed < limit {
+                        position -= 1;
+                        if list[position] == element {
+                            list.remove(position);
+                            removed += 1;
+                        }
+                    }
+                }
+                let now_empty = list.is_empty();
+                if removed > 0 {
+                    value.mark_modified();
+                }
+                drop(value);
+                
+                if removed > 0 {
+                    data.notify('l', "lrem", parts[1]);
+                }
+                if now_empty {
+                    data.remove(parts[1]);
+                    data.notify('g', "del", parts[1]);
+                }
+                Ok(Reply::Integer(removed as i64))
+            }
+            "LPOS" => {
+                // LPOS key element [RANK rank] [COUNT count]: the index of the
+                // rank-th match, counting from the tail for a negative rank,
+                // or with COUNT the indexes of up to count matches from there
+                // on (all of them for 0)
+                if parts.len() < 3 || parts.len().is_multiple_of(2) {
+                    return Ok(Reply::error("LPOS requires a key and an element, with optional RANK and COUNT"));
+                }
+                let (mut rank, mut count) = (1i64, None);
+                for option in parts[3..].chunks(2) {
+                    let Ok(number) = option[1].parse::<i64>() else {
+                        return Ok(Reply::error(format!("LPOS {} must be an integer", option[0].to_uppercase())));
+                    };
+                    match option[0].to_uppercase().as_str() {
+                        // Its absolute value has to fit, to count matches with
+                        "RANK" if number == 0 || number == i64::MIN => {
+                            return Ok(Reply::error("RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list"));
+                        }
+                        "RANK" => rank = number,
+                        "COUNT" if number < 0 => return Ok(Reply::error("COUNT can't be negative")),
+                        "COUNT" => count = Some(number as usize),
+                        _ => return Ok(Reply::error(format!("Unknown LPOS option '{}'", option[0]))),
+                    }
+                }
+                let not_found = if count.is_some() { Reply::Array(Vec::new()) } else { Reply::Nil };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(value) = get_live(data, parts[1]) else {
+                    return Ok(not_found);
+                };
+                let list = value.value.as_list()?;
+                let element = args[2].as_slice();
+                // Matches in search order, skipping those before the rank-th one
+                let position = |n: usize| if rank > 0 { n } else { list.len() - 1 - n };
+                let mut matches = (0..list.len())
+                    .map(position)
+                    .filter(|&index| list[index] == element)
+                    .skip(rank.unsigned_abs() as usize - 1);
+                match count {
+                    None => Ok(matches.next().map_or(Reply::Nil, |index| Reply::Integer(index as i64))),
+                    Some(count) => {
+                        let limit = if count == 0 { usize::MAX } else { count };
+                        Ok(Reply::Array(matches.take(limit).map(|index| Reply::Integer(index as i64)).collect()))
+                    }
+                }
+            }
+            "LTRIM" => {
+                // LTRIM key start stop keeps only the elements from start to
+                // stop inclusive, with negative indexes counting back from the tail
+                if parts.len() != 4 {
+                    return Ok(Reply::error("LTRIM requires a key, a start and a stop"));
+                }
+                let (start, stop) = match (parts[2].parse::<i64>(), parts[3].parse::<i64>()) {
+                    (Ok(start), Ok(stop)) => (start, stop),
+                    _ => return Ok(Reply::error("LTRIM start and stop must be integers")),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(Reply::ok());
+                };
+                let list = value.value.as_list_mut()?;
+                match index_range(list.len(), start, stop) {
+                    Some(range) => {
+                        list.truncate(range.end() + 1);
+                        list.drain(..*range.start());
+                    }
+                    None => list.clear(),
+                }
+                let now_empty = list.is_empty();
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('l', "ltrim", parts[1]);
+                if now_empty {
+                    data.remove(parts[1]);
+                    data.notify('g', "del", parts[1]);
+                }
+                Ok(Reply::ok())
+            }
+            "HSET" => {
+                // HSET key field value [field value ...], returning how many
+                // fields were added rather than updated
+                if parts.len() < 4 || !parts.len().is_multiple_of(2) {
+                    return Ok(Reply::error("HSET requires a key followed by field and value pairs"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                if get_live(data, parts[1]).is_none() {
+                    data.insert(parts[1].to_string(), RedisValue::new(Hash::default(), None));
+                }
+                let mut value = data.get_mut(parts[1]).unwrap();
+                let hash = value.value.as_hash_mut()?;
+                
+                let mut added = 0;
+                for (field, value) in parts[2..].iter().step_by(2).zip(args[3..].iter().step_by(2)) {
+                    if hash.insert(field.to_string(), value.clone()).is_none() {
+                        added += 1;
+                    }
+                }
+                value.mark_modified();
+                drop(value);
+                
+                data.notify('h', "hset", parts[1]);
+                Ok(Reply::Integer(added))
+            }
+            "HGET" => {
+                if parts.len() != 3 {
+                    return Ok(Reply::error("HGET requires a key and a field"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_hash()).transpose()? {
+                    Some(hash) => Ok(hash.get(parts[2]).map_or(Reply::Nil, |value| Reply::bulk(value.clone()))),
+                    None => Ok(Reply::Nil),
+                }
+            }
+            "HMGET" => {
+                // HMGET key field [field ...], with nil for each field that
+                // isn't set
+                if parts.len() < 3 {
+                    return Ok(Reply::error("HMGET requires a key and at least one field"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let hash = value.as_ref().map(|value| value.value.as_hash()).transpose()?;
+                let values = parts[2..].iter()
+                    .map(|field| match hash.and_then(|hash| hash.get(field)) {
+                        Some(value) => Reply::bulk(value.clone()),
+                        None => Reply::Nil,
+                    })
+                    .collect();
+                Ok(Reply::Array(values))
+            }
+            "HSETNX" => {
+                // HSETNX key field value, only setting a field that isn't set yet
+                if parts.len() != 4 {
+                    return Ok(Reply::error("HSETNX requires a key, a field and a value"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let result = update_hash_field(data, parts[1], parts[2], |current| match current {
+                    // Not really an error: the field just stays as it is
+                    Some(_) => Err(Reply::Integer(0)),
+                    None => Ok(args[3].clone()),
+                });
+                match result {
+                    Ok(_) => {
+                        data.notify('h', "hset", parts[1]);
+                        Ok(Reply::Integer(1))
+                    }
+                    Err(reply) => Ok(reply),
+                }
+            }
+            "HDEL" => {
+                // HDEL key field [field ...], returning how many were removed
+                if parts.len() < 3 {
+                    return Ok(Reply::error("HDEL requires a key and at least one field"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(Reply::Integer(0));
+                };
+                let hash = value.value.as_hash_mut()?;
+                let removed = parts[2..].iter().filter(|field| hash.remove(field).is_some()).count();
+                let now_empty = hash.is_empty();
+                if removed > 0 {
+                    value.mark_modified();
+                }
+                drop(value);
+                
+                if removed > 0 {
+                    data.notify('h', "hdel", parts[1]);
+                }
+                // Like Redis, a hash that loses its last field is deleted
+                if now_empty {
+                    data.remove(parts[1]);
+                    data.notify('g', "del", parts[1]);
+                }
+                Ok(Reply::Integer(removed as i64))
+            }
+            "HGETALL" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("HGETALL requires exactly one argument"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let hash = match value.as_ref().map(|value| value.value.as_hash()).transpose()? {
+                    Some(hash) => hash,
+                    None => return Ok(Reply::Map(Vec::new())),
+                };
+                
+                let fields = hash.iter()
+                    .map(|(field, value)| (Reply::bulk(field.clone()), Reply::bulk(value.clone())))
+                    .collect();
+                Ok(Reply::Map(fields))
+            }
+            "HEXISTS" => {
+                if parts.len() != 3 {
+                    return Ok(Reply::error("HEXISTS requires a key and a field"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_hash()).transpose()? {
+                    Some(hash) => Ok(Reply::Integer(hash.contains_key(parts[2]) as i64)),
+                    None => Ok(Reply::Integer(0)),
+                }
+            }
+            "HKEYS" | "HVALS" => {
+                // Every field name, or every value, in no particular order
+                if parts.len() != 2 {
+                    return Ok(Reply::error(format!("{} requires exactly one argument", command)));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let hash = match value.as_ref().map(|value| value.value.as_hash()).transpose()? {
+                    Some(hash) => hash,
+                    None => return Ok(Reply::Array(Vec::new())),
+                };
+                let replies = match command {
+                    "HKEYS" => hash.keys().map(|field| Reply::bulk(field.clone())).collect(),
+                    _ => hash.values().map(|value| Reply::bulk(value.clone())).collect(),
+                };
+                Ok(Reply::Array(replies))
+            }
+            "HLEN" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("HLEN requires exactly one argument"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                match value.as_ref().map(|value| value.value.as_hash()).transpose()? {
+                    Some(hash) => Ok(Reply::Integer(hash.len() as i64)),
+                    None => Ok(Reply::Integer(0)),
+                }
+            }
+            "HINCRBY" => {
+                // HINCRBY key field increment, counting a missing field as 0
+                if parts.len() != 4 {
+                    return Ok(Reply::error("HINCRBY requires a key, a field and an increment"));
+                }
+                let Ok(increment) = parts[3].parse::<i64>() else {
+                    return Ok(Reply::error("value is not an integer or out of range"));
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let result = update_hash_field(data, parts[1], parts[2], |current| {
+                    let current = match current {
+                        Some(bytes) => std::str::from_utf8(bytes).ok()
+                            .and_then(|text| text.parse::<i64>().ok())
+                            .ok_or_else(|| Reply::error("hash value is not an integer"))?,
+                        None => 0,
+                    };
+                    let sum = current.checked_add(increment)
+                        .ok_or_else(|| Reply::error("increment or decrement would overflow"))?;
+                    Ok(sum.to_string().into_bytes())
+                });
+                match result {
+                    Ok(sum) => {
+                        data.notify('h', "hincrby", parts[1]);
+                        Ok(Reply::Integer(String::from_utf8_lossy(&sum).parse().unwrap()))
+                    }
+                    Err(reply) => Ok(reply),
+                }
+            }
+            "HINCRBYFLOAT" => {
+                // HINCRBYFLOAT key field increment, counting a missing field
+                // as 0. The sum is stored and returned as a string.
+                if parts.len() != 4 {
+                    return Ok(Reply::error("HINCRBYFLOAT requires a key, a field and an increment"));
+                }
+                let Some(increment) = zset::parse_score(parts[3]) else {
+                    return Ok(Reply::error("value is not a valid float"));
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let result = update_hash_field(data, parts[1], parts[2], |current| {
+                    let current = match current {
+                        Some(bytes) => std::str::from_utf8(bytes).ok()
+                            .and_then(|text| text.parse::<f64>().ok())
+                            .filter(|current| current.is_finite())
+                            .ok_or_else(|| Reply::error("hash value is not a float"))?,
+                        None => 0.0,
+                    };
+                    let sum = current + increment;
+                    if !sum.is_finite() {
+                        return Err(Reply::error("increment would produce NaN or Infinity"));
+                    }
+                    Ok(sum.to_string().into_bytes())
+                });
+                match result {
+                    Ok(sum) => {
+                        data.notify('h', "hincrbyfloat", parts[1]);
+                        Ok(Reply::bulk(sum))
+                    }
+                    Err(reply) => Ok(reply),
+                }
+            }
+            "EXPIRE" => {
+                // EXPIRE key seconds [NX | XX | GT | LT]
+                if parts.len() < 3 {
+                    return Ok(Reply::error("EXPIRE requires a key and a number of seconds"));
+                }
+                
+                let key = parts[1];
+                let condition = match ExpireCondition::parse(&parts[3..]) {
+                    Ok(condition) => condition,
+                    Err(reply) => return Ok(reply),
+                };
+                
+                if let Ok(seconds) = parts[2].parse::<u64>() {
+                    let expires_at = match expires_after(command, Duration::from_secs(seconds)) {
+                        Ok(expires_at) => expires_at,
+                        Err(reply) => return Ok(reply),
+                    };
+                    let mut databases = self.data.lock().unwrap();
+                    let data = &mut databases[session.db];
+                    
+                    let Some(mut value) = get_live(data, key) else {
+                        return Ok(Reply::Integer(0));  // Key doesn't exist
+                    };
+                    if !condition.allows(value.expires_at, expires_at) {
+                        return Ok(Reply::Integer(0));
+                    }
+                    // Update the expiration time
+                    value.expires_at = Some(expires_at);
+                    value.mark_modified();
+                    drop(value);
+                    
+                    data.notify('g', "expire", key);
+                    Ok(Reply::Integer(1))
+                } else {
+                    Ok(Reply::error("EXPIRE seconds must be a positive integer"))
+                }
+            }
+            "EXPIREAT" | "PEXPIREAT" => {
+                // EXPIREAT key unix-seconds, PEXPIREAT key unix-milliseconds
+                if parts.len() != 3 {
+                    return Ok(Reply::error(format!("{} requires exactly two arguments", command)));
+                }
+                
+                let unix_ms = match parts[2].parse::<i64>() {
+                    Ok(seconds) if command == "EXPIREAT" => seconds.checked_mul(1000),
+                    Ok(ms) => Some(ms),
+                    Err(_) => None,
+                };
+                // A time before the epoch is just as much in the past as the epoch
+                let expires_at = unix_ms.and_then(|ms| persistence::unix_ms_to_instant(ms.max(0) as u64));
+                let Some(expires_at) = expires_at else {
+                    return Ok(invalid_expire_time(command));
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let Some(mut value) = get_live(data, parts[1]) else {
+                    return Ok(Reply::Integer(0));
+                };
+                value.expires_at = Some(expires_at);
+                value.mark_modified();
+                let expired = value.is_expired();
+                drop(value);
+                
+                // A time already passed deletes the key right away
+                if expired {
+                    data.remove(parts[1]);
+                    data.notify('g', "del", parts[1]);
+                } else {
+                    data.notify('g', "expire", parts[1]);
+                }
+                Ok(Reply::Integer(1))
+            }
+            "TTL" | "PTTL" => {
+                // TTL reports seconds, PTTL milliseconds
+                if parts.len() != 2 {
+                    return Ok(Reply::error(format!("{} requires exactly one argument", command)));
+                }
+                
+                let key = parts[1];
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                // A key get_live finds is live, even one at the very instant it
+                // expires, which reports no time left rather than -2
+                let value = get_live(data, key);
+                match value {
+                    Some(value) => match value.expires_at {
+                        Some(expires_at) => {
+                            // Seconds are rounded up, so a key with 500ms left
+                            // reports 1 rather than 0
+                            let remaining = expires_at.saturating_duration_since(Instant::now()).as_millis();
+                            if command == "PTTL" {
+                                Ok(Reply::Integer(remaining as i64))
+                            } else {
+                                Ok(Reply::Integer(remaining.div_ceil(1000) as i64))
+                            }
+                        }
+                        None => Ok(Reply::Integer(-1)),  // Key exists but has no expiry
+                    },
+                    None => Ok(Reply::Integer(-2)),  // Key doesn't exist
+                }
+            }
+            "DEL" | "UNLINK" => {
+                // DEL key [key ...] and UNLINK key [key ...], returning how many
+                // of the keys existed. UNLINK frees large values in the
+                // background instead of while the keyspace is locked.
+                if parts.len() < 2 {
+                    return Ok(Reply::error(format!("{} requires at least one key", command)));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let mut deleted = 0;
+                let mut large = Vec::new();
+                for key in &parts[1..] {
+                    // An expired key is removed as well, but doesn't count as deleted
+                    if data.remove_if_expired(key) {
+                        continue;
+                    }
+                    let Some(value) = data.remove(key) else {
+                        continue;
+                    };
+                    data.notify('g', "del", key);
+                    deleted += 1;
+                    if command == "UNLINK" && value.value.free_effort() > LAZYFREE_THRESHOLD {
+                        large.push(value);
+                    }
+                }
+                drop(databases);
+                
+                if !large.is_empty() {
+                    tokio::task::spawn_blocking(move || drop(large));
+                }
+                Ok(Reply::Integer(deleted))
+            }
+            "EXISTS" => {
+                // EXISTS key [key ...], counting a key given twice twice
+                if parts.len() < 2 {
+                    return Ok(Reply::error("EXISTS requires at least one key"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let existing = parts[1..].iter().filter(|key| get_live(data, key).is_some()).count();
+                Ok(Reply::Integer(existing as i64))
+            }
+            "TOUCH" => {
+                // TOUCH key [key ...]: get_live bumps each key's last access,
+                // which is all there is to it
+                if parts.len() < 2 {
+                    return Ok(Reply::error("TOUCH requires at least one key"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let touched = parts[1..].iter().filter(|key| get_live(data, key).is_some()).count();
+                Ok(Reply::Integer(touched as i64))
+            }
+            "TYPE" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("TYPE requires exactly one argument"));
+                }
+                
+                let key = parts[1];
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                // Missing and expired keys both report "none"
+                let value = get_live(data, key);
+                match value {
+                    Some(value) => Ok(Reply::Simple(value.value.type_name().to_string())),
+                    None => Ok(Reply::Simple("none".to_string())),
+                }
+            }
+            "OBJECT" => {
+                if parts.len() != 3 {
+                    return Ok(Reply::error("OBJECT requires a subcommand (ENCODING, REFCOUNT, IDLETIME or FREQ) and a key"));
+                }
+                
+                let subcommand = parts[1].to_uppercase();
+                if !matches!(subcommand.as_str(), "ENCODING" | "REFCOUNT" | "IDLETIME" | "FREQ") {
+                    return Ok(Reply::error(format!("Unknown OBJECT subcommand '{}'", parts[1])));
+                }
+                if subcommand == "FREQ" && self.config.lock().unwrap().maxmemory_policy != MaxmemoryPolicy::AllKeysLfu {
+                    return Ok(Reply::error("An LFU maxmemory policy is not selected, access frequency not tracked"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                // Looking a key over isn't using it, so unlike get_live this
+                // leaves its last access alone
+                if data.remove_if_expired(parts[2]) {
+                    return Ok(Reply::Nil);
+                }
+                let Some(value) = data.get(parts[2]) else {
+                    return Ok(Reply::Nil);
+                };
+                match subcommand.as_str() {
+                    "ENCODING" => Ok(Reply::bulk(value.encoding(data.limits()))),
+                    "IDLETIME" => Ok(Reply::Integer(value.last_access.elapsed().as_secs() as i64)),
+                    "FREQ" => Ok(Reply::Integer(value.frequency() as i64)),
+                    // Values are never shared between keys
+                    _ => Ok(Reply::Integer(1)),
+                }
+            }
+            "COPY" => {
+                // COPY source destination [DB destination-db] [REPLACE]
+                if parts.len() < 3 {
+                    return Ok(Reply::error("COPY requires a source and a destination key"));
+                }
+                
+                let (source, destination) = (parts[1], parts[2]);
+                let mut destination_db = session.db;
+                let mut replace = false;
+                let mut options = parts[3..].iter();
+                while let Some(option) = options.next() {
+                    match option.to_uppercase().as_str() {
+                        "REPLACE" => replace = true,
+                        "DB" => match options.next().map(|db| db.parse::<usize>()) {
+                            Some(Ok(db)) => destination_db = db,
+                            _ => return Ok(Reply::error("COPY DB requires a database index")),
+                        },
+                        _ => return Ok(Reply::error(format!("Unknown COPY option '{}'", option))),
+                    }
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                if destination_db >= databases.len() {
+                    return Ok(Reply::error("DB index is out of range"));
+                }
+                if destination_db == session.db && source == destination {
+                    return Ok(Reply::error("source and destination objects are the same"));
+                }
+                
+                // The copy keeps the TTL but is a separate value from then on
+                let copy = match get_live(&mut databases[session.db], source) {
+                    Some(value) => value.clone(),
+                    None => return Ok(Reply::Integer(0)),
+                };
+                
+                let target = &mut databases[destination_db];
+                if !replace && get_live(target, destination).is_some() {
+                    return Ok(Reply::Integer(0));
+                }
+                target.insert(destination.to_string(), copy);
+                target.notify('g', "copy_to", destination);
+                Ok(Reply::Integer(1))
+            }
+            "DUMP" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("DUMP requires exactly one key"));
+                }
+                
+                let mut databases = self.data.lock().unwrap();
+                let Some(value) = get_live(&mut databases[session.db], parts[1]) else {
+                    return Ok(Reply::Nil);
+                };
+                Ok(Reply::bulk(dump::dump(&value.value)))
+            }
+            "RESTORE" => {
+                // RESTORE key ttl payload [REPLACE]
+                if parts.len() < 4 {
+                    return Ok(Reply::error("RESTORE requires a key, a TTL and a payload"));
+                }
+                
+                let mut replace = false;
+                for option in &parts[4..] {
+                    match option.to_uppercase().as_str() {
+                        "REPLACE" => replace = true,
+                        _ => return Ok(Reply::error(format!("Unknown RESTORE option '{}'", option))),
+                    }
+                }
+                let Ok(ttl_ms) = parts[2].parse::<u64>() else {
+                    return Ok(Reply::error("Invalid TTL value, must be >= 0"));
+                };
+                let value = match dump::restore(parts[3]) {
+                    Ok(value) => value,
+                    Err(e) => return Ok(Reply::error(e)),
+                };
+                
+                // A TTL of 0 restores the key without one
+                let ttl = Some(Duration::from_millis(ttl_ms)).filter(|ttl| !ttl.is_zero());
+                let expires_at = match ttl.map(|ttl| expires_after(command, ttl)).transpose() {
+                    Ok(expires_at) => expires_at,
+                    Err(reply) => return Ok(reply),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                if !replace && get_live(data, parts[1]).is_some() {
+                    return Ok(Reply::error_code("BUSYKEY", "Target key name already exists."));
+                }
+                data.insert(parts[1].to_string(), RedisValue::new(value, expires_at));
+                data.notify('g', "restore", parts[1]);
+                Ok(Reply::ok())
+            }
+            "KEYS" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("KEYS requires exactly one argument"));
+                }
+                
+                let pattern = parts[1];
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                // KEYS touches every key, so drop the expired ones while we're here
+                data.remove_expired();
+                
+                // Glob-style pattern matching (*, ?, [...] and \ escapes)
+                let keys = data.keys()
+                    .filter(|k| glob_match(pattern, k))
+                    .map(|k| Reply::bulk(k.clone()))
+                    .collect();
+                
+                Ok(Reply::Array(keys))
+            }
+            "RANDOMKEY" => {
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                let mut rng = rand::rng();
+                
+                // Keep drawing until a live key comes up, reclaiming the
+                // expired ones drawn along the way
+                loop {
+                    let key = match data.keys().choose(&mut rng) {
+                        Some(key) => key.clone(),
+                        None => return Ok(Reply::Nil),
+                    };
+                    if !data.remove_if_expired(&key) {
+                        return Ok(Reply::bulk(key));
+                    }
+                }
+            }
+            "SCAN" => {
+                // SCAN cursor [MATCH pattern] [COUNT count]
+                if parts.len() < 2 || !parts.len().is_multiple_of(2) {
+                    return Ok(Reply::error("SCAN requires a cursor with optional MATCH and COUNT"));
+                }
+                
+                let cursor = match parts[1].parse::<u64>() {
+                    Ok(cursor) => cursor,
+                    Err(_) => return Ok(Reply::error("SCAN cursor must be a non-negative integer")),
+                };
+                
+                let (pattern, count) = match scan_options(command, &parts[2..]) {
+                    Ok(options) => options,
+                    Err(reply) => return Ok(reply),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                let (next_cursor, keys) = scan_keys(data, cursor, count, pattern);
+                
+                // The next cursor comes first, followed by the keys
+                Ok(Reply::Array(vec![
+                    Reply::bulk(next_cursor.to_string()),
+                    Reply::Array(keys.into_iter().map(Reply::bulk).collect()),
+                ]))
+            }
+            "HSCAN" | "SSCAN" | "ZSCAN" => {
+                // HSCAN key cursor [MATCH pattern] [COUNT count], and SSCAN and
+                // ZSCAN likewise, iterate over the fields of a hash, the
+                // members of a set or the members of a sorted set as SCAN
+                // does over keys. HSCAN follows each field with its value and
+                // ZSCAN each member with its score.
+                if parts.len() < 3 || parts.len().is_multiple_of(2) {
+                    return Ok(Reply::error(format!("{} requires a key and a cursor with optional MATCH and COUNT", command)));
+                }
+                
+                let cursor = match parts[2].parse::<u64>() {
+                    Ok(cursor) => cursor,
+                    Err(_) => return Ok(Reply::error(format!("{} cursor must be a non-negative integer", command))),
+                };
+                let (pattern, count) = match scan_options(command, &parts[3..]) {
+                    Ok(options) => options,
+                    Err(reply) => return Ok(reply),
+                };
+                
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                
+                let value = get_live(data, parts[1]);
+                let (next_cursor, elements): (u64, Vec<Reply>) = match (command, value.as_ref().map(|value| &value.value)) {
+                    (_, None) => (0, Vec::new()),
+                    ("HSCAN", Some(Value::Hash(hash))) => {
+                        let fields = hash.iter().map(|(field, value)| (field.as_str(), value));
+                        let (next_cursor, fields) = scan_elements(fields, cursor, count, pattern);
+                        let fields = fields.into_iter()
+                            .flat_map(|(field, value)| [Reply::bulk(field), Reply::bulk(value.clone())])
+                            .collect();
+                        (next_cursor, fields)
+                    }
+                    ("SSCAN", Some(Value::Set(set))) => {
+                        let members = set.iter().map(|member| (member.as_str(), ()));
+                        let (next_cursor, members) = scan_elements(members, cursor, count, pattern);
+                        (next_cursor, members.into_iter().map(|(member, _)| Reply::bulk(member)).collect())
+                    }
+                    ("ZSCAN", Some(Value::SortedSet(zset))) => {
+                        let (next_cursor, members) = scan_elements(zset.iter(), cursor, count, pattern);
+                        let members = members.into_iter()
+                            .flat_map(|(member, score)| [Reply::bulk(member), Reply::Double(score)])
+                            .collect();
+                        (next_cursor, members)
+                    }
+                    _ => return Err(RedisError::WrongType),
+                };
+                
+                Ok(Reply::Array(vec![Reply::bulk(next_cursor.to_string()), Reply::Array(elements)]))
+            }
+            "FLUSHALL" => {
+                let mut databases = self.data.lock().unwrap();
+                for data in databases.iter_mut() {
+                    data.clear();
+                }
+                Ok(Reply::ok())
+            }
+            "FLUSHDB" => {
+                // Only the selected database is cleared
+                let mut databases = self.data.lock().unwrap();
+                databases[session.db].clear();
+                Ok(Reply::ok())
+            }
+            "SELECT" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("SELECT requires exactly one argument"));
+                }
+                
+                let databases = self.data.lock().unwrap().len();
+                match parts[1].parse::<usize>() {
+                    Ok(db) if db < databases => {
+                        session.db = db;
+                        self.update_client(session);
+                        Ok(Reply::ok())
+                    }
+                    _ => Ok(Reply::error("DB index is out of range")),
+                }
+            }
+            "INFO" => {
+                if parts.len() > 2 {
+                    return Ok(Reply::error("INFO accepts at most one section name"));
+                }
+                
+                Ok(Reply::bulk(self.info(parts.get(1).copied())))
+            }
+            "DBSIZE" => {
+                // Like Redis, this counts keys that expired but haven't been reclaimed yet
+                let databases = self.data.lock().unwrap();
+                Ok(Reply::Integer(databases[session.db].len() as i64))
+            }
+            "CLIENT" => {
+                // CLIENT ID, SETNAME name, GETNAME, TRACKING, LIST, KILL filter value ...
+                // and UNBLOCK id
+                match (parts.get(1).map(|sub| sub.to_uppercase()).as_deref(), parts.len()) {
+                    (Some("ID"), 2) => Ok(Reply::Integer(session.id as i64)),
+                    (Some("SETNAME"), 3) => {
+                        // Names show up in space-separated lists, so like Redis
+                        // only printable characters other than space are allowed
+                        let name = parts[2];
+                        if !name.bytes().all(|byte| byte.is_ascii_graphic()) {
+                            return Ok(Reply::error("Client names cannot contain spaces, newlines or special characters."));
+                        }
+                        // An empty name removes it
+                        session.name = Some(name.to_string()).filter(|name| !name.is_empty());
+                        self.update_client(session);
+                        Ok(Reply::ok())
+                    }
+                    (Some("GETNAME"), 2) => Ok(session.name.clone().map_or(Reply::Nil, Reply::bulk)),
+                    (Some("TRACKING"), 3 | 5) => {
+                        // CLIENT TRACKING ON [REDIRECT id] | OFF
+                        match (parts[2].to_uppercase().as_str(), parts.get(3).map(|option| option.to_uppercase())) {
+                            ("OFF", None) => {
+                                self.tracking.lock().unwrap().disable(session.id);
+                                Ok(Reply::ok())
+                            }
+                            ("ON", None) => {
+                                // The pushes would garble a RESP2 reply stream
+                                if !session.resp3 {
+                                    return Ok(Reply::error("CLIENT TRACKING without REDIRECT requires RESP3, switch with HELLO 3"));
+                                }
+                                if let Some(queue) = self.pubsub.lock().unwrap().queue(session.id) {
+                                    session.messages = Some(queue);
+                                }
+                                self.tracking.lock().unwrap().enable(session.id, Target::Push(session.id));
+                                Ok(Reply::ok())
+                            }
+                            ("ON", Some(option)) if option == "REDIRECT" => {
+                                let Ok(redirect) = parts[4].parse::<u64>() else {
+                                    return Ok(Reply::error("value is not an integer or out of range"));
+                                };
+                                if self.clients.lock().unwrap().get_mut(redirect).is_none() {
+                                    return Ok(Reply::error("The client ID you want redirect to does not exist"));
+                                }
+                                self.tracking.lock().unwrap().enable(session.id, Target::Redirect(redirect));
+                                Ok(Reply::ok())
+                            }
+                            _ => Ok(Reply::error("CLIENT TRACKING requires ON [REDIRECT id] or OFF")),
+                        }
+                    }
+                    (Some("LIST"), 2) => Ok(Reply::bulk(self.clients.lock().unwrap().list().join("\n"))),
+                    (Some("KILL"), len) if len >= 4 && len.is_multiple_of(2) => {
+                        // CLIENT KILL [ID id] [ADDR ip:port], killing the
+                        // clients matching every filter given
+                        let (mut id, mut addr) = (None, None);
+                        for filter in parts[2..].chunks(2) {
+                            match filter[0].to_uppercase().as_str() {
+                                "ID" => match filter[1].parse::<u64>() {
+                                    Ok(value) => id = Some(value),
+                                    Err(_) => return Ok(Reply::error("client-id should be greater than 0")),
+                                },
+                                "ADDR" => addr = Some(filter[1]),
+                                _ => return Ok(Reply::error(format!("Unknown CLIENT KILL filter '{}'", filter[0]))),
+                            }
+                        }
+                        Ok(Reply::Integer(self.clients.lock().unwrap().kill(id, addr) as i64))
+                    }
+                    (Some("UNBLOCK"), 3 | 4) => {
+                        // CLIENT UNBLOCK id [TIMEOUT|ERROR] ends a client's
+                        // BLPOP or BRPOP, returning 1 if it was blocked
+                        let Ok(id) = parts[2].parse::<u64>() else {
+                            return Ok(Reply::error("value is not an integer or out of range"));
+                        };
+                        let how = match parts.get(3).map(|how| how.to_uppercase()).as_deref() {
+                            None | Some("TIMEOUT") => Unblock::Timeout,
+                            Some("ERROR") => Unblock::Error,
+                            Some(_) => return Ok(Reply::error("CLIENT UNBLOCK reason should be TIMEOUT or ERROR")),
+                        };
+                        Ok(Reply::Integer(self.blocking.lock().unwrap().force_unblock(id, how) as i64))
+                    }
+                    _ => Ok(Reply::error("CLIENT requires ID, SETNAME name, GETNAME, TRACKING ON|OFF, LIST, KILL ID id | ADDR ip:port or UNBLOCK id")),
+                }
+            }
+            "CONFIG" => {
+                if parts.len() < 2 {
+                    return Ok(Reply::error("CONFIG requires a subcommand (GET or SET)"));
                 }
                 
-                let key = parts[1];
-                let data = self.data.lock().unwrap();
-                
-                match data.get(key) {
-                    Some(value) if !value.is_expired() => {
-                        Ok(format!("{}\n", value.value))
-                    },
-                    _ => Ok("(nil)\n".to_string()),
+                match parts[1].to_uppercase().as_str() {
+                    "GET" => {
+                        if parts.len() != 3 {
+                            return Ok(Reply::error("CONFIG GET requires exactly one parameter"));
+                        }
+                        
+                        let config = self.config.lock().unwrap();
+                        let params = config.get(parts[2]);
+                        
+                        let result = params.into_iter()
+                            .map(|(name, value)| (Reply::bulk(name), Reply::bulk(value)))
+                            .collect();
+                        Ok(Reply::Map(result))
+                    }
+                    "SET" => {
+                        if parts.len() != 4 {
+                            return Ok(Reply::error("CONFIG SET requires a parameter and a value"));
+                        }
+                        
+                        if Config::is_startup_only(parts[2]) {
+                            return Ok(Reply::error(format!("{} can only be set at startup", parts[2])));
+                        }
+                        
+                        let mut config = self.config.lock().unwrap();
+                        match config.set(parts[2], parts[3]) {
+                            Ok(()) => {
+                                let limits = config.encoding_limits;
+                                drop(config);
+                                self.set_encoding_limits(limits);
+                                Ok(Reply::ok())
+                            }
+                            Err(e) => Ok(Reply::error(e)),
+                        }
+                    }
+                    _ => Ok(Reply::error(format!("Unknown CONFIG subcommand '{}'", parts[1]))),
                 }
             }
-            "SET" => {
-                // Basic SET command with optional expiration
-                if parts.len() < 3 || parts.len() > 5 {
-                    return Ok("ERROR: SET requires two arguments (key value) with optional EX/TTL\n".to_string());
+            "SLOWLOG" => {
+                // SLOWLOG GET [count], SLOWLOG LEN and SLOWLOG RESET
+                let mut slowlog = self.slowlog.lock().unwrap();
+                match (parts.get(1).map(|sub| sub.to_uppercase()).as_deref(), parts.len()) {
+                    (Some("GET"), 2 | 3) => {
+                        // The 10 newest entries unless asked for more, or all of
+                        // them with a count of -1
+                        let count = match parts.get(2).map(|count| count.parse::<i64>()) {
+                            None => 10,
+                            Some(Ok(-1)) => usize::MAX,
+                            Some(Ok(count)) if count >= 0 => count as usize,
+                            Some(_) => return Ok(Reply::error("count should be greater than or equal to -1")),
+                        };
+                        Ok(Reply::Array(slowlog.newest(count).map(|entry| entry.reply()).collect()))
+                    }
+                    (Some("LEN"), 2) => Ok(Reply::Integer(slowlog.len() as i64)),
+                    (Some("RESET"), 2) => {
+                        slowlog.reset();
+                        Ok(Reply::ok())
+                    }
+                    _ => Ok(Reply::error("SLOWLOG requires GET [count], LEN or RESET")),
+                }
+            }
+            "LATENCY" => {
+                if parts.len() < 2 {
+                    return Ok(Reply::error("LATENCY requires a subcommand (LATEST, HISTORY, RESET or HISTOGRAM)"));
                 }
                 
-                let key = parts[1].to_string();
-                let value = parts[2].to_string();
+                let mut latency = self.latency.lock().unwrap();
                 
-                // Check for TTL option (SET key value EX seconds)
-                let mut ttl = None;
-                if parts.len() >= 4 && parts[3].to_uppercase() == "EX" && parts.len() == 5 {
-                    if let Ok(seconds) = parts[4].parse::<u64>() {
-                        ttl = Some(seconds);
+                match parts[1].to_uppercase().as_str() {
+                    "LATEST" => {
+                        // One line per event: name, time of the latest sample, its latency and the max
+                        let lines = latency.latest().into_iter()
+                            .map(|(event, sample, max_ms)| {
+                                Reply::bulk(format!("{} {} {} {}", event, sample.time, sample.latency_ms, max_ms))
+                            })
+                            .collect();
+                        
+                        Ok(Reply::Array(lines))
+                    }
+                    "HISTORY" => {
+                        if parts.len() != 3 {
+                            return Ok(Reply::error("LATENCY HISTORY requires exactly one event name"));
+                        }
+                        
+                        // One line per sample: time and latency in milliseconds
+                        let lines = latency.history(parts[2]).into_iter()
+                            .map(|sample| Reply::bulk(format!("{} {}", sample.time, sample.latency_ms)))
+                            .collect();
+                        
+                        Ok(Reply::Array(lines))
+                    }
+                    "RESET" => {
+                        // LATENCY RESET [event ...], returning how many events were reset
+                        Ok(Reply::Integer(latency.reset(&parts[2..]) as i64))
                     }
+                    "HISTOGRAM" => {
+                        // LATENCY HISTOGRAM [command ...], one line per command:
+                        // its name, how many times it ran, then for each bucket
+                        // "<microseconds>:<calls taking at most that long>"
+                        let lines = latency.histograms(&parts[2..]).into_iter()
+                            .map(|(command, histogram)| {
+                                let mut line = format!("{} {}", command, histogram.calls());
+                                for (micros, calls) in histogram.cumulative() {
+                                    line.push_str(&format!(" {}:{}", micros, calls));
+                                }
+                                Reply::bulk(line)
+                            })
+                            .collect();
+                        
+                        Ok(Reply::Array(lines))
+                    }
+                    _ => Ok(Reply::error(format!("Unknown LATENCY subcommand '{}'", parts[1]))),
                 }
+            }
+            // Only reached from a transaction, when no script can be running
+            "SCRIPT" => match parts[1].to_uppercase().as_str() {
+                "KILL" => Ok(self.kill_script(false)),
+                _ => Ok(Reply::error(format!("Unknown SCRIPT subcommand '{}'", parts[1]))),
+            },
+            "FUNCTION" => {
+                let mut functions = self.functions.lock().unwrap();
                 
-                let mut data = self.data.lock().unwrap();
-                let redis_value = RedisValue::new(value, ttl);
-                data.insert(key, redis_value);
+                match parts[1].to_uppercase().as_str() {
+                    // FUNCTION LOAD [REPLACE] code
+                    "LOAD" => {
+                        let (replace, code) = match parts[2..] {
+                            [code] => (false, code),
+                            [replace, code] if replace.eq_ignore_ascii_case("REPLACE") => (true, code),
+                            _ => return Ok(Reply::error("FUNCTION LOAD requires [REPLACE] and the library code")),
+                        };
+                        match functions.load(code, replace) {
+                            Ok(name) => Ok(Reply::bulk(name)),
+                            Err(e) => Ok(Reply::error(e)),
+                        }
+                    }
+                    "DELETE" => {
+                        if parts.len() != 3 {
+                            return Ok(Reply::error("FUNCTION DELETE requires exactly one library name"));
+                        }
+                        if functions.delete(parts[2]) {
+                            Ok(Reply::ok())
+                        } else {
+                            Ok(Reply::error("Library not found"))
+                        }
+                    }
+                    // FUNCTION LIST [LIBRARYNAME pattern] [WITHCODE]
+                    "LIST" => {
+                        let (mut pattern, mut with_code) = (None, false);
+                        let mut options = parts[2..].iter();
+                        while let Some(option) = options.next() {
+                            match option.to_uppercase().as_str() {
+                                "WITHCODE" => with_code = true,
+                                "LIBRARYNAME" => match options.next() {
+                                    Some(name) => pattern = Some(*name),
+                                    None => return Ok(Reply::error("syntax error")),
+                                },
+                                _ => return Ok(Reply::error("syntax error")),
+                            }
+                        }
+                        
+                        let libraries = functions.libraries()
+                            .filter(|library| pattern.is_none_or(|pattern| glob_match(pattern, &library.name)))
+                            .map(|library| {
+                                let listed = library.functions.iter()
+                                    .map(|function| Reply::Map(vec![
+                                        (Reply::bulk("name"), Reply::bulk(function.name.as_str())),
+                                        (Reply::bulk("description"), function.description.as_deref().map_or(Reply::Nil, Reply::bulk)),
+                                    ]))
+                                    .collect();
+                                let mut fields = vec![
+                                    (Reply::bulk("library_name"), Reply::bulk(library.name.as_str())),
+                                    (Reply::bulk("engine"), Reply::bulk("LUA")),
+                                    (Reply::bulk("functions"), Reply::Array(listed)),
+                                ];
+                                if with_code {
+                                    fields.push((Reply::bulk("library_code"), Reply::bulk(library.code.as_str())));
+                                }
+                                Reply::Map(fields)
+                            })
+                            .collect();
+                        Ok(Reply::Array(libraries))
+                    }
+                    // Like SCRIPT KILL, only reached from a transaction
+                    "KILL" => Ok(self.kill_script(true)),
+                    _ => Ok(Reply::error(format!("Unknown FUNCTION subcommand '{}'", parts[1]))),
+                }
+            }
+            "DEBUG" => {
+                if parts.len() < 2 {
+                    return Ok(Reply::error("DEBUG requires a subcommand"));
+                }
                 
-                Ok("OK\n".to_string())
+                match parts[1].to_uppercase().as_str() {
+                    "LATENCY-SPIKE" => {
+                        // Inject a synthetic sample so latency reporting can be tested
+                        // without a genuinely slow operation
+                        if parts.len() != 4 {
+                            return Ok(Reply::error("DEBUG LATENCY-SPIKE requires an event name and milliseconds"));
+                        }
+                        
+                        match parts[3].parse::<u64>() {
+                            Ok(ms) => {
+                                self.latency.lock().unwrap().add_sample(parts[2], ms);
+                                Ok(Reply::ok())
+                            }
+                            Err(_) => Ok(Reply::error("DEBUG LATENCY-SPIKE milliseconds must be a positive integer")),
+                        }
+                    }
+                    "SET-ACTIVE-EXPIRE" => {
+                        let enabled = match parts.get(2..) {
+                            Some(["0"]) => false,
+                            Some(["1"]) => true,
+                            _ => return Ok(Reply::error("DEBUG SET-ACTIVE-EXPIRE requires 0 or 1")),
+                        };
+                        
+                        // Let the sweep plan again, now that it may have keys to wait for
+                        self.active_expire.store(enabled, Ordering::Relaxed);
+                        self.sooner_expiry.notify_one();
+                        Ok(Reply::ok())
+                    }
+                    "QUICKLIST-PACKED-THRESHOLD" => {
+                        let Some(threshold) = parts.get(2..).and_then(|args| match args {
+                            [threshold] => config::parse_memory(threshold).and_then(|bytes| usize::try_from(bytes).ok()),
+                            _ => None,
+                        }) else {
+                            return Ok(Reply::error("DEBUG QUICKLIST-PACKED-THRESHOLD requires a size in bytes"));
+                        };
+                        
+                        let limits = {
+                            let mut config = self.config.lock().unwrap();
+                            config.encoding_limits.list_packed_threshold = threshold;
+                            config.encoding_limits
+                        };
+                        self.set_encoding_limits(limits);
+                        Ok(Reply::ok())
+                    }
+                    "OBJECT" => {
+                        // DEBUG OBJECT key: Redis's line of internals, with how
+                        // many elements a collection holds added. The length is
+                        // that of the value in a snapshot.
+                        let [_, _, key] = parts[..] else {
+                            return Ok(Reply::error("DEBUG OBJECT requires a key"));
+                        };
+                        let mut databases = self.data.lock().unwrap();
+                        let data = &mut databases[session.db];
+                        
+                        // Like OBJECT, looking a key over leaves its last access alone
+                        if data.remove_if_expired(key) {
+                            return Ok(Reply::error("no such key"));
+                        }
+                        let Some(value) = data.get(key) else {
+                            return Ok(Reply::error("no such key"));
+                        };
+                        let mut serialized = Vec::new();
+                        persistence::encode_value(&mut serialized, &value.value);
+                        let mut line = format!(
+                            "refcount:1 encoding:{} serializedlength:{} lru_seconds_idle:{}",
+                            value.encoding(data.limits()),
+                            serialized.len(),
+                            value.last_access.elapsed().as_secs(),
+                        );
+                        if let Some(elements) = value.value.elements() {
+                            line += &format!(" elements:{}", elements);
+                        }
+                        Ok(Reply::Simple(line))
+                    }
+                    // Redis keeps this for compatibility and does nothing
+                    "JMAP" => Ok(Reply::ok()),
+                    _ => Ok(Reply::error(format!("Unknown DEBUG subcommand '{}'", parts[1]))),
+                }
             }
-            "EXPIRE" => {
-                if parts.len() != 3 {
-                    return Ok("ERROR: EXPIRE requires exactly two arguments\n".to_string());
+            "SAVE" => {
+                match self.save() {
+                    Ok(()) => Ok(Reply::ok()),
+                    Err(e) => Ok(Reply::error(format!("Saving the snapshot failed: {}", e))),
+                }
+            }
+            "BGSAVE" => {
+                match self.background_save() {
+                    Ok(()) => Ok(Reply::Simple("Background saving started".to_string())),
+                    Err(e) => Ok(Reply::error(e)),
+                }
+            }
+            "LASTSAVE" => {
+                // When the last SAVE or BGSAVE succeeded, as a Unix time in
+                // seconds; before any has, when the server started
+                Ok(Reply::Integer(self.stats.last_save.load(Ordering::SeqCst) as i64))
+            }
+            "REPLICAOF" => {
+                // REPLICAOF host port starts following a leader, whose
+                // snapshot replaces everything this server holds; REPLICAOF NO
+                // ONE stops following it, keeping the data as it is
+                if parts[1].eq_ignore_ascii_case("NO") && parts[2].eq_ignore_ascii_case("ONE") {
+                    if let Some(leader) = self.leader.lock().unwrap().take() {
+                        info!("No longer replicating {}:{}", leader.host, leader.port);
+                    }
+                    return Ok(Reply::ok());
                 }
+                let Ok(port) = parts[2].parse::<u16>() else {
+                    return Ok(Reply::error("Invalid master port"));
+                };
                 
-                let key = parts[1];
+                let mut leader = self.leader.lock().unwrap();
+                if leader.as_ref().is_some_and(|leader| leader.host == parts[1] && leader.port == port) {
+                    return Ok(Reply::Simple("OK Already connected to specified master".to_string()));
+                }
+                // Replacing the leader stops the task following the old one
+                let link_up = Arc::new(AtomicBool::new(false));
+                let task = tokio::spawn(self.clone().follow(parts[1].to_string(), port, link_up.clone()));
+                *leader = Some(Leader { host: parts[1].to_string(), port, link_up, task });
+                info!("Replicating {}:{}", parts[1], port);
+                Ok(Reply::ok())
+            }
+            "WAIT" => {
+                // WAIT numreplicas timeout
+                if parts.len() != 3 {
+                    return Ok(Reply::error("WAIT requires a number of replicas and a timeout"));
+                }
+                if parts[1].parse::<u64>().is_err() || parts[2].parse::<u64>().is_err() {
+                    return Ok(Reply::error("WAIT number of replicas and timeout must be non-negative integers"));
+                }
                 
-                if let Ok(seconds) = parts[2].parse::<u64>() {
-                    let mut data = self.data.lock().unwrap();
-                    
-                    if let Some(value) = data.get_mut(key) {
-                        // Update the expiration time
-                        value.expires_at = Some(Instant::now() + Duration::from_secs(seconds));
-                        Ok("1\n".to_string())
+                // Replicas don't acknowledge writes, so there is nothing to wait for
+                Ok(Reply::Integer(0))
+            }
+            "COMMAND" => {
+                // Without names, INFO and DOCS describe every command
+                let named = |names: &[&str]| -> Vec<Option<&commands::CommandSpec>> {
+                    if names.is_empty() {
+                        COMMANDS.iter().map(Some).collect()
                     } else {
-                        Ok("0\n".to_string())  // Key doesn't exist
+                        names.iter().map(|name| commands::lookup(name)).collect()
                     }
-                } else {
-                    Ok("ERROR: EXPIRE seconds must be a positive integer\n".to_string())
+                };
+                
+                let Some(subcommand) = parts.get(1) else {
+                    return Ok(Reply::Array(COMMANDS.iter().map(|spec| spec.info()).collect()));
+                };
+                match subcommand.to_uppercase().as_str() {
+                    "COUNT" => Ok(Reply::Integer(COMMANDS.len() as i64)),
+                    "INFO" => {
+                        // Unknown commands get a nil entry
+                        let infos = named(&parts[2..]).into_iter()
+                            .map(|spec| spec.map_or(Reply::Nil, |spec| spec.info()))
+                            .collect();
+                        Ok(Reply::Array(infos))
+                    }
+                    "DOCS" => {
+                        // Each known command's name is followed by its documentation,
+                        // of which there is none beyond the name for now
+                        let docs = named(&parts[2..]).into_iter()
+                            .flatten()
+                            .flat_map(|spec| [Reply::bulk(spec.name.to_lowercase()), Reply::Array(Vec::new())])
+                            .collect();
+                        Ok(Reply::Array(docs))
+                    }
+                    _ => Ok(Reply::error(format!("Unknown COMMAND subcommand '{}'", subcommand))),
                 }
             }
-            "TTL" => {
-                if parts.len() != 2 {
-                    return Ok("ERROR: TTL requires exactly one argument\n".to_string());
+            "MONITOR" => {
+                // From now on this connection is sent a line for every command
+                // other clients send, and can still send commands itself
+                if session.monitor.is_none() {
+                    session.monitor = Some(self.monitors.subscribe());
+                }
+                Ok(Reply::ok())
+            }
+            "SUBSCRIBE" | "PSUBSCRIBE" => {
+                // SUBSCRIBE channel [channel ...], confirming each subscription
+                // with the number of channels and patterns the client is now
+                // subscribed to. PSUBSCRIBE pattern [pattern ...] subscribes
+                // to every channel matching the glob patterns.
+                if parts.len() < 2 {
+                    return Ok(Reply::error(format!("{} requires at least one {}", command, if command == "SUBSCRIBE" { "channel" } else { "pattern" })));
                 }
                 
-                let key = parts[1];
-                let data = self.data.lock().unwrap();
-                
-                match data.get(key) {
-                    Some(value) => {
-                        match value.expires_at {
-                            Some(expires_at) => {
-                                let now = Instant::now();
-                                if expires_at > now {
-                                    let remaining = expires_at.duration_since(now).as_secs();
-                                    Ok(format!("{}\n", remaining))
-                                } else {
-                                    Ok("-2\n".to_string())  // Key expired
-                                }
-                            },
-                            None => Ok("-1\n".to_string()),  // Key exists but has no expiry
-                        }
-                    },
-                    None => Ok("-2\n".to_string()),  // Key doesn't exist
+                let mut pubsub = self.pubsub.lock().unwrap();
+                let mut replies = Vec::new();
+                for name in &parts[1..] {
+                    let (count, messages) = if command == "SUBSCRIBE" {
+                        pubsub.subscribe(session.id, name)
+                    } else {
+                        pubsub.psubscribe(session.id, name)
+                    };
+                    if messages.is_some() {
+                        session.messages = messages;
+                    }
+                    replies.push(subscription_reply(&command.to_lowercase(), Some(name), count));
                 }
+                Ok(Reply::Several(replies))
             }
-            "DEL" => {
-                if parts.len() != 2 {
-                    return Ok("ERROR: DEL requires exactly one argument\n".to_string());
+            "UNSUBSCRIBE" | "PUNSUBSCRIBE" => {
+                // UNSUBSCRIBE [channel ...], from every channel if none is
+                // given, and PUNSUBSCRIBE [pattern ...] likewise for patterns
+                let kind = command.to_lowercase();
+                let mut pubsub = self.pubsub.lock().unwrap();
+                let names = match parts.len() {
+                    1 if command == "UNSUBSCRIBE" => pubsub.channels_of(session.id),
+                    1 => pubsub.patterns_of(session.id),
+                    _ => parts[1..].iter().map(|name| name.to_string()).collect(),
+                };
+                if names.is_empty() {
+                    return Ok(subscription_reply(&kind, None, pubsub.subscription_count(session.id)));
                 }
                 
-                let key = parts[1];
-                let mut data = self.data.lock().unwrap();
+                let replies = names.iter()
+                    .map(|name| {
+                        let count = if command == "UNSUBSCRIBE" {
+                            pubsub.unsubscribe(session.id, name)
+                        } else {
+                            pubsub.punsubscribe(session.id, name)
+                        };
+                        subscription_reply(&kind, Some(name), count)
+                    })
+                    .collect();
+                Ok(Reply::Several(replies))
+            }
+            "PUBLISH" => {
+                // PUBLISH channel message, returning how many subscriptions,
+                // to the channel or to patterns matching it, received it
+                if parts.len() != 3 {
+                    return Ok(Reply::error("PUBLISH requires a channel and a message"));
+                }
+                
+                let received = self.pubsub.lock().unwrap().publish(parts[1], &args[2]);
+                Ok(Reply::Integer(received as i64))
+            }
+            "SHUTDOWN" => {
+                // Stop accepting connections and let every client finish its current command
+                info!("SHUTDOWN requested by a client");
+                self.shutdown.send_replace(true);
+                Ok(Reply::ok())
+            }
+            "WATCH" => {
+                if parts.len() < 2 {
+                    return Ok(Reply::error("WATCH requires at least one key"));
+                }
                 
-                match data.remove(key) {
-                    Some(_) => Ok("1\n".to_string()),
-                    None => Ok("0\n".to_string()),
+                let mut databases = self.data.lock().unwrap();
+                let data = &mut databases[session.db];
+                for &key in &parts[1..] {
+                    // An expired key is watched as the missing key it is
+                    data.remove_if_expired(key);
+                    let version = data.watch(key);
+                    session.watched.push((session.db, key.to_string(), version));
                 }
+                Ok(Reply::ok())
             }
-            "EXISTS" => {
-                if parts.len() != 2 {
-                    return Ok("ERROR: EXISTS requires exactly one argument\n".to_string());
+            "UNWATCH" => {
+                if parts.len() != 1 {
+                    return Ok(Reply::error("UNWATCH takes no arguments"));
                 }
                 
-                let key = parts[1];
-                let data = self.data.lock().unwrap();
+                self.unwatch(session);
+                Ok(Reply::ok())
+            }
+            "PING" => {
+                // PING [message], which echoes the message instead of PONG
+                if parts.len() > 2 {
+                    return Ok(Reply::error("wrong number of arguments for 'ping' command"));
+                }
                 
-                match data.get(key) {
-                    Some(value) if !value.is_expired() => Ok("1\n".to_string()),
-                    _ => Ok("0\n".to_string()),
+                // A subscriber gets it in the shape of a message, so it can
+                // tell the two apart
+                if self.is_subscribed(session) {
+                    let message = args.get(1).cloned().unwrap_or_default();
+                    return Ok(Reply::Array(vec![Reply::bulk("pong"), Reply::bulk(message)]));
+                }
+                match args.get(1) {
+                    Some(message) => Ok(Reply::bulk(message.clone())),
+                    None => Ok(Reply::Simple("PONG".to_string())),
                 }
             }
-            "KEYS" => {
-                if parts.len() != 2 {
-                    return Ok("ERROR: KEYS requires exactly one argument\n".to_string());
+            "AUTH" => {
+                // AUTH [username] password. There is only the default user.
+                let (user, password) = match &parts[1..] {
+                    [password] if self.config.lock().unwrap().requirepass.is_empty() => {
+                        return Ok(Reply::error(
+                            "AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?",
+                        ));
+                    }
+                    [password] => ("default", *password),
+                    [user, password] => (*user, *password),
+                    _ => return Ok(Reply::error("syntax error")),
+                };
+                match self.authenticate(session, user, password) {
+                    Ok(()) => Ok(Reply::ok()),
+                    Err(reply) => Ok(reply),
                 }
-                
-                let pattern = parts[1];
-                let data = self.data.lock().unwrap();
-                
-                // Simple pattern matching (only supporting * wildcard)
-                let keys: Vec<String> = if pattern == "*" {
-                    // Return all non-expired keys
-                    data.iter()
-                        .filter(|(_, v)| !v.is_expired())
-                        .map(|(k, _)| k.clone())
-                        .collect()
-                } else {
-                    // Return keys that match pattern (simple contains for now)
-                    data.iter()
-                        .filter(|(k, v)| !v.is_expired() && k.contains(&pattern.replace("*", "")))
-                        .map(|(k, _)| k.clone())
-                        .collect()
+            }
+            "HELLO" => {
+                // HELLO [protover [AUTH username password]]
+                let version = match parts.get(1).map(|version| version.parse::<i64>()) {
+                    None => None,
+                    Some(Ok(version @ (2 | 3))) => Some(version),
+                    Some(Ok(_)) => return Ok(Reply::error_code("NOPROTO", "unsupported protocol version")),
+                    Some(Err(_)) => return Ok(Reply::error("Protocol version is not an integer or out of range")),
                 };
+                match &parts[parts.len().min(2)..] {
+                    [] if !session.authenticated => {
+                        return Ok(Reply::error_code(
+                            "NOAUTH",
+                            "HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time",
+                        ));
+                    }
+                    [] => {}
+                    [auth, user, password] if auth.eq_ignore_ascii_case("AUTH") => {
+                        if let Err(reply) = self.authenticate(session, user, password) {
+                            return Ok(reply);
+                        }
+                    }
+                    [option, ..] => return Ok(Reply::error(format!("Syntax error in HELLO option '{}'", option))),
+                }
                 
-                if keys.is_empty() {
-                    Ok("(empty list)\n".to_string())
-                } else {
-                    let result = keys.join("\n");
-                    Ok(format!("{}\n", result))
+                if let Some(version) = version {
+                    session.resp3 = version == 3;
+                    session.protocol = match (session.protocol, session.resp3) {
+                        (Protocol::Resp2, true) => Protocol::Resp3,
+                        (Protocol::Resp3, false) => Protocol::Resp2,
+                        (protocol, _) => protocol,
+                    };
                 }
+                
+                let proto = if session.resp3 { 3 } else { 2 };
+                let role = if self.leader.lock().unwrap().is_some() { "replica" } else { "master" };
+                Ok(Reply::Map(vec![
+                    (Reply::bulk("server"), Reply::bulk("redis")),
+                    (Reply::bulk("version"), Reply::bulk(env!("CARGO_PKG_VERSION"))),
+                    (Reply::bulk("proto"), Reply::Integer(proto)),
+                    (Reply::bulk("id"), Reply::Integer(session.id as i64)),
+                    (Reply::bulk("mode"), Reply::bulk("standalone")),
+                    (Reply::bulk("role"), Reply::bulk(role)),
+                    (Reply::bulk("modules"), Reply::Array(Vec::new())),
+                ]))
             }
-            "FLUSHALL" => {
-                let mut data = self.data.lock().unwrap();
-                data.clear();
-                Ok("OK\n".to_string())
+            "ECHO" => {
+                if parts.len() != 2 {
+                    return Ok(Reply::error("ECHO requires exactly one argument"));
+                }
+                Ok(Reply::bulk(args[1].clone()))
             }
-            "PING" => {
-                Ok("PONG\n".to_string())
+            "TIME" => {
+                // The server's clock as Unix seconds and the microseconds
+                // since, both as bulk strings like Redis
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                Ok(Reply::Array(vec![
+                    Reply::bulk(now.as_secs().to_string()),
+                    Reply::bulk(now.subsec_micros().to_string()),
+                ]))
+            }
+            "LOLWUT" => {
+                // LOLWUT [VERSION version], a bit of art and the server's
+                // version. Redis draws different art for some versions; every
+                // version draws the same here.
+                match &parts[1..] {
+                    [] => {}
+                    [option, version] if option.eq_ignore_ascii_case("VERSION") => {
+                        if version.parse::<i64>().is_err() {
+                            return Ok(Reply::error("value is not an integer or out of range"));
+                        }
+                    }
+                    _ => return Ok(Reply::error("syntax error")),
+                }
+                Ok(Reply::bulk(format!("{}\nRedis ver. {}\n", LOLWUT_ART, env!("CARGO_PKG_VERSION"))))
             }
             "HELP" => {
-                Ok("Available commands: GET, SET, DEL, EXISTS, EXPIRE, TTL, KEYS, FLUSHALL, PING, HELP\n".to_string())
+                let names: Vec<&str> = COMMANDS.iter().map(|spec| spec.name).collect();
+                Ok(Reply::bulk(format!("Available commands: {}", names.join(", "))))
             }
             _ => {
                 Err(RedisError::UnknownCommand(parts[0].to_string()))
@@ -282,6 +5181,158 @@ impl RedisServer {
     }
 }
 
+// Apply the tcp-nodelay and tcp-keepalive settings to a client's socket
+fn configure_socket(socket: &TcpStream, config: &Config) -> std::io::Result<()> {
+    socket.set_nodelay(config.tcp_nodelay)?;
+    
+    let socket = socket2::SockRef::from(socket);
+    match config.keepalive_interval() {
+        Some(interval) => socket.set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(interval)),
+        None => socket.set_keepalive(false),
+    }
+}
+
+// Confirmation of a subscription change: what changed, the channel (none when
+// unsubscribing from nothing) and how many channels the client is left with
+fn subscription_reply(kind: &str, channel: Option<&str>, count: usize) -> Reply {
+    Reply::Array(vec![
+        Reply::bulk(kind),
+        channel.map_or(Reply::Nil, Reply::bulk),
+        Reply::Integer(count as i64),
+    ])
+}
+
+// The next message published to a subscribed client, or never if it didn't
+// subscribe to anything. None once it was dropped for falling behind.
+async fn next_message(messages: &mut Option<mpsc::Receiver<Reply>>) -> Option<Reply> {
+    match messages {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Resolves when a blocked command may be able to go ahead: one of its keys
+// changed or its timeout passed
+async fn next_wakeup(blocked: &Option<BlockedCommand>) {
+    let Some(blocked) = blocked else {
+        return std::future::pending().await;
+    };
+    match blocked.deadline {
+        Some(deadline) => {
+            tokio::select! {
+                _ = blocked.woken.notified() => {}
+                _ = sleep_until(deadline.into()) => {}
+            }
+        }
+        None => blocked.woken.notified().await,
+    }
+}
+
+// The next write to send a replica, None once it fell too far behind, or
+// never if the client isn't a replica
+async fn next_replicated_write(replica: &mut Option<ReplicaFeed>) -> Option<Vec<u8>> {
+    match replica {
+        Some(feed) => feed.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+// The next line for a client in MONITOR mode, or never if it isn't monitoring
+async fn next_monitor_line(monitor: &mut Option<broadcast::Receiver<String>>) -> String {
+    let Some(receiver) = monitor else {
+        return std::future::pending().await;
+    };
+    loop {
+        match receiver.recv().await {
+            Ok(line) => return line,
+            // Lines a slow monitor couldn't keep up with are dropped
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+// Resolve once the server should shut down: on SIGINT, SIGTERM or a SHUTDOWN command
+async fn shutdown_signal(mut shutdown: watch::Receiver<bool>) {
+    #[cfg(unix)]
+    let terminate = async {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler");
+        sigterm.recv().await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+        _ = shutdown.wait_for(|&stop| stop) => {}
+    }
+}
+
+// Serve a newly accepted client on a task of its own
+fn spawn_client<S>(clients: &mut JoinSet<()>, redis_server: &RedisServer, socket: S, addr: String)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    info!("New client connection: {}", addr);
+    
+    // Clone the shared server state for this connection
+    let server = redis_server.clone();
+    
+    clients.spawn(async move {
+        if let Err(e) = server.handle_client(socket, addr.clone()).await {
+            error!("Error handling client {}: {}", addr, e);
+        }
+        info!("Client {} disconnected", addr);
+    });
+}
+
+// Serve a newly accepted TLS client once the handshake is done, on a task of
+// its own so a slow handshake holds nobody else up
+fn spawn_tls_client(
+    clients: &mut JoinSet<()>,
+    redis_server: &RedisServer,
+    acceptor: &TlsAcceptor,
+    socket: TcpStream,
+    addr: String,
+) {
+    info!("New TLS client connection: {}", addr);
+    
+    let server = redis_server.clone();
+    let acceptor = acceptor.clone();
+    
+    clients.spawn(async move {
+        let socket = match acceptor.accept(socket).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("TLS handshake with {} failed: {}", addr, e);
+                return;
+            }
+        };
+        if let Err(e) = server.handle_client(socket, addr.clone()).await {
+            error!("Error handling client {}: {}", addr, e);
+        }
+        info!("Client {} disconnected", addr);
+    });
+}
+
+// Accept a client on the TLS port, or wait forever without one
+async fn accept_tls(listener: Option<&(TcpListener, TlsAcceptor)>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some((listener, _)) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Accept a client on the Unix socket, or wait forever without one
+async fn accept_unix(listener: Option<&UnixListener>) -> std::io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(socket, _)| socket),
+        None => std::future::pending().await,
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Load .env file if present
@@ -290,38 +5341,381 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logger
     env_logger::init();
     
+    let options = match args::parse(env::args().skip(1)) {
+        Ok(Action::Run(options)) => options,
+        Ok(Action::Help) => {
+            println!("{}", args::USAGE);
+            return Ok(());
+        }
+        Ok(Action::Version) => {
+            println!("redis {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, args::USAGE);
+            std::process::exit(1);
+        }
+    };
+    
     // Default to 6379 (standard Redis port) if not specified
-    let port = env::var("REDIS_PORT").unwrap_or_else(|_| "6379".to_string());
-    let addr = format!("127.0.0.1:{}", port);
+    let port = match options.port {
+        Some(port) => port,
+        None => match env::var("REDIS_PORT") {
+            Ok(port) => port.parse().map_err(|_| format!("REDIS_PORT: Invalid port '{}'", port))?,
+            Err(_) => 6379,
+        },
+    };
+    
+    // Create our Redis server instance
+    let mut config = Config::from_env()?;
+    if let Some(bind) = &options.bind {
+        config.set("bind", bind).map_err(|e| format!("--bind: {}", e))?;
+    }
+    if let Some(password) = &options.password {
+        config.set("requirepass", password)?;
+    }
+    if let Some(maxmemory) = &options.maxmemory {
+        config.set("maxmemory", maxmemory).map_err(|e| format!("--maxmemory: {}", e))?;
+    }
+    let addr = SocketAddr::new(config.bind, port);
+    let snapshot_path = config.snapshot_path();
+    let databases = config.databases;
+    let aof_path = config.appendonly.then(|| config.aof_path());
+    let mut redis_server = RedisServer::new(config);
+    
+    // Restore the data set: the append-only file is the more complete record,
+    // so like Redis it takes precedence over the snapshot when enabled
+    if let Some(aof_path) = aof_path {
+        if let Some(commands) = persistence::load_aof(&aof_path)? {
+            redis_server.replay(&commands)?;
+            info!("Replayed {} commands from {}", commands.len(), aof_path.display());
+        }
+        
+        let aof = AppendOnlyFile::open(&aof_path, redis_server.config.clone()).await?;
+        redis_server.aof = Some(Arc::new(Mutex::new(aof)));
+    } else if let Some(mut data) = persistence::load_snapshot(&snapshot_path, databases)? {
+        let keys: usize = data.iter().map(Keyspace::len).sum();
+        info!("Loaded {} keys from {}", keys, snapshot_path.display());
+        // Loading the keys doesn't count as changing them
+        for data in data.iter_mut() {
+            data.take_changes();
+        }
+        *redis_server.data.lock().unwrap() = data;
+    }
+    
+    // Bind the TLS port first, if one is configured, so the server is
+    // reachable over TLS by the time it is on the plain port
+    let (tls_port, tls_cert_file, tls_key_file) = {
+        let config = redis_server.config.lock().unwrap();
+        (config.tls_port, config.tls_cert_file.clone(), config.tls_key_file.clone())
+    };
+    let tls_listener = if tls_port == 0 {
+        None
+    } else {
+        if tls_cert_file.is_empty() || tls_key_file.is_empty() {
+            return Err("tls-port requires tls-cert-file and tls-key-file".into());
+        }
+        let acceptor = tls::acceptor(&tls_cert_file, &tls_key_file)?;
+        let tls_addr = SocketAddr::new(addr.ip(), tls_port);
+        let listener = TcpListener::bind(tls_addr)
+            .await
+            .map_err(|e| format!("Could not listen on {}: {}", tls_addr, e))?;
+        info!("Redis server listening for TLS on {}", tls_addr);
+        Some((listener, acceptor))
+    };
     
     // Create and bind the TCP listener
-    let listener = TcpListener::bind(&addr).await?;
+    let listener = TcpListener::bind(addr).await.map_err(|e| format!("Could not listen on {}: {}", addr, e))?;
     info!("Redis server listening on {}", addr);
     
-    // Create our Redis server instance
-    let redis_server = RedisServer::new();
+    // And the Unix socket, if one is configured. Like Redis, replace a socket
+    // file left behind by a server that didn't shut down cleanly.
+    let unixsocket = redis_server.config.lock().unwrap().unixsocket.clone();
+    let unix_listener = if unixsocket.is_empty() {
+        None
+    } else {
+        match std::fs::remove_file(&unixsocket) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&unixsocket)?;
+        info!("Redis server listening on {}", unixsocket);
+        Some(listener)
+    };
     
-    // Accept and handle connections
+    let shutdown = shutdown_signal(redis_server.shutdown.subscribe());
+    tokio::pin!(shutdown);
+    
+    // Accept and handle connections until asked to shut down
+    let mut clients = JoinSet::new();
     loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                info!("New client connection: {}", addr);
-                
-                // Clone the server data for this connection
-                let server_data = redis_server.data.clone();
-                
-                // Spawn a new task to handle this client
-                tokio::spawn(async move {
-                    let server = RedisServer { data: server_data };
-                    if let Err(e) = server.handle_client(socket).await {
-                        error!("Error handling client {}: {}", addr, e);
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, addr)) => {
+                    // The client can still be served without them, just less well
+                    if let Err(e) = configure_socket(&socket, &redis_server.config.lock().unwrap()) {
+                        error!("Setting socket options failed: {}", e);
                     }
-                    info!("Client {} disconnected", addr);
-                });
+                    spawn_client(&mut clients, &redis_server, socket, addr.to_string());
+                }
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                }
+            },
+            accepted = accept_tls(tls_listener.as_ref()) => match accepted {
+                Ok((socket, addr)) => {
+                    if let Err(e) = configure_socket(&socket, &redis_server.config.lock().unwrap()) {
+                        error!("Setting socket options failed: {}", e);
+                    }
+                    if let Some((_, acceptor)) = &tls_listener {
+                        spawn_tls_client(&mut clients, &redis_server, acceptor, socket, addr.to_string());
+                    }
+                }
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                }
+            },
+            accepted = accept_unix(unix_listener.as_ref()) => match accepted {
+                // Unix socket clients have no address of their own, so like
+                // Redis they are known by the socket's path
+                Ok(socket) => spawn_client(&mut clients, &redis_server, socket, format!("{}:0", unixsocket)),
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                }
+            },
+            // Reap handlers of clients that already disconnected
+            Some(_) = clients.join_next(), if !clients.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+    
+    // Stop accepting, then wait for connected clients to finish their current command
+    info!("Shutting down, no longer accepting connections");
+    drop(listener);
+    drop(tls_listener);
+    if unix_listener.is_some() {
+        drop(unix_listener);
+        let _ = std::fs::remove_file(&unixsocket);
+    }
+    redis_server.shutdown.send_replace(true);
+    while clients.join_next().await.is_some() {}
+    
+    info!("Redis server stopped");
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn expired_value() -> RedisValue {
+        RedisValue {
+            value: Value::String(b"stale".to_vec()),
+            expires_at: Some(Instant::now() - Duration::from_millis(10)),
+            last_access: Instant::now(),
+            frequency: LFU_INIT_VAL,
+        }
+    }
+    
+    #[test]
+    fn access_frequency_decays_while_unused() {
+        let mut value = RedisValue::new("value".to_string(), None);
+        value.frequency = 10;
+        value.last_access = Instant::now() - Duration::from_secs(3 * 60 + 10);
+        assert_eq!(value.frequency(), 7);
+        
+        // An access starts from the decayed count, then may add one to it
+        value.touch();
+        assert!(matches!(value.frequency, 7 | 8), "{}", value.frequency);
+        
+        value.last_access = Instant::now() - Duration::from_secs(1000 * 60);
+        assert_eq!(value.frequency(), 0);
+        value.touch();
+        assert_eq!(value.frequency, 1);
+        
+        value.frequency = u8::MAX;
+        value.touch();
+        assert_eq!(value.frequency, u8::MAX);
+    }
+    
+    #[test]
+    fn values_give_their_own_kind_only() {
+        let mut value = Value::List(VecDeque::from([b"a".to_vec()]));
+        assert_eq!(value.as_list().unwrap().len(), 1);
+        value.as_list_mut().unwrap().push_back(b"b".to_vec());
+        assert_eq!(value, Value::List(VecDeque::from([b"a".to_vec(), b"b".to_vec()])));
+        assert!(matches!(value.as_string(), Err(RedisError::WrongType)));
+        assert!(matches!(value.as_hash_mut(), Err(RedisError::WrongType)));
+        assert!(matches!(Value::String(Vec::new()).as_zset(), Err(RedisError::WrongType)));
+        assert_eq!(wrong_type(), Reply::error_code("WRONGTYPE", "Operation against a key holding the wrong kind of value"));
+    }
+    
+    #[test]
+    fn get_live_removes_expired_key() {
+        let mut data = Keyspace::default();
+        data.insert("key".to_string(), expired_value());
+        
+        assert!(get_live(&mut data, "key").is_none());
+        assert!(data.is_empty());
+    }
+    
+    #[test]
+    fn get_live_returns_live_key() {
+        let mut data = Keyspace::default();
+        data.insert("key".to_string(), RedisValue::new("fresh".to_string(), Some(Instant::now() + Duration::from_secs(100))));
+        
+        assert_eq!(get_live(&mut data, "key").unwrap().value, Value::String(b"fresh".to_vec()));
+        assert_eq!(data.len(), 1);
+    }
+    
+    #[test]
+    fn expire_conditions() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(10);
+        let condition = |options: &[&str]| ExpireCondition::parse(options).unwrap();
+        
+        assert!(condition(&[]).allows(Some(now), later));
+        assert!(condition(&["nx"]).allows(None, later));
+        assert!(!condition(&["NX"]).allows(Some(now), later));
+        assert!(!condition(&["XX"]).allows(None, later));
+        assert!(condition(&["XX"]).allows(Some(now), later));
+        assert!(condition(&["GT"]).allows(Some(now), later));
+        assert!(!condition(&["GT"]).allows(Some(later), now));
+        assert!(!condition(&["GT"]).allows(None, later));
+        assert!(condition(&["LT"]).allows(Some(later), now));
+        assert!(!condition(&["LT"]).allows(Some(now), now));
+        assert!(condition(&["LT"]).allows(None, later));
+        assert!(!condition(&["XX", "LT"]).allows(None, later));
+        
+        assert!(ExpireCondition::parse(&["NX", "GT"]).is_err());
+        assert!(ExpireCondition::parse(&["GT", "LT"]).is_err());
+        assert!(ExpireCondition::parse(&["SOON"]).is_err());
+    }
+    
+    #[test]
+    fn limits() {
+        let limited = |offset: &str, count: &str| -> Vec<i32> {
+            Limit::parse(offset, count).unwrap().apply(1..=5).collect()
+        };
+        
+        assert_eq!(limited("0", "2"), [1, 2]);
+        assert_eq!(limited("3", "10"), [4, 5]);
+        assert_eq!(limited("1", "-1"), [2, 3, 4, 5]);
+        // Offset past the end, zero count and negative offset
+        assert_eq!(limited("5", "1"), []);
+        assert_eq!(limited("0", "0"), []);
+        assert_eq!(limited("-1", "2"), []);
+        
+        assert_eq!(Limit::parse("x", "1"), None);
+        assert_eq!(Limit::parse("1", "1.5"), None);
+    }
+    
+    #[test]
+    fn byte_ranges() {
+        let bytes = b"This is a string";
+        
+        assert_eq!(byte_range(bytes, 0, 3), b"This");
+        assert_eq!(byte_range(bytes, -3, -1), b"ing");
+        assert_eq!(byte_range(bytes, 0, -1), bytes);
+        assert_eq!(byte_range(bytes, 10, 100), b"string");
+        assert_eq!(byte_range(bytes, -100, 3), b"This");
+        assert_eq!(byte_range(bytes, 100, 200), b"");
+        assert_eq!(byte_range(bytes, 5, 2), b"");
+        assert_eq!(byte_range(b"", 0, -1), b"");
+    }
+    
+    #[test]
+    fn scan_visits_every_key_while_keys_are_added() {
+        let mut data = Keyspace::default();
+        for i in 0..100 {
+            data.insert(format!("key:{}", i), RedisValue::new("value".to_string(), None));
+        }
+        
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next, keys) = scan_keys(&mut data, cursor, 3, None);
+            seen.extend(keys);
+            
+            // Growing the keyspace mid-iteration must not prevent termination
+            data.insert(format!("new:{}", calls), RedisValue::new("value".to_string(), None));
+            
+            calls += 1;
+            assert!(calls <= 1000, "SCAN did not terminate");
+            if next == 0 {
+                break;
             }
-            Err(e) => {
-                error!("Error accepting connection: {}", e);
+            assert!(next > cursor);
+            cursor = next;
+        }
+        
+        for i in 0..100 {
+            assert!(seen.contains(&format!("key:{}", i)));
+        }
+    }
+    
+    #[tokio::test]
+    async fn every_command_checks_its_arity() {
+        let server = RedisServer::new(Config::default());
+        let mut session = Session::default();
+        
+        for spec in COMMANDS {
+            let fewest = spec.arity.unsigned_abs() as usize;
+            let mut wrong_counts = vec![fewest - 1];
+            if spec.arity > 0 {
+                wrong_counts.push(fewest + 1);
+            }
+            // A call needs at least the name to be a call to the command
+            for argc in wrong_counts.into_iter().filter(|&argc| argc > 0) {
+                let mut args = vec![spec.name.as_bytes().to_vec()];
+                args.resize(argc, b"x".to_vec());
+                let response = server.process_command(&mut session, &args).await.unwrap();
+                let expected = format!("-ERR wrong number of arguments for '{}' command\r\n", spec.name.to_lowercase());
+                assert_eq!(response.encode(Protocol::Resp2), expected.as_bytes(), "{:?}", args);
             }
         }
     }
+    
+    #[tokio::test]
+    async fn expired_key_is_gone_after_single_access() {
+        let server = RedisServer::new(Config::default());
+        let mut session = Session::default();
+        
+        // Each command should see the expired key as absent and delete it
+        // without any help from the background sweep
+        for (command, expected) in [
+            ("GET key", "(nil)\n"),
+            ("EXISTS key", "0\n"),
+            ("TTL key", "-2\n"),
+            ("EXPIRE key 10", "0\n"),
+            ("DEL key", "0\n"),
+        ] {
+            server.data.lock().unwrap()[0].insert("key".to_string(), expired_value());
+            
+            let args: Args = command.split_whitespace().map(|arg| arg.as_bytes().to_vec()).collect();
+            let response = server.process_command(&mut session, &args).await.unwrap();
+            assert_eq!(response.encode(Protocol::Text), expected.as_bytes(), "{}", command);
+            assert!(server.data.lock().unwrap()[0].is_empty(), "{}", command);
+        }
+    }
+    
+    #[tokio::test]
+    async fn socket_options_follow_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        
+        let mut config = Config::default();
+        configure_socket(&socket, &config).unwrap();
+        assert!(socket.nodelay().unwrap());
+        assert!(socket2::SockRef::from(&socket).keepalive().unwrap());
+        
+        config.set("tcp-nodelay", "no").unwrap();
+        config.set("tcp-keepalive", "0").unwrap();
+        configure_socket(&socket, &config).unwrap();
+        assert!(!socket.nodelay().unwrap());
+        assert!(!socket2::SockRef::from(&socket).keepalive().unwrap());
+    }
 }
@@ -0,0 +1,74 @@
+// MONITOR output: one line for every command a client sends, in the format
+// Redis uses:
+//
+//   1339518083.107412 [0 127.0.0.1:60866] "SET" "key" "value"
+//
+// that is the Unix time with microseconds, the database the command runs in
+// and the client's address, followed by the arguments quoted like redis-cli
+// would quote them.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn format_line<T: AsRef<[u8]>>(time: SystemTime, db: usize, addr: &str, args: &[T]) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut line = format!("{}.{:06} [{} {}]", since_epoch.as_secs(), since_epoch.subsec_micros(), db, addr);
+    for arg in args {
+        line.push(' ');
+        line.push_str(&quote(arg.as_ref()));
+    }
+    line
+}
+
+// Quote an argument so the line stays on one line and can be read back
+// unambiguously: quotes and backslashes are escaped, control characters
+// and bytes that aren't valid UTF-8 written as escapes
+fn quote(arg: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for chunk in arg.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' | '\\' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            quoted.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    #[test]
+    fn lines_look_like_redis() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_339_518_083_107_412);
+        let line = format_line(time, 0, "127.0.0.1:60866", &["SET", "key", "two words"]);
+        assert_eq!(line, r#"1339518083.107412 [0 127.0.0.1:60866] "SET" "key" "two words""#);
+        
+        // Microseconds keep their leading zeros
+        let time = UNIX_EPOCH + Duration::from_micros(5_000_042);
+        assert_eq!(format_line(time, 3, "addr", &["PING"]), r#"5.000042 [3 addr] "PING""#);
+    }
+    
+    #[test]
+    fn arguments_are_escaped() {
+        assert_eq!(quote(br#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote(b"back\\slash"), r#""back\\slash""#);
+        assert_eq!(quote(b"line\r\nbreak\t\x01"), r#""line\r\nbreak\t\x01""#);
+        assert_eq!(quote("caf\u{e9}".as_bytes()), "\"caf\u{e9}\"");
+        assert_eq!(quote(b"\xff\xfe"), r#""\xff\xfe""#);
+        assert_eq!(quote(b""), r#""""#);
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::compact::{Hash, Set};
+use crate::config::{AppendFsync, Config};
+use crate::keyspace::Keyspace;
+use crate::protocol::{self, Protocol, Reply};
+use crate::zset::SortedSet;
+use crate::{RedisValue, Value};
+
+// Snapshot file layout:
+//
+//   magic "RRDB", format version (u8)
+//   per non-empty database: SELECT_DB marker, database index (u32)
+//     per key: ENTRY marker, key, value type, value, expiry
+//   END marker
+//
+// Strings are a little-endian u32 length followed by the bytes. A string value
+// is a single string; a list is a u32 element count followed by each element;
+// a hash is a u32 field count followed by each field and its value;
+// a set is a u32 member count followed by each member;
+// a sorted set is a u32 member count followed by each member and its score as
+// a little-endian f64. The expiry is a flag byte,
+// followed when set by the absolute expiry time in Unix milliseconds, so keys
+// keep expiring on schedule across a restart.
+// Version 1 files predate multiple databases: they have no SELECT_DB markers
+// and all their keys load into database 0. Versions 1 and 2 only hold strings
+// and have no value type byte.
+const MAGIC: &[u8; 4] = b"RRDB";
+const VERSION: u8 = 3;
+const SELECT_DB: u8 = 0xFE;
+const ENTRY: u8 = 0x01;
+const END: u8 = 0xFF;
+
+// Value types
+const TYPE_STRING: u8 = 0;
+const TYPE_SORTED_SET: u8 = 1;
+const TYPE_LIST: u8 = 2;
+const TYPE_HASH: u8 = 3;
+const TYPE_SET: u8 = 4;
+
+// Serialize every live key of every database with its value and expiry
+pub fn encode_snapshot(databases: &[Keyspace]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    
+    for (index, data) in databases.iter().enumerate().filter(|(_, data)| !data.is_empty()) {
+        out.push(SELECT_DB);
+        out.extend_from_slice(&(index as u32).to_le_bytes());
+        
+        for (key, value) in data.iter().filter(|(_, value)| !value.is_expired()) {
+            encode_entry(&mut out, key, value);
+        }
+    }
+    
+    out.push(END);
+    out
+}
+
+fn encode_entry(out: &mut Vec<u8>, key: &str, value: &RedisValue) {
+    out.push(ENTRY);
+    write_bytes(out, key.as_bytes());
+    encode_value(out, &value.value);
+    
+    match value.expires_at {
+        Some(expires_at) => {
+            out.push(1);
+            out.extend_from_slice(&instant_to_unix_ms(expires_at).to_le_bytes());
+        }
+        None => out.push(0),
+    }
+}
+
+// A value's type byte followed by the value, as laid out in the snapshot
+pub fn encode_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(bytes) => {
+            out.push(TYPE_STRING);
+            write_bytes(out, bytes);
+        }
+        Value::List(list) => {
+            out.push(TYPE_LIST);
+            out.extend_from_slice(&(list.len() as u32).to_le_bytes());
+            for element in list {
+                write_bytes(out, element);
+            }
+        }
+        Value::Hash(hash) => {
+            out.push(TYPE_HASH);
+            out.extend_from_slice(&(hash.len() as u32).to_le_bytes());
+            for (field, value) in hash.iter() {
+                write_bytes(out, field.as_bytes());
+                write_bytes(out, value);
+            }
+        }
+        Value::Set(set) => {
+            out.push(TYPE_SET);
+            out.extend_from_slice(&(set.len() as u32).to_le_bytes());
+            for member in set.iter() {
+                write_bytes(out, member.as_bytes());
+            }
+        }
+        Value::SortedSet(zset) => {
+            out.push(TYPE_SORTED_SET);
+            out.extend_from_slice(&(zset.len() as u32).to_le_bytes());
+            for (member, score) in zset.iter() {
+                write_bytes(out, member.as_bytes());
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+// Read back a value written by encode_value, which must be all of `bytes`
+pub fn decode_value(bytes: &[u8]) -> io::Result<Value> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let value = reader.value()?;
+    if reader.pos != bytes.len() {
+        return Err(invalid("trailing bytes after the value"));
+    }
+    Ok(value)
+}
+
+// Rebuild the databases from a snapshot, dropping keys whose expiry has passed.
+// Fails if the snapshot uses more databases than are configured.
+pub fn decode_snapshot(bytes: &[u8], databases: usize) -> io::Result<Vec<Keyspace>> {
+    let mut reader = Reader { bytes, pos: 0 };
+    
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+    let version = reader.byte()?;
+    if !(1..=VERSION).contains(&version) {
+        return Err(invalid(&format!("unsupported snapshot version {}", version)));
+    }
+    
+    let mut data: Vec<Keyspace> = (0..databases).map(|_| Keyspace::default()).collect();
+    let mut db = 0;
+    loop {
+        match reader.byte()? {
+            SELECT_DB => {
+                db = reader.u32()? as usize;
+                if db >= databases {
+                    return Err(invalid(&format!("database {} is out of range, only {} configured", db, databases)));
+                }
+            }
+            ENTRY => {
+                let key = reader.string()?;
+                let value = if version < 3 {
+                    Value::String(reader.take_bytes()?)
+                } else {
+                    reader.value()?
+                };
+                let expires_at = match reader.byte()? {
+                    0 => None,
+                    1 => Some(unix_ms_to_instant(reader.u64()?).ok_or_else(|| invalid("expiry out of range"))?),
+                    _ => return Err(invalid("bad expiry flag")),
+                };
+                
+                let entry = RedisValue::new(value, expires_at);
+                if !entry.is_expired() {
+                    data[db].insert(key, entry);
+                }
+            }
+            END => return Ok(data),
+            marker => return Err(invalid(&format!("unexpected marker {:#04x}", marker))),
+        }
+    }
+}
+
+// Write the snapshot to a temporary file first and rename it into place,
+// so a crash mid-write never leaves a truncated snapshot behind. The file is
+// fsynced before the rename, and the directory after it, so a power loss
+// can't leave the new name pointing at data that never reached the disk.
+pub fn write_snapshot(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    
+    fs::rename(&tmp_path, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+// Load the snapshot at `path`, or None if there is no snapshot yet
+pub fn load_snapshot(path: &Path, databases: usize) -> io::Result<Option<Vec<Keyspace>>> {
+    match fs::read(path) {
+        Ok(bytes) => decode_snapshot(&bytes, databases).map(Some),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Append-only file: every write command is appended as a RESP array of its
+// arguments, the way a client would send it, and replayed in order on startup.
+//
+// Commands are handed to a background task that owns the file. The reply to a
+// write is only sent once its command has reached the operating system, and with
+// appendfsync always once it has been fsynced, so a crashed server never loses a
+// write it already acknowledged (with everysec, up to a second on power loss).
+pub struct AppendOnlyFile {
+    sender: mpsc::UnboundedSender<AofEntry>,
+    // Database the logged commands currently apply to, so a SELECT is only
+    // logged when a command targets a different one
+    selected_db: Option<usize>,
+}
+
+struct AofEntry {
+    command: Vec<u8>,
+    written: oneshot::Sender<()>,
+}
+
+impl AppendOnlyFile {
+    // Open (or create) the file for appending and start its writer task.
+    // The fsync policy is re-read for every batch so CONFIG SET applies live.
+    pub async fn open(path: &Path, config: Arc<Mutex<Config>>) -> io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_aof_writer(file, receiver, config));
+        
+        Ok(AppendOnlyFile { sender, selected_db: None })
+    }
+    
+    // Queue a command run against database `db` for the log. Entries are
+    // written in the order they are queued, so the caller must queue while
+    // still holding whatever lock ordered the command's execution. The returned
+    // receiver completes once the command is durable according to the fsync policy.
+    pub fn append<T: AsRef<[u8]>>(&mut self, db: usize, args: &[T]) -> oneshot::Receiver<()> {
+        if self.selected_db != Some(db) {
+            self.selected_db = Some(db);
+            // Written before the command, so the command's acknowledgement covers it
+            drop(self.send(&["SELECT", &db.to_string()]));
+        }
+        self.send(args)
+    }
+    
+    fn send<T: AsRef<[u8]>>(&self, args: &[T]) -> oneshot::Receiver<()> {
+        let command = Reply::Array(args.iter().map(|arg| Reply::bulk(arg.as_ref())).collect()).encode(Protocol::Resp2);
+        let (written, receiver) = oneshot::channel();
+        // If the writer is gone the receiver just errors out and the caller moves on
+        let _ = self.sender.send(AofEntry { command, written });
+        receiver
+    }
+}
+
+async fn run_aof_writer(
+    mut file: tokio::fs::File,
+    mut entries: mpsc::UnboundedReceiver<AofEntry>,
+    config: Arc<Mutex<Config>>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut unsynced = false;
+    
+    loop {
+        tokio::select! {
+            entry = entries.recv() => {
+                let Some(entry) = entry else { break };
+                
+                // Write everything queued so far in one go
+                let mut batch = vec![entry];
+                while let Ok(entry) = entries.try_recv() {
+                    batch.push(entry);
+                }
+                let mut bytes = Vec::new();
+                for entry in &batch {
+                    bytes.extend_from_slice(&entry.command);
+                }
+                
+                let policy = config.lock().unwrap().appendfsync;
+                let mut result = file.write_all(&bytes).await;
+                if result.is_ok() {
+                    result = file.flush().await;
+                }
+                if result.is_ok() && policy == AppendFsync::Always {
+                    result = file.sync_data().await;
+                } else {
+                    unsynced = true;
+                }
+                
+                match result {
+                    Ok(()) => {
+                        for entry in batch {
+                            let _ = entry.written.send(());
+                        }
+                    }
+                    // Dropping the entries without acknowledging them tells the
+                    // clients their write did not make it to the log
+                    Err(e) => error!("Writing to the append-only file failed: {}", e),
+                }
+            }
+            _ = ticker.tick() => {
+                let policy = config.lock().unwrap().appendfsync;
+                if unsynced && policy == AppendFsync::EverySec {
+                    match file.sync_data().await {
+                        Ok(()) => unsynced = false,
+                        Err(e) => error!("Fsync of the append-only file failed: {}", e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Read the arguments of the commands logged in the append-only file, or None
+// if there is none. An incomplete final command is a write cut short by a
+// crash; like Redis with aof-load-truncated, it is dropped with a warning.
+// Files from before commands were logged as RESP hold one command per line,
+// which parse as inline commands.
+pub fn load_aof(path: &Path) -> io::Result<Option<Vec<protocol::Args>>> {
+    let mut buffer = match fs::read(path) {
+        Ok(bytes) => bytes::BytesMut::from(&bytes[..]),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    
+    let mut commands = Vec::new();
+    loop {
+        // The log only holds commands the server accepted, so no length is refused
+        match protocol::parse_command(&mut buffer, usize::MAX) {
+            Ok(Some((args, _))) if args.is_empty() => {}
+            Ok(Some((args, _))) => commands.push(args),
+            Ok(None) => break,
+            Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, format!("corrupt append-only file: {}", e))),
+        }
+    }
+    if !buffer.is_empty() {
+        warn!("Ignoring truncated command at the end of the append-only file: {:?}", String::from_utf8_lossy(&buffer));
+    }
+    
+    Ok(Some(commands))
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("corrupt snapshot: {}", reason))
+}
+
+// Convert a monotonic deadline into wall-clock Unix milliseconds and back
+pub fn instant_to_unix_ms(instant: Instant) -> u64 {
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let remaining = instant.saturating_duration_since(Instant::now()).as_millis() as u64;
+    now_ms + remaining
+}
+
+// None if the time is too far in the future to represent
+pub fn unix_ms_to_instant(unix_ms: u64) -> Option<Instant> {
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let now = Instant::now();
+    match unix_ms.checked_sub(now_ms) {
+        Some(remaining) => now.checked_add(Duration::from_millis(remaining)),
+        // Already in the past: the key is expired
+        None => Some(now.checked_sub(Duration::from_millis(1)).unwrap_or(now)),
+    }
+}
+
+// Cursor over the snapshot bytes that fails cleanly on truncated input
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        let end = self.pos.checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid("unexpected end of file"))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+    
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    
+    fn take_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+    
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.take_bytes()?).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+    
+    fn value(&mut self) -> io::Result<Value> {
+        match self.byte()? {
+            TYPE_STRING => Ok(Value::String(self.take_bytes()?)),
+            TYPE_LIST => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.u32()? {
+                    list.push_back(self.take_bytes()?);
+                }
+                Ok(Value::List(list))
+            }
+            TYPE_HASH => {
+                let mut hash = HashMap::new();
+                for _ in 0..self.u32()? {
+                    let field = self.string()?;
+                    hash.insert(field, self.take_bytes()?);
+                }
+                Ok(Value::Hash(Hash::from(hash)))
+            }
+            TYPE_SET => {
+                let mut set = HashSet::new();
+                for _ in 0..self.u32()? {
+                    set.insert(self.string()?);
+                }
+                Ok(Value::Set(Set::from(set)))
+            }
+            TYPE_SORTED_SET => {
+                let mut zset = SortedSet::default();
+                for _ in 0..self.u32()? {
+                    let member = self.string()?;
+                    let score = f64::from_le_bytes(self.take(8)?.try_into().unwrap());
+                    if score.is_nan() {
+                        return Err(invalid("sorted set score is NaN"));
+                    }
+                    zset.insert(member, score);
+                }
+                Ok(Value::SortedSet(zset))
+            }
+            value_type => Err(invalid(&format!("unknown value type {}", value_type))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn snapshot_round_trip() {
+        let mut data = Keyspace::default();
+        data.insert("plain".to_string(), RedisValue::new("value".to_string(), None));
+        data.insert("expiring".to_string(), RedisValue::new("soon".to_string(), Some(Instant::now() + Duration::from_secs(100))));
+        data.insert("expired".to_string(), RedisValue::new(Value::String(b"gone".to_vec()), Some(Instant::now() - Duration::from_secs(1))));
+        
+        let mut other = Keyspace::default();
+        let mut zset = SortedSet::default();
+        zset.insert("member".to_string(), 1.5);
+        zset.insert("lowest".to_string(), f64::NEG_INFINITY);
+        other.insert("elsewhere".to_string(), RedisValue::new("value".to_string(), None));
+        other.insert("zset".to_string(), RedisValue::new(zset.clone(), None));
+        let list = VecDeque::from([b"first".to_vec(), b"second".to_vec()]);
+        other.insert("list".to_string(), RedisValue::new(list.clone(), None));
+        let hash = Hash::from_iter([("field".to_string(), b"value".to_vec())]);
+        other.insert("hash".to_string(), RedisValue::new(hash.clone(), None));
+        let set = Set::from_iter(["member".to_string(), "other".to_string()]);
+        other.insert("set".to_string(), RedisValue::new(set.clone(), None));
+        
+        let mut loaded = decode_snapshot(&encode_snapshot(&[data, Keyspace::default(), other]), 3).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert!(loaded[1].is_empty());
+        assert!(loaded[2].get("elsewhere").is_some());
+        assert_eq!(loaded[2].get("zset").unwrap().value, Value::SortedSet(zset));
+        assert_eq!(loaded[2].get("list").unwrap().value, Value::List(list));
+        assert_eq!(loaded[2].get("hash").unwrap().value, Value::Hash(hash));
+        assert_eq!(loaded[2].get("set").unwrap().value, Value::Set(set));
+        let loaded = loaded.swap_remove(0);
+        
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get("plain").unwrap().value, Value::String(b"value".to_vec()));
+        assert!(loaded.get("plain").unwrap().expires_at.is_none());
+        
+        // The TTL survives the round trip, give or take clock rounding
+        let ttl = loaded.get("expiring").unwrap().expires_at.unwrap().duration_since(Instant::now());
+        assert!(ttl > Duration::from_secs(98) && ttl <= Duration::from_secs(100));
+    }
+    
+    #[test]
+    fn corrupt_snapshot_is_rejected() {
+        let mut data = Keyspace::default();
+        data.insert("key".to_string(), RedisValue::new("value".to_string(), None));
+        let bytes = encode_snapshot(&[data]);
+        
+        // Truncated file, wrong magic, unknown version and too many databases
+        assert!(decode_snapshot(&bytes[..bytes.len() - 3], 1).is_err());
+        assert!(decode_snapshot(b"NOPE\x01\xff", 1).is_err());
+        assert!(decode_snapshot(b"RRDB\x09\xff", 1).is_err());
+        assert!(decode_snapshot(&bytes, 0).is_err());
+        assert!(decode_snapshot(b"RRDB\x02\xff", 1).unwrap()[0].is_empty());
+        
+        // Version 1 snapshots load into database 0
+        let v1 = b"RRDB\x01\x01\x01\x00\x00\x00k\x01\x00\x00\x00v\x00\xff";
+        assert_eq!(decode_snapshot(v1, 16).unwrap()[0].get("k").unwrap().value, Value::String(b"v".to_vec()));
+    }
+    
+    #[test]
+    fn aof_truncated_last_command_is_dropped() {
+        let path = std::env::temp_dir().join(format!("redis-aof-test-{}.aof", std::process::id()));
+        
+        assert!(load_aof(&path).unwrap().is_none());
+        
+        fs::write(&path, "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$3\r\n1 2\r\n*2\r\n$3\r\nDEL\r\n$1\r\na\r\n*2\r\n$3\r\nSET").unwrap();
+        let commands = load_aof(&path).unwrap().unwrap();
+        
+        assert_eq!(commands, vec![vec![&b"SET"[..], b"a", b"1 2"], vec![&b"DEL"[..], b"a"]]);
+        
+        // Files from before commands were logged as RESP hold text lines
+        fs::write(&path, "SET a 1\nDEL a\nSET b").unwrap();
+        let commands = load_aof(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        
+        assert_eq!(commands, vec![vec![&b"SET"[..], b"a", b"1"], vec![&b"DEL"[..], b"a"]]);
+    }
+}
@@ -0,0 +1,491 @@
+// Replies and their encodings.
+//
+// Commands produce a `Reply`, which is encoded for the connection it is sent
+// on: RESP2 or RESP3 for clients speaking the Redis protocol, or the plain
+// text protocol for inline commands typed by hand.
+//
+// The text protocol is for display only and can't be parsed back. It loses:
+//   - the difference between simple strings, bulk strings, integers and doubles
+//     (all printed as they are)
+//   - nil and empty arrays, printed as "(nil)" and "(empty list)" just like
+//     strings with that content would be
+//   - errors, printed as a line starting with "ERROR: " (and without the
+//     generic ERR code, for readability)
+//   - nesting: elements of nested arrays are printed one per line, flattened
+//   - bytes that aren't valid UTF-8, and strings containing line breaks
+// RESP3 represents every reply exactly. RESP2 has no double type, so doubles
+// are sent as bulk strings.
+
+use bytes::BytesMut;
+
+#[derive(Debug, Clone)]
+pub enum Reply {
+    // Short status such as OK, never containing a line break
+    Simple(String),
+    // Error code and message, such as "ERR bad value", never containing a line break
+    Error(String),
+    Integer(i64),
+    // Binary-safe string
+    Bulk(Vec<u8>),
+    // Missing value
+    Nil,
+    Array(Vec<Reply>),
+    // Field and value pairs, a flat array of them in RESP2
+    Map(Vec<(Reply, Reply)>),
+    // Out-of-band data such as an invalidation, an array in RESP2
+    Push(Vec<Reply>),
+    // A bulk string in RESP2
+    Double(f64),
+    // Replies sent one after the other, for commands such as SUBSCRIBE that
+    // answer once for each argument. Never nested in an array.
+    Several(Vec<Reply>),
+}
+
+impl PartialEq for Reply {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Reply::Simple(a), Reply::Simple(b)) | (Reply::Error(a), Reply::Error(b)) => a == b,
+            (Reply::Integer(a), Reply::Integer(b)) => a == b,
+            (Reply::Bulk(a), Reply::Bulk(b)) => a == b,
+            (Reply::Nil, Reply::Nil) => true,
+            (Reply::Array(a), Reply::Array(b)) | (Reply::Several(a), Reply::Several(b)) => a == b,
+            (Reply::Map(a), Reply::Map(b)) => a == b,
+            (Reply::Push(a), Reply::Push(b)) => a == b,
+            // Every NaN is the same reply
+            (Reply::Double(a), Reply::Double(b)) => a == b || (a.is_nan() && b.is_nan()),
+            _ => false,
+        }
+    }
+}
+
+// The protocol replies are encoded in for a connection
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Protocol {
+    Text,
+    #[default]
+    Resp2,
+    // Negotiated with HELLO 3
+    Resp3,
+}
+
+impl Reply {
+    pub fn ok() -> Reply {
+        Reply::Simple("OK".to_string())
+    }
+    
+    // An error with the generic ERR code
+    pub fn error(message: impl AsRef<str>) -> Reply {
+        Reply::error_code("ERR", message)
+    }
+    
+    // An error with a specific code, such as EXECABORT, that clients can match on
+    pub fn error_code(code: &str, message: impl AsRef<str>) -> Reply {
+        Reply::Error(format!("{} {}", code, message.as_ref()))
+    }
+    
+    pub fn bulk(value: impl Into<Vec<u8>>) -> Reply {
+        Reply::Bulk(value.into())
+    }
+    
+    pub fn is_error(&self) -> bool {
+        matches!(self, Reply::Error(_))
+    }
+    
+    pub fn encode(&self, protocol: Protocol) -> Vec<u8> {
+        let mut out = Vec::new();
+        match protocol {
+            Protocol::Text => self.encode_text(&mut out),
+            Protocol::Resp2 | Protocol::Resp3 => self.encode_resp(&mut out, protocol),
+        }
+        out
+    }
+    
+    fn encode_text(&self, out: &mut Vec<u8>) {
+        let mut lines = Vec::new();
+        self.text_lines(&mut lines);
+        
+        // An array with nothing to show still needs a line
+        if lines.is_empty() {
+            lines.push(b"(empty list)".to_vec());
+        }
+        for line in lines {
+            out.extend_from_slice(&line);
+            out.push(b'\n');
+        }
+    }
+    
+    fn text_lines(&self, lines: &mut Vec<Vec<u8>>) {
+        match self {
+            Reply::Simple(s) => lines.push(s.as_bytes().to_vec()),
+            Reply::Error(message) => {
+                let message = message.strip_prefix("ERR ").unwrap_or(message);
+                lines.push(format!("ERROR: {}", message).into_bytes());
+            }
+            Reply::Integer(n) => lines.push(n.to_string().into_bytes()),
+            Reply::Bulk(bytes) => lines.push(bytes.clone()),
+            Reply::Nil => lines.push(b"(nil)".to_vec()),
+            Reply::Array(items) | Reply::Push(items) | Reply::Several(items) => {
+                for item in items {
+                    item.text_lines(lines);
+                }
+            }
+            Reply::Map(pairs) => {
+                for (field, value) in pairs {
+                    field.text_lines(lines);
+                    value.text_lines(lines);
+                }
+            }
+            Reply::Double(d) => lines.push(format_double(*d).into_bytes()),
+        }
+    }
+    
+    fn encode_resp(&self, out: &mut Vec<u8>, protocol: Protocol) {
+        match self {
+            Reply::Simple(s) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+            }
+            Reply::Error(message) => {
+                out.push(b'-');
+                out.extend_from_slice(message.as_bytes());
+            }
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}", n).as_bytes()),
+            Reply::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Reply::Nil if protocol == Protocol::Resp3 => out.push(b'_'),
+            Reply::Nil => out.extend_from_slice(b"$-1"),
+            Reply::Array(items) | Reply::Push(items) => {
+                let kind = if matches!(self, Reply::Push(_)) && protocol == Protocol::Resp3 { '>' } else { '*' };
+                out.extend_from_slice(format!("{}{}\r\n", kind, items.len()).as_bytes());
+                for item in items {
+                    item.encode_resp(out, protocol);
+                }
+                // Each item already ended its own line
+                return;
+            }
+            Reply::Map(pairs) => {
+                match protocol {
+                    Protocol::Resp3 => out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes()),
+                    _ => out.extend_from_slice(format!("*{}\r\n", 2 * pairs.len()).as_bytes()),
+                }
+                for (field, value) in pairs {
+                    field.encode_resp(out, protocol);
+                    value.encode_resp(out, protocol);
+                }
+                return;
+            }
+            Reply::Double(d) if protocol == Protocol::Resp3 => {
+                out.extend_from_slice(format!(",{}", format_double(*d)).as_bytes());
+            }
+            Reply::Double(d) => return Reply::bulk(format_double(*d)).encode_resp(out, protocol),
+            Reply::Several(replies) => {
+                for reply in replies {
+                    reply.encode_resp(out, protocol);
+                }
+                return;
+            }
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+// Doubles as Redis prints them: shortest round-tripping form, inf, -inf or nan
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+// A command's arguments, the name first, as the bytes the client sent
+pub type Args = Vec<Vec<u8>>;
+
+// Split the next complete command off the front of the buffer, returning its
+// arguments and the protocol to reply in. A client either sends RESP arrays of
+// bulk strings, answered in RESP2, or inline commands terminated by a newline,
+// answered in the text protocol. Returns None until a whole command has arrived.
+// A bulk string longer than `max_bulk_len` is refused as soon as its length is
+// read, rather than waited for. Arguments are the bytes sent, valid UTF-8 or not.
+pub fn parse_command(buffer: &mut BytesMut, max_bulk_len: usize) -> Result<Option<(Args, Protocol)>, String> {
+    if buffer.first() == Some(&b'*') {
+        let (request, used) = match parse_resp(buffer, max_bulk_len) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(format!("Protocol error: {}", e)),
+        };
+        let Reply::Array(items) = request else {
+            return Err("Protocol error: expected an array of bulk strings".to_string());
+        };
+        
+        let mut args = Vec::new();
+        for item in items {
+            match item {
+                Reply::Bulk(bytes) => args.push(bytes),
+                _ => return Err("Protocol error: expected an array of bulk strings".to_string()),
+            }
+        }
+        let _ = buffer.split_to(used);
+        return Ok(Some((args, Protocol::Resp2)));
+    }
+    
+    let Some(end) = buffer.iter().position(|&b| b == b'\n') else { return Ok(None) };
+    let line = buffer.split_to(end + 1);
+    let args = line[..]
+        .split(u8::is_ascii_whitespace)
+        .filter(|arg| !arg.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect();
+    Ok(Some((args, Protocol::Text)))
+}
+
+// Parse one RESP2 or RESP3 value from the start of `bytes`. Returns the value
+// and the number of bytes it took, None if the value is still incomplete, or
+// an error if the bytes are not valid RESP or hold a bulk string longer than
+// `max_bulk_len`.
+pub fn parse_resp(bytes: &[u8], max_bulk_len: usize) -> Result<Option<(Reply, usize)>, String> {
+    let Some(line_end) = find_crlf(bytes) else { return Ok(None) };
+    let line = std::str::from_utf8(&bytes[1..line_end])
+        .map_err(|_| "invalid UTF-8 in RESP header".to_string())?;
+    let mut consumed = line_end + 2;
+    
+    let reply = match bytes[0] {
+        b'+' => Reply::Simple(line.to_string()),
+        b'-' => Reply::Error(line.to_string()),
+        b':' => Reply::Integer(parse_number(line)?),
+        b',' => Reply::Double(line.parse::<f64>().map_err(|_| format!("invalid double '{}'", line))?),
+        b'_' => Reply::Nil,
+        b'$' => {
+            let len = parse_number(line)?;
+            if len == -1 {
+                Reply::Nil
+            } else {
+                let len = usize::try_from(len).ok()
+                    .filter(|&len| len <= max_bulk_len)
+                    .ok_or_else(|| format!("invalid bulk length {}", len))?;
+                let end = consumed + len;
+                if bytes.len() < end + 2 {
+                    return Ok(None);
+                }
+                if &bytes[end..end + 2] != b"\r\n" {
+                    return Err("bulk string not terminated by CRLF".to_string());
+                }
+                let value = bytes[consumed..end].to_vec();
+                consumed = end + 2;
+                Reply::Bulk(value)
+            }
+        }
+        b'*' | b'%' | b'>' => {
+            let len = parse_number(line)?;
+            if len == -1 && bytes[0] == b'*' {
+                Reply::Nil
+            } else {
+                let len = usize::try_from(len).map_err(|_| format!("invalid aggregate length {}", len))?;
+                // A map's length counts pairs
+                let count = if bytes[0] == b'%' { 2 * len } else { len };
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    match parse_resp(&bytes[consumed..], max_bulk_len)? {
+                        Some((item, used)) => {
+                            items.push(item);
+                            consumed += used;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                match bytes[0] {
+                    b'%' => {
+                        let mut items = items.into_iter();
+                        Reply::Map(std::iter::from_fn(|| Some((items.next()?, items.next()?))).collect())
+                    }
+                    b'>' => Reply::Push(items),
+                    _ => Reply::Array(items),
+                }
+            }
+        }
+        other => return Err(format!("unknown RESP type '{}'", other as char)),
+    };
+    
+    Ok(Some((reply, consumed)))
+}
+
+fn find_crlf(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(2).position(|window| window == b"\r\n")
+}
+
+fn parse_number(line: &str) -> Result<i64, String> {
+    line.parse::<i64>().map_err(|_| format!("invalid number '{}'", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    
+    const NO_LIMIT: usize = usize::MAX;
+    
+    fn round_trip(reply: &Reply, protocol: Protocol) -> Reply {
+        let bytes = reply.encode(protocol);
+        let (parsed, used) = parse_resp(&bytes, NO_LIMIT).unwrap().unwrap();
+        assert_eq!(used, bytes.len());
+        parsed
+    }
+    
+    // What a reply turns into after a RESP2 round trip, where doubles become
+    // bulk strings, and maps and pushes arrays
+    fn as_resp2(reply: &Reply) -> Reply {
+        match reply {
+            Reply::Double(d) => Reply::bulk(format_double(*d)),
+            Reply::Array(items) | Reply::Push(items) => Reply::Array(items.iter().map(as_resp2).collect()),
+            Reply::Map(pairs) => Reply::Array(pairs.iter().flat_map(|(field, value)| [as_resp2(field), as_resp2(value)]).collect()),
+            other => other.clone(),
+        }
+    }
+    
+    fn random_line(rng: &mut StdRng) -> String {
+        let len = rng.random_range(0..12);
+        (0..len)
+            .map(|_| rng.random_range(b' '..=b'~') as char)
+            .collect()
+    }
+    
+    fn random_reply(rng: &mut StdRng, depth: u32) -> Reply {
+        let kinds = if depth == 0 { 6 } else { 9 };
+        match rng.random_range(0..kinds) {
+            0 => Reply::Simple(random_line(rng)),
+            1 => Reply::Error(random_line(rng)),
+            2 => Reply::Integer(match rng.random_range(0..4) {
+                0 => i64::MIN,
+                1 => i64::MAX,
+                _ => rng.random(),
+            }),
+            3 => {
+                // Any bytes at all, including CR, LF and invalid UTF-8
+                let len = rng.random_range(0..40);
+                Reply::Bulk((0..len).map(|_| rng.random()).collect())
+            }
+            4 => Reply::Nil,
+            5 => Reply::Double(match rng.random_range(0..6) {
+                0 => f64::INFINITY,
+                1 => f64::NEG_INFINITY,
+                2 => f64::NAN,
+                3 => f64::from_bits(rng.random()),
+                _ => rng.random_range(-1e6..1e6),
+            }),
+            6 => {
+                let len = rng.random_range(0..5);
+                Reply::Array((0..len).map(|_| random_reply(rng, depth - 1)).collect())
+            }
+            7 => {
+                let len = rng.random_range(0..5);
+                Reply::Push((0..len).map(|_| random_reply(rng, depth - 1)).collect())
+            }
+            _ => {
+                let len = rng.random_range(0..3);
+                Reply::Map((0..len).map(|_| (random_reply(rng, depth - 1), random_reply(rng, depth - 1))).collect())
+            }
+        }
+    }
+    
+    #[test]
+    fn random_replies_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..5000 {
+            let reply = random_reply(&mut rng, 3);
+            assert_eq!(round_trip(&reply, Protocol::Resp3), reply);
+            assert_eq!(round_trip(&reply, Protocol::Resp2), as_resp2(&reply));
+        }
+    }
+    
+    #[test]
+    fn resp_encodings() {
+        let reply = Reply::Array(vec![
+            Reply::ok(),
+            Reply::error("bad"),
+            Reply::Integer(-3),
+            Reply::bulk("hi"),
+            Reply::Nil,
+            Reply::Double(1.5),
+        ]);
+        
+        assert_eq!(reply.encode(Protocol::Resp2), b"*6\r\n+OK\r\n-ERR bad\r\n:-3\r\n$2\r\nhi\r\n$-1\r\n$3\r\n1.5\r\n");
+        assert_eq!(reply.encode(Protocol::Resp3), b"*6\r\n+OK\r\n-ERR bad\r\n:-3\r\n$2\r\nhi\r\n_\r\n,1.5\r\n");
+        assert_eq!(Reply::Double(f64::NEG_INFINITY).encode(Protocol::Resp3), b",-inf\r\n");
+        
+        let map = Reply::Map(vec![(Reply::bulk("a"), Reply::Integer(1)), (Reply::bulk("b"), Reply::Double(2.5))]);
+        assert_eq!(map.encode(Protocol::Resp2), b"*4\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$3\r\n2.5\r\n");
+        assert_eq!(map.encode(Protocol::Resp3), b"%2\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n,2.5\r\n");
+        assert_eq!(map.encode(Protocol::Text), b"a\n1\nb\n2.5\n");
+        
+        let push = Reply::Push(vec![Reply::bulk("invalidate"), Reply::Nil]);
+        assert_eq!(push.encode(Protocol::Resp2), b"*2\r\n$10\r\ninvalidate\r\n$-1\r\n");
+        assert_eq!(push.encode(Protocol::Resp3), b">2\r\n$10\r\ninvalidate\r\n_\r\n");
+        
+        let several = Reply::Several(vec![Reply::Integer(1), Reply::Array(vec![Reply::ok()])]);
+        assert_eq!(several.encode(Protocol::Resp2), b":1\r\n*1\r\n+OK\r\n");
+        assert_eq!(several.encode(Protocol::Text), b"1\nOK\n");
+    }
+    
+    #[test]
+    fn text_encoding() {
+        assert_eq!(Reply::ok().encode(Protocol::Text), b"OK\n");
+        assert_eq!(Reply::error("bad").encode(Protocol::Text), b"ERROR: bad\n");
+        assert_eq!(Reply::error_code("EXECABORT", "bad").encode(Protocol::Text), b"ERROR: EXECABORT bad\n");
+        assert_eq!(Reply::Nil.encode(Protocol::Text), b"(nil)\n");
+        assert_eq!(Reply::Array(vec![]).encode(Protocol::Text), b"(empty list)\n");
+        
+        // Nested arrays are flattened, one element per line
+        let scan = Reply::Array(vec![
+            Reply::bulk("0"),
+            Reply::Array(vec![Reply::bulk("a"), Reply::bulk("b")]),
+        ]);
+        assert_eq!(scan.encode(Protocol::Text), b"0\na\nb\n");
+        let nothing = Reply::Array(vec![Reply::Array(vec![])]);
+        assert_eq!(nothing.encode(Protocol::Text), b"(empty list)\n");
+    }
+    
+    #[test]
+    fn commands_in_either_protocol() {
+        let mut buffer = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$5\r\na key\r\nPING\r\n  \n*1\r\n$4\r\nPI"[..]);
+        
+        let resp = (vec![b"GET".to_vec(), b"a key".to_vec()], Protocol::Resp2);
+        assert_eq!(parse_command(&mut buffer, NO_LIMIT), Ok(Some(resp)));
+        assert_eq!(parse_command(&mut buffer, NO_LIMIT), Ok(Some((vec![b"PING".to_vec()], Protocol::Text))));
+        assert_eq!(parse_command(&mut buffer, NO_LIMIT), Ok(Some((vec![], Protocol::Text))));
+        
+        // The rest of the buffer stays put until the whole command is in
+        assert_eq!(parse_command(&mut buffer, NO_LIMIT), Ok(None));
+        assert_eq!(&buffer[..], b"*1\r\n$4\r\nPI");
+        
+        assert!(parse_command(&mut BytesMut::from(&b"*1\r\n:1\r\n"[..]), NO_LIMIT).is_err());
+        
+        // Bytes that aren't valid UTF-8 are kept as they are
+        let binary = (vec![b"\xff\xfe".to_vec()], Protocol::Resp2);
+        assert_eq!(parse_command(&mut BytesMut::from(&b"*1\r\n$2\r\n\xff\xfe\r\n"[..]), NO_LIMIT), Ok(Some(binary)));
+        let binary = (vec![b"GET".to_vec(), b"\xff".to_vec()], Protocol::Text);
+        assert_eq!(parse_command(&mut BytesMut::from(&b"GET \xff\n"[..]), NO_LIMIT), Ok(Some(binary)));
+    }
+    
+    #[test]
+    fn incomplete_and_invalid_input() {
+        let bytes = Reply::Array(vec![Reply::bulk("GET"), Reply::bulk("key")]).encode(Protocol::Resp2);
+        
+        // Every strict prefix is incomplete rather than an error
+        for end in 0..bytes.len() {
+            assert_eq!(parse_resp(&bytes[..end], NO_LIMIT), Ok(None), "{}", end);
+        }
+        
+        assert!(parse_resp(b"?what\r\n", NO_LIMIT).is_err());
+        assert!(parse_resp(b"$-5\r\n", NO_LIMIT).is_err());
+        assert!(parse_resp(b"$3\r\nabcde\r\n", NO_LIMIT).is_err());
+        assert!(parse_resp(b":12x\r\n", NO_LIMIT).is_err());
+        
+        // A bulk string over the limit is refused before any of it arrives
+        assert!(parse_resp(b"$11\r\n", 10).is_err());
+        assert_eq!(parse_resp(b"$10\r\n", 10), Ok(None));
+        assert!(parse_command(&mut BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$11\r\n"[..]), 10).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+// Pub/Sub: channels and the clients subscribed to them, by name or by glob
+// pattern.
+//
+// Every subscribed client has a queue its connection task takes messages
+// from, so publishing never waits on a slow subscriber. A subscriber that lets
+// its queue fill up is dropped instead, like Redis disconnects a subscriber
+// whose output buffer passes its limit: its queue is closed, which ends the
+// connection once the messages already queued are written out.
+
+use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc;
+
+use crate::glob::glob_match;
+use crate::protocol::Reply;
+
+// Messages that may wait for a subscriber before it is dropped
+const QUEUE_LEN: usize = 1024;
+
+struct Subscriber {
+    queue: mpsc::Sender<Reply>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+}
+
+impl Subscriber {
+    // Channels and patterns together, as subscription confirmations count them
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+#[derive(Default)]
+pub struct PubSub {
+    // By client ID
+    subscribers: HashMap<u64, Subscriber>,
+    // IDs of the clients subscribed to each channel
+    channels: HashMap<String, HashSet<u64>>,
+    // IDs of the clients subscribed to each pattern
+    patterns: HashMap<String, HashSet<u64>>,
+}
+
+impl PubSub {
+    // Subscribe a client to a channel and return how many channels and
+    // patterns it is subscribed to now. A client subscribing for the first
+    // time also gets the receiving end of its queue.
+    pub fn subscribe(&mut self, client: u64, channel: &str) -> (usize, Option<mpsc::Receiver<Reply>>) {
+        self.join(client, channel, false)
+    }
+    
+    // Like subscribe, for the channels matching a glob pattern
+    pub fn psubscribe(&mut self, client: u64, pattern: &str) -> (usize, Option<mpsc::Receiver<Reply>>) {
+        self.join(client, pattern, true)
+    }
+    
+    // Unsubscribe a client from a channel and return how many channels and
+    // patterns it is still subscribed to. Its queue stays open for when it
+    // subscribes again.
+    pub fn unsubscribe(&mut self, client: u64, channel: &str) -> usize {
+        self.leave(client, channel, false)
+    }
+    
+    pub fn punsubscribe(&mut self, client: u64, pattern: &str) -> usize {
+        self.leave(client, pattern, true)
+    }
+    
+    // The channels a client is subscribed to, in no particular order
+    pub fn channels_of(&self, client: u64) -> Vec<String> {
+        self.subscribers.get(&client)
+            .map(|subscriber| subscriber.channels.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    
+    // The patterns a client is subscribed to, in no particular order
+    pub fn patterns_of(&self, client: u64) -> Vec<String> {
+        self.subscribers.get(&client)
+            .map(|subscriber| subscriber.patterns.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    
+    pub fn subscription_count(&self, client: u64) -> usize {
+        self.subscribers.get(&client).map_or(0, Subscriber::count)
+    }
+    
+    // Queue a message for every subscriber of the channel and of each pattern
+    // matching it, returning how many messages were queued. A client
+    // subscribed both ways gets the message once for each.
+    pub fn publish(&mut self, channel: &str, message: impl AsRef<[u8]>) -> usize {
+        let message = message.as_ref();
+        let mut deliveries = Vec::new();
+        for client in self.channels.get(channel).into_iter().flatten() {
+            let reply = Reply::Array(vec![Reply::bulk("message"), Reply::bulk(channel), Reply::bulk(message)]);
+            deliveries.push((*client, reply));
+        }
+        for (pattern, clients) in &self.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for client in clients {
+                let reply = Reply::Array(vec![
+                    Reply::bulk("pmessage"),
+                    Reply::bulk(pattern.clone()),
+                    Reply::bulk(channel),
+                    Reply::bulk(message),
+                ]);
+                deliveries.push((*client, reply));
+            }
+        }
+        
+        let mut received = 0;
+        let mut dropped = Vec::new();
+        for (client, reply) in deliveries {
+            match self.subscribers[&client].queue.try_send(reply) {
+                Ok(()) => received += 1,
+                // Fallen too far behind, or already disconnected
+                Err(_) => dropped.push(client),
+            }
+        }
+        
+        for client in dropped {
+            self.remove_client(client);
+        }
+        received
+    }
+    
+    // The receiving end of a client's queue, created without subscribing it to
+    // anything, for messages such as invalidations. None if the client already has one.
+    pub fn queue(&mut self, client: u64) -> Option<mpsc::Receiver<Reply>> {
+        let mut receiver = None;
+        self.subscribers.entry(client).or_insert_with(|| {
+            let (queue, messages) = mpsc::channel(QUEUE_LEN);
+            receiver = Some(messages);
+            Subscriber { queue, channels: HashSet::new(), patterns: HashSet::new() }
+        });
+        receiver
+    }
+    
+    pub fn is_subscribed_to(&self, client: u64, channel: &str) -> bool {
+        self.subscribers.get(&client).is_some_and(|subscriber| subscriber.channels.contains(channel))
+    }
+    
+    // Queue a message for one client, if it has a queue. Like publish, a
+    // client that fell too far behind is dropped.
+    pub fn send(&mut self, client: u64, reply: Reply) {
+        let Some(subscriber) = self.subscribers.get(&client) else {
+            return;
+        };
+        if subscriber.queue.try_send(reply).is_err() {
+            self.remove_client(client);
+        }
+    }
+    
+    // Forget a client and everything it is subscribed to, closing its queue
+    pub fn remove_client(&mut self, client: u64) {
+        let Some(subscriber) = self.subscribers.remove(&client) else {
+            return;
+        };
+        for channel in &subscriber.channels {
+            forget(&mut self.channels, client, channel);
+        }
+        for pattern in &subscriber.patterns {
+            forget(&mut self.patterns, client, pattern);
+        }
+    }
+    
+    fn join(&mut self, client: u64, name: &str, pattern: bool) -> (usize, Option<mpsc::Receiver<Reply>>) {
+        let receiver = self.queue(client);
+        let subscriber = self.subscribers.get_mut(&client).unwrap();
+        
+        let (names, subscribed) = if pattern {
+            (&mut subscriber.patterns, &mut self.patterns)
+        } else {
+            (&mut subscriber.channels, &mut self.channels)
+        };
+        names.insert(name.to_string());
+        subscribed.entry(name.to_string()).or_default().insert(client);
+        (subscriber.count(), receiver)
+    }
+    
+    fn leave(&mut self, client: u64, name: &str, pattern: bool) -> usize {
+        let Some(subscriber) = self.subscribers.get_mut(&client) else {
+            return 0;
+        };
+        
+        let (names, subscribed) = if pattern {
+            (&mut subscriber.patterns, &mut self.patterns)
+        } else {
+            (&mut subscriber.channels, &mut self.channels)
+        };
+        if names.remove(name) {
+            forget(subscribed, client, name);
+        }
+        subscriber.count()
+    }
+}
+
+// Take a client off the subscribers of a channel or pattern, forgetting the
+// ones nobody is subscribed to anymore
+fn forget(subscribed: &mut HashMap<String, HashSet<u64>>, client: u64, name: &str) {
+    if let Some(clients) = subscribed.get_mut(name) {
+        clients.remove(&client);
+        if clients.is_empty() {
+            subscribed.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn messages_reach_subscribers() {
+        let mut pubsub = PubSub::default();
+        let (count, messages) = pubsub.subscribe(1, "news");
+        assert_eq!(count, 1);
+        let mut messages = messages.unwrap();
+        
+        // Only the first subscription opens a queue
+        assert_eq!(pubsub.subscribe(1, "sport").0, 2);
+        assert!(pubsub.subscribe(1, "sport").1.is_none());
+        
+        assert_eq!(pubsub.publish("news", "hello"), 1);
+        assert_eq!(pubsub.publish("weather", "rain"), 0);
+        let expected = Reply::Array(vec![Reply::bulk("message"), Reply::bulk("news"), Reply::bulk("hello")]);
+        assert_eq!(messages.try_recv().unwrap(), expected);
+        assert!(messages.try_recv().is_err());
+        
+        assert_eq!(pubsub.unsubscribe(1, "news"), 1);
+        assert_eq!(pubsub.publish("news", "hello"), 0);
+        assert_eq!(pubsub.channels_of(1), ["sport"]);
+    }
+    
+    #[test]
+    fn patterns_match_channels() {
+        let mut pubsub = PubSub::default();
+        let mut messages = pubsub.subscribe(1, "news.tech").1.unwrap();
+        assert_eq!(pubsub.psubscribe(1, "news.*").0, 2);
+        let _other = pubsub.subscribe(2, "news.tech").1.unwrap();
+        
+        // Both of the first client's subscriptions match
+        assert_eq!(pubsub.publish("news.tech", "hello"), 3);
+        assert_eq!(messages.try_recv().unwrap(), Reply::Array(vec![Reply::bulk("message"), Reply::bulk("news.tech"), Reply::bulk("hello")]));
+        let expected = Reply::Array(vec![Reply::bulk("pmessage"), Reply::bulk("news.*"), Reply::bulk("news.tech"), Reply::bulk("hello")]);
+        assert_eq!(messages.try_recv().unwrap(), expected);
+        assert_eq!(pubsub.publish("news.sport", "goal"), 1);
+        assert_eq!(pubsub.publish("weather", "rain"), 0);
+        
+        assert_eq!(pubsub.punsubscribe(1, "news.*"), 1);
+        assert_eq!(pubsub.publish("news.sport", "goal"), 0);
+        assert!(pubsub.patterns_of(1).is_empty());
+        
+        pubsub.psubscribe(1, "*");
+        pubsub.remove_client(1);
+        assert!(pubsub.patterns.is_empty());
+    }
+    
+    #[test]
+    fn slow_subscribers_are_dropped() {
+        let mut pubsub = PubSub::default();
+        let mut messages = pubsub.subscribe(1, "news").1.unwrap();
+        
+        for _ in 0..QUEUE_LEN {
+            assert_eq!(pubsub.publish("news", "hello"), 1);
+        }
+        assert_eq!(pubsub.publish("news", "hello"), 0);
+        assert_eq!(pubsub.subscription_count(1), 0);
+        
+        // What was queued is still delivered, then the queue is closed
+        for _ in 0..QUEUE_LEN {
+            assert!(messages.try_recv().is_ok());
+        }
+        assert_eq!(messages.try_recv(), Err(mpsc::error::TryRecvError::Disconnected));
+    }
+}
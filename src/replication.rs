@@ -0,0 +1,111 @@
+// Leader/follower replication. A replica connects to its leader and sends
+// SYNC, the leader answers with a snapshot of its keyspace as a bulk string,
+// and from then on passes on every write it makes, as the commands its
+// append-only file would log, so the replica applies exactly the same
+// changes. Like Redis's original SYNC there is no partial resync: a replica
+// that loses its connection, or falls too far behind, starts over with a new
+// snapshot.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::protocol::{Args, Protocol, Reply};
+
+// Writes a replica may fall behind by before the leader drops it
+pub const BACKLOG: usize = 1 << 14;
+
+// A write to pass on: the command as logged and the database it ran against
+pub struct Write {
+    pub db: usize,
+    pub args: Args,
+}
+
+// The leader's side of a replica connection
+pub struct ReplicaFeed {
+    writes: broadcast::Receiver<Arc<Write>>,
+    // Database the commands sent so far apply to, so a SELECT is only sent
+    // when a write targets a different one
+    selected_db: Option<usize>,
+}
+
+impl ReplicaFeed {
+    pub fn new(writes: broadcast::Receiver<Arc<Write>>) -> Self {
+        ReplicaFeed { writes, selected_db: None }
+    }
+    
+    // The bytes to send for the next write, or None once the replica fell too
+    // far behind to catch up
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        let write = match self.writes.recv().await {
+            Ok(write) => write,
+            Err(broadcast::error::RecvError::Lagged(_)) => return None,
+            Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+        };
+        
+        let mut bytes = Vec::new();
+        if self.selected_db != Some(write.db) {
+            self.selected_db = Some(write.db);
+            bytes.extend(encode_command(&["SELECT", &write.db.to_string()]));
+        }
+        bytes.extend(encode_command(&write.args));
+        Some(bytes)
+    }
+}
+
+// A command as a client sends it in RESP
+pub fn encode_command<T: AsRef<[u8]>>(args: &[T]) -> Vec<u8> {
+    Reply::Array(args.iter().map(|arg| Reply::bulk(arg.as_ref())).collect()).encode(Protocol::Resp2)
+}
+
+// The replica's side: the leader it follows and the task following it,
+// which stops when this is dropped
+pub struct Leader {
+    pub host: String,
+    pub port: u16,
+    // Set while the task is connected and has loaded the leader's snapshot
+    pub link_up: Arc<AtomicBool>,
+    pub task: JoinHandle<()>,
+}
+
+impl Leader {
+    pub fn is_link_up(&self) -> bool {
+        self.link_up.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn writes_select_their_database_once() {
+        let (sender, receiver) = broadcast::channel(4);
+        let mut feed = ReplicaFeed::new(receiver);
+        for (db, key) in [(0, "a"), (0, "b"), (3, "c")] {
+            sender.send(Arc::new(Write { db, args: vec![b"SET".to_vec(), key.as_bytes().to_vec(), b"1".to_vec()] })).ok();
+        }
+        
+        let mut expected = encode_command(&["SELECT", "0"]);
+        expected.extend(encode_command(&["SET", "a", "1"]));
+        assert_eq!(feed.next().await, Some(expected));
+        assert_eq!(feed.next().await, Some(encode_command(&["SET", "b", "1"])));
+        let mut expected = encode_command(&["SELECT", "3"]);
+        expected.extend(encode_command(&["SET", "c", "1"]));
+        assert_eq!(feed.next().await, Some(expected));
+        
+        // A replica that falls too far behind is given up on
+        for _ in 0..5 {
+            sender.send(Arc::new(Write { db: 3, args: vec![b"DEL".to_vec(), b"a".to_vec()] })).ok();
+        }
+        assert_eq!(feed.next().await, None);
+    }
+}
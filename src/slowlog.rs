@@ -0,0 +1,131 @@
+// SLOWLOG: the most recent commands that took longer than
+// slowlog-log-slower-than, newest first, in a log holding at most
+// slowlog-max-len of them. Like Redis, long argument lists and long arguments
+// are cut short so a huge command doesn't pin its arguments in memory.
+
+use std::collections::VecDeque;
+
+use crate::protocol::Reply;
+
+// Arguments and bytes per argument kept for each entry, as in Redis
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+pub struct SlowLogEntry {
+    // Unique and increasing, never reused even after SLOWLOG RESET
+    id: u64,
+    // Unix time in seconds the command started
+    time: u64,
+    duration_micros: u64,
+    args: Vec<String>,
+    // Address and name of the client that ran it, the name empty if it has none
+    addr: String,
+    name: String,
+}
+
+impl SlowLogEntry {
+    // The entry as SLOWLOG GET reports it: ID, Unix time, duration in
+    // microseconds, arguments, client address and client name
+    pub fn reply(&self) -> Reply {
+        Reply::Array(vec![
+            Reply::Integer(self.id as i64),
+            Reply::Integer(self.time as i64),
+            Reply::Integer(self.duration_micros as i64),
+            Reply::Array(self.args.iter().map(|arg| Reply::bulk(arg.clone())).collect()),
+            Reply::bulk(self.addr.clone()),
+            Reply::bulk(self.name.clone()),
+        ])
+    }
+}
+
+#[derive(Default)]
+pub struct SlowLog {
+    // Newest first
+    entries: VecDeque<SlowLogEntry>,
+    next_id: u64,
+}
+
+impl SlowLog {
+    // Log a command, dropping the oldest entries past `max_len`
+    pub fn push(&mut self, time: u64, duration_micros: u64, args: &[String], addr: &str, name: &str, max_len: usize) {
+        let mut kept: Vec<String> = args.iter().take(MAX_ARGS).map(|arg| shorten(arg)).collect();
+        if args.len() > MAX_ARGS {
+            kept[MAX_ARGS - 1] = format!("... ({} more arguments)", args.len() - MAX_ARGS + 1);
+        }
+        
+        self.entries.push_front(SlowLogEntry {
+            id: self.next_id,
+            time,
+            duration_micros,
+            args: kept,
+            addr: addr.to_string(),
+            name: name.to_string(),
+        });
+        self.next_id += 1;
+        self.entries.truncate(max_len);
+    }
+    
+    // The `count` newest entries, newest first
+    pub fn newest(&self, count: usize) -> impl Iterator<Item = &SlowLogEntry> {
+        self.entries.iter().take(count)
+    }
+    
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+fn shorten(arg: &str) -> String {
+    if arg.len() <= MAX_ARG_LEN {
+        return arg.to_string();
+    }
+    // Cut on a character boundary so the argument stays valid UTF-8
+    let end = (0..=MAX_ARG_LEN).rev().find(|&end| arg.is_char_boundary(end)).unwrap();
+    format!("{}... ({} more bytes)", &arg[..end], arg.len() - end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+    
+    #[test]
+    fn oldest_entries_are_dropped() {
+        let mut log = SlowLog::default();
+        for i in 0..5 {
+            log.push(100 + i, 20_000, &args(&["DEBUG", "SLEEP", "0.02"]), "127.0.0.1:5000", "", 3);
+        }
+        assert_eq!(log.len(), 3);
+        let ids: Vec<u64> = log.newest(10).map(|entry| entry.id).collect();
+        assert_eq!(ids, [4, 3, 2]);
+        assert_eq!(log.newest(1).count(), 1);
+        
+        // IDs keep counting up across a reset
+        log.reset();
+        assert_eq!(log.len(), 0);
+        log.push(200, 1, &args(&["PING"]), "addr", "", 3);
+        assert_eq!(log.newest(1).next().unwrap().id, 5);
+    }
+    
+    #[test]
+    fn long_commands_are_cut_short() {
+        let mut log = SlowLog::default();
+        let many: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        log.push(0, 1, &many, "addr", "", 10);
+        let entry = log.newest(1).next().unwrap();
+        assert_eq!(entry.args.len(), MAX_ARGS);
+        assert_eq!(entry.args[MAX_ARGS - 2], "30");
+        assert_eq!(entry.args[MAX_ARGS - 1], "... (9 more arguments)");
+        
+        let long = "é".repeat(100);
+        assert_eq!(shorten(&long), format!("{}... (72 more bytes)", "é".repeat(64)));
+        assert_eq!(shorten("short"), "short");
+    }
+}
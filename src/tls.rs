@@ -0,0 +1,56 @@
+// TLS for clients connecting on tls-port. The certificate chain and private
+// key are read from the PEM files tls-cert-file and tls-key-file once, when
+// the server starts, and every TLS connection is served with them. Once the
+// handshake is done, a TLS client is served like any other.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+// An acceptor presenting the certificate chain in `cert_file`, signed for
+// by the key in `key_file`
+pub fn acceptor(cert_file: &str, key_file: &str) -> Result<TlsAcceptor, String> {
+    let open = |path: &str| File::open(path).map(BufReader::new).map_err(|e| format!("Could not open {}: {}", path, e));
+    
+    let certs = rustls_pemfile::certs(&mut open(cert_file)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Could not read certificates from {}: {}", cert_file, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_file));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_file)?)
+        .map_err(|e| format!("Could not read the private key from {}: {}", key_file, e))?
+        .ok_or_else(|| format!("No private key found in {}", key_file))?;
+    
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path: PathBuf = std::env::temp_dir().join(format!("redis-tls-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+    
+    #[test]
+    fn bad_files_are_refused() {
+        let missing = temp_file("missing", "");
+        std::fs::remove_file(&missing).unwrap();
+        assert!(acceptor(&missing, &missing).err().unwrap().starts_with("Could not open"));
+        
+        let empty = temp_file("empty", "");
+        assert_eq!(acceptor(&empty, &empty).err(), Some(format!("No certificates found in {}", empty)));
+        std::fs::remove_file(&empty).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+// Client-side caching: the keys each client with CLIENT TRACKING on has read,
+// so it can be told to drop them from its cache once they change. Like Redis
+// in its default mode, a key is remembered from the first read since it last
+// changed, forgotten once the invalidation goes out, and the same for every
+// database.
+
+use std::collections::{HashMap, HashSet};
+
+// Where a tracking client's invalidations go
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Target {
+    // Pushed on its own connection, which needs RESP3
+    Push(u64),
+    // Published to another client subscribed to __redis__:invalidate
+    Redirect(u64),
+}
+
+// Keys a target has to drop, or None to drop everything after a flush
+#[derive(PartialEq, Debug)]
+pub struct Invalidation {
+    pub target: Target,
+    pub keys: Option<Vec<String>>,
+}
+
+#[derive(Default)]
+pub struct Tracking {
+    // By client ID
+    clients: HashMap<u64, Target>,
+    // IDs of the clients that read each key since it last changed
+    keys: HashMap<String, HashSet<u64>>,
+}
+
+impl Tracking {
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+    
+    pub fn is_tracking(&self, client: u64) -> bool {
+        self.clients.contains_key(&client)
+    }
+    
+    pub fn enable(&mut self, client: u64, target: Target) {
+        self.clients.insert(client, target);
+    }
+    
+    pub fn disable(&mut self, client: u64) {
+        if self.clients.remove(&client).is_none() {
+            return;
+        }
+        self.keys.retain(|_, clients| {
+            clients.remove(&client);
+            !clients.is_empty()
+        });
+    }
+    
+    // Remember that a client read the keys, if it is tracking
+    pub fn read<'a>(&mut self, client: u64, keys: impl IntoIterator<Item = &'a str>) {
+        if !self.is_tracking(client) {
+            return;
+        }
+        for key in keys {
+            self.keys.entry(key.to_string()).or_default().insert(client);
+        }
+    }
+    
+    // The invalidations for keys that changed, one per target with all its keys
+    pub fn invalidate<'a>(&mut self, changed: impl IntoIterator<Item = &'a str>) -> Vec<Invalidation> {
+        let mut by_target: Vec<(Target, Vec<String>)> = Vec::new();
+        for key in changed {
+            let Some(clients) = self.keys.remove(key) else {
+                continue;
+            };
+            for client in clients {
+                let Some(&target) = self.clients.get(&client) else {
+                    continue;
+                };
+                match by_target.iter_mut().find(|(existing, _)| *existing == target) {
+                    Some((_, keys)) => keys.push(key.to_string()),
+                    None => by_target.push((target, vec![key.to_string()])),
+                }
+            }
+        }
+        by_target.into_iter()
+            .map(|(target, keys)| Invalidation { target, keys: Some(keys) })
+            .collect()
+    }
+    
+    // After a flush every tracking client drops its whole cache, whatever it read
+    pub fn flush(&mut self) -> Vec<Invalidation> {
+        self.keys.clear();
+        let mut targets: Vec<Target> = Vec::new();
+        for target in self.clients.values() {
+            if !targets.contains(target) {
+                targets.push(*target);
+            }
+        }
+        targets.into_iter().map(|target| Invalidation { target, keys: None }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn keys_are_invalidated_once() {
+        let mut tracking = Tracking::default();
+        tracking.enable(1, Target::Push(1));
+        tracking.enable(2, Target::Redirect(9));
+        tracking.read(1, ["a", "b"]);
+        tracking.read(2, ["a"]);
+        // Only tracking clients are remembered
+        tracking.read(3, ["a", "c"]);
+        
+        let mut invalidations = tracking.invalidate(["a", "c"]);
+        invalidations.sort_by_key(|invalidation| matches!(invalidation.target, Target::Redirect(_)));
+        assert_eq!(invalidations, [
+            Invalidation { target: Target::Push(1), keys: Some(vec!["a".to_string()]) },
+            Invalidation { target: Target::Redirect(9), keys: Some(vec!["a".to_string()]) },
+        ]);
+        
+        // Until it is read again
+        assert!(tracking.invalidate(["a"]).is_empty());
+        tracking.disable(1);
+        assert!(tracking.invalidate(["b"]).is_empty());
+    }
+    
+    #[test]
+    fn flush_reaches_every_client() {
+        let mut tracking = Tracking::default();
+        tracking.enable(1, Target::Redirect(9));
+        tracking.enable(2, Target::Redirect(9));
+        tracking.read(1, ["a"]);
+        
+        assert_eq!(tracking.flush(), [Invalidation { target: Target::Redirect(9), keys: None }]);
+        assert!(tracking.invalidate(["a"]).is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+// Rough per-member bookkeeping cost on top of the member bytes: the map slot,
+// the tree node, the score stored twice and the second copy of the member
+const MEMBER_OVERHEAD: usize = 48;
+
+// A score, which is never NaN, ordered so it can key the tree
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Parse a score, accepting anything that reads as a float except NaN,
+// including inf, +inf and -inf
+pub fn parse_score(text: &str) -> Option<f64> {
+    text.parse::<f64>().ok().filter(|score| !score.is_nan())
+}
+
+// One end of a score range, such as ZRANGEBYSCORE's min and max
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ScoreBound {
+    score: f64,
+    // Set by a "(" prefix: the score itself is outside the range
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    // Parse a bound such as 1.5, (1.5, -inf or +inf
+    pub fn parse(text: &str) -> Option<Self> {
+        let (text, exclusive) = match text.strip_prefix('(') {
+            Some(rest) => (rest, true),
+            None => (text, false),
+        };
+        Some(ScoreBound { score: parse_score(text)?, exclusive })
+    }
+    
+    fn is_above(&self, score: f64) -> bool {
+        if self.exclusive { score > self.score } else { score >= self.score }
+    }
+    
+    fn is_below(&self, score: f64) -> bool {
+        if self.exclusive { score < self.score } else { score <= self.score }
+    }
+}
+
+// The value of a sorted set key: members with a score each, ordered by score
+// and then by the member's bytes for equal scores, as in Redis. The map finds
+// a member's score and the tree walks members in order.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    ordered: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+    
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+    
+    // Set a member's score, returning whether the member is new
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        // -0 and 0 are the same score, and must sort as one
+        let score = if score == 0.0 { 0.0 } else { score };
+        
+        match self.scores.insert(member.clone(), score) {
+            Some(old) => {
+                self.ordered.remove(&(Score(old), member.clone()));
+                self.ordered.insert((Score(score), member));
+                false
+            }
+            None => {
+                self.ordered.insert((Score(score), member));
+                true
+            }
+        }
+    }
+    
+    // Remove a member, returning whether it was in the set
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.ordered.remove(&(Score(score), member.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+    
+    // A member's 0-based position in the set's order, lowest score first
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.ordered.range(..(Score(score), member.to_string())).count())
+    }
+    
+    // Members and their scores, lowest score first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&str, f64)> + ExactSizeIterator {
+        self.ordered.iter().map(|(score, member)| (member.as_str(), score.0))
+    }
+    
+    // Members whose score is between `min` and `max`, lowest score first
+    pub fn range_by_score(&self, min: ScoreBound, max: ScoreBound) -> impl Iterator<Item = (&str, f64)> {
+        // The empty string sorts before every other member with the same score
+        self.ordered.range((Score(min.score), String::new())..)
+            .skip_while(move |(score, _)| !min.is_above(score.0))
+            .take_while(move |(score, _)| max.is_below(score.0))
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+    
+    // Approximate memory used by the members and their scores
+    pub fn memory(&self) -> usize {
+        self.scores.keys().map(|member| 2 * member.len() + MEMBER_OVERHEAD).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn ordered_by_score_then_member() {
+        let mut zset = SortedSet::default();
+        assert!(zset.insert("b".to_string(), 1.0));
+        assert!(zset.insert("a".to_string(), 1.0));
+        assert!(zset.insert("low".to_string(), f64::NEG_INFINITY));
+        assert!(zset.insert("zero".to_string(), -0.0));
+        
+        let members: Vec<&str> = zset.iter().map(|(member, _)| member).collect();
+        assert_eq!(members, ["low", "zero", "a", "b"]);
+        for (rank, member) in members.iter().enumerate() {
+            assert_eq!(zset.rank(member), Some(rank));
+        }
+        assert_eq!(zset.rank("missing"), None);
+        
+        // Updating a score moves the member instead of adding it again
+        assert!(!zset.insert("low".to_string(), 2.5));
+        assert_eq!(zset.len(), 4);
+        assert_eq!(zset.score("low"), Some(2.5));
+        assert_eq!(zset.iter().last(), Some(("low", 2.5)));
+    }
+    
+    fn range<'a>(zset: &'a SortedSet, min: &str, max: &str) -> Vec<&'a str> {
+        let (min, max) = (ScoreBound::parse(min).unwrap(), ScoreBound::parse(max).unwrap());
+        zset.range_by_score(min, max).map(|(member, _)| member).collect()
+    }
+    
+    #[test]
+    fn ranges_by_score() {
+        let mut zset = SortedSet::default();
+        for (member, score) in [("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)] {
+            zset.insert(member.to_string(), score);
+        }
+        assert_eq!(range(&zset, "2", "3"), ["b", "c", "d"]);
+        assert_eq!(range(&zset, "(2", "3"), ["d"]);
+        assert_eq!(range(&zset, "1", "(2"), ["a"]);
+        assert_eq!(range(&zset, "(1", "(3"), ["b", "c"]);
+        assert_eq!(range(&zset, "-inf", "+inf"), ["a", "b", "c", "d"]);
+        assert_eq!(range(&zset, "(2", "(2"), Vec::<&str>::new());
+        assert_eq!(range(&zset, "3", "1"), Vec::<&str>::new());
+        
+        assert!(zset.remove("b"));
+        assert!(!zset.remove("b"));
+        assert_eq!(range(&zset, "2", "2"), ["c"]);
+        
+        assert_eq!(ScoreBound::parse("(nan"), None);
+        assert_eq!(ScoreBound::parse("(("), None);
+    }
+    
+    #[test]
+    fn scores_must_be_floats() {
+        assert_eq!(parse_score("1.5"), Some(1.5));
+        assert_eq!(parse_score("-inf"), Some(f64::NEG_INFINITY));
+        assert_eq!(parse_score("+inf"), Some(f64::INFINITY));
+        assert_eq!(parse_score("nan"), None);
+        assert_eq!(parse_score("one"), None);
+        assert_eq!(parse_score(""), None);
+    }
+}
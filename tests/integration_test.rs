@@ -1,50 +1,158 @@
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
-use std::process::{Child, Command};
+use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 struct TestServer {
     server: Child,
+    port: u16,
+    // Working directory of the server process, so files it writes by default stay isolated
+    workdir: PathBuf,
 }
 
 impl TestServer {
     fn new() -> Self {
+        TestServer::with_env(&[])
+    }
+    
+    // Start a server with extra environment variables, e.g. config overrides
+    fn with_env(envs: &[(&str, &str)]) -> Self {
+        TestServer::start(envs, &[], Stdio::inherit())
+    }
+    
+    // Start a server logging at `level`, its log kept for take_log
+    fn with_log(level: &str) -> Self {
+        TestServer::start(&[("RUST_LOG", level)], &[], Stdio::piped())
+    }
+    
+    // Start a server with command-line arguments. Its port is given with
+    // --port, while REDIS_PORT names one it couldn't bind, so the server only
+    // starts if the argument wins.
+    fn with_args(args: &[&str]) -> Self {
+        TestServer::start(&[("REDIS_PORT", "1")], args, Stdio::inherit())
+    }
+    
+    fn start(envs: &[(&str, &str)], args: &[&str], stderr: Stdio) -> Self {
+        // Let the OS pick a free port so tests can run in parallel
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Failed to find a free port")
+            .port();
+        
         // Start the Redis server
-        let server = Command::new("cargo")
-            .args(["run", "--quiet"])
-            .env("REDIS_PORT", "6380")
+        let workdir = temp_dir(&format!("server-{}", port));
+        let mut command = Command::new(env!("CARGO_BIN_EXE_redis"));
+        command.current_dir(&workdir)
+            .env("REDIS_PORT", port.to_string())
             .env("RUST_LOG", "error")
-            .spawn()
+            .envs(envs.iter().copied())
+            .stderr(stderr);
+        if !args.is_empty() {
+            command.arg("--port").arg(port.to_string()).args(args);
+        }
+        let server = command.spawn()
             .expect("Failed to start Redis server");
         
-        // Wait for the server to start up
-        sleep(Duration::from_secs(1));
+        // Wait for the server to start accepting connections
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(Instant::now() < deadline, "Redis server did not start in time");
+            sleep(Duration::from_millis(50));
+        }
         
-        TestServer { server }
+        TestServer { server, port, workdir }
     }
     
     fn client(&self) -> TcpStream {
-        TcpStream::connect("127.0.0.1:6380").expect("Failed to connect to Redis server")
+        TcpStream::connect(("127.0.0.1", self.port)).expect("Failed to connect to Redis server")
+    }
+    
+    // Stop a server started with_log and return everything it logged
+    fn take_log(&mut self) -> String {
+        let _ = self.server.kill();
+        let mut log = String::new();
+        self.server.stderr.take().expect("Server log wasn't kept").read_to_string(&mut log).unwrap();
+        log
+    }
+    
+    // Wait for the server process to exit on its own
+    fn wait_for_exit(&mut self) -> ExitStatus {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(status) = self.server.try_wait().unwrap() {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "Redis server did not exit in time");
+            sleep(Duration::from_millis(50));
+        }
     }
 }
 
 impl Drop for TestServer {
     fn drop(&mut self) {
-        // Terminate the server
-        self.server.kill().expect("Failed to kill Redis server");
+        // Terminate the server, unless a test already shut it down
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+        let _ = fs::remove_dir_all(&self.workdir);
     }
 }
 
-fn send_command(stream: &mut TcpStream, command: &str) -> String {
+// A fresh, empty directory for the files a test's server writes
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("redis-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn send_command(stream: &mut (impl Read + Write), command: &str) -> String {
     stream.write_all(command.as_bytes()).unwrap();
     stream.flush().unwrap();
     
-    let mut reader = BufReader::new(stream);
-    let mut response = String::new();
-    reader.read_line(&mut response).unwrap();
+    // Replies such as KEYS span several lines, so keep reading until the
+    // server has sent a complete, newline-terminated response
+    let mut response = Vec::new();
+    let mut chunk = [0; 4096];
+    while !response.ends_with(b"\n") {
+        let n = stream.read(&mut chunk).unwrap();
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&chunk[..n]);
+    }
+    
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+// A command encoded as a RESP array of bulk strings
+fn resp_command(args: &[&str]) -> String {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    command
+}
+
+// Send a RESP command and read a reply of the expected length
+fn send_resp(stream: &mut (impl Read + Write), args: &[&str], expected: &str) {
+    stream.write_all(resp_command(args).as_bytes()).unwrap();
+    
+    let mut response = vec![0; expected.len()];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(String::from_utf8_lossy(&response), expected, "{:?}", args);
+}
+
+// Read a message published to a subscribed connection, in RESP
+fn expect_message(stream: &mut TcpStream, channel: &str, message: &str) {
+    let expected = format!("*3\r\n$7\r\nmessage\r\n${}\r\n{}\r\n${}\r\n{}\r\n", channel.len(), channel, message.len(), message);
     
-    response
+    let mut response = vec![0; expected.len()];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(String::from_utf8_lossy(&response), expected);
 }
 
 #[test]
@@ -55,6 +163,12 @@ fn test_basic_commands() {
     // Test PING
     let response = send_command(&mut client, "PING\n");
     assert_eq!(response, "PONG\n");
+    send_resp(&mut client, &["PING"], "+PONG\r\n");
+    
+    // With a message, PING echoes it back as a bulk string
+    send_resp(&mut client, &["PING", "token"], "$5\r\ntoken\r\n");
+    send_resp(&mut client, &["PING", ""], "$0\r\n\r\n");
+    send_resp(&mut client, &["PING", "a", "b"], "-ERR wrong number of arguments for 'ping' command\r\n");
     
     // Test SET/GET
     let response = send_command(&mut client, "SET testkey testvalue\n");
@@ -75,6 +189,153 @@ fn test_basic_commands() {
     assert_eq!(response, "(nil)\n");
 }
 
+#[test]
+fn test_keys_and_values_keep_their_case() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Command names and option keywords ignore case
+    let response = send_command(&mut client, "set ex EX\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "Get ex\n");
+    assert_eq!(response, "EX\n");
+    let response = send_command(&mut client, "TTL ex\n");
+    assert_eq!(response, "-1\n");
+    
+    // But keys and values don't, even spelled like keywords
+    let response = send_command(&mut client, "GET EX\n");
+    assert_eq!(response, "(nil)\n");
+    send_command(&mut client, "SET EX px\n");
+    let response = send_command(&mut client, "GET EX\n");
+    assert_eq!(response, "px\n");
+    let response = send_command(&mut client, "GET ex\n");
+    assert_eq!(response, "EX\n");
+    
+    send_command(&mut client, "SET px EX ex 100 nx\n");
+    let response = send_command(&mut client, "GET px\n");
+    assert_eq!(response, "EX\n");
+    let response = send_command(&mut client, "TTL px\n");
+    assert_eq!(response, "100\n");
+    
+    send_command(&mut client, "HSET hash GET SET\n");
+    let response = send_command(&mut client, "hget hash GET\n");
+    assert_eq!(response, "SET\n");
+    let response = send_command(&mut client, "HGET hash get\n");
+    assert_eq!(response, "(nil)\n");
+}
+
+#[test]
+fn test_getdel() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET token abc123\n");
+    let response = send_command(&mut client, "GETDEL token\n");
+    assert_eq!(response, "abc123\n");
+    let response = send_command(&mut client, "EXISTS token\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "GETDEL token\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // Other types are left alone
+    send_command(&mut client, "RPUSH list a\n");
+    let response = send_command(&mut client, "GETDEL list\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "EXISTS list\n");
+    assert_eq!(response, "1\n");
+}
+
+#[test]
+fn test_getex() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Without an option it is GET and leaves the TTL alone
+    send_command(&mut client, "SET session data\n");
+    let response = send_command(&mut client, "GETEX session\n");
+    assert_eq!(response, "data\n");
+    let response = send_command(&mut client, "TTL session\n");
+    assert_eq!(response, "-1\n");
+    
+    let response = send_command(&mut client, "GETEX session EX 100\n");
+    assert_eq!(response, "data\n");
+    let response = send_command(&mut client, "TTL session\n");
+    assert_eq!(response, "100\n");
+    send_command(&mut client, "GETEX session PX 50000\n");
+    let response = send_command(&mut client, "TTL session\n");
+    assert_eq!(response, "50\n");
+    
+    let response = send_command(&mut client, "GETEX session PERSIST\n");
+    assert_eq!(response, "data\n");
+    let response = send_command(&mut client, "TTL session\n");
+    assert_eq!(response, "-1\n");
+    
+    // An absolute time already passed deletes the key once it is read
+    let response = send_command(&mut client, "GETEX session EXAT 1\n");
+    assert_eq!(response, "data\n");
+    let response = send_command(&mut client, "EXISTS session\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "GETEX session PERSIST\n");
+    assert_eq!(response, "(nil)\n");
+    
+    send_command(&mut client, "SET session data\n");
+    for command in ["GETEX session EX 0\n", "GETEX session EX\n", "GETEX session EX 10 PERSIST\n", "GETEX session KEEPTTL\n"] {
+        let response = send_command(&mut client, command);
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    send_command(&mut client, "RPUSH list a\n");
+    let response = send_command(&mut client, "GETEX list PERSIST\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+}
+
+#[test]
+fn test_unlink() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // A sorted set big enough to be freed in the background
+    let mut zadd = vec!["ZADD", "big"];
+    let members: Vec<String> = (0..200).map(|i| i.to_string()).collect();
+    for member in &members {
+        zadd.extend([member.as_str(), member.as_str()]);
+    }
+    send_resp(&mut client, &zadd, ":200\r\n");
+    send_command(&mut client, "SET small value\n");
+    
+    // Counted like DEL counts keys: missing ones don't count
+    let response = send_command(&mut client, "UNLINK big small missing\n");
+    assert_eq!(response, "2\n");
+    for key in ["big", "small"] {
+        let response = send_command(&mut client, &format!("EXISTS {}\n", key));
+        assert_eq!(response, "0\n");
+    }
+    let response = send_command(&mut client, "UNLINK big\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_del_and_exists_take_several_keys() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    for key in ["a", "b", "c"] {
+        send_command(&mut client, &format!("SET {} value\n", key));
+    }
+    
+    // A key given twice is counted twice
+    let response = send_command(&mut client, "EXISTS a b missing a\n");
+    assert_eq!(response, "3\n");
+    
+    // Only the keys that existed are counted, and each only once
+    let response = send_command(&mut client, "DEL a missing b a\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "EXISTS a b c\n");
+    assert_eq!(response, "1\n");
+    
+    let response = send_command(&mut client, "DEL\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
 #[test]
 fn test_expiration() {
     let server = TestServer::new();
@@ -118,4 +379,4083 @@ fn test_keys_and_flushall() {
     // Verify all keys are gone
     let response = send_command(&mut client, "KEYS *\n");
     assert_eq!(response, "(empty list)\n");
-} 
\ No newline at end of file
+} 
+
+#[test]
+fn test_flushdb() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET key1 value1\n");
+    send_command(&mut client, "SET key2 value2 EX 100\n");
+    
+    let response = send_command(&mut client, "FLUSHDB\n");
+    assert_eq!(response, "OK\n");
+    
+    let response = send_command(&mut client, "KEYS *\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    let response = send_command(&mut client, "GET key1\n");
+    assert_eq!(response, "(nil)\n");
+}
+
+#[test]
+fn test_config_get_set() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // A value set at runtime should be reported back by CONFIG GET
+    let response = send_command(&mut client, "CONFIG SET maxmemory 1048576\n");
+    assert_eq!(response, "OK\n");
+    
+    let response = send_command(&mut client, "CONFIG GET maxmemory\n");
+    assert_eq!(response, "maxmemory\n1048576\n");
+    
+    // CONFIG GET * dumps every parameter
+    let response = send_command(&mut client, "CONFIG GET *\n");
+    assert!(response.contains("maxmemory\n1048576\n"));
+    assert!(response.contains("maxclients\n"));
+    assert!(response.contains("hz\n"));
+    
+    // Invalid values and unknown parameters are rejected
+    let response = send_command(&mut client, "CONFIG SET hz 0\n");
+    assert!(response.starts_with("ERROR"));
+    
+    let response = send_command(&mut client, "CONFIG SET nosuchparam 1\n");
+    assert!(response.starts_with("ERROR"));
+    
+    let response = send_command(&mut client, "CONFIG GET nosuchparam\n");
+    assert_eq!(response, "(empty list)\n");
+}
+
+#[test]
+fn test_socket_options() {
+    // Clients are served the same with Nagle's algorithm and keepalives on or off
+    for (nodelay, keepalive) in [("yes", "60"), ("no", "0")] {
+        let server = TestServer::with_env(&[("REDIS_TCP_NODELAY", nodelay), ("REDIS_TCP_KEEPALIVE", keepalive)]);
+        let mut client = server.client();
+        
+        let response = send_command(&mut client, "PING\n");
+        assert_eq!(response, "PONG\n");
+        let response = send_command(&mut client, "CONFIG GET tcp-nodelay\n");
+        assert_eq!(response, format!("tcp-nodelay\n{}\n", nodelay));
+    }
+}
+
+#[test]
+fn test_command_line_options() {
+    // The server only comes up on the port given with --port
+    let server = TestServer::with_args(&["--password=secret", "--maxmemory", "1mb"]);
+    let mut client = server.client();
+    
+    send_resp(&mut client, &["CONFIG", "GET", "maxmemory"], "-NOAUTH Authentication required.\r\n");
+    send_resp(&mut client, &["AUTH", "secret"], "+OK\r\n");
+    let response = send_command(&mut client, "CONFIG GET maxmemory\n");
+    assert_eq!(response, "maxmemory\n1048576\n");
+}
+
+#[test]
+fn test_bind_address() {
+    let server = TestServer::with_env(&[("REDIS_BIND", "127.0.0.1")]);
+    let mut client = server.client();
+    let response = send_command(&mut client, "CONFIG GET bind\n");
+    assert_eq!(response, "bind\n127.0.0.1\n");
+    let response = send_command(&mut client, "CONFIG SET bind 0.0.0.0\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    // An address that doesn't parse stops the server before it binds anything
+    let run = |envs: &[(&str, &str)], args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_redis")).envs(envs.iter().copied()).args(args).output().unwrap()
+    };
+    for output in [run(&[("REDIS_BIND", "not-an-address")], &[]), run(&[], &["--bind", "300.0.0.1"])] {
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Invalid bind address"), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_help_and_version() {
+    let run = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_redis")).args(args).output().unwrap();
+    
+    let output = run(&["--version"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("redis {}\n", env!("CARGO_PKG_VERSION")));
+    
+    let output = run(&["--help"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("--port PORT"));
+    
+    // Mistakes are reported with the usage, without starting the server
+    for args in [&["--port", "http"][..], &["--verbose"], &["--maxmemory", "lots"]] {
+        let output = run(args);
+        assert!(!output.status.success(), "{:?}", args);
+        assert!(!output.stderr.is_empty(), "{:?}", args);
+    }
+}
+
+#[test]
+fn test_unix_socket() {
+    let server = TestServer::with_env(&[("REDIS_UNIXSOCKET", "redis.sock")]);
+    let socket_path = server.workdir.join("redis.sock");
+    let mut client = UnixStream::connect(&socket_path).expect("Failed to connect to the Unix socket");
+    
+    let response = send_command(&mut client, "PING\n");
+    assert_eq!(response, "PONG\n");
+    send_resp(&mut client, &["PING"], "+PONG\r\n");
+    
+    // Both listeners serve the same data
+    let response = send_command(&mut client, "SET key value\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut server.client(), "GET key\n");
+    assert_eq!(response, "value\n");
+    
+    let response = send_command(&mut client, "CONFIG GET unixsocket\n");
+    assert!(response.contains("redis.sock"), "{}", response);
+}
+
+#[test]
+fn test_tls() {
+    use std::sync::Arc;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    
+    // A self-signed certificate for localhost, which the client trusts
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = temp_dir("tls");
+    let (cert_file, key_file) = (dir.join("redis.crt"), dir.join("redis.key"));
+    fs::write(&cert_file, certified.cert.pem()).unwrap();
+    fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+    
+    let tls_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+    let server = TestServer::with_env(&[
+        ("REDIS_TLS_PORT", &tls_port),
+        ("REDIS_TLS_CERT_FILE", cert_file.to_str().unwrap()),
+        ("REDIS_TLS_KEY_FILE", key_file.to_str().unwrap()),
+    ]);
+    
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from(certified.cert.der().to_vec())).unwrap();
+    let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let connection = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    let socket = TcpStream::connect(format!("127.0.0.1:{}", tls_port)).expect("Failed to connect to the TLS port");
+    let mut client = StreamOwned::new(connection, socket);
+    
+    send_resp(&mut client, &["PING"], "+PONG\r\n");
+    
+    // Both listeners serve the same data
+    send_resp(&mut client, &["SET", "key", "value"], "+OK\r\n");
+    let response = send_command(&mut server.client(), "GET key\n");
+    assert_eq!(response, "value\n");
+    
+    // Clients that don't speak TLS are dropped without taking the port down
+    let mut plain = TcpStream::connect(format!("127.0.0.1:{}", tls_port)).unwrap();
+    plain.write_all(b"PING\r\n").unwrap();
+    let mut buffer = Vec::new();
+    let _ = plain.read_to_end(&mut buffer);
+    assert!(!buffer.starts_with(b"+PONG"));
+    send_resp(&mut client, &["GET", "key"], "$5\r\nvalue\r\n");
+    
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_tls_needs_a_certificate() {
+    // Refused before anything is bound
+    let dir = temp_dir("tls-no-certificate");
+    let output = Command::new(env!("CARGO_BIN_EXE_redis"))
+        .current_dir(&dir)
+        .env("REDIS_PORT", "1")
+        .env("REDIS_TLS_PORT", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("tls-port requires tls-cert-file and tls-key-file"), "{}", stderr);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_config_hz_speeds_up_expiration() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Sweep for expired keys 20 times per second instead of once
+    let response = send_command(&mut client, "CONFIG SET hz 20\n");
+    assert_eq!(response, "OK\n");
+    
+    send_command(&mut client, "SET expkey value EX 1\n");
+    
+    // DBSIZE doesn't expire keys itself, so the key only disappears once
+    // the background sweep has run
+    sleep(Duration::from_millis(1500));
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_expiration_sweep_runs_when_keys_are_due() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // The sweep sleeps until the key is due rather than waking up on a fixed
+    // schedule, and DBSIZE doesn't expire keys itself, so a prompt drop to 0
+    // means the sweep ran right on time
+    send_command(&mut client, "SET expkey value EX 3\n");
+    sleep(Duration::from_millis(2500));
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "1\n");
+    
+    sleep(Duration::from_millis(800));
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "0\n");
+    
+    // A key added with a sooner expiry than the one the sweep is waiting for
+    // wakes it up early
+    send_command(&mut client, "SET slow value EX 100\n");
+    send_command(&mut client, "PSETEX fast 200 value\n");
+    sleep(Duration::from_millis(1300));
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "1\n");
+}
+
+#[test]
+fn test_debug_object() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "RPUSH list a bb ccc\n");
+    let response = send_command(&mut client, "DEBUG OBJECT list\n");
+    let fields: Vec<&str> = response.trim_end().split(' ').collect();
+    assert_eq!(fields[..2], ["refcount:1", "encoding:listpack"], "{}", response);
+    assert!(fields[2].strip_prefix("serializedlength:").unwrap().parse::<usize>().unwrap() > 6, "{}", response);
+    assert_eq!(fields[3..], ["lru_seconds_idle:0", "elements:3"], "{}", response);
+    
+    // Strings have no element count
+    send_command(&mut client, "SET counter 12\n");
+    let response = send_command(&mut client, "DEBUG OBJECT counter\n");
+    assert!(response.starts_with("refcount:1 encoding:int serializedlength:"), "{}", response);
+    assert!(response.ends_with(" lru_seconds_idle:0\n"), "{}", response);
+    
+    send_resp(&mut client, &["DEBUG", "OBJECT", "missing"], "-ERR no such key\r\n");
+    send_resp(&mut client, &["DEBUG", "OBJECT"], "-ERR DEBUG OBJECT requires a key\r\n");
+}
+
+#[test]
+fn test_debug_set_active_expire() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // With the sweep off, an expired key stays until something touches it
+    let response = send_command(&mut client, "DEBUG SET-ACTIVE-EXPIRE 0\n");
+    assert_eq!(response, "OK\n");
+    send_command(&mut client, "PSETEX key 100 value\n");
+    sleep(Duration::from_millis(1300));
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "1\n");
+    
+    let response = send_command(&mut client, "GET key\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "0\n");
+    
+    // Switched back on, the sweep picks up keys that expired meanwhile
+    send_command(&mut client, "PSETEX key 100 value\n");
+    sleep(Duration::from_millis(300));
+    send_command(&mut client, "DEBUG SET-ACTIVE-EXPIRE 1\n");
+    sleep(Duration::from_millis(1300));
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, "DEBUG SET-ACTIVE-EXPIRE yes\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_debug_log_reports_command_outcomes() {
+    let mut server = TestServer::with_log("debug");
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "SET key value\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "GET missing\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "INCR key\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    let log = server.take_log();
+    for (command, status) in [("set", "ok"), ("get", "nil"), ("incr", "error")] {
+        let line = log.lines()
+            .find(|line| line.contains(&format!("command={} ", command)))
+            .unwrap_or_else(|| panic!("No log line for {} in {}", command, log));
+        assert!(line.contains(&format!("status={} elapsed_us=", status)), "{}", line);
+        assert!(line.contains(" client="), "{}", line);
+    }
+}
+
+#[test]
+fn test_expired_keys_look_the_same_to_every_read() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // With the sweep off, each key is first looked at by a different command,
+    // which all have to agree it is gone
+    send_command(&mut client, "DEBUG SET-ACTIVE-EXPIRE 0\n");
+    for key in ["get", "exists", "ttl", "type"] {
+        send_command(&mut client, &format!("PSETEX {} 50 value\n", key));
+    }
+    sleep(Duration::from_millis(200));
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "4\n");
+    
+    let response = send_command(&mut client, "GET get\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "EXISTS exists\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "TTL ttl\n");
+    assert_eq!(response, "-2\n");
+    let response = send_command(&mut client, "TYPE type\n");
+    assert_eq!(response, "none\n");
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "0\n");
+    
+    // And a key that hasn't expired yet is there for all of them
+    send_command(&mut client, "PSETEX key 5000 value\n");
+    let response = send_command(&mut client, "GET key\n");
+    assert_eq!(response, "value\n");
+    let response = send_command(&mut client, "EXISTS key\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "PTTL key\n");
+    let remaining: i64 = response.trim().parse().unwrap();
+    assert!((0..=5000).contains(&remaining), "{}", response);
+    let response = send_command(&mut client, "TYPE key\n");
+    assert_eq!(response, "string\n");
+}
+
+#[test]
+fn test_debug_sleep() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let mut other = server.client();
+    
+    let start = Instant::now();
+    client.write_all(b"DEBUG SLEEP 0.5\n").unwrap();
+    
+    // Other clients are served while it sleeps
+    let response = send_command(&mut other, "PING\n");
+    assert_eq!(response, "PONG\n");
+    assert!(start.elapsed() < Duration::from_millis(500));
+    
+    let response = send_command(&mut client, "");
+    assert_eq!(response, "OK\n");
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    
+    for command in ["DEBUG SLEEP\n", "DEBUG SLEEP -1\n", "DEBUG SLEEP soon\n"] {
+        let response = send_command(&mut client, command);
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    
+    send_command(&mut client, "MULTI\n");
+    let response = send_command(&mut client, "DEBUG SLEEP 1\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    send_command(&mut client, "DISCARD\n");
+    
+    let response = send_command(&mut client, "DEBUG JMAP\n");
+    assert_eq!(response, "OK\n");
+}
+
+#[test]
+fn test_debug_latency_spike() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "LATENCY HISTORY command\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    let response = send_command(&mut client, "DEBUG LATENCY-SPIKE command 250\n");
+    assert_eq!(response, "OK\n");
+    
+    // The spike shows up as a "<unix time> <latency>" sample for the event
+    let response = send_command(&mut client, "LATENCY HISTORY command\n");
+    let fields: Vec<&str> = response.split_whitespace().collect();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields[1], "250");
+    
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let time: u64 = fields[0].parse().unwrap();
+    assert!(now.abs_diff(time) <= 5);
+    
+    // LATENCY LATEST reports the event with its latest and max latency
+    let response = send_command(&mut client, "LATENCY LATEST\n");
+    assert!(response.starts_with("command "));
+    assert!(response.ends_with(" 250 250\n"));
+    
+    // Other events are unaffected
+    let response = send_command(&mut client, "LATENCY HISTORY expire-cycle\n");
+    assert_eq!(response, "(empty list)\n");
+}
+
+#[test]
+fn test_command_latency() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Nothing is sampled until a threshold is set
+    send_command(&mut client, "DEBUG SLEEP 0.2\n");
+    let response = send_command(&mut client, "LATENCY HISTORY debug\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    let response = send_command(&mut client, "CONFIG SET latency-monitor-threshold 100\n");
+    assert_eq!(response, "OK\n");
+    send_command(&mut client, "DEBUG SLEEP 0.2\n");
+    send_command(&mut client, "GET key\n");
+    
+    let response = send_command(&mut client, "LATENCY HISTORY debug\n");
+    let fields: Vec<&str> = response.split_whitespace().collect();
+    assert_eq!(fields.len(), 2, "{}", response);
+    let latency_ms: u64 = fields[1].parse().unwrap();
+    assert!((200..1000).contains(&latency_ms), "{}", latency_ms);
+    let response = send_command(&mut client, "LATENCY LATEST\n");
+    assert!(response.starts_with(&format!("debug {} {} ", fields[0], latency_ms)), "{}", response);
+    let response = send_command(&mut client, "LATENCY HISTORY get\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    // Every call lands in its command's histogram, slow or not
+    let response = send_command(&mut client, "LATENCY HISTOGRAM debug get\n");
+    let lines: Vec<&str> = response.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", response);
+    assert!(lines[0].starts_with("debug 2 "), "{}", lines[0]);
+    assert!(lines[0].ends_with(":2"), "{}", lines[0]);
+    assert!(lines[1].starts_with("get 1 "), "{}", lines[1]);
+    
+    let response = send_command(&mut client, "LATENCY RESET\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "LATENCY LATEST\n");
+    assert_eq!(response, "(empty list)\n");
+    let response = send_command(&mut client, "LATENCY RESET debug\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_slowlog() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // The default threshold is 10ms
+    send_command(&mut client, "GET key\n");
+    let response = send_command(&mut client, "SLOWLOG LEN\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, "CONFIG SET slowlog-log-slower-than 100000\n");
+    assert_eq!(response, "OK\n");
+    send_command(&mut client, "CLIENT SETNAME sleeper\n");
+    send_command(&mut client, "DEBUG SLEEP 0.2\n");
+    send_command(&mut client, "SET key value\n");
+    
+    // Flattened by the text protocol: ID, time, duration, arguments, address and name
+    let response = send_command(&mut client, "SLOWLOG GET\n");
+    let lines: Vec<&str> = response.lines().collect();
+    assert_eq!(lines.len(), 8, "{}", response);
+    assert_eq!(lines[0], "0");
+    let duration: u64 = lines[2].parse().unwrap();
+    assert!((200_000..1_000_000).contains(&duration), "{}", duration);
+    assert_eq!(&lines[3..6], ["DEBUG", "SLEEP", "0.2"]);
+    assert_eq!(lines[6], client.local_addr().unwrap().to_string());
+    assert_eq!(lines[7], "sleeper");
+    
+    let response = send_command(&mut client, "SLOWLOG LEN\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "SLOWLOG RESET\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "SLOWLOG GET\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    // The log keeps the newest entries up to slowlog-max-len
+    send_command(&mut client, "CONFIG SET slowlog-log-slower-than 0\n");
+    send_command(&mut client, "CONFIG SET slowlog-max-len 2\n");
+    for _ in 0..3 {
+        send_command(&mut client, "PING\n");
+    }
+    let response = send_command(&mut client, "SLOWLOG LEN\n");
+    assert_eq!(response, "2\n");
+    
+    // The newest entry is the SLOWLOG LEN just logged
+    let response = send_command(&mut client, "SLOWLOG GET 1\n");
+    let lines: Vec<&str> = response.lines().collect();
+    assert_eq!(&lines[3..5], ["SLOWLOG", "LEN"], "{}", response);
+}
+
+// Extract the value of a "field:value" line from an INFO report
+fn info_field<'a>(info: &'a str, field: &str) -> Option<&'a str> {
+    info.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+}
+
+#[test]
+fn test_info() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET key1 value1\n");
+    send_command(&mut client, "SET key2 value2\n");
+    send_command(&mut client, "SET key3 value3 EX 100\n");
+    
+    let info = send_command(&mut client, "INFO\n");
+    assert!(info.contains("# Server\n"));
+    assert!(info.contains("# Clients\n"));
+    assert!(info.contains("# Keyspace\n"));
+    
+    let clients: u64 = info_field(&info, "connected_clients").unwrap().parse().unwrap();
+    assert!(clients >= 1);
+    
+    let commands: u64 = info_field(&info, "total_commands_processed").unwrap().parse().unwrap();
+    assert!(commands >= 4);
+    
+    assert!(info_field(&info, "uptime_in_seconds").is_some());
+    
+    // The keyspace line reflects the current DBSIZE
+    let dbsize = send_command(&mut client, "DBSIZE\n");
+    let keyspace = info_field(&info, "db0").unwrap();
+    assert!(keyspace.starts_with(&format!("keys={},expires=1,", dbsize.trim())));
+    
+    // A single section can be requested on its own
+    let info = send_command(&mut client, "INFO clients\n");
+    assert!(info.starts_with("# Clients\n"));
+    assert!(!info.contains("# Server"));
+}
+
+#[test]
+fn test_scan_count_one_terminates() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let total = 500;
+    for i in 0..total {
+        send_command(&mut client, &format!("SET key:{} value\n", i));
+    }
+    
+    // Walk the whole keyspace one key of work per call
+    let mut seen = std::collections::HashSet::new();
+    let mut cursor = "0".to_string();
+    let mut calls = 0;
+    loop {
+        let response = send_command(&mut client, &format!("SCAN {} COUNT 1\n", cursor));
+        let mut lines = response.lines();
+        cursor = lines.next().unwrap().to_string();
+        seen.extend(lines.map(|key| key.to_string()));
+        
+        calls += 1;
+        assert!(calls <= total + 1, "SCAN did not terminate");
+        if cursor == "0" {
+            break;
+        }
+    }
+    
+    assert_eq!(seen.len(), total);
+}
+
+#[test]
+fn test_scan_match() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET user:1 a\n");
+    send_command(&mut client, "SET user:2 b\n");
+    send_command(&mut client, "SET session:1 c\n");
+    
+    // A single call with a large enough COUNT covers the whole keyspace
+    let response = send_command(&mut client, "SCAN 0 MATCH user:* COUNT 100\n");
+    let mut lines: Vec<&str> = response.lines().collect();
+    assert_eq!(lines.remove(0), "0");
+    lines.sort();
+    assert_eq!(lines, ["user:1", "user:2"]);
+    
+    // KEYS uses the same glob matching
+    let response = send_command(&mut client, "KEYS session:?\n");
+    assert_eq!(response, "session:1\n");
+    
+    let response = send_command(&mut client, "SCAN notacursor\n");
+    assert!(response.starts_with("ERROR"));
+}
+
+#[test]
+fn test_collection_scans() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let total = 50;
+    let fields: Vec<String> = (0..total).map(|i| format!("field:{} value:{}", i, i)).collect();
+    send_command(&mut client, &format!("HSET hash {}\n", fields.join(" ")));
+    
+    // Reassemble the hash a few fields at a time
+    let mut seen = std::collections::HashMap::new();
+    let mut cursor = "0".to_string();
+    let mut calls = 0;
+    loop {
+        let response = send_command(&mut client, &format!("HSCAN hash {} COUNT 7\n", cursor));
+        let mut lines = response.lines();
+        cursor = lines.next().unwrap().to_string();
+        let pairs: Vec<&str> = lines.collect();
+        for pair in pairs.chunks(2) {
+            seen.insert(pair[0].to_string(), pair[1].to_string());
+        }
+        
+        calls += 1;
+        assert!(calls <= total, "HSCAN did not terminate");
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert!(calls > 1);
+    assert_eq!(seen.len(), total);
+    assert!((0..total).all(|i| seen[&format!("field:{}", i)] == format!("value:{}", i)));
+    
+    send_command(&mut client, "SADD set apple avocado banana\n");
+    let response = send_command(&mut client, "SSCAN set 0 MATCH a*\n");
+    let mut lines: Vec<&str> = response.lines().collect();
+    assert_eq!(lines.remove(0), "0");
+    lines.sort();
+    assert_eq!(lines, ["apple", "avocado"]);
+    
+    send_command(&mut client, "ZADD zset 1.5 one\n");
+    let response = send_command(&mut client, "ZSCAN zset 0\n");
+    assert_eq!(response, "0\none\n1.5\n");
+    
+    let response = send_command(&mut client, "SSCAN missing 0\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "ZSCAN set 0\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "HSCAN hash 0 COUNT 0\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_info_run_id() {
+    let server = TestServer::new();
+    let mut first = server.client();
+    let mut second = server.client();
+    
+    let info = send_command(&mut first, "INFO server\n");
+    let run_id = info_field(&info, "run_id").unwrap().to_string();
+    assert_eq!(run_id.len(), 40);
+    assert!(run_id.chars().all(|c| c.is_ascii_hexdigit()));
+    
+    let pid: u32 = info_field(&info, "process_id").unwrap().parse().unwrap();
+    assert_eq!(pid, server.server.id());
+    assert!(info_field(&info, "io_threads_active").is_some());
+    
+    // The run_id identifies the server instance, not the connection
+    let info = send_command(&mut second, "INFO server\n");
+    assert_eq!(info_field(&info, "run_id").unwrap(), run_id);
+    
+    // A different server instance gets a different run_id
+    let other = TestServer::new();
+    let info = send_command(&mut other.client(), "INFO server\n");
+    assert_ne!(info_field(&info, "run_id").unwrap(), run_id);
+}
+
+#[test]
+fn test_wait_without_replicas() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET key value\n");
+    let started = Instant::now();
+    let response = send_command(&mut client, "WAIT 0 100\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "WAIT 1 5000\n");
+    assert_eq!(response, "0\n");
+    assert!(started.elapsed() < Duration::from_secs(1));
+    
+    for command in ["WAIT", "WAIT 1", "WAIT -1 100", "WAIT 1 soon"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+}
+
+#[test]
+fn test_command_introspection() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // COUNT agrees with HELP, as both come from the same registry
+    let help = send_command(&mut client, "HELP\n");
+    let names: Vec<&str> = help.trim().trim_start_matches("Available commands: ").split(", ").collect();
+    assert!(names.contains(&"COMMAND"));
+    let response = send_command(&mut client, "COMMAND COUNT\n");
+    assert_eq!(response, format!("{}\n", names.len()));
+    
+    // COMMAND has an entry for every command
+    let mut other = server.client();
+    other.write_all(resp_command(&["COMMAND"]).as_bytes()).unwrap();
+    let header = format!("*{}\r\n", names.len());
+    let mut response = vec![0; header.len()];
+    other.read_exact(&mut response).unwrap();
+    assert_eq!(String::from_utf8_lossy(&response), header);
+    
+    send_resp(&mut client, &["COMMAND", "INFO", "get", "nosuchcommand"], "*2\r\n*6\r\n$3\r\nget\r\n:2\r\n*1\r\n+readonly\r\n:1\r\n:1\r\n:1\r\n$-1\r\n");
+    send_resp(&mut client, &["COMMAND", "INFO", "set"], "*1\r\n*6\r\n$3\r\nset\r\n:-3\r\n*2\r\n+write\r\n+denyoom\r\n:1\r\n:1\r\n:1\r\n");
+    send_resp(&mut client, &["COMMAND", "DOCS", "ping"], "*2\r\n$4\r\nping\r\n*0\r\n");
+}
+
+#[test]
+fn test_monitor() {
+    let server = TestServer::new();
+    let mut monitor = server.client();
+    let mut client = server.client();
+    
+    let response = send_command(&mut monitor, "MONITOR\n");
+    assert_eq!(response, "OK\n");
+    
+    // The monitor's own commands still run, but aren't shown to monitors
+    let response = send_command(&mut monitor, "PING\n");
+    assert_eq!(response, "PONG\n");
+    
+    send_command(&mut client, "SET key value\n");
+    send_command(&mut client, "SELECT 2\n");
+    send_resp(&mut client, &["GET", "two words"], "$-1\r\n");
+    
+    let mut lines = std::io::BufReader::new(monitor.try_clone().unwrap()).lines();
+    let client_addr = client.local_addr().unwrap().to_string();
+    for expected in [
+        format!("[0 {}] \"SET\" \"key\" \"value\"", client_addr),
+        format!("[0 {}] \"SELECT\" \"2\"", client_addr),
+        format!("[2 {}] \"GET\" \"two words\"", client_addr),
+    ] {
+        let line = lines.next().unwrap().unwrap();
+        let (time, rest) = line.split_once(' ').unwrap();
+        assert!(time.parse::<f64>().is_ok(), "{}", line);
+        assert_eq!(rest, expected);
+    }
+}
+
+#[test]
+fn test_pubsub() {
+    let server = TestServer::new();
+    let mut subscriber = server.client();
+    let mut publisher = server.client();
+    
+    // Each channel is confirmed with the number of channels subscribed to
+    send_resp(&mut subscriber, &["SUBSCRIBE", "news", "sport"], "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$5\r\nsport\r\n:2\r\n");
+    
+    send_resp(&mut publisher, &["PUBLISH", "news", "hello"], ":1\r\n");
+    send_resp(&mut publisher, &["PUBLISH", "weather", "rain"], ":0\r\n");
+    expect_message(&mut subscriber, "news", "hello");
+    
+    // A subscriber can only manage its subscriptions and PING
+    send_resp(&mut subscriber, &["GET", "key"], "-ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n");
+    send_resp(&mut subscriber, &["PING"], "*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+    send_resp(&mut subscriber, &["PING", "token"], "*2\r\n$4\r\npong\r\n$5\r\ntoken\r\n");
+    
+    send_resp(&mut subscriber, &["UNSUBSCRIBE", "news"], "*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+    send_resp(&mut publisher, &["PUBLISH", "news", "hello"], ":0\r\n");
+    
+    // Without channels it unsubscribes from the rest, and then from nothing
+    send_resp(&mut subscriber, &["UNSUBSCRIBE"], "*3\r\n$11\r\nunsubscribe\r\n$5\r\nsport\r\n:0\r\n");
+    send_resp(&mut subscriber, &["UNSUBSCRIBE"], "*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n");
+    send_resp(&mut subscriber, &["PING"], "+PONG\r\n");
+}
+
+#[test]
+fn test_pattern_subscriptions() {
+    let server = TestServer::new();
+    let mut subscriber = server.client();
+    let mut publisher = server.client();
+    
+    send_resp(&mut subscriber, &["PSUBSCRIBE", "news.*"], "*3\r\n$10\r\npsubscribe\r\n$6\r\nnews.*\r\n:1\r\n");
+    send_resp(&mut subscriber, &["SUBSCRIBE", "news.tech"], "*3\r\n$9\r\nsubscribe\r\n$9\r\nnews.tech\r\n:2\r\n");
+    
+    // The exact subscription and the pattern both receive it
+    send_resp(&mut publisher, &["PUBLISH", "news.tech", "rust"], ":2\r\n");
+    expect_message(&mut subscriber, "news.tech", "rust");
+    let expected = "*4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$9\r\nnews.tech\r\n$4\r\nrust\r\n";
+    let mut response = vec![0; expected.len()];
+    subscriber.read_exact(&mut response).unwrap();
+    assert_eq!(String::from_utf8_lossy(&response), expected);
+    
+    send_resp(&mut publisher, &["PUBLISH", "weather", "rain"], ":0\r\n");
+    send_resp(&mut subscriber, &["UNSUBSCRIBE"], "*3\r\n$11\r\nunsubscribe\r\n$9\r\nnews.tech\r\n:1\r\n");
+    send_resp(&mut publisher, &["PUBLISH", "news.sport", "goal"], ":1\r\n");
+    let expected = "*4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$10\r\nnews.sport\r\n$4\r\ngoal\r\n";
+    let mut response = vec![0; expected.len()];
+    subscriber.read_exact(&mut response).unwrap();
+    assert_eq!(String::from_utf8_lossy(&response), expected);
+    
+    // Still subscribed to the pattern, so still only listening
+    send_resp(&mut subscriber, &["GET", "key"], "-ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n");
+    send_resp(&mut subscriber, &["PUNSUBSCRIBE"], "*3\r\n$12\r\npunsubscribe\r\n$6\r\nnews.*\r\n:0\r\n");
+    send_resp(&mut publisher, &["PUBLISH", "news.sport", "goal"], ":0\r\n");
+    send_resp(&mut subscriber, &["PING"], "+PONG\r\n");
+}
+
+#[test]
+fn test_keyspace_notifications() {
+    let server = TestServer::new();
+    let mut subscriber = server.client();
+    let mut client = server.client();
+    
+    // Nothing is published until notify-keyspace-events enables it
+    let response = send_command(&mut client, "CONFIG SET notify-keyspace-events KEgx\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "CONFIG SET hz 20\n");
+    assert_eq!(response, "OK\n");
+    
+    let channels = ["__keyspace@0__:key", "__keyevent@0__:del", "__keyevent@0__:expired"];
+    let confirmations: String = channels.iter().enumerate()
+        .map(|(i, channel)| format!("*3\r\n$9\r\nsubscribe\r\n${}\r\n{}\r\n:{}\r\n", channel.len(), channel, i + 1))
+        .collect();
+    send_resp(&mut subscriber, &[&["SUBSCRIBE"][..], &channels].concat(), &confirmations);
+    
+    // String events aren't enabled, so only the deletion is published
+    send_command(&mut client, "SET key value\n");
+    send_command(&mut client, "DEL key\n");
+    expect_message(&mut subscriber, "__keyspace@0__:key", "del");
+    expect_message(&mut subscriber, "__keyevent@0__:del", "key");
+    
+    // The background sweep reports the keys it expires
+    send_command(&mut client, "PSETEX cache 50 value\n");
+    subscriber.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    expect_message(&mut subscriber, "__keyevent@0__:expired", "cache");
+}
+
+#[test]
+fn test_shutdown_command() {
+    let mut server = TestServer::new();
+    let mut client = server.client();
+    let mut idle = server.client();
+    
+    // Make sure the idle connection was accepted, not still in the backlog
+    let response = send_command(&mut idle, "PING\n");
+    assert_eq!(response, "PONG\n");
+    
+    let response = send_command(&mut client, "SHUTDOWN\n");
+    assert_eq!(response, "OK\n");
+    
+    // Connected clients are disconnected and the process exits cleanly
+    let mut buf = [0; 16];
+    assert_eq!(idle.read(&mut buf).unwrap(), 0);
+    assert!(server.wait_for_exit().success());
+    
+    // The listener is gone, so new connections are refused
+    assert!(TcpStream::connect(("127.0.0.1", server.port)).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_shutdown_on_sigterm() {
+    let mut server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "PING\n");
+    assert_eq!(response, "PONG\n");
+    
+    let status = Command::new("kill")
+        .args(["-TERM", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    
+    let mut buf = [0; 16];
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+    assert!(server.wait_for_exit().success());
+    assert!(TcpStream::connect(("127.0.0.1", server.port)).is_err());
+}
+
+#[test]
+fn test_save_survives_restart() {
+    let dir = temp_dir("save");
+    let env = [("REDIS_DIR", dir.to_str().unwrap())];
+    
+    {
+        let server = TestServer::with_env(&env);
+        let mut client = server.client();
+        
+        send_command(&mut client, "SET name John\n");
+        send_command(&mut client, "SET session active EX 100\n");
+        
+        let response = send_command(&mut client, "SAVE\n");
+        assert_eq!(response, "OK\n");
+        assert!(dir.join("dump.rdb").exists());
+        
+        // Written after the snapshot, so lost when the server is killed
+        send_command(&mut client, "SET unsaved value\n");
+    }
+    
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "GET name\n");
+    assert_eq!(response, "John\n");
+    
+    // The TTL is restored rather than reset or dropped
+    let response = send_command(&mut client, "TTL session\n");
+    let ttl: u64 = response.trim().parse().unwrap();
+    assert!((95..=100).contains(&ttl));
+    
+    let response = send_command(&mut client, "GET unsaved\n");
+    assert_eq!(response, "(nil)\n");
+    
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_bgsave_survives_restart() {
+    let dir = temp_dir("bgsave");
+    let env = [
+        ("REDIS_DIR", dir.to_str().unwrap()),
+        ("REDIS_DBFILENAME", "background.rdb"),
+    ];
+    
+    {
+        let server = TestServer::with_env(&env);
+        let mut client = server.client();
+        
+        send_command(&mut client, "SET key1 value1\n");
+        send_command(&mut client, "SET key2 value2\n");
+        
+        let response = send_command(&mut client, "BGSAVE\n");
+        assert_eq!(response, "Background saving started\n");
+        
+        // Wait for the background save to land on disk
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !dir.join("background.rdb").exists() {
+            assert!(Instant::now() < deadline, "BGSAVE did not write a snapshot");
+            sleep(Duration::from_millis(50));
+        }
+    }
+    
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "GET key2\n");
+    assert_eq!(response, "value2\n");
+    
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_save_points() {
+    let dir = temp_dir("save-points");
+    let env = [
+        ("REDIS_DIR", dir.to_str().unwrap()),
+        ("REDIS_SAVE", "1 1 100 1000"),
+    ];
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    let response = send_command(&mut client, "CONFIG GET save\n");
+    assert_eq!(response, "save\n1 1 100 1000\n");
+    
+    // Nothing is saved until something changes
+    sleep(Duration::from_millis(1500));
+    assert!(!dir.join("dump.rdb").exists());
+    
+    send_command(&mut client, "SET name John\n");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !dir.join("dump.rdb").exists() {
+        assert!(Instant::now() < deadline, "the save point did not write a snapshot");
+        sleep(Duration::from_millis(50));
+    }
+    drop(client);
+    drop(server);
+    
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    let response = send_command(&mut client, "GET name\n");
+    assert_eq!(response, "John\n");
+    
+    // Without save points nothing is saved
+    send_resp(&mut client, &["CONFIG", "SET", "save", ""], "+OK\r\n");
+    fs::remove_file(dir.join("dump.rdb")).unwrap();
+    send_command(&mut client, "SET name Jane\n");
+    sleep(Duration::from_millis(1500));
+    assert!(!dir.join("dump.rdb").exists());
+    
+    let response = send_command(&mut client, "CONFIG SET save 900\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_lastsave() {
+    let dir = temp_dir("lastsave");
+    let server = TestServer::with_env(&[("REDIS_DIR", dir.to_str().unwrap()), ("REDIS_SAVE", "")]);
+    let mut client = server.client();
+    
+    // Until something is saved, LASTSAVE is when the server started
+    let started: u64 = send_command(&mut client, "LASTSAVE\n").trim().parse().unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    assert!(now.abs_diff(started) <= 5, "{} vs {}", started, now);
+    
+    send_command(&mut client, "SET a 1\n");
+    send_command(&mut client, "SET b 2\n");
+    send_command(&mut client, "DEL b\n");
+    send_command(&mut client, "GET a\n");
+    let info = send_command(&mut client, "INFO persistence\n");
+    assert!(info.starts_with("# Persistence\n"), "{}", info);
+    assert_eq!(info_field(&info, "rdb_changes_since_last_save"), Some("3"));
+    assert_eq!(info_field(&info, "rdb_bgsave_in_progress"), Some("0"));
+    assert_eq!(info_field(&info, "rdb_last_save_time"), Some(started.to_string().as_str()));
+    
+    // LASTSAVE counts in seconds, so wait for the next one
+    sleep(Duration::from_millis(1100));
+    send_command(&mut client, "SAVE\n");
+    let saved: u64 = send_command(&mut client, "LASTSAVE\n").trim().parse().unwrap();
+    assert!(saved > started, "{} vs {}", saved, started);
+    let info = send_command(&mut client, "INFO persistence\n");
+    assert_eq!(info_field(&info, "rdb_changes_since_last_save"), Some("0"));
+    assert_eq!(info_field(&info, "rdb_last_save_time"), Some(saved.to_string().as_str()));
+    
+    // A background save resets the count once it is written
+    send_command(&mut client, "SET c 3\n");
+    sleep(Duration::from_millis(1100));
+    send_command(&mut client, "BGSAVE\n");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let info = send_command(&mut client, "INFO persistence\n");
+        if info_field(&info, "rdb_changes_since_last_save") == Some("0") {
+            let last_save: u64 = info_field(&info, "rdb_last_save_time").unwrap().parse().unwrap();
+            assert!(last_save > saved, "{} vs {}", last_save, saved);
+            break;
+        }
+        assert!(Instant::now() < deadline, "BGSAVE did not reset the count: {}", info);
+        sleep(Duration::from_millis(50));
+    }
+    
+    // A failed save leaves both alone
+    send_command(&mut client, "SET d 4\n");
+    fs::remove_dir_all(&dir).unwrap();
+    let response = send_command(&mut client, "SAVE\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let info = send_command(&mut client, "INFO persistence\n");
+    assert_eq!(info_field(&info, "rdb_changes_since_last_save"), Some("1"));
+}
+
+#[test]
+fn test_type() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "TYPE missing\n");
+    assert_eq!(response, "none\n");
+    
+    send_command(&mut client, "SET key value\n");
+    let response = send_command(&mut client, "TYPE key\n");
+    assert_eq!(response, "string\n");
+    
+    // Right after the key goes away, by any route, TYPE cleanly reports none
+    send_command(&mut client, "DEL key\n");
+    let response = send_command(&mut client, "TYPE key\n");
+    assert_eq!(response, "none\n");
+    
+    send_command(&mut client, "SET key value\n");
+    send_command(&mut client, "FLUSHDB\n");
+    let response = send_command(&mut client, "TYPE key\n");
+    assert_eq!(response, "none\n");
+    
+    send_command(&mut client, "SET key value EX 1\n");
+    sleep(Duration::from_millis(1100));
+    let response = send_command(&mut client, "TYPE key\n");
+    assert_eq!(response, "none\n");
+    
+    // A collection emptied by its last removal is gone as well
+    send_command(&mut client, "ZADD zset 1 member\n");
+    let response = send_command(&mut client, "TYPE zset\n");
+    assert_eq!(response, "zset\n");
+    send_command(&mut client, "ZREM zset member\n");
+    let response = send_command(&mut client, "TYPE zset\n");
+    assert_eq!(response, "none\n");
+    let response = send_command(&mut client, "OBJECT ENCODING zset\n");
+    assert_eq!(response, "(nil)\n");
+    send_resp(&mut client, &["DEBUG", "OBJECT", "zset"], "-ERR no such key\r\n");
+    
+    send_command(&mut client, "RPUSH list element\n");
+    let response = send_command(&mut client, "TYPE list\n");
+    assert_eq!(response, "list\n");
+    send_command(&mut client, "LPOP list\n");
+    let response = send_command(&mut client, "TYPE list\n");
+    assert_eq!(response, "none\n");
+    
+    // Including when it is inspected in the same transaction that emptied it
+    send_resp(&mut client, &["MULTI"], "+OK\r\n");
+    for command in [
+        &["ZADD", "zset", "1", "member"][..],
+        &["ZREM", "zset", "member"],
+        &["TYPE", "zset"],
+        &["OBJECT", "ENCODING", "zset"],
+        &["DEBUG", "OBJECT", "zset"],
+        &["EXISTS", "zset"],
+    ] {
+        send_resp(&mut client, command, "+QUEUED\r\n");
+    }
+    send_resp(&mut client, &["EXEC"], "*6\r\n:1\r\n:1\r\n+none\r\n$-1\r\n-ERR no such key\r\n:0\r\n");
+}
+
+// Wait for a command to get the expected response, as replicated writes
+// arrive in the background
+fn wait_for_response(client: &mut TcpStream, command: &str, expected: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let response = send_command(client, command);
+        if response == expected {
+            return;
+        }
+        assert!(Instant::now() < deadline, "{}: {}", command.trim(), response);
+        sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_replication() {
+    let leader = TestServer::new();
+    let replica = TestServer::new();
+    let mut writer = leader.client();
+    let mut reader = replica.client();
+    
+    // The leader's snapshot replaces whatever the replica held
+    send_command(&mut writer, "SET before snapshot\n");
+    send_command(&mut reader, "SET stale value\n");
+    let response = send_command(&mut reader, &format!("REPLICAOF 127.0.0.1 {}\n", leader.port));
+    assert_eq!(response, "OK\n");
+    wait_for_response(&mut reader, "GET before\n", "snapshot\n");
+    let response = send_command(&mut reader, "EXISTS stale\n");
+    assert_eq!(response, "0\n");
+    
+    let info = send_command(&mut reader, "INFO replication\n");
+    assert_eq!(info_field(&info, "role"), Some("slave"));
+    assert_eq!(info_field(&info, "master_port"), Some(leader.port.to_string().as_str()));
+    assert_eq!(info_field(&info, "master_link_status"), Some("up"));
+    let info = send_command(&mut writer, "INFO replication\n");
+    assert_eq!(info_field(&info, "role"), Some("master"));
+    assert_eq!(info_field(&info, "connected_slaves"), Some("1"));
+    let response = send_command(&mut reader, &format!("REPLICAOF 127.0.0.1 {}\n", leader.port));
+    assert_eq!(response, "OK Already connected to specified master\n");
+    
+    // Later writes follow, in order and in their own database, with
+    // relative expiries made absolute
+    send_command(&mut writer, "SET name John\n");
+    send_command(&mut writer, "RPUSH list a b c\n");
+    send_command(&mut writer, "LPOP list\n");
+    send_command(&mut writer, "SET session active EX 100\n");
+    send_command(&mut writer, "SELECT 2\n");
+    send_command(&mut writer, "SET other db\n");
+    send_command(&mut writer, "SELECT 0\n");
+    send_command(&mut writer, "HINCRBY counters hits 1\n");
+    send_command(&mut writer, "HINCRBY counters hits 1\n");
+    wait_for_response(&mut reader, "HGET counters hits\n", "2\n");
+    let response = send_command(&mut reader, "GET name\n");
+    assert_eq!(response, "John\n");
+    let response = send_command(&mut reader, "LRANGE list 0 -1\n");
+    assert_eq!(response, "b\nc\n");
+    let ttl: u64 = send_command(&mut reader, "TTL session\n").trim().parse().unwrap();
+    assert!((95..=100).contains(&ttl), "{}", ttl);
+    send_command(&mut reader, "SELECT 2\n");
+    let response = send_command(&mut reader, "GET other\n");
+    assert_eq!(response, "db\n");
+    send_command(&mut reader, "SELECT 0\n");
+    
+    // Failed writes and reads aren't passed on, so nothing else changes
+    send_command(&mut writer, "LPUSH name oops\n");
+    send_command(&mut writer, "SET last write\n");
+    wait_for_response(&mut reader, "GET last\n", "write\n");
+    let response = send_command(&mut reader, "GET name\n");
+    assert_eq!(response, "John\n");
+    
+    // Once it stops replicating, the replica keeps its data but no new writes
+    let response = send_command(&mut reader, "REPLICAOF NO ONE\n");
+    assert_eq!(response, "OK\n");
+    let info = send_command(&mut reader, "INFO replication\n");
+    assert_eq!(info_field(&info, "role"), Some("master"));
+    send_command(&mut writer, "SET name Jane\n");
+    sleep(Duration::from_millis(300));
+    let response = send_command(&mut reader, "GET name\n");
+    assert_eq!(response, "John\n");
+    
+    for command in ["REPLICAOF 127.0.0.1 port", "REPLICAOF 127.0.0.1", "REPLICAOF 127.0.0.1 70000"] {
+        let response = send_command(&mut reader, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+}
+
+#[test]
+fn test_aof_replayed_after_kill() {
+    let dir = temp_dir("aof");
+    let env = [
+        ("REDIS_DIR", dir.to_str().unwrap()),
+        ("REDIS_APPENDONLY", "yes"),
+    ];
+    
+    {
+        let server = TestServer::with_env(&env);
+        let mut client = server.client();
+        
+        send_command(&mut client, "SET name John\n");
+        send_command(&mut client, "SET city Paris\n");
+        send_command(&mut client, "SET name Jane\n");
+        send_command(&mut client, "DEL city\n");
+        send_command(&mut client, "GET name\n");
+        
+        // Dropping the server kills it without a SAVE
+    }
+    
+    // Reads are never logged
+    let log = fs::read_to_string(dir.join("appendonly.aof")).unwrap();
+    let expected: String = [
+        resp_command(&["SELECT", "0"]),
+        resp_command(&["SET", "name", "John"]),
+        resp_command(&["SET", "city", "Paris"]),
+        resp_command(&["SET", "name", "Jane"]),
+        resp_command(&["DEL", "city"]),
+    ].concat();
+    assert_eq!(log, expected);
+    assert!(!dir.join("dump.rdb").exists());
+    
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "GET name\n");
+    assert_eq!(response, "Jane\n");
+    
+    let response = send_command(&mut client, "GET city\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // Logging carries on after the replay
+    send_command(&mut client, "SET country France\n");
+    let log = fs::read_to_string(dir.join("appendonly.aof")).unwrap();
+    let expected = [
+        resp_command(&["DEL", "city"]),
+        resp_command(&["SELECT", "0"]),
+        resp_command(&["SET", "country", "France"]),
+    ].concat();
+    assert!(log.ends_with(&expected));
+    
+    // The file is picked once at startup, the fsync policy can change live
+    let response = send_command(&mut client, "CONFIG SET appendonly no\n");
+    assert!(response.starts_with("ERROR"));
+    let response = send_command(&mut client, "CONFIG SET appendfsync always\n");
+    assert_eq!(response, "OK\n");
+    
+    drop(client);
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_aof_keeps_absolute_expiry() {
+    let dir = temp_dir("aof-expiry");
+    let env = [
+        ("REDIS_DIR", dir.to_str().unwrap()),
+        ("REDIS_APPENDONLY", "yes"),
+    ];
+    
+    {
+        let server = TestServer::with_env(&env);
+        let mut client = server.client();
+        
+        send_command(&mut client, "SET session active EX 100\n");
+        send_command(&mut client, "SET token abc\n");
+        send_command(&mut client, "EXPIRE token 100\n");
+        send_command(&mut client, "SETEX cache 100 hit\n");
+    }
+    
+    // Relative expiries are logged as the absolute time they resolved to
+    let log = fs::read_to_string(dir.join("appendonly.aof")).unwrap();
+    assert_eq!(log.matches("PEXPIREAT").count(), 3, "{:?}", log);
+    assert!(!log.contains("$6\r\nEXPIRE\r\n"), "{:?}", log);
+    
+    // So time spent down counts against the TTL instead of restarting it
+    sleep(Duration::from_millis(1100));
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    for key in ["session", "token", "cache"] {
+        let response = send_command(&mut client, &format!("TTL {}\n", key));
+        let ttl: u64 = response.trim().parse().unwrap();
+        assert!((90..=99).contains(&ttl), "{}: {}", key, ttl);
+    }
+    
+    drop(client);
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_pipelined_commands() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Several commands in one write, the last one split across two writes
+    client.write_all(b"SET a 1\nGET a\r\nPING\nGE").unwrap();
+    client.flush().unwrap();
+    let mut response = send_command(&mut client, "T a\n");
+    
+    // The replies to the first write may arrive on their own
+    while response.lines().count() < 4 {
+        let mut chunk = [0; 64];
+        let n = client.read(&mut chunk).unwrap();
+        assert!(n > 0, "connection closed after {:?}", response);
+        response.push_str(std::str::from_utf8(&chunk[..n]).unwrap());
+    }
+    assert_eq!(response, "OK\n1\nPONG\n1\n");
+}
+
+#[test]
+fn test_pipeline_limit_applies_backpressure() {
+    let server = TestServer::with_env(&[("REDIS_MAX_PIPELINE", "16")]);
+    let mut client = server.client();
+    client.set_write_timeout(Some(Duration::from_secs(1))).unwrap();
+    
+    // Pipeline commands without ever reading a reply. Once the socket buffers
+    // fill up the server must stop reading, so the writes eventually block
+    // long before the whole payload has been accepted.
+    let chunk = "PING\n".repeat(10_000);
+    let limit = 200 * 1024 * 1024;
+    let mut sent = 0;
+    while sent < limit {
+        // Resume from wherever a partial write left off
+        match client.write(&chunk.as_bytes()[sent % chunk.len()..]) {
+            Ok(n) => sent += n,
+            Err(_) => break,
+        }
+    }
+    assert!(sent < limit, "server kept reading {} bytes without replies being read", sent);
+    
+    // Draining the replies lets the server carry on; every complete command
+    // that was sent is answered, in order
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut replies = String::new();
+    client.read_to_string(&mut replies).unwrap();
+    
+    assert_eq!(replies.len(), sent / 5 * 5);
+    assert!(replies.lines().all(|line| line == "PONG"));
+}
+
+#[test]
+fn test_maxmemory_evicts_least_recently_used() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Each key takes roughly 210 bytes, so about eleven fit
+    send_command(&mut client, "CONFIG SET maxmemory 2400\n");
+    send_command(&mut client, "CONFIG SET maxmemory-policy allkeys-lru\n");
+    // Sampling every key makes the pick exact rather than approximate
+    send_command(&mut client, "CONFIG SET maxmemory-samples 64\n");
+    let value = "x".repeat(100);
+    
+    for i in 0..10 {
+        send_command(&mut client, &format!("SET key:{} {}\n", i, value));
+    }
+    
+    // Reading key:0 makes it more recent than keys 1 to 9
+    send_command(&mut client, "GET key:0\n");
+    
+    for i in 10..20 {
+        let response = send_command(&mut client, &format!("SET key:{} {}\n", i, value));
+        assert_eq!(response, "OK\n");
+    }
+    
+    let response = send_command(&mut client, "EXISTS key:0\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "EXISTS key:1\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "EXISTS key:19\n");
+    assert_eq!(response, "1\n");
+    
+    let info = send_command(&mut client, "INFO\n");
+    let evicted: u64 = info_field(&info, "evicted_keys").unwrap().parse().unwrap();
+    assert!(evicted >= 8);
+    let used: u64 = info_field(&info, "used_memory").unwrap().parse().unwrap();
+    assert!(used <= 2400 + 250);
+}
+
+#[test]
+fn test_maxmemory_evicts_least_frequently_used() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "OBJECT FREQ key:0\n");
+    assert_eq!(response, "ERROR: An LFU maxmemory policy is not selected, access frequency not tracked\n");
+    
+    // Each key takes roughly 210 bytes, so about eleven fit
+    send_command(&mut client, "CONFIG SET maxmemory 2400\n");
+    send_command(&mut client, "CONFIG SET maxmemory-policy allkeys-lfu\n");
+    // Sampling every key makes the pick exact rather than approximate
+    send_command(&mut client, "CONFIG SET maxmemory-samples 64\n");
+    let value = "x".repeat(100);
+    
+    for i in 0..10 {
+        send_command(&mut client, &format!("SET key:{} {}\n", i, value));
+    }
+    let response = send_command(&mut client, "OBJECT FREQ key:1\n");
+    assert_eq!(response, "5\n");
+    
+    // Reading key:0 makes it more frequently used than any other key, even
+    // the ones written after it
+    for _ in 0..20 {
+        send_command(&mut client, "GET key:0\n");
+    }
+    let frequency: u8 = send_command(&mut client, "OBJECT FREQ key:0\n").trim().parse().unwrap();
+    assert!(frequency > 5);
+    
+    for i in 10..20 {
+        let response = send_command(&mut client, &format!("SET key:{} {}\n", i, value));
+        assert_eq!(response, "OK\n");
+    }
+    
+    let response = send_command(&mut client, "EXISTS key:0\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "EXISTS key:1\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "EXISTS key:19\n");
+    assert_eq!(response, "1\n");
+}
+
+#[test]
+fn test_maxmemory_noeviction_refuses_writes() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "CONFIG SET maxmemory 500\n");
+    let value = "x".repeat(100);
+    
+    for i in 0..3 {
+        send_command(&mut client, &format!("SET key:{} {}\n", i, value));
+    }
+    
+    // Nothing may be evicted, so further writes fail until memory is freed
+    let response = send_command(&mut client, &format!("SET key:3 {}\n", value));
+    assert!(response.starts_with("ERROR: OOM"), "{}", response);
+    
+    let response = send_command(&mut client, "DEL key:0\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, &format!("SET key:3 {}\n", value));
+    assert_eq!(response, "OK\n");
+    
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "3\n");
+}
+
+#[test]
+fn test_select_databases() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET key zero\n");
+    
+    let response = send_command(&mut client, "SELECT 1\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "GET key\n");
+    assert_eq!(response, "(nil)\n");
+    send_command(&mut client, "SET key one\n");
+    
+    // The selection is per connection
+    let mut other = server.client();
+    let response = send_command(&mut other, "GET key\n");
+    assert_eq!(response, "zero\n");
+    
+    let response = send_command(&mut client, "SELECT 16\n");
+    assert!(response.starts_with("ERROR"));
+    
+    let info = send_command(&mut client, "INFO keyspace\n");
+    assert!(info.contains("db0:keys=1,"));
+    assert!(info.contains("db1:keys=1,"));
+    
+    // FLUSHDB only clears the selected database, FLUSHALL clears them all
+    send_command(&mut client, "FLUSHDB\n");
+    let response = send_command(&mut other, "DBSIZE\n");
+    assert_eq!(response, "1\n");
+    send_command(&mut client, "FLUSHALL\n");
+    let response = send_command(&mut other, "DBSIZE\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_object_encoding_and_refcount() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    for (value, expected) in [
+        ("12345", "int"),
+        ("-42", "int"),
+        // Not the canonical form of an integer
+        ("007", "embstr"),
+        ("+1", "embstr"),
+        ("99999999999999999999", "embstr"),
+        ("hello", "embstr"),
+        (&"x".repeat(44), "embstr"),
+        (&"x".repeat(45), "raw"),
+    ] {
+        send_command(&mut client, &format!("SET key {}\n", value));
+        let response = send_command(&mut client, "OBJECT ENCODING key\n");
+        assert_eq!(response, format!("{}\n", expected), "{}", value);
+    }
+    
+    send_command(&mut client, "RPUSH list element\n");
+    let response = send_command(&mut client, "OBJECT ENCODING list\n");
+    assert_eq!(response, "listpack\n");
+    send_command(&mut client, &format!("RPUSH list {}\n", "x".repeat(9000)));
+    let response = send_command(&mut client, "OBJECT ENCODING list\n");
+    assert_eq!(response, "quicklist\n");
+    
+    send_command(&mut client, "SADD set 1 2 3\n");
+    let response = send_command(&mut client, "OBJECT ENCODING set\n");
+    assert_eq!(response, "intset\n");
+    send_command(&mut client, "SADD set member\n");
+    let response = send_command(&mut client, "OBJECT ENCODING set\n");
+    assert_eq!(response, "listpack\n");
+    
+    let response = send_command(&mut client, "OBJECT REFCOUNT key\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "OBJECT ENCODING missing\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "OBJECT SIZE key\n");
+    assert!(response.starts_with("ERROR"));
+}
+
+#[test]
+fn test_object_idletime() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET key value\n");
+    let response = send_command(&mut client, "OBJECT IDLETIME key\n");
+    assert_eq!(response, "0\n");
+    
+    // Asking doesn't count as an access
+    sleep(Duration::from_millis(2100));
+    for _ in 0..2 {
+        let response = send_command(&mut client, "OBJECT IDLETIME key\n");
+        let idle: u64 = response.trim().parse().unwrap();
+        assert!(idle >= 2, "{}", idle);
+    }
+    
+    send_command(&mut client, "GET key\n");
+    let response = send_command(&mut client, "OBJECT IDLETIME key\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, "OBJECT IDLETIME missing\n");
+    assert_eq!(response, "(nil)\n");
+}
+
+#[test]
+fn test_touch() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET a one EX 100\n");
+    send_command(&mut client, "SET b two\n");
+    sleep(Duration::from_millis(1100));
+    let response = send_command(&mut client, "OBJECT IDLETIME a\n");
+    assert_eq!(response, "1\n");
+    
+    // A key given twice counts twice, like EXISTS
+    let response = send_command(&mut client, "TOUCH a b missing a\n");
+    assert_eq!(response, "3\n");
+    let response = send_command(&mut client, "OBJECT IDLETIME a\n");
+    assert_eq!(response, "0\n");
+    
+    // Values and TTLs are left alone
+    let response = send_command(&mut client, "OBJECT IDLETIME b\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "GET a\n");
+    assert_eq!(response, "one\n");
+    let ttl: u64 = send_command(&mut client, "TTL a\n").trim().parse().unwrap();
+    assert!((99..=100).contains(&ttl), "{}", ttl);
+    let response = send_command(&mut client, "TTL b\n");
+    assert_eq!(response, "-1\n");
+    
+    let response = send_command(&mut client, "TOUCH missing\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_copy() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET source value EX 100\n");
+    
+    // Within the database, keeping the TTL
+    let response = send_command(&mut client, "COPY source copy\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "GET copy\n");
+    assert_eq!(response, "value\n");
+    let response = send_command(&mut client, "TTL copy\n");
+    let ttl: u64 = response.trim().parse().unwrap();
+    assert!((95..=100).contains(&ttl));
+    
+    // The copy is independent of the source
+    send_command(&mut client, "SET source changed\n");
+    let response = send_command(&mut client, "GET copy\n");
+    assert_eq!(response, "value\n");
+    
+    // An existing destination is only overwritten with REPLACE
+    let response = send_command(&mut client, "COPY source copy\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "GET copy\n");
+    assert_eq!(response, "value\n");
+    let response = send_command(&mut client, "COPY source copy REPLACE\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "GET copy\n");
+    assert_eq!(response, "changed\n");
+    
+    // Across databases
+    let response = send_command(&mut client, "COPY source source DB 3\n");
+    assert_eq!(response, "1\n");
+    send_command(&mut client, "SELECT 3\n");
+    let response = send_command(&mut client, "GET source\n");
+    assert_eq!(response, "changed\n");
+    
+    let response = send_command(&mut client, "COPY missing other\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "COPY source source\n");
+    assert!(response.starts_with("ERROR"));
+    let response = send_command(&mut client, "COPY source other DB 99\n");
+    assert!(response.starts_with("ERROR"));
+}
+
+#[test]
+fn test_dump_and_restore() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "ZADD zset 1 one 2 two\n");
+    let payload = send_command(&mut client, "DUMP zset\n");
+    let payload = payload.trim();
+    
+    let response = send_command(&mut client, &format!("RESTORE copy 0 {}\n", payload));
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "ZRANGE copy 0 -1 WITHSCORES\n");
+    assert_eq!(response, "one\n1\ntwo\n2\n");
+    let response = send_command(&mut client, "TTL copy\n");
+    assert_eq!(response, "-1\n");
+    
+    // An existing key is only overwritten with REPLACE
+    let response = send_command(&mut client, &format!("RESTORE copy 0 {}\n", payload));
+    assert!(response.starts_with("ERROR"), "{}", response);
+    send_command(&mut client, "ZREM copy one\n");
+    let response = send_command(&mut client, &format!("RESTORE copy 0 {} REPLACE\n", payload));
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "ZCARD copy\n");
+    assert_eq!(response, "2\n");
+    
+    // The TTL doesn't travel with the value: a TTL of 0 restores it without one
+    send_command(&mut client, "SET string value EX 100\n");
+    let payload = send_command(&mut client, "DUMP string\n");
+    let payload = payload.trim();
+    send_command(&mut client, &format!("RESTORE persistent 0 {}\n", payload));
+    let response = send_command(&mut client, "TTL persistent\n");
+    assert_eq!(response, "-1\n");
+    send_command(&mut client, &format!("RESTORE given 5000 {}\n", payload));
+    let ttl: u64 = send_command(&mut client, "TTL given\n").trim().parse().unwrap();
+    assert_eq!(ttl, 5);
+    let response = send_command(&mut client, "GET given\n");
+    assert_eq!(response, "value\n");
+    
+    // A damaged payload is rejected
+    let mut damaged = payload.to_string();
+    let last = if damaged.ends_with('0') { '1' } else { '0' };
+    damaged.pop();
+    damaged.push(last);
+    for payload in [damaged.as_str(), "deadbeef", "zz"] {
+        let response = send_command(&mut client, &format!("RESTORE bad 0 {}\n", payload));
+        assert!(response.starts_with("ERROR"), "{}: {}", payload, response);
+    }
+    let response = send_command(&mut client, "EXISTS bad\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, "DUMP missing\n");
+    assert_eq!(response, "(nil)\n");
+}
+
+#[test]
+fn test_randomkey() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "RANDOMKEY\n");
+    assert_eq!(response, "(nil)\n");
+    
+    let keys = ["a", "b", "c", "d"];
+    for key in keys {
+        send_command(&mut client, &format!("SET {} value\n", key));
+    }
+    send_command(&mut client, "SET expiring value EX 1\n");
+    sleep(Duration::from_millis(1100));
+    
+    // Expired keys are never returned
+    for _ in 0..20 {
+        let response = send_command(&mut client, "RANDOMKEY\n");
+        assert!(keys.contains(&response.trim()), "{}", response);
+    }
+    
+    send_command(&mut client, "FLUSHDB\n");
+    let response = send_command(&mut client, "RANDOMKEY\n");
+    assert_eq!(response, "(nil)\n");
+}
+
+#[test]
+fn test_setex_and_psetex() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "SETEX session 100 active\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "GET session\n");
+    assert_eq!(response, "active\n");
+    let response = send_command(&mut client, "TTL session\n");
+    let ttl: u64 = response.trim().parse().unwrap();
+    assert!((99..=100).contains(&ttl));
+    
+    let response = send_command(&mut client, "PSETEX token 5000 abc\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "PTTL token\n");
+    let pttl: u64 = response.trim().parse().unwrap();
+    assert!((4000..=5000).contains(&pttl));
+    
+    // Bad durations are rejected without storing anything
+    for command in ["SETEX bad 0 value", "SETEX bad -5 value", "SETEX bad ten value", "PSETEX bad 0 value", "PSETEX bad 1.5 value", "SETEX bad 18446744073709551615 value"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    let response = send_command(&mut client, "EXISTS bad\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, "PSETEX short 100 value\n");
+    assert_eq!(response, "OK\n");
+    sleep(Duration::from_millis(200));
+    let response = send_command(&mut client, "GET short\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "PTTL short\n");
+    assert_eq!(response, "-2\n");
+}
+
+#[test]
+fn test_ttl_rounds_up() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // A fraction of a second left counts as a whole one, while PTTL is exact
+    let response = send_command(&mut client, "SET key value PX 1500\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "TTL key\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "PTTL key\n");
+    let pttl: u64 = response.trim().parse().unwrap();
+    assert!((1400..=1500).contains(&pttl), "{}", pttl);
+    
+    send_command(&mut client, "SET key value PX 500\n");
+    let response = send_command(&mut client, "TTL key\n");
+    assert_eq!(response, "1\n");
+}
+
+#[test]
+fn test_set_options() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // NX only sets a missing key, XX only an existing one
+    let response = send_command(&mut client, "SET key first XX\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "EXISTS key\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "SET key first NX\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "SET key second NX\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "SET key second XX\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "GET key\n");
+    assert_eq!(response, "second\n");
+    
+    // GET replies with the old value, even when NX or XX skips the set
+    let response = send_command(&mut client, "SET key third GET\n");
+    assert_eq!(response, "second\n");
+    let response = send_command(&mut client, "SET key fourth NX GET\n");
+    assert_eq!(response, "third\n");
+    let response = send_command(&mut client, "GET key\n");
+    assert_eq!(response, "third\n");
+    let response = send_command(&mut client, "SET fresh value NX GET\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "GET fresh\n");
+    assert_eq!(response, "value\n");
+    let response = send_command(&mut client, "SET missing value XX GET\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "EXISTS missing\n");
+    assert_eq!(response, "0\n");
+    
+    // A plain SET clears the expiry, KEEPTTL keeps it
+    send_command(&mut client, "SET key value EX 100\n");
+    let response = send_command(&mut client, "SET key other KEEPTTL\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "TTL key\n");
+    let ttl: u64 = response.trim().parse().unwrap();
+    assert!((99..=100).contains(&ttl));
+    let response = send_command(&mut client, "SET key value\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "TTL key\n");
+    assert_eq!(response, "-1\n");
+    
+    // Conflicting and unknown options are refused without storing anything
+    for command in [
+        "SET other value NX XX",
+        "SET other value EX 10 KEEPTTL",
+        "SET other value EX 10 PX 10000",
+        "SET other value SOMETIMES",
+    ] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert_eq!(response, "ERROR: syntax error\n", "{}", command);
+    }
+    let response = send_command(&mut client, "EXISTS other\n");
+    assert_eq!(response, "0\n");
+    
+    // Like SETEX, EX takes a positive integer the expiry can be computed from
+    for seconds in ["abc", "0", "-5", "18446744073709551615"] {
+        let response = send_command(&mut client, &format!("SET other value EX {}\n", seconds));
+        assert_eq!(response, "ERROR: invalid expire time in 'set' command\n", "{}", seconds);
+    }
+    let response = send_command(&mut client, "SET other value EX\n");
+    assert_eq!(response, "ERROR: syntax error\n");
+    let response = send_command(&mut client, "EXISTS other\n");
+    assert_eq!(response, "0\n");
+    
+    // PX likewise, and a refused SET leaves an existing key as it was
+    send_command(&mut client, "SET other old\n");
+    for command in ["SET other new PX soon", "SET other new PX", "SET other new PX 0"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    let response = send_command(&mut client, "GET other\n");
+    assert_eq!(response, "old\n");
+    let response = send_command(&mut client, "TTL other\n");
+    assert_eq!(response, "-1\n");
+    let response = send_command(&mut client, "PING\n");
+    assert_eq!(response, "PONG\n");
+}
+
+#[test]
+fn test_getrange_and_setrange() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SET key Hello_World\n");
+    for (range, expected) in [
+        ("0 4", "Hello"),
+        ("-5 -1", "World"),
+        ("0 -1", "Hello_World"),
+        ("6 100", "World"),
+        ("-100 4", "Hello"),
+        ("20 30", ""),
+        ("4 2", ""),
+    ] {
+        let response = send_command(&mut client, &format!("GETRANGE key {}\n", range));
+        assert_eq!(response, format!("{}\n", expected), "{}", range);
+    }
+    let response = send_command(&mut client, "GETRANGE missing 0 -1\n");
+    assert_eq!(response, "\n");
+    let response = send_command(&mut client, "GETRANGE key zero 1\n");
+    assert!(response.starts_with("ERROR"));
+    
+    // Overwriting in place keeps the length and the TTL
+    send_command(&mut client, "EXPIRE key 100\n");
+    let response = send_command(&mut client, "SETRANGE key 6 Redis\n");
+    assert_eq!(response, "11\n");
+    let response = send_command(&mut client, "GET key\n");
+    assert_eq!(response, "Hello_Redis\n");
+    let response = send_command(&mut client, "TTL key\n");
+    assert_ne!(response, "-1\n");
+    
+    // Writing past the end pads the gap with zero bytes
+    let response = send_command(&mut client, "SETRANGE padded 3 abc\n");
+    assert_eq!(response, "6\n");
+    let response = send_command(&mut client, "GET padded\n");
+    assert_eq!(response, "\0\0\0abc\n");
+    let response = send_command(&mut client, "SETRANGE padded 1 x\n");
+    assert_eq!(response, "6\n");
+    let response = send_command(&mut client, "GETRANGE padded 0 2\n");
+    assert_eq!(response, "\0x\0\n");
+    
+    // An empty value doesn't create a key, and offsets can't be negative
+    send_resp(&mut client, &["SETRANGE", "empty", "5", ""], ":0\r\n");
+    let response = send_command(&mut client, "EXISTS empty\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "SETRANGE key -1 x\n");
+    assert!(response.starts_with("ERROR"));
+    
+    // Nor can a write end past the 512MB a string is allowed, and refusing it
+    // leaves the key as it was
+    for offset in ["536870912", "18446744073709551615"] {
+        let response = send_command(&mut client, &format!("SETRANGE key {} x\n", offset));
+        assert_eq!(response, "ERROR: string exceeds maximum allowed size\n", "{}", offset);
+    }
+    let response = send_command(&mut client, "GET key\n");
+    assert_eq!(response, "Hello_Redis\n");
+}
+
+#[test]
+fn test_append() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "APPEND greeting Hello\n");
+    assert_eq!(response, "5\n");
+    send_resp(&mut client, &["APPEND", "greeting", " World"], ":11\r\n");
+    let response = send_command(&mut client, "GET greeting\n");
+    assert_eq!(response, "Hello World\n");
+    
+    // Appending keeps the TTL, and nothing still creates the key
+    send_command(&mut client, "EXPIRE greeting 100\n");
+    send_command(&mut client, "APPEND greeting !\n");
+    let response = send_command(&mut client, "TTL greeting\n");
+    assert_ne!(response, "-1\n");
+    send_resp(&mut client, &["APPEND", "empty", ""], ":0\r\n");
+    let response = send_command(&mut client, "EXISTS empty\n");
+    assert_eq!(response, "1\n");
+    
+    send_command(&mut client, "LPUSH list a\n");
+    send_resp(&mut client, &["APPEND", "list", "b"], "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+}
+
+#[test]
+fn test_proto_max_bulk_len() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Strings can grow up to the limit, but not past it
+    let response = send_command(&mut client, "CONFIG SET proto-max-bulk-len 1mb\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "SETRANGE key 1048575 x\n");
+    assert_eq!(response, "1048576\n");
+    let response = send_command(&mut client, "APPEND key y\n");
+    assert_eq!(response, "ERROR: string exceeds maximum allowed size\n");
+    let response = send_command(&mut client, "SETRANGE other 1048576 x\n");
+    assert_eq!(response, "ERROR: string exceeds maximum allowed size\n");
+    let response = send_command(&mut client, "SETBIT other 8388607 1\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "SETBIT other 8388608 1\n");
+    assert_eq!(response, "ERROR: bit offset is not an integer or out of range\n");
+    
+    // A client announcing a longer bulk string is cut off before sending it
+    client.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n$1048577\r\n").unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "-ERR Protocol error: invalid bulk length 1048577\r\n");
+}
+
+#[test]
+fn test_client_query_buffer_limit() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let response = send_command(&mut client, "CONFIG SET client-query-buffer-limit 1mb\n");
+    assert_eq!(response, "OK\n");
+    
+    // A line that never ends is cut off once it passes the limit
+    let mut other = server.client();
+    other.write_all(&vec![b'a'; (1 << 20) + 1]).unwrap();
+    let mut response = String::new();
+    other.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "-ERR Protocol error: request exceeds client-query-buffer-limit\r\n");
+    
+    // While commands up to it still go through
+    let value = "v".repeat((1 << 20) - 20);
+    let response = send_command(&mut client, &format!("SET key {}\n", value));
+    assert_eq!(response, "OK\n");
+}
+
+#[test]
+fn test_bit_operations() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Bits straddling the first byte boundary grow the value to two bytes
+    for offset in [1, 7, 8, 14] {
+        let response = send_command(&mut client, &format!("SETBIT flags {} 1\n", offset));
+        assert_eq!(response, "0\n", "{}", offset);
+    }
+    let response = send_command(&mut client, "SETBIT flags 7 0\n");
+    assert_eq!(response, "1\n");
+    for (offset, expected) in [(0, "0"), (1, "1"), (7, "0"), (8, "1"), (14, "1"), (15, "0"), (1000, "0")] {
+        let response = send_command(&mut client, &format!("GETBIT flags {}\n", offset));
+        assert_eq!(response, format!("{}\n", expected), "{}", offset);
+    }
+    
+    // Bits 1, 8 and 14 are the bytes 0x40 and 0x82
+    let response = send_command(&mut client, "GET flags\n");
+    assert_eq!(response, "@\u{fffd}\n");
+    let response = send_command(&mut client, "BITCOUNT flags\n");
+    assert_eq!(response, "3\n");
+    let response = send_command(&mut client, "BITCOUNT flags 1 1\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "BITCOUNT flags -2 -2\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "BITCOUNT missing\n");
+    assert_eq!(response, "0\n");
+    
+    // Bits apply to existing strings too: "a" is 0x61
+    send_command(&mut client, "SET letter a\n");
+    let response = send_command(&mut client, "BITCOUNT letter\n");
+    assert_eq!(response, "3\n");
+    send_command(&mut client, "SETBIT letter 6 1\n");
+    let response = send_command(&mut client, "GET letter\n");
+    assert_eq!(response, "c\n");
+    
+    for command in ["SETBIT flags -1 1", "SETBIT flags x 1", "SETBIT flags 0 2", "GETBIT flags -3", "SETBIT flags 4294967296 1"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+}
+
+#[test]
+fn test_hyperloglog() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "PFADD visitors\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "PFCOUNT visitors\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "PFADD visitors alice bob\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "PFADD visitors alice\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "PFCOUNT visitors\n");
+    assert_eq!(response, "2\n");
+    
+    // Many distinct elements, in batches of 100 per command
+    for batch in 0..100 {
+        let elements: Vec<String> = (0..100).map(|i| format!("user:{}", batch * 100 + i)).collect();
+        send_command(&mut client, &format!("PFADD many {}\n", elements.join(" ")));
+    }
+    let response = send_command(&mut client, "PFCOUNT many\n");
+    let count: f64 = response.trim().parse().unwrap();
+    assert!((count - 10_000.0).abs() < 300.0, "estimated {}", count);
+    
+    // Several keys are counted as their union, with missing keys as empty
+    for i in 5000..15_000 {
+        if i % 100 == 0 {
+            let elements: Vec<String> = (i..i + 100).map(|i| format!("user:{}", i)).collect();
+            send_command(&mut client, &format!("PFADD more {}\n", elements.join(" ")));
+        }
+    }
+    let response = send_command(&mut client, "PFCOUNT many more missing\n");
+    let count: f64 = response.trim().parse().unwrap();
+    assert!((count - 15_000.0).abs() < 450.0, "estimated {}", count);
+    
+    send_command(&mut client, "SET string value\n");
+    let response = send_command(&mut client, "PFADD string element\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    send_command(&mut client, "RPUSH list element\n");
+    let response = send_command(&mut client, "PFCOUNT list\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+}
+
+#[test]
+fn test_geo() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "GEOADD cities 13.361389 38.115556 Palermo 15.087269 37.502669 Catania\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "GEOADD cities 12.496366 41.902782 Rome 13.361389 38.115556 Palermo\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "TYPE cities\n");
+    assert_eq!(response, "zset\n");
+    
+    // Catania is 166km from Palermo, Rome about 420km
+    let response = send_command(&mut client, "GEOSEARCH cities FROMMEMBER Palermo BYRADIUS 200 km ASC\n");
+    assert_eq!(response, "Palermo\nCatania\n");
+    let response = send_command(&mut client, "GEOSEARCH cities FROMMEMBER Palermo BYRADIUS 500 km DESC\n");
+    assert_eq!(response, "Rome\nCatania\nPalermo\n");
+    let response = send_command(&mut client, "GEOSEARCH cities FROMMEMBER Palermo BYRADIUS 100 mi\n");
+    assert_eq!(response, "Palermo\n");
+    let response = send_command(&mut client, "GEOSEARCH cities FROMLONLAT 15 37 BYRADIUS 100 km\n");
+    assert_eq!(response, "Catania\n");
+    let response = send_command(&mut client, "GEOSEARCH missing FROMLONLAT 15 37 BYRADIUS 100 km\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    // Positions are validated before anything is added
+    let response = send_command(&mut client, "GEOADD cities 10 45 Milan 13.4 86 North\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "GEOADD cities 181 0 Nowhere\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "ZCARD cities\n");
+    assert_eq!(response, "3\n");
+    
+    let response = send_command(&mut client, "GEOSEARCH cities FROMMEMBER Atlantis BYRADIUS 100 km\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "GEOSEARCH cities FROMMEMBER Palermo BYRADIUS 100 parsecs\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_list_basics() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // LPUSH pushes one element at a time, so they end up reversed
+    let response = send_command(&mut client, "LPUSH list b a\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "RPUSH list c d e\n");
+    assert_eq!(response, "5\n");
+    let response = send_command(&mut client, "LRANGE list 0 -1\n");
+    assert_eq!(response, "a\nb\nc\nd\ne\n");
+    let response = send_command(&mut client, "LRANGE list -2 100\n");
+    assert_eq!(response, "d\ne\n");
+    let response = send_command(&mut client, "LRANGE list 3 1\n");
+    assert_eq!(response, "(empty list)\n");
+    let response = send_command(&mut client, "LLEN list\n");
+    assert_eq!(response, "5\n");
+    
+    let response = send_command(&mut client, "LINDEX list 1\n");
+    assert_eq!(response, "b\n");
+    let response = send_command(&mut client, "LINDEX list -1\n");
+    assert_eq!(response, "e\n");
+    let response = send_command(&mut client, "LINDEX list 5\n");
+    assert_eq!(response, "(nil)\n");
+    
+    let response = send_command(&mut client, "LPOP list\n");
+    assert_eq!(response, "a\n");
+    let response = send_command(&mut client, "RPOP list 2\n");
+    assert_eq!(response, "e\nd\n");
+    send_resp(&mut client, &["LPOP", "list", "1"], "*1\r\n$1\r\nb\r\n");
+    send_resp(&mut client, &["LPOP", "list", "0"], "*0\r\n");
+    
+    // Popping the last element deletes the list
+    let response = send_command(&mut client, "RPOP list 10\n");
+    assert_eq!(response, "c\n");
+    let response = send_command(&mut client, "EXISTS list\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "LPOP list\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "LLEN list\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, "LPOP list -1\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    send_command(&mut client, "SET string value\n");
+    let response = send_command(&mut client, "LPUSH string element\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "LRANGE string 0 -1\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+}
+
+#[test]
+fn test_list_moves() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Rotating a list onto itself
+    send_command(&mut client, "RPUSH ring a b c\n");
+    let response = send_command(&mut client, "RPOPLPUSH ring ring\n");
+    assert_eq!(response, "c\n");
+    let response = send_command(&mut client, "LMOVE ring ring LEFT RIGHT\n");
+    assert_eq!(response, "c\n");
+    let response = send_command(&mut client, "LRANGE ring 0 -1\n");
+    assert_eq!(response, "a\nb\nc\n");
+    send_command(&mut client, "RPUSH single only\n");
+    let response = send_command(&mut client, "LMOVE single single left left\n");
+    assert_eq!(response, "only\n");
+    let response = send_command(&mut client, "LRANGE single 0 -1\n");
+    assert_eq!(response, "only\n");
+    
+    // Moving between two lists creates the destination and deletes the
+    // source once it is empty
+    send_command(&mut client, "RPUSH queue job1 job2\n");
+    let response = send_command(&mut client, "RPOPLPUSH queue processing\n");
+    assert_eq!(response, "job2\n");
+    let response = send_command(&mut client, "LMOVE queue processing LEFT RIGHT\n");
+    assert_eq!(response, "job1\n");
+    let response = send_command(&mut client, "LRANGE processing 0 -1\n");
+    assert_eq!(response, "job2\njob1\n");
+    let response = send_command(&mut client, "EXISTS queue\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "RPOPLPUSH queue processing\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // Nothing moves when either key holds something else
+    send_command(&mut client, "SET string value\n");
+    let response = send_command(&mut client, "RPOPLPUSH processing string\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "RPOPLPUSH string processing\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "LLEN processing\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "LMOVE processing ring UP DOWN\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_list_editing() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "RPUSH list a b c\n");
+    let response = send_command(&mut client, "LSET list -1 z\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "LSET list 3 z\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "LSET missing 0 z\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    let response = send_command(&mut client, "LINSERT list BEFORE b x\n");
+    assert_eq!(response, "4\n");
+    let response = send_command(&mut client, "LINSERT list after z y\n");
+    assert_eq!(response, "5\n");
+    let response = send_command(&mut client, "LRANGE list 0 -1\n");
+    assert_eq!(response, "a\nx\nb\nz\ny\n");
+    
+    // A missing pivot leaves the list alone, and a missing list is 0
+    let response = send_command(&mut client, "LINSERT list BEFORE nothere x\n");
+    assert_eq!(response, "-1\n");
+    let response = send_command(&mut client, "LLEN list\n");
+    assert_eq!(response, "5\n");
+    let response = send_command(&mut client, "LINSERT missing BEFORE a x\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "LINSERT list NEXTTO a x\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    // A positive count removes from the head, a negative one from the tail
+    send_command(&mut client, "DEL list\n");
+    send_command(&mut client, "RPUSH list v 1 v 2 v 3 v\n");
+    let response = send_command(&mut client, "LREM list -2 v\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "LRANGE list 0 -1\n");
+    assert_eq!(response, "v\n1\nv\n2\n3\n");
+    let response = send_command(&mut client, "LREM list 1 v\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "LRANGE list 0 -1\n");
+    assert_eq!(response, "1\nv\n2\n3\n");
+    let response = send_command(&mut client, "LREM list 0 v\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "LREM list 0 nothere\n");
+    assert_eq!(response, "0\n");
+    
+    // Removing everything deletes the list
+    send_command(&mut client, "LREM list 0 1\n");
+    send_command(&mut client, "LREM list 0 2\n");
+    send_command(&mut client, "LREM list 0 3\n");
+    let response = send_command(&mut client, "EXISTS list\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_lpos() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "RPUSH list a b c 1 2 3 c c\n");
+    let response = send_command(&mut client, "LPOS list c\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "LPOS list c RANK 2\n");
+    assert_eq!(response, "6\n");
+    
+    // A negative rank searches from the tail, -1 giving the last match
+    let response = send_command(&mut client, "LPOS list c RANK -1\n");
+    assert_eq!(response, "7\n");
+    let response = send_command(&mut client, "LPOS list c RANK -3\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "LPOS list c RANK 4\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // COUNT limits the matches returned, 0 returning all of them
+    let response = send_command(&mut client, "LPOS list c COUNT 2\n");
+    assert_eq!(response, "2\n6\n");
+    let response = send_command(&mut client, "LPOS list c COUNT 0\n");
+    assert_eq!(response, "2\n6\n7\n");
+    let response = send_command(&mut client, "LPOS list c RANK -2 COUNT 0\n");
+    assert_eq!(response, "6\n2\n");
+    
+    // Missing elements and keys
+    let response = send_command(&mut client, "LPOS list nothere\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "LPOS list nothere COUNT 0\n");
+    assert_eq!(response, "(empty list)\n");
+    let response = send_command(&mut client, "LPOS missing a\n");
+    assert_eq!(response, "(nil)\n");
+    
+    for command in ["LPOS list c RANK 0", "LPOS list c COUNT -1", "LPOS list c RANK x", "LPOS list c MAXLEN", "LPOS list c FROM 1"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    send_command(&mut client, "SET string value\n");
+    let response = send_command(&mut client, "LPOS string a\n");
+    assert!(response.starts_with("ERROR: WRONGTYPE"), "{}", response);
+}
+
+#[test]
+fn test_ltrim() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // A capped log: keep the last 3 entries
+    send_command(&mut client, "RPUSH log 1 2 3 4 5\n");
+    let response = send_command(&mut client, "LTRIM log -3 -1\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "LRANGE log 0 -1\n");
+    assert_eq!(response, "3\n4\n5\n");
+    
+    // Out of range indexes are clamped to the list
+    send_command(&mut client, "LTRIM log 1 100\n");
+    let response = send_command(&mut client, "LRANGE log 0 -1\n");
+    assert_eq!(response, "4\n5\n");
+    
+    // An empty range deletes the list
+    let response = send_command(&mut client, "LTRIM log 5 10\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "EXISTS log\n");
+    assert_eq!(response, "0\n");
+    send_command(&mut client, "RPUSH log 1 2\n");
+    send_command(&mut client, "LTRIM log 1 0\n");
+    let response = send_command(&mut client, "EXISTS log\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, "LTRIM missing 0 1\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "LTRIM log a 1\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_pushx() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Onto an existing list they push like LPUSH and RPUSH
+    send_command(&mut client, "RPUSH queue b\n");
+    let response = send_command(&mut client, "LPUSHX queue a\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "RPUSHX queue c d\n");
+    assert_eq!(response, "4\n");
+    let response = send_command(&mut client, "LRANGE queue 0 -1\n");
+    assert_eq!(response, "a\nb\nc\nd\n");
+    
+    // A queue that was deleted stays deleted
+    for command in ["LPUSHX", "RPUSHX"] {
+        let response = send_command(&mut client, &format!("{} gone a b\n", command));
+        assert_eq!(response, "0\n", "{}", command);
+    }
+    let response = send_command(&mut client, "EXISTS gone\n");
+    assert_eq!(response, "0\n");
+    
+    send_command(&mut client, "SET name value\n");
+    send_resp(&mut client, &["LPUSHX", "name", "a"], "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+    send_resp(&mut client, &["RPUSHX", "name", "a"], "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+    send_resp(&mut client, &["RPUSHX", "queue"], "-ERR wrong number of arguments for 'rpushx' command\r\n");
+}
+
+#[test]
+fn test_blocking_pops() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let mut other = server.client();
+    
+    // An element already there is popped right away, from the first key holding a list
+    send_command(&mut other, "RPUSH second a b\n");
+    let response = send_command(&mut client, "BLPOP first second 0\n");
+    assert_eq!(response, "second\na\n");
+    let response = send_command(&mut client, "BRPOP first second 0\n");
+    assert_eq!(response, "second\nb\n");
+    let response = send_command(&mut client, "EXISTS second\n");
+    assert_eq!(response, "0\n");
+    
+    // Otherwise the client waits until another one pushes, while other clients are served
+    client.write_all(b"BLPOP jobs urgent 0\nECHO after\n").unwrap();
+    sleep(Duration::from_millis(100));
+    let response = send_command(&mut other, "RPUSH urgent job1 job2\n");
+    assert_eq!(response, "2\n");
+    // Commands pipelined after it wait their turn
+    let mut response = send_command(&mut client, "");
+    while !response.ends_with("after\n") {
+        response += &send_command(&mut client, "");
+    }
+    assert_eq!(response, "urgent\njob1\nafter\n");
+    let response = send_command(&mut other, "LRANGE urgent 0 -1\n");
+    assert_eq!(response, "job2\n");
+    
+    // Blocked clients are counted in INFO and show their keys in CLIENT LIST
+    let waiting_id = send_command(&mut client, "CLIENT ID\n").trim().to_string();
+    client.write_all(b"BLPOP tasks later 0\n").unwrap();
+    sleep(Duration::from_millis(100));
+    let info = send_command(&mut other, "INFO clients\n");
+    assert_eq!(info_field(&info, "blocked_clients"), Some("1"), "{}", info);
+    let list = send_command(&mut other, "CLIENT LIST\n");
+    let line = list.lines().find(|line| line.starts_with(&format!("id={} ", waiting_id))).unwrap();
+    assert!(line.ends_with(" blocked=tasks,later"), "{}", line);
+    send_command(&mut other, "RPUSH later task1\n");
+    let response = send_command(&mut client, "");
+    assert_eq!(response, "later\ntask1\n");
+    let info = send_command(&mut other, "INFO clients\n");
+    assert_eq!(info_field(&info, "blocked_clients"), Some("0"), "{}", info);
+    let list = send_command(&mut other, "CLIENT LIST\n");
+    assert!(!list.contains("blocked=tasks"), "{}", list);
+    
+    // Or until another client unblocks it, as if it timed out or with an error
+    client.write_all(b"BLPOP tasks 0\n").unwrap();
+    sleep(Duration::from_millis(100));
+    let response = send_command(&mut other, &format!("CLIENT UNBLOCK {}\n", waiting_id));
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "");
+    assert_eq!(response, "(nil)\n");
+    client.write_all(b"BRPOP tasks 0\n").unwrap();
+    sleep(Duration::from_millis(100));
+    let response = send_command(&mut other, &format!("CLIENT UNBLOCK {} ERROR\n", waiting_id));
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "");
+    assert!(response.contains("UNBLOCKED"), "{}", response);
+    let response = send_command(&mut other, &format!("CLIENT UNBLOCK {}\n", waiting_id));
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut other, "INFO clients\n");
+    assert_eq!(info_field(&response, "blocked_clients"), Some("0"), "{}", response);
+    
+    // Or until the timeout passes
+    let start = Instant::now();
+    let response = send_command(&mut client, "BRPOP empty 0.2\n");
+    assert_eq!(response, "(nil)\n");
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    
+    // A client that disconnects while blocked doesn't take an element
+    let mut gone = server.client();
+    gone.write_all(b"BLPOP jobs 0\n").unwrap();
+    sleep(Duration::from_millis(100));
+    drop(gone);
+    sleep(Duration::from_millis(100));
+    send_command(&mut other, "LPUSH jobs job3\n");
+    let response = send_command(&mut other, "LLEN jobs\n");
+    assert_eq!(response, "1\n");
+    
+    // Inside a transaction it never blocks
+    send_command(&mut client, "MULTI\n");
+    send_command(&mut client, "BLPOP empty 0\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "(nil)\n");
+    
+    for command in ["BLPOP jobs\n", "BLPOP jobs -1\n", "BLPOP jobs soon\n"] {
+        let response = send_command(&mut client, command);
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    send_command(&mut client, "SET text value\n");
+    let response = send_command(&mut client, "BLPOP text 0\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+}
+
+#[test]
+fn test_hash_basics() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "HSET user name alice age 30\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "HSET user age 31 city paris\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "HGET user age\n");
+    assert_eq!(response, "31\n");
+    let response = send_command(&mut client, "HGET user missing\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "TYPE user\n");
+    assert_eq!(response, "hash\n");
+    
+    // Fields come back in no particular order
+    let response = send_command(&mut client, "HGETALL user\n");
+    let lines: Vec<&str> = response.lines().collect();
+    let mut pairs: Vec<(&str, &str)> = lines.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+    pairs.sort();
+    assert_eq!(pairs, [("age", "31"), ("city", "paris"), ("name", "alice")]);
+    
+    let response = send_command(&mut client, "HDEL user age missing\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "HDEL user name city\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "EXISTS user\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "HGETALL user\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    let response = send_command(&mut client, "HSET user name\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    send_command(&mut client, "SET string value\n");
+    let response = send_command(&mut client, "HGET string field\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+}
+
+#[test]
+fn test_hash_reads() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let sorted_lines = |response: String| {
+        let mut lines: Vec<String> = response.lines().map(str::to_string).collect();
+        lines.sort();
+        lines
+    };
+    
+    send_command(&mut client, "HSET user name alice age 30 city paris\n");
+    let response = send_command(&mut client, "HEXISTS user name\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "HEXISTS user email\n");
+    assert_eq!(response, "0\n");
+    assert_eq!(sorted_lines(send_command(&mut client, "HKEYS user\n")), ["age", "city", "name"]);
+    assert_eq!(sorted_lines(send_command(&mut client, "HVALS user\n")), ["30", "alice", "paris"]);
+    let response = send_command(&mut client, "HLEN user\n");
+    assert_eq!(response, "3\n");
+    
+    // A missing key is an empty hash
+    let response = send_command(&mut client, "HEXISTS missing name\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "HKEYS missing\n");
+    assert_eq!(response, "(empty list)\n");
+    let response = send_command(&mut client, "HVALS missing\n");
+    assert_eq!(response, "(empty list)\n");
+    let response = send_command(&mut client, "HLEN missing\n");
+    assert_eq!(response, "0\n");
+    
+    send_command(&mut client, "RPUSH list element\n");
+    for command in ["HEXISTS list name", "HKEYS list", "HVALS list", "HLEN list"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.contains("WRONGTYPE"), "{}: {}", command, response);
+    }
+}
+
+#[test]
+fn test_hmget_and_hsetnx() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "HSET user name alice age 30\n");
+    send_resp(&mut client, &["HMGET", "user", "name", "email", "age"], "*3\r\n$5\r\nalice\r\n$-1\r\n$2\r\n30\r\n");
+    send_resp(&mut client, &["HMGET", "missing", "name", "age"], "*2\r\n$-1\r\n$-1\r\n");
+    
+    // HSETNX only sets fields that aren't there yet
+    let response = send_command(&mut client, "HSETNX user name bob\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "HGET user name\n");
+    assert_eq!(response, "alice\n");
+    let response = send_command(&mut client, "HSETNX user email alice@example.com\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "HGET user email\n");
+    assert_eq!(response, "alice@example.com\n");
+    let response = send_command(&mut client, "HSETNX fresh field value\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "HLEN fresh\n");
+    assert_eq!(response, "1\n");
+    
+    send_command(&mut client, "SET string value\n");
+    for command in ["HMGET string name", "HSETNX string name value"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.contains("WRONGTYPE"), "{}: {}", command, response);
+    }
+}
+
+#[test]
+fn test_set_basics() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "SADD tags red green red\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "SADD tags green blue\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "SCARD tags\n");
+    assert_eq!(response, "3\n");
+    let response = send_command(&mut client, "SISMEMBER tags blue\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "SISMEMBER tags pink\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "TYPE tags\n");
+    assert_eq!(response, "set\n");
+    
+    // Members come back in no particular order
+    let response = send_command(&mut client, "SMEMBERS tags\n");
+    let mut members: Vec<&str> = response.lines().collect();
+    members.sort();
+    assert_eq!(members, ["blue", "green", "red"]);
+    
+    let response = send_command(&mut client, "SREM tags red pink\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "SREM tags green blue\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "EXISTS tags\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "SMEMBERS tags\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    send_command(&mut client, "SET string value\n");
+    for command in ["SADD string member", "SCARD string", "SINTER string"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.contains("WRONGTYPE"), "{}: {}", command, response);
+    }
+}
+
+#[test]
+fn test_set_operations() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SADD a 1 2 3 4\n");
+    send_command(&mut client, "SADD b 2 3 5\n");
+    send_command(&mut client, "SADD c 3 4 5 6\n");
+    
+    let mut sorted = |command: &str| {
+        let response = send_command(&mut client, command);
+        let mut members: Vec<String> = response.lines().map(str::to_string).collect();
+        members.sort();
+        members
+    };
+    assert_eq!(sorted("SINTER a b c\n"), ["3"]);
+    assert_eq!(sorted("SINTER a c\n"), ["3", "4"]);
+    assert_eq!(sorted("SUNION a b c\n"), ["1", "2", "3", "4", "5", "6"]);
+    assert_eq!(sorted("SDIFF a b c\n"), ["1"]);
+    assert_eq!(sorted("SDIFF c a\n"), ["5", "6"]);
+    
+    // A missing key is an empty set
+    assert_eq!(sorted("SINTER a missing\n"), ["(empty list)"]);
+    assert_eq!(sorted("SUNION missing b\n"), ["2", "3", "5"]);
+    assert_eq!(sorted("SDIFF a missing\n"), ["1", "2", "3", "4"]);
+    assert_eq!(sorted("SDIFF missing a\n"), ["(empty list)"]);
+}
+
+#[test]
+fn test_set_store_operations() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SADD a 1 2 3\n");
+    send_command(&mut client, "SADD b 2 3 4\n");
+    
+    // The destination is replaced, whatever it held before
+    send_command(&mut client, "SET dest value EX 100\n");
+    let response = send_command(&mut client, "SINTERSTORE dest a b\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "SMEMBERS dest\n");
+    let mut members: Vec<&str> = response.lines().collect();
+    members.sort();
+    assert_eq!(members, ["2", "3"]);
+    let response = send_command(&mut client, "TTL dest\n");
+    assert_eq!(response, "-1\n");
+    
+    let response = send_command(&mut client, "SUNIONSTORE dest a b\n");
+    assert_eq!(response, "4\n");
+    let response = send_command(&mut client, "SDIFFSTORE dest a b\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "SMEMBERS dest\n");
+    assert_eq!(response, "1\n");
+    
+    // A source can also be the destination
+    let response = send_command(&mut client, "SUNIONSTORE a a b\n");
+    assert_eq!(response, "4\n");
+    
+    // An empty result deletes the destination
+    let response = send_command(&mut client, "SINTERSTORE dest b missing\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "EXISTS dest\n");
+    assert_eq!(response, "0\n");
+    
+    send_command(&mut client, "SET string value\n");
+    let response = send_command(&mut client, "SUNIONSTORE dest a string\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+}
+
+#[test]
+fn test_set_random_members() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SADD set a b c\n");
+    
+    // Counts larger than the set return every member once
+    let response = send_command(&mut client, "SRANDMEMBER set 10\n");
+    let mut members: Vec<&str> = response.lines().collect();
+    members.sort();
+    assert_eq!(members, ["a", "b", "c"]);
+    
+    // A negative count repeats members to return exactly that many
+    let response = send_command(&mut client, "SRANDMEMBER set -20\n");
+    let members: Vec<&str> = response.lines().collect();
+    assert_eq!(members.len(), 20);
+    assert!(members.iter().all(|member| ["a", "b", "c"].contains(member)), "{:?}", members);
+    
+    let response = send_command(&mut client, "SRANDMEMBER set\n");
+    assert!(["a\n", "b\n", "c\n"].contains(&response.as_str()), "{}", response);
+    let response = send_command(&mut client, "SCARD set\n");
+    assert_eq!(response, "3\n");
+    
+    let response = send_command(&mut client, "SPOP set\n");
+    let popped = response.trim().to_string();
+    let response = send_command(&mut client, &format!("SISMEMBER set {}\n", popped));
+    assert_eq!(response, "0\n");
+    
+    // Popping more than is left empties the set, which deletes it
+    let response = send_command(&mut client, "SPOP set 10\n");
+    assert_eq!(response.lines().count(), 2);
+    let response = send_command(&mut client, "EXISTS set\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, "SPOP set\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "SPOP set 2\n");
+    assert_eq!(response, "(empty list)\n");
+    let response = send_command(&mut client, "SRANDMEMBER set -3\n");
+    assert_eq!(response, "(empty list)\n");
+    let response = send_command(&mut client, "SPOP set -1\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_aof_logs_spop_as_srem() {
+    let dir = temp_dir("aof-spop");
+    let env = [
+        ("REDIS_DIR", dir.to_str().unwrap()),
+        ("REDIS_APPENDONLY", "yes"),
+    ];
+    
+    let remaining = {
+        let server = TestServer::with_env(&env);
+        let mut client = server.client();
+        
+        send_command(&mut client, "SADD set a b c d e\n");
+        send_command(&mut client, "SPOP set 2\n");
+        send_command(&mut client, "SPOP set\n");
+        let response = send_command(&mut client, "SMEMBERS set\n");
+        let mut remaining: Vec<String> = response.lines().map(str::to_string).collect();
+        remaining.sort();
+        remaining
+    };
+    
+    let log = fs::read_to_string(dir.join("appendonly.aof")).unwrap();
+    assert_eq!(log.matches("SREM").count(), 2, "{:?}", log);
+    assert!(!log.contains("SPOP"), "{:?}", log);
+    
+    // The replay removes the members that were popped, not others
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    let response = send_command(&mut client, "SMEMBERS set\n");
+    let mut members: Vec<&str> = response.lines().collect();
+    members.sort();
+    assert_eq!(members, remaining);
+    
+    drop(client);
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_aof_logs_blocking_pops_as_pops() {
+    let dir = temp_dir("aof-blpop");
+    let env = [
+        ("REDIS_DIR", dir.to_str().unwrap()),
+        ("REDIS_APPENDONLY", "yes"),
+    ];
+    
+    {
+        let server = TestServer::with_env(&env);
+        let mut client = server.client();
+        let mut other = server.client();
+        
+        send_command(&mut client, "RPUSH jobs a b c\n");
+        send_command(&mut client, "BRPOP jobs 0\n");
+        client.write_all(b"BLPOP waiting 0\n").unwrap();
+        sleep(Duration::from_millis(100));
+        send_command(&mut other, "RPUSH waiting x\n");
+        send_command(&mut client, "");
+        send_command(&mut client, "BLPOP jobs 0\n");
+        send_command(&mut client, "BLPOP empty 0.01\n");
+    }
+    
+    // Only pops that happened are logged, and a replay never blocks
+    let log = fs::read_to_string(dir.join("appendonly.aof")).unwrap();
+    assert!(!log.contains("BLPOP") && !log.contains("BRPOP"), "{:?}", log);
+    assert_eq!(log.matches("LPOP").count(), 2, "{:?}", log);
+    assert_eq!(log.matches("RPOP").count(), 1, "{:?}", log);
+    
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    let response = send_command(&mut client, "LRANGE jobs 0 -1\n");
+    assert_eq!(response, "b\n");
+    let response = send_command(&mut client, "EXISTS waiting\n");
+    assert_eq!(response, "0\n");
+    
+    drop(client);
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sort() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "RPUSH numbers 10 2 -1.5 2 33\n");
+    let response = send_command(&mut client, "SORT numbers\n");
+    assert_eq!(response, "-1.5\n2\n2\n10\n33\n");
+    let response = send_command(&mut client, "SORT numbers DESC\n");
+    assert_eq!(response, "33\n10\n2\n2\n-1.5\n");
+    // The list itself is left as it was
+    let response = send_command(&mut client, "LRANGE numbers 0 -1\n");
+    assert_eq!(response, "10\n2\n-1.5\n2\n33\n");
+    
+    // Numbers compare as strings with ALPHA
+    send_command(&mut client, "SADD words pear apple 10 9\n");
+    let response = send_command(&mut client, "SORT words ALPHA\n");
+    assert_eq!(response, "10\n9\napple\npear\n");
+    let response = send_command(&mut client, "SORT words ALPHA DESC\n");
+    assert_eq!(response, "pear\napple\n9\n10\n");
+    let response = send_command(&mut client, "SORT words\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    // LIMIT windows the sorted result
+    let response = send_command(&mut client, "SORT numbers LIMIT 1 2\n");
+    assert_eq!(response, "2\n2\n");
+    let response = send_command(&mut client, "SORT numbers DESC LIMIT 3 10\n");
+    assert_eq!(response, "2\n-1.5\n");
+    let response = send_command(&mut client, "SORT numbers LIMIT 10 2\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    let response = send_command(&mut client, "SORT missing\n");
+    assert_eq!(response, "(empty list)\n");
+    send_command(&mut client, "SET text value\n");
+    let response = send_command(&mut client, "SORT text\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "SORT numbers LIMIT 1\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_smove() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SADD source a b\n");
+    let response = send_command(&mut client, "SMOVE source destination a\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "SISMEMBER source a\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "SMEMBERS destination\n");
+    assert_eq!(response, "a\n");
+    
+    // A member that isn't in the source stays where it is
+    let response = send_command(&mut client, "SMOVE source destination missing\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "SMOVE source destination a\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "SCARD destination\n");
+    assert_eq!(response, "1\n");
+    
+    // Moving the last member deletes the source
+    let response = send_command(&mut client, "SMOVE source destination b\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "EXISTS source\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "SCARD destination\n");
+    assert_eq!(response, "2\n");
+    
+    // Either key holding another type is an error, even with nothing to move
+    send_command(&mut client, "SET string value\n");
+    let response = send_command(&mut client, "SMOVE destination string a\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "SMOVE string destination a\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "SMOVE missing string a\n");
+    assert!(response.contains("WRONGTYPE"), "{}", response);
+    let response = send_command(&mut client, "SCARD destination\n");
+    assert_eq!(response, "2\n");
+}
+
+#[test]
+fn test_hash_increments() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // A missing field, or hash, starts from 0
+    let response = send_command(&mut client, "HINCRBY counters hits 5\n");
+    assert_eq!(response, "5\n");
+    let response = send_command(&mut client, "HINCRBY counters hits -7\n");
+    assert_eq!(response, "-2\n");
+    let response = send_command(&mut client, "HINCRBYFLOAT counters ratio 0.5\n");
+    assert_eq!(response, "0.5\n");
+    let response = send_command(&mut client, "HINCRBYFLOAT counters ratio 2\n");
+    assert_eq!(response, "2.5\n");
+    let response = send_command(&mut client, "HINCRBYFLOAT counters hits 1.5\n");
+    assert_eq!(response, "-0.5\n");
+    let response = send_command(&mut client, "HGET counters hits\n");
+    assert_eq!(response, "-0.5\n");
+    
+    // Fields that don't hold a number, and results that don't fit, are refused
+    send_command(&mut client, "HSET counters name alice big 9223372036854775807\n");
+    for command in [
+        "HINCRBY counters name 1",
+        "HINCRBY counters hits 1",
+        "HINCRBY counters big 1",
+        "HINCRBY counters hits one",
+        "HINCRBYFLOAT counters name 1",
+        "HINCRBYFLOAT counters ratio inf",
+        "HINCRBYFLOAT counters ratio nan",
+    ] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    let response = send_command(&mut client, "HGET counters name\n");
+    assert_eq!(response, "alice\n");
+    let response = send_command(&mut client, "HGET counters big\n");
+    assert_eq!(response, "9223372036854775807\n");
+    
+    // A failed increment doesn't create the hash
+    let response = send_command(&mut client, "HINCRBYFLOAT fresh ratio inf\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "EXISTS fresh\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_sorted_set_basics() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "ZADD board 30 carol 10 alice 20 bob\n");
+    assert_eq!(response, "3\n");
+    let response = send_command(&mut client, "ZCARD board\n");
+    assert_eq!(response, "3\n");
+    let response = send_command(&mut client, "TYPE board\n");
+    assert_eq!(response, "zset\n");
+    
+    // Ascending by score, with ties in byte order
+    send_command(&mut client, "ZADD board 20 aaron\n");
+    let response = send_command(&mut client, "ZRANGE board 0 -1\n");
+    assert_eq!(response, "alice\naaron\nbob\ncarol\n");
+    let response = send_command(&mut client, "ZRANGE board -2 -1 WITHSCORES\n");
+    assert_eq!(response, "bob\n20\ncarol\n30\n");
+    let response = send_command(&mut client, "ZRANGE board 5 10\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    // Updating a score moves the member and doesn't count as an addition
+    let response = send_command(&mut client, "ZADD board 1.5 carol 40 dave\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "ZSCORE board carol\n");
+    assert_eq!(response, "1.5\n");
+    let response = send_command(&mut client, "ZRANGE board 0 0\n");
+    assert_eq!(response, "carol\n");
+    let response = send_command(&mut client, "ZSCORE board nobody\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // Scores are bulk strings in RESP2
+    send_resp(&mut client, &["ZRANGE", "board", "0", "1", "WITHSCORES"], "*4\r\n$5\r\ncarol\r\n$3\r\n1.5\r\n$5\r\nalice\r\n$2\r\n10\r\n");
+    send_resp(&mut client, &["ZADD", "infinite", "-inf", "low", "+inf", "high"], ":2\r\n");
+    send_resp(&mut client, &["ZRANGE", "infinite", "0", "-1", "WITHSCORES"], "*4\r\n$3\r\nlow\r\n$4\r\n-inf\r\n$4\r\nhigh\r\n$3\r\ninf\r\n");
+    
+    // A score that isn't a float is rejected and nothing is added
+    let response = send_command(&mut client, "ZADD board 5 eve abc frank\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "ZADD board nan eve\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "ZCARD board\n");
+    assert_eq!(response, "5\n");
+    
+    // Sorted set and string commands refuse each other's keys
+    send_command(&mut client, "SET name value\n");
+    send_resp(&mut client, &["ZADD", "name", "1", "member"], "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+    for command in ["ZRANGE name 0 -1", "ZSCORE name member", "ZCARD name", "GET board", "GETRANGE board 0 1", "SETBIT board 0 1"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR: WRONGTYPE"), "{}: {}", command, response);
+    }
+    let response = send_command(&mut client, "ZCARD missing\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_sorted_set_ranks() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Ties are broken by the member's bytes, in both directions
+    send_command(&mut client, "ZADD board 20 bob 10 alice 20 aaron 30 carol 20 bea\n");
+    let response = send_command(&mut client, "ZRANGE board 0 -1\n");
+    assert_eq!(response, "alice\naaron\nbea\nbob\ncarol\n");
+    let response = send_command(&mut client, "ZREVRANGE board 0 -1\n");
+    assert_eq!(response, "carol\nbob\nbea\naaron\nalice\n");
+    let response = send_command(&mut client, "ZREVRANGE board 0 1 WITHSCORES\n");
+    assert_eq!(response, "carol\n30\nbob\n20\n");
+    let response = send_command(&mut client, "ZREVRANGE board -2 -1\n");
+    assert_eq!(response, "aaron\nalice\n");
+    let response = send_command(&mut client, "ZREVRANGE board 5 10\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    // Each member's rank is its position in ZRANGE, and its reverse rank its
+    // position in ZREVRANGE
+    for (rank, member) in ["alice", "aaron", "bea", "bob", "carol"].iter().enumerate() {
+        let response = send_command(&mut client, &format!("ZRANK board {}\n", member));
+        assert_eq!(response, format!("{}\n", rank));
+        let response = send_command(&mut client, &format!("ZRANGE board {} {}\n", rank, rank));
+        assert_eq!(response, format!("{}\n", member));
+        let response = send_command(&mut client, &format!("ZREVRANK board {}\n", member));
+        assert_eq!(response, format!("{}\n", 4 - rank));
+        let response = send_command(&mut client, &format!("ZREVRANGE board {} {}\n", 4 - rank, 4 - rank));
+        assert_eq!(response, format!("{}\n", member));
+    }
+    
+    // Ranks follow score changes
+    send_command(&mut client, "ZADD board 5 carol\n");
+    let response = send_command(&mut client, "ZRANK board carol\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "ZREVRANK board bob\n");
+    assert_eq!(response, "0\n");
+    
+    // Nil for a missing member or key
+    send_resp(&mut client, &["ZRANK", "board", "nobody"], "$-1\r\n");
+    send_resp(&mut client, &["ZREVRANK", "missing", "alice"], "$-1\r\n");
+    send_resp(&mut client, &["ZREVRANGE", "missing", "0", "-1"], "*0\r\n");
+    
+    send_command(&mut client, "SET name value\n");
+    for command in ["ZRANK name member", "ZREVRANK name member", "ZREVRANGE name 0 -1"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR: WRONGTYPE"), "{}: {}", command, response);
+    }
+    for command in ["ZRANK board", "ZREVRANGE board 0", "ZREVRANGE board a 1", "ZREVRANGE board 0 1 BYSCORE"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+}
+
+#[test]
+fn test_sorted_set_score_ranges() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "ZADD scores 1 a 2 b 2 c 3 d -5 low\n");
+    
+    // Inclusive and exclusive bounds
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores 2 3\n");
+    assert_eq!(response, "b\nc\nd\n");
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores (2 3\n");
+    assert_eq!(response, "d\n");
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores 1 (2 WITHSCORES\n");
+    assert_eq!(response, "a\n1\n");
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores (1 (3\n");
+    assert_eq!(response, "b\nc\n");
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores (3 +inf\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    // Infinite bounds, and LIMIT applied to the matching members
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores -inf +inf\n");
+    assert_eq!(response, "low\na\nb\nc\nd\n");
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores -inf (1\n");
+    assert_eq!(response, "low\n");
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores -inf +inf LIMIT 1 2\n");
+    assert_eq!(response, "a\nb\n");
+    let response = send_command(&mut client, "ZRANGEBYSCORE scores 2 +inf WITHSCORES LIMIT 2 -1\n");
+    assert_eq!(response, "d\n3\n");
+    
+    for command in ["ZRANGEBYSCORE scores x 1", "ZRANGEBYSCORE scores 1 (", "ZRANGEBYSCORE scores 0 1 LIMIT 1", "ZRANGEBYSCORE scores 0 1 BYLEX"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    
+    // ZINCRBY adds a missing member at the increment, to a new key too
+    let response = send_command(&mut client, "ZINCRBY scores 2.5 a\n");
+    assert_eq!(response, "3.5\n");
+    let response = send_command(&mut client, "ZINCRBY scores 4 new\n");
+    assert_eq!(response, "4\n");
+    let response = send_command(&mut client, "ZINCRBY fresh -1 member\n");
+    assert_eq!(response, "-1\n");
+    let response = send_command(&mut client, "ZRANGE scores -2 -1\n");
+    assert_eq!(response, "a\nnew\n");
+    let response = send_command(&mut client, "ZINCRBY scores one a\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    send_command(&mut client, "ZADD scores +inf top\n");
+    let response = send_command(&mut client, "ZINCRBY scores -inf top\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    // ZREM counts only members that were there, and deletes an emptied set
+    let response = send_command(&mut client, "ZREM scores a b nobody\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "ZREM fresh member\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "EXISTS fresh\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "ZREM fresh member\n");
+    assert_eq!(response, "0\n");
+    
+    send_command(&mut client, "SET name value\n");
+    for command in ["ZRANGEBYSCORE name 0 1", "ZREM name a", "ZINCRBY name 1 a"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR: WRONGTYPE"), "{}: {}", command, response);
+    }
+}
+
+
+#[test]
+fn test_sorted_set_range_removal() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "ZADD scores 1 a 2 b 2 c 3 d -5 low 10 high\n");
+    
+    // ZCOUNT takes the same bounds as ZRANGEBYSCORE
+    let response = send_command(&mut client, "ZCOUNT scores 2 3\n");
+    assert_eq!(response, "3\n");
+    let response = send_command(&mut client, "ZCOUNT scores (2 3\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "ZCOUNT scores -inf +inf\n");
+    assert_eq!(response, "6\n");
+    let response = send_command(&mut client, "ZCOUNT scores (3 (10\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "ZCOUNT missing -inf +inf\n");
+    assert_eq!(response, "0\n");
+    
+    // Removing by score
+    let response = send_command(&mut client, "ZREMRANGEBYSCORE scores (1 2\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "ZCARD scores\n");
+    assert_eq!(response, "4\n");
+    let response = send_command(&mut client, "ZRANGE scores 0 -1\n");
+    assert_eq!(response, "low\na\nd\nhigh\n");
+    let response = send_command(&mut client, "ZREMRANGEBYSCORE scores 4 (10\n");
+    assert_eq!(response, "0\n");
+    
+    // Removing by rank, with negative ranks from the highest score
+    let response = send_command(&mut client, "ZREMRANGEBYRANK scores -2 -1\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "ZRANGE scores 0 -1\n");
+    assert_eq!(response, "low\na\n");
+    let response = send_command(&mut client, "ZREMRANGEBYRANK scores 5 10\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "ZCARD scores\n");
+    assert_eq!(response, "2\n");
+    
+    // The key goes once the last member does
+    let response = send_command(&mut client, "ZREMRANGEBYRANK scores 0 -1\n");
+    assert_eq!(response, "2\n");
+    let response = send_command(&mut client, "EXISTS scores\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "ZREMRANGEBYSCORE scores -inf +inf\n");
+    assert_eq!(response, "0\n");
+    
+    send_command(&mut client, "SET name value\n");
+    for command in ["ZCOUNT name 0 1", "ZREMRANGEBYSCORE name 0 1", "ZREMRANGEBYRANK name 0 1"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR: WRONGTYPE"), "{}: {}", command, response);
+    }
+    for command in ["ZCOUNT name x 1", "ZREMRANGEBYSCORE name 0 (", "ZREMRANGEBYRANK name 0 1.5", "ZCOUNT name 0"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+        assert!(!response.contains("WRONGTYPE"), "{}: {}", command, response);
+    }
+}
+#[test]
+fn test_expire_conditions() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let ttl = |client: &mut TcpStream| send_command(client, "TTL key\n");
+    
+    send_command(&mut client, "SET key value\n");
+    
+    // Without a TTL: XX and GT refuse, since no expiry counts as infinite
+    for option in ["XX", "GT"] {
+        let response = send_command(&mut client, &format!("EXPIRE key 100 {}\n", option));
+        assert_eq!(response, "0\n", "{}", option);
+        assert_eq!(ttl(&mut client), "-1\n");
+    }
+    let response = send_command(&mut client, "EXPIRE key 100 NX\n");
+    assert_eq!(response, "1\n");
+    assert_eq!(ttl(&mut client), "100\n");
+    let response = send_command(&mut client, "EXPIRE key 200 NX\n");
+    assert_eq!(response, "0\n");
+    
+    // With one: GT only extends it, LT only shortens it
+    let response = send_command(&mut client, "EXPIRE key 50 GT\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "EXPIRE key 200 GT\n");
+    assert_eq!(response, "1\n");
+    assert_eq!(ttl(&mut client), "200\n");
+    let response = send_command(&mut client, "EXPIRE key 300 LT\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, "EXPIRE key 150 lt\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "EXPIRE key 120 XX\n");
+    assert_eq!(response, "1\n");
+    assert_eq!(ttl(&mut client), "120\n");
+    
+    // LT sets a TTL on a key that has none
+    send_command(&mut client, "SET key value\n");
+    let response = send_command(&mut client, "EXPIRE key 100 LT\n");
+    assert_eq!(response, "1\n");
+    assert_eq!(ttl(&mut client), "100\n");
+    
+    for options in ["NX XX", "GT LT", "NX GT", "SOMETIME"] {
+        let response = send_command(&mut client, &format!("EXPIRE key 10 {}\n", options));
+        assert!(response.starts_with("ERROR"), "{}: {}", options, response);
+    }
+    assert_eq!(ttl(&mut client), "100\n");
+}
+
+#[test]
+fn test_expireat_and_pexpireat() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    
+    send_command(&mut client, "SET key value\n");
+    let response = send_command(&mut client, &format!("EXPIREAT key {}\n", now.as_secs() + 100));
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "TTL key\n");
+    let ttl: u64 = response.trim().parse().unwrap();
+    assert!((98..=100).contains(&ttl), "{}", ttl);
+    
+    let response = send_command(&mut client, &format!("PEXPIREAT key {}\n", now.as_millis() + 5000));
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "PTTL key\n");
+    let pttl: u64 = response.trim().parse().unwrap();
+    assert!((4000..=5000).contains(&pttl), "{}", pttl);
+    
+    // A time in the past deletes the key straight away
+    let response = send_command(&mut client, &format!("PEXPIREAT key {}\n", now.as_millis() - 1000));
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "DBSIZE\n");
+    assert_eq!(response, "0\n");
+    
+    send_command(&mut client, "SET key value\n");
+    let response = send_command(&mut client, "EXPIREAT key -5\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "EXISTS key\n");
+    assert_eq!(response, "0\n");
+    
+    let response = send_command(&mut client, &format!("EXPIREAT missing {}\n", now.as_secs() + 100));
+    assert_eq!(response, "0\n");
+    for command in ["EXPIREAT key soon", "EXPIREAT key 9223372036854775807", "PEXPIREAT key 1.5"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+    
+    // A relative TTL too large for the clock is refused the same way
+    send_command(&mut client, "SET key value\n");
+    let response = send_command(&mut client, "EXPIRE key 18446744073709551615\n");
+    assert_eq!(response, "ERROR: invalid expire time in 'expire' command\n");
+    let response = send_command(&mut client, "TTL key\n");
+    assert_eq!(response, "-1\n");
+}
+
+#[test]
+fn test_transaction_runs_past_failing_command() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "MULTI\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "SET a 1\n");
+    assert_eq!(response, "QUEUED\n");
+    
+    // Valid when queued, but the duration is rejected when it runs
+    send_command(&mut client, "SETEX b 0 2\n");
+    send_command(&mut client, "SET c 3\n");
+    send_command(&mut client, "KEYS [ac]\n");
+    
+    // Nothing runs before EXEC
+    let mut other = server.client();
+    let response = send_command(&mut other, "GET a\n");
+    assert_eq!(response, "(nil)\n");
+    
+    let response = send_command(&mut client, "EXEC\n");
+    let lines: Vec<&str> = response.lines().collect();
+    assert_eq!(lines.len(), 5, "{}", response);
+    assert_eq!(lines[0], "OK");
+    assert!(lines[1].starts_with("ERROR"), "{}", response);
+    assert_eq!(lines[2], "OK");
+    
+    // The failure didn't undo or stop the other commands
+    let response = send_command(&mut client, "GET a\n");
+    assert_eq!(response, "1\n");
+    let response = send_command(&mut client, "GET c\n");
+    assert_eq!(response, "3\n");
+    let response = send_command(&mut client, "EXISTS b\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_transaction_discard_and_abort() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "EXEC\n");
+    assert!(response.starts_with("ERROR"));
+    
+    send_command(&mut client, "MULTI\n");
+    send_command(&mut client, "SET a 1\n");
+    let response = send_command(&mut client, "DISCARD\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "GET a\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // An unknown command is refused when queued and aborts the whole transaction
+    send_command(&mut client, "MULTI\n");
+    send_command(&mut client, "SET a 1\n");
+    let response = send_command(&mut client, "NOSUCHCOMMAND\n");
+    assert!(response.starts_with("ERROR"));
+    let response = send_command(&mut client, "EXEC\n");
+    assert!(response.contains("EXECABORT"), "{}", response);
+    let response = send_command(&mut client, "GET a\n");
+    assert_eq!(response, "(nil)\n");
+    
+    send_command(&mut client, "MULTI\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "(empty list)\n");
+}
+
+#[test]
+fn test_watch() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let mut other = server.client();
+    
+    // Another client modifying the key makes EXEC abort
+    send_command(&mut client, "SET counter 1\n");
+    let response = send_command(&mut client, "WATCH counter\n");
+    assert_eq!(response, "OK\n");
+    send_command(&mut other, "SET counter 2\n");
+    send_command(&mut client, "MULTI\n");
+    send_command(&mut client, "SET counter 10\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "GET counter\n");
+    assert_eq!(response, "2\n");
+    
+    // EXEC ended the watch, so the next transaction runs; reads of the key
+    // and the client's own changes before MULTI don't count
+    send_command(&mut client, "WATCH counter missing\n");
+    send_command(&mut other, "GET counter\n");
+    send_command(&mut client, "MULTI\n");
+    send_command(&mut client, "SET counter 10\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "OK\n");
+    
+    // Creating a missing key counts as modifying it
+    send_command(&mut client, "WATCH missing\n");
+    send_command(&mut other, "SET missing value\n");
+    send_command(&mut client, "MULTI\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // Commands that turn out to change nothing, or fail on the type, don't
+    // count as modifying the key
+    send_command(&mut client, "SADD set member\n");
+    send_command(&mut client, "WATCH set\n");
+    let response = send_command(&mut other, "SREM set absent\n");
+    assert_eq!(response, "0\n");
+    send_command(&mut other, "HDEL set field\n");
+    send_command(&mut client, "MULTI\n");
+    send_command(&mut client, "SCARD set\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "1\n");
+    
+    // Removing a member does
+    send_command(&mut client, "WATCH set\n");
+    send_command(&mut other, "SREM set member\n");
+    send_command(&mut client, "MULTI\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // UNWATCH and DISCARD forget the watched keys
+    send_command(&mut client, "WATCH counter\n");
+    let response = send_command(&mut client, "UNWATCH\n");
+    assert_eq!(response, "OK\n");
+    send_command(&mut other, "DEL counter\n");
+    send_command(&mut client, "MULTI\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "(empty list)\n");
+    
+    send_command(&mut client, "WATCH missing\n");
+    send_command(&mut client, "MULTI\n");
+    send_command(&mut client, "DISCARD\n");
+    send_command(&mut other, "FLUSHDB\n");
+    send_command(&mut client, "MULTI\n");
+    let response = send_command(&mut client, "WATCH missing\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "EXEC\n");
+    assert_eq!(response, "(empty list)\n");
+}
+
+#[test]
+fn test_client_id_and_name() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let mut other = server.client();
+    
+    // IDs are unique and stay the same for the connection's lifetime
+    let id = send_command(&mut client, "CLIENT ID\n");
+    assert!(id.trim().parse::<u64>().is_ok(), "{}", id);
+    assert_ne!(send_command(&mut other, "CLIENT ID\n"), id);
+    assert_eq!(send_command(&mut client, "CLIENT ID\n"), id);
+    
+    let response = send_command(&mut client, "CLIENT GETNAME\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "CLIENT SETNAME worker-1\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "CLIENT GETNAME\n");
+    assert_eq!(response, "worker-1\n");
+    
+    // The name belongs to the connection alone
+    let response = send_command(&mut other, "CLIENT GETNAME\n");
+    assert_eq!(response, "(nil)\n");
+    let mut fresh = server.client();
+    let response = send_command(&mut fresh, "CLIENT GETNAME\n");
+    assert_eq!(response, "(nil)\n");
+    
+    // Names with spaces or newlines are refused and leave the old name in
+    // place; RESP is needed to send them as one argument
+    let refused = "-ERR Client names cannot contain spaces, newlines or special characters.\r\n";
+    send_resp(&mut client, &["CLIENT", "SETNAME", "two words"], refused);
+    send_resp(&mut client, &["CLIENT", "SETNAME", "two\nlines"], refused);
+    send_resp(&mut client, &["CLIENT", "GETNAME"], "$8\r\nworker-1\r\n");
+    
+    // An empty name removes it, as does RESET
+    send_resp(&mut client, &["CLIENT", "SETNAME", ""], "+OK\r\n");
+    let response = send_command(&mut client, "CLIENT GETNAME\n");
+    assert_eq!(response, "(nil)\n");
+    send_command(&mut client, "CLIENT SETNAME worker-1\n");
+    send_command(&mut client, "RESET\n");
+    let response = send_command(&mut client, "CLIENT GETNAME\n");
+    assert_eq!(response, "(nil)\n");
+    assert_eq!(send_command(&mut client, "CLIENT ID\n"), id);
+}
+
+#[test]
+fn test_client_list_and_kill() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let mut other = server.client();
+    send_command(&mut other, "CLIENT SETNAME victim\n");
+    send_command(&mut other, "SELECT 3\n");
+    let client_id = send_command(&mut client, "CLIENT ID\n").trim().to_string();
+    let other_id = send_command(&mut other, "CLIENT ID\n").trim().to_string();
+    
+    // One line per client, oldest first
+    let response = send_command(&mut client, "CLIENT LIST\n");
+    let lines: Vec<&str> = response.lines().collect();
+    assert_eq!(lines, [
+        format!("id={} addr={} name= age=0 db=0 blocked=", client_id, client.local_addr().unwrap()),
+        format!("id={} addr={} name=victim age=0 db=3 blocked=", other_id, other.local_addr().unwrap()),
+    ]);
+    
+    let response = send_command(&mut client, "CLIENT KILL ID 999999\n");
+    assert_eq!(response, "0\n");
+    let response = send_command(&mut client, &format!("CLIENT KILL ID {}\n", other_id));
+    assert_eq!(response, "1\n");
+    
+    // The killed connection is closed and gone from the list
+    let mut buffer = [0; 16];
+    assert_eq!(other.read(&mut buffer).unwrap(), 0);
+    let response = send_command(&mut client, "CLIENT LIST\n");
+    assert_eq!(response.lines().count(), 1, "{}", response);
+    
+    // Killing by address, here the client's own, still answers first
+    let mut third = server.client();
+    let command = format!("CLIENT KILL ADDR {}\n", third.local_addr().unwrap());
+    let response = send_command(&mut third, &command);
+    assert_eq!(response, "1\n");
+    assert_eq!(third.read(&mut buffer).unwrap(), 0);
+    let response = send_command(&mut client, "CLIENT KILL NAME victim\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_quit() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // The commands pipelined after QUIT are never run
+    client.write_all(b"SET before 1\nQUIT\nSET after 1\n").unwrap();
+    let mut replies = String::new();
+    client.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "OK\nOK\n");
+    
+    let mut other = server.client();
+    let response = send_command(&mut other, "EXISTS before after\n");
+    assert_eq!(response, "1\n");
+    
+    // Also from inside a transaction, without running it
+    send_command(&mut other, "MULTI\n");
+    send_command(&mut other, "SET queued 1\n");
+    let response = send_command(&mut other, "QUIT\n");
+    assert_eq!(response, "OK\n");
+    let mut buffer = [0; 16];
+    assert_eq!(other.read(&mut buffer).unwrap(), 0);
+    
+    let mut client = server.client();
+    let response = send_command(&mut client, "EXISTS queued\n");
+    assert_eq!(response, "0\n");
+}
+
+#[test]
+fn test_echo() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "ECHO hello\n");
+    assert_eq!(response, "hello\n");
+    
+    // Spaces and line breaks only survive as one argument in RESP
+    send_resp(&mut client, &["ECHO", "hello world\r\nagain"], "$18\r\nhello world\r\nagain\r\n");
+    send_resp(&mut client, &["ECHO", ""], "$0\r\n\r\n");
+    
+    let response = send_command(&mut client, "ECHO\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut client, "ECHO two words\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_time() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "TIME\n");
+    let parts: Vec<u64> = response.lines().map(|part| part.parse().unwrap()).collect();
+    assert_eq!(parts.len(), 2, "{}", response);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    assert!(parts[0].abs_diff(now) <= 5, "{} vs {}", parts[0], now);
+    assert!(parts[1] < 1_000_000, "{}", parts[1]);
+    
+    let response = send_command(&mut client, "TIME now\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+}
+
+#[test]
+fn test_lolwut() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let response = send_command(&mut client, "LOLWUT\n");
+    assert!(!response.starts_with("ERROR"), "{}", response);
+    assert!(response.contains(&format!("Redis ver. {}", env!("CARGO_PKG_VERSION"))), "{}", response);
+    let response = send_command(&mut client, "LOLWUT VERSION 5\n");
+    assert!(response.contains("Redis ver."), "{}", response);
+    
+    for command in ["LOLWUT VERSION", "LOLWUT VERSION five", "LOLWUT 5"] {
+        let response = send_command(&mut client, &format!("{}\n", command));
+        assert!(response.starts_with("ERROR"), "{}: {}", command, response);
+    }
+}
+
+#[test]
+fn test_hello() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let id = send_command(&mut client, "CLIENT ID\n").trim().to_string();
+    
+    let fields = |proto: u8| {
+        let version = env!("CARGO_PKG_VERSION");
+        format!(
+            "{}\r\n$6\r\nserver\r\n$5\r\nredis\r\n$7\r\nversion\r\n${}\r\n{}\r\n$5\r\nproto\r\n:{}\r\n$2\r\nid\r\n:{}\r\n\
+             $4\r\nmode\r\n$10\r\nstandalone\r\n$4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            if proto == 3 { "%7" } else { "*14" }, version.len(), version, proto, id,
+        )
+    };
+    send_resp(&mut client, &["HELLO", "2"], &fields(2));
+    send_resp(&mut client, &["GET", "missing"], "$-1\r\n");
+    
+    // RESP3 from the HELLO reply on, which is a map, until RESET
+    send_resp(&mut client, &["HELLO", "3", "AUTH", "default", "anything"], &fields(3));
+    send_resp(&mut client, &["GET", "missing"], "_\r\n");
+    send_resp(&mut client, &["HELLO"], &fields(3));
+    send_resp(&mut client, &["RESET"], "+RESET\r\n");
+    send_resp(&mut client, &["GET", "missing"], "$-1\r\n");
+    
+    // A failed HELLO changes nothing
+    send_resp(&mut client, &["HELLO", "4"], "-NOPROTO unsupported protocol version\r\n");
+    send_resp(&mut client, &["HELLO", "3", "AUTH", "someone", "secret"], "-WRONGPASS invalid username-password pair or user is disabled.\r\n");
+    send_resp(&mut client, &["HELLO", "3", "SETNAME"], "-ERR Syntax error in HELLO option 'SETNAME'\r\n");
+    send_resp(&mut client, &["GET", "missing"], "$-1\r\n");
+}
+
+#[test]
+fn test_auth() {
+    let server = TestServer::with_env(&[("REDIS_REQUIREPASS", "secret")]);
+    let mut client = server.client();
+    
+    // Until it authenticates a client can only authenticate, or leave
+    send_resp(&mut client, &["GET", "key"], "-NOAUTH Authentication required.\r\n");
+    send_resp(&mut client, &["PING"], "-NOAUTH Authentication required.\r\n");
+    send_resp(&mut client, &["HELLO", "3"], "-NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time\r\n");
+    send_resp(&mut client, &["AUTH", "wrong"], "-WRONGPASS invalid username-password pair or user is disabled.\r\n");
+    send_resp(&mut client, &["AUTH", "someone", "secret"], "-WRONGPASS invalid username-password pair or user is disabled.\r\n");
+    send_resp(&mut client, &["AUTH", "secret"], "+OK\r\n");
+    send_resp(&mut client, &["SET", "key", "value"], "+OK\r\n");
+    let id = send_command(&mut client, "CLIENT ID\n").trim().to_string();
+    
+    // RESET logs it out again, and HELLO can log it back in
+    send_resp(&mut client, &["RESET"], "+RESET\r\n");
+    send_resp(&mut client, &["GET", "key"], "-NOAUTH Authentication required.\r\n");
+    send_resp(&mut client, &["HELLO", "2", "AUTH", "default", "wrong"], "-WRONGPASS invalid username-password pair or user is disabled.\r\n");
+    let version = env!("CARGO_PKG_VERSION");
+    let hello = format!(
+        "*14\r\n$6\r\nserver\r\n$5\r\nredis\r\n$7\r\nversion\r\n${}\r\n{}\r\n$5\r\nproto\r\n:2\r\n$2\r\nid\r\n:{}\r\n\
+         $4\r\nmode\r\n$10\r\nstandalone\r\n$4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+        version.len(), version, id,
+    );
+    send_resp(&mut client, &["HELLO", "2", "AUTH", "default", "secret"], &hello);
+    send_resp(&mut client, &["GET", "key"], "$5\r\nvalue\r\n");
+    
+    // AUTH isn't shown to monitors, so its password doesn't leak
+    let mut other = server.client();
+    send_resp(&mut client, &["MONITOR"], "+OK\r\n");
+    send_resp(&mut other, &["AUTH", "secret"], "+OK\r\n");
+    send_resp(&mut other, &["ECHO", "hi"], "$2\r\nhi\r\n");
+    let mut line = String::new();
+    std::io::BufReader::new(&mut client).read_line(&mut line).unwrap();
+    assert!(line.ends_with("\"ECHO\" \"hi\"\r\n"), "{}", line);
+    drop(client);
+    let mut client = server.client();
+    send_resp(&mut client, &["AUTH", "secret"], "+OK\r\n");
+    
+    // Once the password is cleared, clients that never authenticated are let in
+    let mut fresh = server.client();
+    send_resp(&mut client, &["CONFIG", "SET", "requirepass", ""], "+OK\r\n");
+    send_resp(&mut fresh, &["GET", "key"], "$5\r\nvalue\r\n");
+    send_resp(&mut fresh, &["AUTH", "anything"], "-ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?\r\n");
+    send_resp(&mut fresh, &["AUTH", "default", "anything"], "+OK\r\n");
+}
+
+#[test]
+fn test_resp3_replies() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    send_command(&mut client, "HSET hash field value\n");
+    send_command(&mut client, "ZADD zset 1.5 member\n");
+    
+    // RESP2 flattens maps into arrays and sends doubles as bulk strings
+    send_resp(&mut client, &["HGETALL", "hash"], "*2\r\n$5\r\nfield\r\n$5\r\nvalue\r\n");
+    send_resp(&mut client, &["CONFIG", "GET", "maxmemory"], "*2\r\n$9\r\nmaxmemory\r\n$1\r\n0\r\n");
+    send_resp(&mut client, &["ZSCORE", "zset", "member"], "$3\r\n1.5\r\n");
+    
+    let response = send_command(&mut client, &resp_command(&["HELLO", "3"]));
+    assert!(response.starts_with("%7\r\n"), "{}", response);
+    
+    send_resp(&mut client, &["HGETALL", "hash"], "%1\r\n$5\r\nfield\r\n$5\r\nvalue\r\n");
+    send_resp(&mut client, &["HGETALL", "missing"], "%0\r\n");
+    send_resp(&mut client, &["CONFIG", "GET", "maxmemory"], "%1\r\n$9\r\nmaxmemory\r\n$1\r\n0\r\n");
+    send_resp(&mut client, &["ZSCORE", "zset", "member"], ",1.5\r\n");
+    
+    // Plain text shows a map the same way in either protocol
+    let response = send_command(&mut client, "HGETALL hash\n");
+    assert_eq!(response, "field\nvalue\n");
+}
+
+#[test]
+fn test_client_tracking() {
+    let server = TestServer::new();
+    let mut writer = server.client();
+    let expect = |stream: &mut TcpStream, expected: &str| {
+        let mut response = vec![0; expected.len()];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(String::from_utf8_lossy(&response), expected);
+    };
+    
+    // In RESP3, invalidations are pushed on the tracking connection itself,
+    // for the keys it read and only once until it reads them again
+    let mut cache = server.client();
+    send_command(&mut cache, &resp_command(&["HELLO", "3"]));
+    send_resp(&mut cache, &["CLIENT", "TRACKING", "ON"], "+OK\r\n");
+    send_command(&mut writer, "SET a 1\n");
+    send_resp(&mut cache, &["GET", "a"], "$1\r\n1\r\n");
+    send_command(&mut writer, "SET unread 1\n");
+    send_command(&mut writer, "SET a 2\n");
+    send_command(&mut writer, "SET a 3\n");
+    expect(&mut cache, ">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\na\r\n");
+    
+    // A flush invalidates everything at once, read or not
+    send_command(&mut writer, "FLUSHALL\n");
+    expect(&mut cache, ">2\r\n$10\r\ninvalidate\r\n_\r\n");
+    send_resp(&mut cache, &["CLIENT", "TRACKING", "OFF"], "+OK\r\n");
+    
+    // In RESP2 they go to another client subscribed to __redis__:invalidate
+    let mut listener = server.client();
+    let listener_id = send_command(&mut listener, "CLIENT ID\n").trim().to_string();
+    send_resp(&mut listener, &["SUBSCRIBE", "__redis__:invalidate"], "*3\r\n$9\r\nsubscribe\r\n$20\r\n__redis__:invalidate\r\n:1\r\n");
+    let mut reader = server.client();
+    let response = send_command(&mut reader, "CLIENT TRACKING ON\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut reader, "CLIENT TRACKING ON REDIRECT 999999\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    let response = send_command(&mut reader, &format!("CLIENT TRACKING ON REDIRECT {}\n", listener_id));
+    assert_eq!(response, "OK\n");
+    
+    send_command(&mut writer, "SET b 1\n");
+    send_command(&mut reader, "GET b\n");
+    send_command(&mut reader, "EXISTS b c\n");
+    send_command(&mut writer, "DEL b c\n");
+    expect(&mut listener, "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$1\r\nb\r\n");
+    send_command(&mut writer, "FLUSHDB\n");
+    expect(&mut listener, "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n$-1\r\n");
+}
+
+#[test]
+fn test_reset() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_command(&mut client, "SELECT 1\n");
+    send_command(&mut client, "SET elsewhere value\n");
+    send_command(&mut client, "MULTI\n");
+    let response = send_command(&mut client, "SET a 1\n");
+    assert_eq!(response, "QUEUED\n");
+    
+    let response = send_command(&mut client, "RESET\n");
+    assert_eq!(response, "RESET\n");
+    
+    // Out of the transaction, and back in database 0
+    let response = send_command(&mut client, "SET a 1\n");
+    assert_eq!(response, "OK\n");
+    let response = send_command(&mut client, "GET elsewhere\n");
+    assert_eq!(response, "(nil)\n");
+    let response = send_command(&mut client, "EXEC\n");
+    assert!(response.starts_with("ERROR"), "{}", response);
+    
+    // A subscriber may reset too, which ends its subscriptions
+    send_resp(&mut client, &["SUBSCRIBE", "news"], "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+    send_resp(&mut client, &["RESET"], "+RESET\r\n");
+    let response = send_command(&mut server.client(), "PUBLISH news hello\n");
+    assert_eq!(response, "0\n");
+    send_resp(&mut client, &["GET", "a"], "$1\r\n1\r\n");
+}
+
+#[test]
+fn test_resp_protocol() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // Arguments are binary safe, so they may contain spaces
+    send_resp(&mut client, &["SET", "greeting", "hello world"], "+OK\r\n");
+    send_resp(&mut client, &["GET", "greeting"], "$11\r\nhello world\r\n");
+    send_resp(&mut client, &["GET", "missing"], "$-1\r\n");
+    send_resp(&mut client, &["EXISTS", "greeting"], ":1\r\n");
+    send_resp(&mut client, &["KEYS", "greet*"], "*1\r\n$8\r\ngreeting\r\n");
+    send_resp(&mut client, &["SCAN", "0"], "*2\r\n$1\r\n0\r\n*1\r\n$8\r\ngreeting\r\n");
+    send_resp(&mut client, &["GET"], "-ERR wrong number of arguments for 'get' command\r\n");
+    
+    // Inline commands still get text replies on the same connection
+    let response = send_command(&mut client, "GET greeting\n");
+    assert_eq!(response, "hello world\n");
+    
+    // Input that isn't valid RESP closes the connection after an error
+    client.write_all(b"*1\r\n:1\r\n").unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "-ERR Protocol error: expected an array of bulk strings\r\n");
+}
+
+// Send a RESP command given as raw bytes and read a reply of the expected length
+fn send_raw(stream: &mut TcpStream, command: &[u8], expected: &[u8]) {
+    stream.write_all(command).unwrap();
+    
+    let mut response = vec![0; expected.len()];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(response, expected, "{}", String::from_utf8_lossy(command));
+}
+
+#[test]
+fn test_binary_values() {
+    let dir = temp_dir("binary");
+    let env = [
+        ("REDIS_DIR", dir.to_str().unwrap()),
+        ("REDIS_APPENDONLY", "yes"),
+    ];
+    
+    {
+        let server = TestServer::with_env(&env);
+        let mut client = server.client();
+        
+        // Values are stored and returned as the bytes sent, valid UTF-8 or not
+        send_raw(&mut client, b"*3\r\n$3\r\nSET\r\n$3\r\nbin\r\n$3\r\n\xff\x00\xfe\r\n", b"+OK\r\n");
+        send_raw(&mut client, b"*2\r\n$3\r\nGET\r\n$3\r\nbin\r\n", b"$3\r\n\xff\x00\xfe\r\n");
+        send_raw(&mut client, b"*4\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$1\r\n\xc3\r\n$1\r\n\xa9\r\n", b":2\r\n");
+        send_raw(&mut client, b"*4\r\n$4\r\nHSET\r\n$4\r\nhash\r\n$5\r\nfield\r\n$1\r\n\x80\r\n", b":1\r\n");
+        send_raw(&mut client, b"*3\r\n$4\r\nHGET\r\n$4\r\nhash\r\n$5\r\nfield\r\n", b"$1\r\n\x80\r\n");
+        
+        // Keys and other arguments are text, but one that isn't valid UTF-8
+        // is refused without closing the connection
+        send_raw(&mut client, b"*2\r\n$3\r\nGET\r\n$1\r\n\xff\r\n", b"-ERR invalid UTF-8 in an argument of 'get' command\r\n");
+        send_raw(&mut client, b"*4\r\n$4\r\nHSET\r\n$4\r\nhash\r\n$1\r\n\xff\r\n$1\r\nv\r\n", b"-ERR invalid UTF-8 in an argument of 'hset' command\r\n");
+        send_resp(&mut client, &["PING"], "+PONG\r\n");
+    }
+    
+    // The append-only file keeps the bytes too
+    let server = TestServer::with_env(&env);
+    let mut client = server.client();
+    send_raw(&mut client, b"*2\r\n$3\r\nGET\r\n$3\r\nbin\r\n", b"$3\r\n\xff\x00\xfe\r\n");
+    send_raw(&mut client, b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$1\r\n0\r\n$2\r\n-1\r\n", b"*2\r\n$1\r\n\xc3\r\n$1\r\n\xa9\r\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_error_replies() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // RESP clients get error frames, with ERR unless a more specific code applies
+    send_resp(&mut client, &["GET"], "-ERR wrong number of arguments for 'get' command\r\n");
+    send_resp(&mut client, &["NOSUCHCOMMAND", "x"], "-ERR unknown command 'NOSUCHCOMMAND'\r\n");
+    send_resp(&mut client, &["MULTI"], "+OK\r\n");
+    send_resp(&mut client, &["NOSUCHCOMMAND"], "-ERR unknown command 'NOSUCHCOMMAND'\r\n");
+    send_resp(&mut client, &["EXEC"], "-EXECABORT Transaction discarded because of previous errors\r\n");
+    // So does a command with the wrong number of arguments
+    send_resp(&mut client, &["MULTI"], "+OK\r\n");
+    send_resp(&mut client, &["SET", "key"], "-ERR wrong number of arguments for 'set' command\r\n");
+    send_resp(&mut client, &["EXEC"], "-EXECABORT Transaction discarded because of previous errors\r\n");
+    
+    // A failing command inside EXEC is an error element of the results
+    send_resp(&mut client, &["MULTI"], "+OK\r\n");
+    send_resp(&mut client, &["SETEX", "key", "0", "value"], "+QUEUED\r\n");
+    send_resp(&mut client, &["EXEC"], "*1\r\n-ERR invalid expire time in 'setex' command\r\n");
+    
+    // The connection survives an unknown command in either protocol
+    send_resp(&mut client, &["PING"], "+PONG\r\n");
+    let response = send_command(&mut client, "NOSUCHCOMMAND\n");
+    assert_eq!(response, "ERROR: unknown command 'NOSUCHCOMMAND'\n");
+    let response = send_command(&mut client, "PING\n");
+    assert_eq!(response, "PONG\n");
+}
+
+#[test]
+fn test_idle_clients_are_disconnected() {
+    let server = TestServer::with_env(&[("REDIS_TIMEOUT", "1")]);
+    let mut idle = server.client();
+    let mut active = server.client();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    
+    // Commands reset the timer, so a client that keeps talking stays connected
+    for _ in 0..3 {
+        sleep(Duration::from_millis(500));
+        let response = send_command(&mut active, "PING\n");
+        assert_eq!(response, "PONG\n");
+    }
+    
+    // By now the silent client has been closed
+    let started = Instant::now();
+    let mut buf = [0; 16];
+    assert_eq!(idle.read(&mut buf).unwrap(), 0);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    
+    // With the timeout turned off, idle clients are left alone
+    let response = send_command(&mut active, "CONFIG SET timeout 0\n");
+    assert_eq!(response, "OK\n");
+    let mut patient = server.client();
+    sleep(Duration::from_millis(1500));
+    let response = send_command(&mut patient, "PING\n");
+    assert_eq!(response, "PONG\n");
+}
+
+#[test]
+fn test_maxclients_rejects_extra_connections() {
+    let server = TestServer::with_env(&[("REDIS_MAXCLIENTS", "2")]);
+    let mut first = server.client();
+    // The connection that found the server up counts until the server sees
+    // it closed, so wait for that before taking the last slot
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !send_command(&mut first, "INFO clients\n").contains("connected_clients:1\n") {
+        assert!(Instant::now() < deadline, "The startup check's connection was still counted");
+        sleep(Duration::from_millis(20));
+    }
+    let mut second = server.client();
+    send_command(&mut first, "PING\n");
+    send_command(&mut second, "PING\n");
+    
+    // One over the limit gets an error and is closed
+    let mut extra = server.client();
+    let mut response = String::new();
+    extra.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "-ERR max number of clients reached\r\n");
+    
+    // The clients already connected are unaffected
+    let response = send_command(&mut first, "INFO stats\n");
+    assert!(response.contains("rejected_connections:1\n"), "{}", response);
+    
+    // A slot frees up once a client leaves
+    drop(second);
+    sleep(Duration::from_millis(200));
+    let mut replacement = server.client();
+    let response = send_command(&mut replacement, "PING\n");
+    assert_eq!(response, "PONG\n");
+}
+
+#[test]
+fn test_eval() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    // The script's commands run as if the client had sent them
+    send_resp(&mut client, &["EVAL", "return redis.call('SET', KEYS[1], ARGV[1])", "1", "key", "value"], "+OK\r\n");
+    send_resp(&mut client, &["GET", "key"], "$5\r\nvalue\r\n");
+    
+    // Replies turn into Lua values and back: nil into false, which replies nil
+    let script = "local value = redis.call('GET', KEYS[1]) return {value, #value, redis.call('EXISTS', 'missing'), redis.call('GET', 'missing')}";
+    send_resp(&mut client, &["EVAL", script, "1", "key"], "*4\r\n$5\r\nvalue\r\n:5\r\n:0\r\n$-1\r\n");
+    send_resp(&mut client, &["EVAL", "return tonumber(ARGV[1]) * 2.5", "0", "3"], ":7\r\n");
+    
+    // A command's error is raised as it is, unless redis.pcall catches it
+    let wrong_type = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+    send_resp(&mut client, &["EVAL", "return redis.call('LPUSH', KEYS[1], 'x')", "1", "key"], wrong_type);
+    send_resp(&mut client, &["EVAL", "local reply = redis.pcall('LPUSH', KEYS[1], 'x') return reply.err ~= nil", "1", "key"], ":1\r\n");
+    send_resp(&mut client, &["EVAL", "return redis.call('NOSUCH')", "0"], "-ERR Unknown Redis command called from script\r\n");
+    send_resp(&mut client, &["EVAL", "return redis.call('MULTI')", "0"], "-ERR Unknown Redis command called from script\r\n");
+    send_resp(&mut client, &["EVAL", "return redis.call('GET')", "0"], "-ERR Wrong number of args calling Redis command from script\r\n");
+    send_resp(&mut client, &["EVAL", "return 1 +", "0"], "-ERR Error compiling script (new function): user_script:1: unexpected symbol near <eof>\r\n");
+    send_resp(&mut client, &["EVAL", "return 1", "2", "a"], "-ERR Number of keys can't be greater than number of args\r\n");
+    send_resp(&mut client, &["EVAL", "return missing", "0"], "-ERR Script attempted to access nonexistent global variable 'missing'\r\n");
+    
+    // A SELECT in the script leaves the client's database alone
+    send_resp(&mut client, &["EVAL", "redis.call('SELECT', 1) return redis.call('SET', 'other', 'db1')", "0"], "+OK\r\n");
+    send_resp(&mut client, &["EXISTS", "other"], ":0\r\n");
+    
+    // Scripts are queued in a transaction like any command
+    send_resp(&mut client, &["MULTI"], "+OK\r\n");
+    send_resp(&mut client, &["EVAL", "return redis.call('DEL', KEYS[1])", "1", "key"], "+QUEUED\r\n");
+    send_resp(&mut client, &["EXEC"], "*1\r\n:1\r\n");
+}
+
+#[test]
+fn test_function_load_and_fcall() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    let library = "#!lua name=counters\nredis.register_function('bump', function(keys, args) return redis.call('HINCRBY', keys[1], 'count', args[1]) end)";
+    send_resp(&mut client, &["FUNCTION", "LOAD", library], "$8\r\ncounters\r\n");
+    send_resp(&mut client, &["FUNCTION", "LOAD", library], "-ERR Library 'counters' already exists\r\n");
+    
+    // Each call runs the function afresh against the current data
+    send_resp(&mut client, &["FCALL", "bump", "1", "stats", "2"], ":2\r\n");
+    send_resp(&mut client, &["FCALL", "bump", "1", "stats", "3"], ":5\r\n");
+    send_resp(&mut client, &["HGET", "stats", "count"], "$1\r\n5\r\n");
+    send_resp(&mut client, &["FCALL", "missing", "0"], "-ERR Function not found\r\n");
+    
+    send_resp(
+        &mut client,
+        &["FUNCTION", "LIST"],
+        "*1\r\n*6\r\n$12\r\nlibrary_name\r\n$8\r\ncounters\r\n$6\r\nengine\r\n$3\r\nLUA\r\n$9\r\nfunctions\r\n*1\r\n*4\r\n$4\r\nname\r\n$4\r\nbump\r\n$11\r\ndescription\r\n$-1\r\n",
+    );
+    send_resp(&mut client, &["FUNCTION", "LIST", "LIBRARYNAME", "other*"], "*0\r\n");
+    
+    // EVAL scripts can't register functions
+    send_resp(
+        &mut client,
+        &["EVAL", "redis.register_function('f', function() end)", "0"],
+        "-ERR redis.register_function can only be called while a function library loads\r\n",
+    );
+    
+    // A replaced library's functions are the ones called from then on
+    let replacement = "#!lua name=counters\nredis.register_function('bump', function(keys) return redis.call('HDEL', keys[1], 'count') end)";
+    send_resp(&mut client, &["FUNCTION", "LOAD", "REPLACE", replacement], "$8\r\ncounters\r\n");
+    send_resp(&mut client, &["FCALL", "bump", "1", "stats"], ":1\r\n");
+    send_resp(&mut client, &["FUNCTION", "DELETE", "counters"], "+OK\r\n");
+    send_resp(&mut client, &["FCALL", "bump", "1", "stats"], "-ERR Function not found\r\n");
+}
+
+#[test]
+fn test_script_kill() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_resp(&mut client, &["SCRIPT", "KILL"], "-NOTBUSY No scripts in execution right now.\r\n");
+    send_resp(&mut client, &["CONFIG", "SET", "busy-reply-threshold", "100"], "+OK\r\n");
+    
+    // Past the threshold, other clients are told a script that never ends is
+    // holding them up, and can kill it
+    let mut looping = server.client();
+    looping.write_all(resp_command(&["EVAL", "while true do end", "0"]).as_bytes()).unwrap();
+    sleep(Duration::from_millis(300));
+    send_resp(&mut client, &["GET", "key"], "-BUSY Redis is busy running a script. You can only call SCRIPT KILL.\r\n");
+    send_resp(&mut client, &["FUNCTION", "KILL"], "-NOTBUSY No scripts in execution right now.\r\n");
+    send_resp(&mut client, &["SCRIPT", "KILL"], "+OK\r\n");
+    send_resp(&mut looping, &["PING"], "-ERR Script killed by user with SCRIPT KILL...\r\n+PONG\r\n");
+    send_resp(&mut client, &["GET", "key"], "$-1\r\n");
+    
+    // A script that has written can't be killed, as its writes would be half done
+    let mut looping = server.client();
+    looping.write_all(resp_command(&["EVAL", "redis.call('SET', 'key', 1) while true do end", "0"]).as_bytes()).unwrap();
+    sleep(Duration::from_millis(300));
+    send_resp(
+        &mut client,
+        &["SCRIPT", "KILL"],
+        "-UNKILLABLE Sorry the script already executed write commands against the dataset. You can only wait for it to finish.\r\n",
+    );
+}
+
+#[test]
+fn test_encodings_switch_past_the_limits() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    
+    send_resp(&mut client, &["CONFIG", "SET", "hash-max-listpack-entries", "4"], "+OK\r\n");
+    send_resp(&mut client, &["CONFIG", "GET", "hash-max-listpack-entries"], "*2\r\n$25\r\nhash-max-listpack-entries\r\n$1\r\n4\r\n");
+    for i in 0..4 {
+        send_resp(&mut client, &["HSET", "hash", &format!("field:{}", i), "value"], ":1\r\n");
+    }
+    send_resp(&mut client, &["OBJECT", "ENCODING", "hash"], "$8\r\nlistpack\r\n");
+    send_resp(&mut client, &["HSET", "hash", "field:4", "value"], ":1\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "hash"], "$9\r\nhashtable\r\n");
+    
+    // Like Redis, a hash table stays one even once it's small again
+    send_resp(&mut client, &["HDEL", "hash", "field:0", "field:1"], ":2\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "hash"], "$9\r\nhashtable\r\n");
+    send_resp(&mut client, &["HGET", "hash", "field:4"], "$5\r\nvalue\r\n");
+    
+    // A long enough value makes a hash table by itself
+    send_resp(&mut client, &["HSET", "long", "field", &"x".repeat(65)], ":1\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "long"], "$9\r\nhashtable\r\n");
+    
+    // Sets of integers start out as an intset
+    send_resp(&mut client, &["CONFIG", "SET", "set-max-listpack-entries", "3"], "+OK\r\n");
+    send_resp(&mut client, &["SADD", "set", "1", "2"], ":2\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "set"], "$6\r\nintset\r\n");
+    send_resp(&mut client, &["SADD", "set", "apple"], ":1\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "set"], "$8\r\nlistpack\r\n");
+    send_resp(&mut client, &["SADD", "set", "banana"], ":1\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "set"], "$9\r\nhashtable\r\n");
+    send_resp(&mut client, &["SCARD", "set"], ":4\r\n");
+    
+    // Lists take an element past the packed threshold as a quicklist
+    send_resp(&mut client, &["RPUSH", "list", "short"], ":1\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "list"], "$8\r\nlistpack\r\n");
+    send_resp(&mut client, &["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "4"], "+OK\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "list"], "$9\r\nquicklist\r\n");
+    send_resp(&mut client, &["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "1k"], "+OK\r\n");
+    send_resp(&mut client, &["OBJECT", "ENCODING", "list"], "$8\r\nlistpack\r\n");
+}
+
+#[test]
+fn test_wrong_type_errors() {
+    let server = TestServer::new();
+    let mut client = server.client();
+    let wrong_type = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+    
+    send_resp(&mut client, &["SET", "string", "value"], "+OK\r\n");
+    send_resp(&mut client, &["RPUSH", "list", "a"], ":1\r\n");
+    send_resp(&mut client, &["HSET", "hash", "field", "value"], ":1\r\n");
+    send_resp(&mut client, &["SADD", "set", "member"], ":1\r\n");
+    send_resp(&mut client, &["ZADD", "zset", "1", "member"], ":1\r\n");
+    
+    // Reading or changing a key as the wrong kind of value fails the same way
+    for args in [
+        &["GET", "list"][..],
+        &["APPEND", "hash", "x"],
+        &["LPUSH", "string", "x"],
+        &["LRANGE", "set", "0", "-1"],
+        &["HGET", "zset", "field"],
+        &["HSET", "list", "field", "value"],
+        &["SADD", "hash", "x"],
+        &["SMEMBERS", "string"],
+        &["SMOVE", "set", "list", "member"],
+        &["ZADD", "set", "1", "x"],
+        &["ZSCORE", "hash", "member"],
+        &["LMOVE", "list", "set", "LEFT", "LEFT"],
+    ] {
+        send_resp(&mut client, args, wrong_type);
+    }
+    
+    // Nothing changed, and a transaction carries on past the error
+    send_resp(&mut client, &["LRANGE", "list", "0", "-1"], "*1\r\n$1\r\na\r\n");
+    send_resp(&mut client, &["SMEMBERS", "set"], "*1\r\n$6\r\nmember\r\n");
+    send_resp(&mut client, &["MULTI"], "+OK\r\n");
+    send_resp(&mut client, &["LLEN", "string"], "+QUEUED\r\n");
+    send_resp(&mut client, &["GET", "string"], "+QUEUED\r\n");
+    send_resp(&mut client, &["EXEC"], &format!("*2\r\n{}$5\r\nvalue\r\n", wrong_type));
+}